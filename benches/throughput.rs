@@ -0,0 +1,113 @@
+//! Bytes per second through one direction of a forward, between a loopback
+//! source and sink, at various buffer sizes. Run with `cargo bench`, the
+//! meter of the metered runs also prints its rates on stdout.
+//!
+//! With criterion on a single core VM, copying took 1.3 GiB/s with 4k
+//! buffers, 1.8-1.9 GiB/s with 16k-32k and 2.1 GiB/s from 64k on, while
+//! splicing levels off at 2.4-2.6 GiB/s from 32k, hence the 64k default.
+//! Sending every 16th chunk to the meter rather than every chunk took
+//! metered copying with 4k buffers from 1.0 to 1.1 GiB/s, about the noise of
+//! the VM. From 16k on, metering costs little either way.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_portforward::{
+    Config::Source,
+    ConnHandle::{forward_stream, next_conn_id, ConnOptions, PeerAddr, StreamMeter},
+    Forwarder::Forwarder,
+    Meter::{Direction, MeterFormat, MeterGranularity, MeterMessageSender},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+const LOCALHOST: &str = "127.0.0.1:0";
+/// The bytes sent through the forward per iteration
+const TRANSFER_SIZE: usize = 64 * 1024 * 1024;
+const WRITE_SIZE: usize = 64 * 1024;
+const BUFF_SIZES: [usize; 5] = [4 * 1024, 16 * 1024, 32 * 1024, 64 * 1024, 256 * 1024];
+
+/// Send `TRANSFER_SIZE` bytes from a source through `forward_stream` to a sink
+async fn transfer(options: &ConnOptions, meter_msg_sender: MeterMessageSender) {
+    let sink = TcpListener::bind(LOCALHOST).await.unwrap();
+    let sink_addr = sink.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await.unwrap();
+        let mut buff = vec![0; WRITE_SIZE];
+        let mut n_bytes = 0;
+        loop {
+            match stream.read(&mut buff).await.unwrap() {
+                0 => return n_bytes,
+                n => n_bytes += n,
+            }
+        }
+    });
+    let proxy = TcpListener::bind(LOCALHOST).await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+
+    let source = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let chunk = vec![0x5a; WRITE_SIZE];
+        for _ in 0..TRANSFER_SIZE / WRITE_SIZE {
+            stream.write_all(&chunk).await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+    });
+    let (client, peer) = proxy.accept().await.unwrap();
+    let target = TcpStream::connect(sink_addr).await.unwrap();
+    let (client_read, _client_write) = client.into_split();
+    let (_target_read, target_write) = target.into_split();
+    let meter = StreamMeter {
+        meter_msg_sender,
+        conn_id: next_conn_id().await,
+        peer: PeerAddr::Tcp(peer),
+        forward: Source::Port(proxy_addr.port()),
+        direction: Direction::From,
+    };
+    forward_stream(client_read, target_write, meter, options)
+        .await
+        .unwrap();
+    source.await.unwrap();
+    assert_eq!(received.await.unwrap(), TRANSFER_SIZE);
+}
+
+fn throughput(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let forwarder = runtime.block_on(async {
+        Forwarder::with_meter_options(MeterFormat::Human, MeterGranularity::Forward)
+    });
+    let mut group = c.benchmark_group("forward_stream");
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.sample_size(10);
+    for splice in [false, true] {
+        for metered in [false, true] {
+            let name = format!(
+                "{} {}",
+                if splice { "splice" } else { "copy" },
+                if metered { "metered" } else { "unmetered" }
+            );
+            let meter_msg_sender = match metered {
+                true => forwarder.meter_msg_sender(),
+                false => MeterMessageSender::disabled(),
+            };
+            for buff_size in BUFF_SIZES {
+                let options = ConnOptions {
+                    buff_size,
+                    splice,
+                    ..ConnOptions::default()
+                };
+                let id = BenchmarkId::new(&name, format!("{}k", buff_size / 1024));
+                group.bench_function(id, |b| {
+                    b.to_async(&runtime)
+                        .iter(|| transfer(&options, meter_msg_sender.clone()))
+                });
+            }
+        }
+    }
+    group.finish();
+    runtime.block_on(forwarder.shutdown()).unwrap();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,126 @@
+use std::{
+    fs::OpenOptions,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{channel, Receiver, Sender},
+    task::JoinHandle,
+};
+
+use crate::{Config::Source, ConnHandle::PeerAddr};
+
+const ACCESS_LOG_CHN_BUFF_SIZE: usize = 1024;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A closed connection as written to the access log
+pub struct AccessLogEntry<'a> {
+    pub peer: &'a PeerAddr,
+    pub forward: &'a Source,
+    pub target: Option<std::net::SocketAddr>,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+}
+
+/// Appends a Common Log Format line per connection to a file. Lines are
+/// written by a background task and flushed whenever it catches up.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    sender: Sender<String>,
+}
+
+impl AccessLog {
+    /// Open `path` for appending, the returned task finishes once every
+    /// clone of the access log is dropped and the last lines are flushed
+    pub fn open(path: &str) -> Result<(AccessLog, JoinHandle<()>), std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = channel(ACCESS_LOG_CHN_BUFF_SIZE);
+        let writer = BufWriter::new(File::from_std(file));
+        let join_handle = tokio::spawn(write_loop(writer, receiver));
+        Ok((AccessLog { sender }, join_handle))
+    }
+
+    /// Log a closed connection as
+    /// `client - - [time] "CONNECT target forward" - dl_bytes ul_bytes duration_secs`
+    pub async fn log(&self, entry: AccessLogEntry<'_>) {
+        let client = match entry.peer {
+            PeerAddr::Tcp(addr) => addr.ip().to_canonical().to_string(),
+            PeerAddr::Unix(_) => "-".to_string(),
+        };
+        let target = match entry.target {
+            Some(target) => target.to_string(),
+            None => "-".to_string(),
+        };
+        let line = format!(
+            "{} - - [{}] \"CONNECT {} {}\" - {} {} {:.3}\n",
+            client,
+            format_clf_time(entry.started),
+            target,
+            entry.forward,
+            entry.to_n_bytes,
+            entry.from_n_bytes,
+            entry.duration.as_secs_f64()
+        );
+        if self.sender.send(line).await.is_err() {
+            warn!("Access log is closed, dropping entry");
+        }
+    }
+}
+
+async fn write_loop(mut writer: BufWriter<File>, mut receiver: Receiver<String>) {
+    while let Some(mut line) = receiver.recv().await {
+        // Write every queued line before flushing them together
+        loop {
+            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                warn!("Failed to write access log: {}", e);
+            }
+            line = match receiver.try_recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+        }
+        if let Err(e) = writer.flush().await {
+            warn!("Failed to flush access log: {}", e);
+        }
+    }
+}
+
+/// Format a time as `10/Oct/2000:13:55:36 +0000`
+fn format_clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days as i64);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Convert days since the epoch to a civil `(year, month, day)`
+pub(crate) fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
@@ -0,0 +1,117 @@
+use std::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+/// Allocates the forwarding buffers, e.g. from an arena or while accounting
+/// for them
+pub trait BufferProvider: Debug + Send + Sync {
+    /// A buffer of `buff_size` bytes for one direction of a connection
+    fn obtain(&self, buff_size: usize) -> Vec<u8>;
+
+    /// Take back a buffer obtained from this provider, once it is no longer
+    /// used or kept in a pool
+    fn release(&self, buff: Vec<u8>) {
+        drop(buff);
+    }
+}
+
+/// Allocates every buffer on the heap and frees it once released
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapBuffers;
+
+impl BufferProvider for HeapBuffers {
+    fn obtain(&self, buff_size: usize) -> Vec<u8> {
+        vec![0; buff_size]
+    }
+}
+
+/// A bounded pool of equally sized forwarding buffers
+#[derive(Debug, Clone)]
+pub struct BufferPool(Arc<PoolInner>);
+
+#[derive(Debug)]
+struct PoolInner {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buff_size: usize,
+    capacity: usize,
+    provider: Arc<dyn BufferProvider>,
+}
+
+impl BufferPool {
+    pub fn new(buff_size: usize, capacity: usize) -> Self {
+        Self::with_provider(buff_size, capacity, Arc::new(HeapBuffers))
+    }
+
+    /// Create a pool whose buffers are obtained from and released to `provider`
+    pub fn with_provider(
+        buff_size: usize,
+        capacity: usize,
+        provider: Arc<dyn BufferProvider>,
+    ) -> Self {
+        BufferPool(Arc::new(PoolInner {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            buff_size,
+            capacity,
+            provider,
+        }))
+    }
+
+    /// The size in bytes of every buffer of the pool
+    pub fn buff_size(&self) -> usize {
+        self.0.buff_size
+    }
+
+    /// Check out a buffer, obtaining a new one if the pool is empty
+    pub fn take(&self) -> PooledBuffer {
+        let pooled = self.0.buffers.lock().unwrap().pop();
+        let buff = pooled.unwrap_or_else(|| self.0.provider.obtain(self.0.buff_size));
+        PooledBuffer {
+            buff,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, buff: Vec<u8>) {
+        let mut buffers = self.0.buffers.lock().unwrap();
+        match buffers.len() < self.0.capacity {
+            true => buffers.push(buff),
+            false => self.0.provider.release(buff),
+        }
+    }
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        for buff in self.buffers.get_mut().unwrap().drain(..) {
+            self.provider.release(buff);
+        }
+    }
+}
+
+/// A buffer that goes back to its pool when dropped
+pub struct PooledBuffer {
+    buff: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.buff
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buff
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buff));
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::timeout,
+};
+
+use crate::{Config::Source, Meter::MeterMessageSender};
+
+/// Sent by the instance compressing toward its target and echoed back by
+/// the instance it connects to, the last byte being the version of the
+/// framing: LZ4 blocks of at most `MAX_BLOCK_SIZE` bytes
+const HELLO: [u8; 5] = *b"RPFZ\x01";
+/// How long the other instance may take to answer the hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most payload bytes in one frame
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+/// Set in the frame header when the block is stored as is, as compressing
+/// it did not make it smaller
+const STORED_FLAG: u32 = 1 << 31;
+
+/// Ask the instance at the other end of `stream` to decompress what is sent
+/// to it, which it agrees to by sending the hello back
+pub async fn connect_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(), io::Error> {
+    stream.write_all(&HELLO).await?;
+    let mut reply = [0; HELLO.len()];
+    read_hello(stream, &mut reply).await?;
+    if reply != HELLO {
+        return Err(handshake_error(
+            "the target did not agree to compress, it must be an instance with --compress client",
+        ));
+    }
+    Ok(())
+}
+
+/// Agree to the compression a client instance asks for
+pub async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(), io::Error> {
+    let mut hello = [0; HELLO.len()];
+    read_hello(stream, &mut hello).await?;
+    if hello != HELLO {
+        return Err(handshake_error(
+            "the client did not ask to compress, it must be an instance with --compress target",
+        ));
+    }
+    stream.write_all(&HELLO).await
+}
+
+async fn read_hello<S: AsyncRead + Unpin>(stream: &mut S, hello: &mut [u8]) -> io::Result<()> {
+    match timeout(HANDSHAKE_TIMEOUT, stream.read_exact(hello)).await {
+        Ok(read) => read.map(drop),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the other instance did not answer the compression hello in time",
+        )),
+    }
+}
+
+fn handshake_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Counts the compressed bytes of a forward as they go over the wire
+#[derive(Clone)]
+pub struct WireMeter {
+    pub meter_msg_sender: MeterMessageSender,
+    pub forward: Source,
+}
+
+/// Compresses what is written into frames of the inner stream, each the
+/// length of its block as a big endian u32 followed by the block. A write
+/// takes its bytes once they are framed, the frame is written out before
+/// the next write, flush or shutdown completes.
+pub struct Compressor<W> {
+    inner: W,
+    meter: WireMeter,
+    /// The frame being written and how much of it is
+    frame: Vec<u8>,
+    written: usize,
+}
+
+impl<W> Compressor<W> {
+    pub fn new(inner: W, meter: WireMeter) -> Self {
+        Compressor {
+            inner,
+            meter,
+            frame: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Compressor<W> {
+    /// Write what is left of the frame being written
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.frame.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.frame[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        if !self.frame.is_empty() {
+            let _ = self
+                .meter
+                .meter_msg_sender
+                .wire(self.meter.forward.clone(), self.frame.len());
+            self.frame.clear();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Compressor<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let accepted = buf.len().min(MAX_BLOCK_SIZE);
+        encode_frame(&buf[..accepted], &mut this.frame);
+        this.written = 0;
+        // The bytes are taken even if the frame cannot be written out yet
+        match this.poll_write_frame(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Ready(Ok(())) | Poll::Pending => Poll::Ready(Ok(accepted)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads the frames of a [`Compressor`] from the inner stream, decompressed
+pub struct Decompressor<R> {
+    inner: R,
+    meter: WireMeter,
+    /// The frames read but not decompressed yet
+    frames: Vec<u8>,
+    /// The block decompressed last and how much of it was read
+    block: Vec<u8>,
+    read: usize,
+}
+
+impl<R> Decompressor<R> {
+    pub fn new(inner: R, meter: WireMeter) -> Self {
+        Decompressor {
+            inner,
+            meter,
+            frames: Vec::new(),
+            block: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Decompressor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.block.len() {
+                let n = buf.remaining().min(this.block.len() - this.read);
+                buf.put_slice(&this.block[this.read..this.read + n]);
+                this.read += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            // Decompress the next frame once it is whole
+            if let Some(header) = this.frames.get(..4) {
+                let header = u32::from_be_bytes(header.try_into().unwrap());
+                let len = (header & !STORED_FLAG) as usize;
+                if len > MAX_BLOCK_SIZE {
+                    return Poll::Ready(Err(invalid("a compressed frame is too large")));
+                }
+                if this.frames.len() >= 4 + len {
+                    let data = &this.frames[4..4 + len];
+                    match header & STORED_FLAG != 0 {
+                        true => {
+                            this.block.clear();
+                            this.block.extend_from_slice(data);
+                        }
+                        false => decompress_block(data, &mut this.block)?,
+                    }
+                    this.read = 0;
+                    let _ = this
+                        .meter
+                        .meter_msg_sender
+                        .wire(this.meter.forward.clone(), 4 + len);
+                    this.frames.drain(..4 + len);
+                    continue;
+                }
+            }
+
+            let mut chunk = [0; 16 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(match this.frames.is_empty() {
+                    true => Ok(()),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the stream ended within a compressed frame",
+                    )),
+                });
+            }
+            this.frames.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Frame `payload` as an LZ4 block, or as is if that is not smaller
+fn encode_frame(payload: &[u8], frame: &mut Vec<u8>) {
+    frame.clear();
+    frame.resize(
+        4 + lz4_flex::block::get_maximum_output_size(payload.len()),
+        0,
+    );
+    let header = match lz4_flex::block::compress_into(payload, &mut frame[4..]) {
+        Ok(len) if len < payload.len() => {
+            frame.truncate(4 + len);
+            len as u32
+        }
+        _ => {
+            frame.truncate(4);
+            frame.extend_from_slice(payload);
+            payload.len() as u32 | STORED_FLAG
+        }
+    };
+    frame[..4].copy_from_slice(&header.to_be_bytes());
+}
+
+/// Decompress the LZ4 block `input` into `out`, which holds at most
+/// `MAX_BLOCK_SIZE` bytes
+fn decompress_block(input: &[u8], out: &mut Vec<u8>) -> Result<(), io::Error> {
+    out.resize(MAX_BLOCK_SIZE, 0);
+    match lz4_flex::block::decompress_into(input, out) {
+        Ok(len) => {
+            out.truncate(len);
+            Ok(())
+        }
+        Err(_) => {
+            out.clear();
+            Err(invalid("a compressed block is corrupt"))
+        }
+    }
+}
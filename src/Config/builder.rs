@@ -0,0 +1,870 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use super::{
+    available_threads, CircuitBreakerConfig, CompressMode, Config, Forward, IpFamily, MaxConnsMode,
+    Protocol, TargetPolicy, BUFF_SIZE_RANGE, DEFAULT_BIND_RETRY_DELAY_MS, DEFAULT_BUFFER_POOL_SIZE,
+    DEFAULT_BUFF_SIZE, DEFAULT_CAPTURE_MAX_KB, DEFAULT_CONNECT_BACKOFF_MS,
+    DEFAULT_HAPPY_EYEBALLS_DELAY_MS, DEFAULT_HEALTH_CHECK_TIMEOUT_SECS,
+    DEFAULT_POOL_IDLE_TIMEOUT_SECS, DEFAULT_QUIT_COMMAND, DEFAULT_STATS_PERSIST_INTERVAL_SECS,
+    DEFAULT_UDP_MAX_DATAGRAM, DEFAULT_UDP_TIMEOUT_SECS, MAX_BUFF_SIZE,
+};
+use crate::{
+    HttpProxy::HttpProxy,
+    Meter::{MeterFormat, MeterGranularity, DEFAULT_METER_CAPACITY},
+    Socks5::Socks5Proxy,
+};
+
+/// Assembles a [`Config`] in code, every option not set keeps the default
+/// of the command line
+pub struct ConfigBuilder {
+    /// Not validated until built
+    pub(crate) config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder {
+            config: Config {
+                forwards: Vec::new(),
+                buffer_size: DEFAULT_BUFF_SIZE,
+                buffer_up: None,
+                buffer_down: None,
+                buffer_pool_size: DEFAULT_BUFFER_POOL_SIZE,
+                n_thread: available_threads(),
+                max_conns: None,
+                max_conns_mode: MaxConnsMode::Reject,
+                max_conns_per_ip: None,
+                udp_timeout: Duration::from_secs(DEFAULT_UDP_TIMEOUT_SECS),
+                udp_max_flows: None,
+                udp_max_datagram: DEFAULT_UDP_MAX_DATAGRAM,
+                idle_timeout: None,
+                write_timeout: None,
+                coalesce_delay: None,
+                coalesce_bytes: None,
+                compress: None,
+                max_lifetime: None,
+                max_bytes_per_conn: None,
+                max_inflight_kb: None,
+                total_rate_kbps: None,
+                accept_rate: None,
+                slow_start: None,
+                circuit_breaker: None,
+                target_policy: TargetPolicy::default(),
+                health_check_interval: None,
+                health_check_timeout: Duration::from_secs(DEFAULT_HEALTH_CHECK_TIMEOUT_SECS),
+                pool_size: None,
+                pool_idle_timeout: Duration::from_secs(DEFAULT_POOL_IDLE_TIMEOUT_SECS),
+                drain_timeout: None,
+                nodelay: false,
+                keepalive: None,
+                keepalive_interval: None,
+                linger: None,
+                rcvbuf: None,
+                sndbuf: None,
+                reuse_port: false,
+                socket_activation: false,
+                strict_bind: false,
+                bind_retries: 0,
+                bind_retry_delay: Duration::from_millis(DEFAULT_BIND_RETRY_DELAY_MS),
+                once: false,
+                transparent: false,
+                bind_interface: None,
+                bind_interface_outbound: false,
+                source_addr: None,
+                socks5: None,
+                http_proxy: None,
+                capture_dir: None,
+                capture_max_kb: DEFAULT_CAPTURE_MAX_KB,
+                hexdump: None,
+                access_log: None,
+                event_log_size: None,
+                events_json: false,
+                pidfile: None,
+                error_log: None,
+                metrics_addr: None,
+                statsd_addr: None,
+                stats_file: None,
+                stats_persist_interval: Duration::from_secs(DEFAULT_STATS_PERSIST_INTERVAL_SECS),
+                otel_endpoint: None,
+                dual_stack: false,
+                resolve_on_connect: false,
+                unresolved_hosts: Vec::new(),
+                sticky: false,
+                happy_eyeballs_delay: Some(Duration::from_millis(DEFAULT_HAPPY_EYEBALLS_DELAY_MS)),
+                connect_timeout: None,
+                connect_retries: 0,
+                connect_backoff: Duration::from_millis(DEFAULT_CONNECT_BACKOFF_MS),
+                dns_server: None,
+                dns_cache_ttl: None,
+                prefer: None,
+                proxy_protocol: false,
+                accept_proxy_protocol: false,
+                tls_cert: None,
+                tls_key: None,
+                splice: true,
+                meter: true,
+                meter_format: MeterFormat::default(),
+                meter_granularity: MeterGranularity::default(),
+                meter_hide_idle: false,
+                meter_show_totals: false,
+                meter_capacity: DEFAULT_METER_CAPACITY,
+                log_level: LevelFilter::Info,
+                quit_command: DEFAULT_QUIT_COMMAND.to_string(),
+                check: false,
+                print_config_json: false,
+                config_from_stdin: false,
+                forward_sources: None,
+            },
+        }
+    }
+
+    pub fn add_forward(mut self, forward: Forward) -> Self {
+        self.config.forwards.push(forward);
+        self
+    }
+
+    /// The buffer size of each connection direction in bytes
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// The client to target buffer size, `buffer_size` if not set
+    pub fn buffer_up(mut self, buffer_up: usize) -> Self {
+        self.config.buffer_up = Some(buffer_up);
+        self
+    }
+
+    /// The target to client buffer size, `buffer_size` if not set
+    pub fn buffer_down(mut self, buffer_down: usize) -> Self {
+        self.config.buffer_down = Some(buffer_down);
+        self
+    }
+
+    pub fn buffer_pool_size(mut self, buffer_pool_size: usize) -> Self {
+        self.config.buffer_pool_size = buffer_pool_size;
+        self
+    }
+
+    pub fn n_threads(mut self, n_thread: usize) -> Self {
+        self.config.n_thread = n_thread;
+        self
+    }
+
+    pub fn max_conns(mut self, max_conns: usize) -> Self {
+        self.config.max_conns = Some(max_conns);
+        self
+    }
+
+    pub fn max_conns_mode(mut self, mode: MaxConnsMode) -> Self {
+        self.config.max_conns_mode = mode;
+        self
+    }
+
+    pub fn max_conns_per_ip(mut self, max_conns_per_ip: usize) -> Self {
+        self.config.max_conns_per_ip = Some(max_conns_per_ip);
+        self
+    }
+
+    pub fn udp_timeout(mut self, udp_timeout: Duration) -> Self {
+        self.config.udp_timeout = udp_timeout;
+        self
+    }
+
+    pub fn udp_max_flows(mut self, udp_max_flows: usize) -> Self {
+        self.config.udp_max_flows = Some(udp_max_flows);
+        self
+    }
+
+    pub fn udp_max_datagram(mut self, n_bytes: usize) -> Self {
+        self.config.udp_max_datagram = n_bytes;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.config.write_timeout = Some(write_timeout);
+        self
+    }
+
+    pub fn coalesce_delay(mut self, delay: Duration) -> Self {
+        self.config.coalesce_delay = Some(delay);
+        self
+    }
+
+    pub fn coalesce_bytes(mut self, n_bytes: usize) -> Self {
+        self.config.coalesce_bytes = Some(n_bytes);
+        self
+    }
+
+    pub fn compress(mut self, mode: CompressMode) -> Self {
+        self.config.compress = Some(mode);
+        self
+    }
+
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.config.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    pub fn max_bytes_per_conn(mut self, max_bytes_per_conn: usize) -> Self {
+        self.config.max_bytes_per_conn = Some(max_bytes_per_conn);
+        self
+    }
+
+    pub fn max_inflight_kb(mut self, max_inflight_kb: usize) -> Self {
+        self.config.max_inflight_kb = Some(max_inflight_kb);
+        self
+    }
+
+    pub fn total_rate_kbps(mut self, total_rate_kbps: usize) -> Self {
+        self.config.total_rate_kbps = Some(total_rate_kbps);
+        self
+    }
+
+    /// Ramp the accept rate up over `duration`, see [`Config::slow_start`]
+    pub fn slow_start(mut self, duration: Duration) -> Self {
+        self.config.slow_start = Some(duration);
+        self
+    }
+
+    pub fn accept_rate(mut self, accept_rate: usize) -> Self {
+        self.config.accept_rate = Some(accept_rate);
+        self
+    }
+
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreakerConfig) -> Self {
+        self.config.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn target_policy(mut self, target_policy: TargetPolicy) -> Self {
+        self.config.target_policy = target_policy;
+        self
+    }
+
+    pub fn health_check(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.health_check_interval = Some(interval);
+        self.config.health_check_timeout = timeout;
+        self
+    }
+
+    /// Keep `size` connections to each target dialed ahead of the clients,
+    /// each kept for at most `idle_timeout`
+    pub fn pool(mut self, size: usize, idle_timeout: Duration) -> Self {
+        self.config.pool_size = Some(size);
+        self.config.pool_idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.config.drain_timeout = Some(drain_timeout);
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Duration) -> Self {
+        self.config.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn keepalive_interval(mut self, keepalive_interval: Duration) -> Self {
+        self.config.keepalive_interval = Some(keepalive_interval);
+        self
+    }
+
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.config.linger = Some(linger);
+        self
+    }
+
+    pub fn rcvbuf(mut self, rcvbuf: usize) -> Self {
+        self.config.rcvbuf = Some(rcvbuf);
+        self
+    }
+
+    pub fn sndbuf(mut self, sndbuf: usize) -> Self {
+        self.config.sndbuf = Some(sndbuf);
+        self
+    }
+
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.config.reuse_port = reuse_port;
+        self
+    }
+
+    pub fn socket_activation(mut self, socket_activation: bool) -> Self {
+        self.config.socket_activation = socket_activation;
+        self
+    }
+
+    pub fn strict_bind(mut self, strict_bind: bool) -> Self {
+        self.config.strict_bind = strict_bind;
+        self
+    }
+
+    pub fn bind_retries(mut self, retries: u32) -> Self {
+        self.config.bind_retries = retries;
+        self
+    }
+
+    pub fn bind_retry_delay(mut self, delay: Duration) -> Self {
+        self.config.bind_retry_delay = delay;
+        self
+    }
+
+    /// Stop accepting after the first connection and exit once it closes
+    pub fn once(mut self, once: bool) -> Self {
+        self.config.once = once;
+        self
+    }
+
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.config.transparent = transparent;
+        self
+    }
+
+    pub fn bind_interface(mut self, interface: impl Into<String>) -> Self {
+        self.config.bind_interface = Some(interface.into());
+        self
+    }
+
+    /// Connect to the targets out of the bind interface too
+    pub fn bind_interface_outbound(mut self, outbound: bool) -> Self {
+        self.config.bind_interface_outbound = outbound;
+        self
+    }
+
+    /// Connect to the targets from this local address
+    pub fn source_addr(mut self, source_addr: IpAddr) -> Self {
+        self.config.source_addr = Some(source_addr);
+        self
+    }
+
+    pub fn socks5(mut self, socks5: Socks5Proxy) -> Self {
+        self.config.socks5 = Some(socks5);
+        self
+    }
+
+    pub fn http_proxy(mut self, http_proxy: HttpProxy) -> Self {
+        self.config.http_proxy = Some(http_proxy);
+        self
+    }
+
+    pub fn capture(mut self, dir: impl Into<PathBuf>, max_kb: usize) -> Self {
+        self.config.capture_dir = Some(dir.into());
+        self.config.capture_max_kb = max_kb;
+        self
+    }
+
+    pub fn hexdump(mut self, max_len: usize) -> Self {
+        self.config.hexdump = Some(max_len);
+        self
+    }
+
+    pub fn access_log(mut self, path: impl Into<String>) -> Self {
+        self.config.access_log = Some(path.into());
+        self
+    }
+
+    pub fn event_log_size(mut self, size: usize) -> Self {
+        self.config.event_log_size = Some(size);
+        self
+    }
+
+    /// Print the opening and closing of every connection as JSON on stdout
+    pub fn events_json(mut self, events_json: bool) -> Self {
+        self.config.events_json = events_json;
+        self
+    }
+
+    pub fn pidfile(mut self, path: impl Into<String>) -> Self {
+        self.config.pidfile = Some(path.into());
+        self
+    }
+
+    pub fn error_log(mut self, path: impl Into<String>) -> Self {
+        self.config.error_log = Some(path.into());
+        self
+    }
+
+    pub fn metrics_addr(mut self, metrics_addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    pub fn statsd_addr(mut self, statsd_addr: SocketAddr) -> Self {
+        self.config.statsd_addr = Some(statsd_addr);
+        self
+    }
+
+    pub fn stats_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.stats_file = Some(path.into());
+        self
+    }
+
+    pub fn stats_persist_interval(mut self, interval: Duration) -> Self {
+        self.config.stats_persist_interval = interval;
+        self
+    }
+
+    pub fn otel_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.otel_endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn resolve_on_connect(mut self, resolve_on_connect: bool) -> Self {
+        self.config.resolve_on_connect = resolve_on_connect;
+        self
+    }
+
+    pub fn sticky(mut self, sticky: bool) -> Self {
+        self.config.sticky = sticky;
+        self
+    }
+
+    /// `None` tries the targets one at a time
+    pub fn happy_eyeballs_delay(mut self, delay: Option<Duration>) -> Self {
+        self.config.happy_eyeballs_delay = delay;
+        self
+    }
+
+    /// The default of the forwards that do not set their own
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// The default of the forwards that do not set their own
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.config.connect_retries = retries;
+        self
+    }
+
+    /// The default of the forwards that do not set their own
+    pub fn connect_backoff(mut self, backoff: Duration) -> Self {
+        self.config.connect_backoff = backoff;
+        self
+    }
+
+    pub fn dns_server(mut self, dns_server: SocketAddr) -> Self {
+        self.config.dns_server = Some(dns_server);
+        self
+    }
+
+    pub fn dns_cache_ttl(mut self, dns_cache_ttl: Duration) -> Self {
+        self.config.dns_cache_ttl = Some(dns_cache_ttl);
+        self
+    }
+
+    pub fn prefer(mut self, family: IpFamily) -> Self {
+        self.config.prefer = Some(family);
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    pub fn accept_proxy_protocol(mut self, accept_proxy_protocol: bool) -> Self {
+        self.config.accept_proxy_protocol = accept_proxy_protocol;
+        self
+    }
+
+    pub fn tls(mut self, cert: impl Into<String>, key: impl Into<String>) -> Self {
+        self.config.tls_cert = Some(cert.into());
+        self.config.tls_key = Some(key.into());
+        self
+    }
+
+    pub fn splice(mut self, splice: bool) -> Self {
+        self.config.splice = splice;
+        self
+    }
+
+    pub fn meter(mut self, meter: bool) -> Self {
+        self.config.meter = meter;
+        self
+    }
+
+    pub fn meter_format(mut self, meter_format: MeterFormat) -> Self {
+        self.config.meter_format = meter_format;
+        self
+    }
+
+    pub fn meter_granularity(mut self, meter_granularity: MeterGranularity) -> Self {
+        self.config.meter_granularity = meter_granularity;
+        self
+    }
+
+    pub fn meter_hide_idle(mut self, meter_hide_idle: bool) -> Self {
+        self.config.meter_hide_idle = meter_hide_idle;
+        self
+    }
+
+    pub fn meter_show_totals(mut self, meter_show_totals: bool) -> Self {
+        self.config.meter_show_totals = meter_show_totals;
+        self
+    }
+
+    /// The number of messages the meter channel holds, past which the
+    /// transfers are dropped instead of slowing the connections down
+    pub fn meter_capacity(mut self, meter_capacity: usize) -> Self {
+        self.config.meter_capacity = meter_capacity;
+        self
+    }
+
+    pub fn log_level(mut self, log_level: LevelFilter) -> Self {
+        self.config.log_level = log_level;
+        self
+    }
+
+    pub fn quit_command(mut self, quit_command: impl Into<String>) -> Self {
+        self.config.quit_command = quit_command.into();
+        self
+    }
+
+    pub fn check(mut self, check: bool) -> Self {
+        self.config.check = check;
+        self
+    }
+
+    pub fn print_config_json(mut self, print_config_json: bool) -> Self {
+        self.config.print_config_json = print_config_json;
+        self
+    }
+
+    pub fn config_from_stdin(mut self, config_from_stdin: bool) -> Self {
+        self.config.config_from_stdin = config_from_stdin;
+        self
+    }
+
+    /// Validate the options and sort the forwards by source
+    pub fn build(self) -> Result<Config, String> {
+        let mut config = self.config;
+
+        // Forwards
+        config.check_forwards(&config.forwards)?;
+
+        // Options
+        for size in [
+            Some(config.buffer_size),
+            config.buffer_up,
+            config.buffer_down,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if size == 0 || size > MAX_BUFF_SIZE {
+                return Err(format!(
+                    "{}b is not a valid buffer size, {}",
+                    size, BUFF_SIZE_RANGE
+                ));
+            }
+        }
+        if config.n_thread == 0 {
+            return Err("0 is not a valid number of threads".to_string());
+        }
+        if config.meter_capacity == 0 {
+            return Err("0 is not a valid meter capacity".to_string());
+        }
+        if config.quit_command.trim().is_empty() {
+            return Err("the quit command cannot be empty".to_string());
+        }
+        if config.keepalive_interval.is_some() && config.keepalive.is_none() {
+            return Err("--keepalive-interval requires --keepalive".to_string());
+        }
+        if config.coalesce_bytes.is_some() && config.coalesce_delay.is_none() {
+            return Err("--coalesce-bytes requires --coalesce-delay-ms".to_string());
+        }
+        // A pooled connection would wait on the handshake until used
+        if config.compress == Some(CompressMode::Target) && config.pool_size.is_some() {
+            return Err("--compress target cannot be used with --pool-size".to_string());
+        }
+        if config.max_conns_mode == MaxConnsMode::Queue && config.max_conns.is_none() {
+            return Err("--max-conns-mode queue requires --max-conns".to_string());
+        }
+        if config.slow_start.is_some() && config.accept_rate.is_none() {
+            return Err("--slow-start-duration requires --accept-rate".to_string());
+        }
+        if config.dns_cache_ttl.is_some() && !config.resolve_on_connect {
+            return Err("--dns-cache-ttl requires --resolve-on-connect".to_string());
+        }
+        #[cfg(not(unix))]
+        if config.reuse_port {
+            return Err("--reuse-port is only supported on unix".to_string());
+        }
+        #[cfg(not(unix))]
+        if config.socket_activation {
+            return Err("--socket-activation is only supported on unix".to_string());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.transparent {
+            return Err("--transparent is only supported on linux".to_string());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.bind_interface.is_some() {
+            return Err("--bind-interface is only supported on linux".to_string());
+        }
+        if config.bind_interface_outbound && config.bind_interface.is_none() {
+            return Err("--bind-interface-outbound requires --bind-interface".to_string());
+        }
+        if config.bind_interface.as_ref().is_some_and(|i| i.is_empty()) {
+            return Err("the bind interface cannot be empty".to_string());
+        }
+        if let Some(source_addr) = config.source_addr {
+            // Binding fails unless the address is assigned to the host
+            if let Err(e) = std::net::TcpListener::bind((source_addr, 0)) {
+                return Err(format!(
+                    "the source address {} cannot be used: {}",
+                    source_addr, e
+                ));
+            }
+        }
+        if config.socks5.is_some() && config.http_proxy.is_some() {
+            return Err("--socks5 and --http-proxy cannot be used together".to_string());
+        }
+        if config.metrics_addr.is_some() && !config.meter {
+            return Err("--metrics-addr cannot be used with --no-meter".to_string());
+        }
+        if config.statsd_addr.is_some() && !config.meter {
+            return Err("--statsd cannot be used with --no-meter".to_string());
+        }
+        if config.stats_file.is_some() && !config.meter {
+            return Err("--stats-file cannot be used with --no-meter".to_string());
+        }
+        if config.meter_hide_idle && !config.meter {
+            return Err("--meter-hide-idle cannot be used with --no-meter".to_string());
+        }
+        if config.meter_show_totals && !config.meter {
+            return Err("--meter-show-totals cannot be used with --no-meter".to_string());
+        }
+        if config.event_log_size == Some(0) {
+            return Err("0 is not a valid event log size".to_string());
+        }
+        if let Some(capture_dir) = &config.capture_dir {
+            if !Path::new(capture_dir).is_dir() {
+                return Err(format!("{} is not a directory", capture_dir.display()));
+            }
+        }
+
+        sort_forwards(&mut config.forwards, config.prefer);
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Validate the forwards against the other options
+    pub(super) fn check_forwards(&self, forwards: &[Forward]) -> Result<(), String> {
+        if forwards.is_empty() {
+            return Err("no forward list found".to_string());
+        }
+        for (i, forward) in forwards.iter().enumerate() {
+            if let Some(other) = forwards[..i].iter().find(|f| f.conflicts_with(forward)) {
+                return Err(format!(
+                    "{} conflicts with {}",
+                    forward.describe(),
+                    other.describe()
+                ));
+            }
+            if forward
+                .buffer_size
+                .is_some_and(|size| size == 0 || size > MAX_BUFF_SIZE)
+            {
+                return Err(format!(
+                    "The buffer size of {} is not valid, {}",
+                    forward.source, BUFF_SIZE_RANGE
+                ));
+            }
+            if forward.connect_timeout.is_some_and(|t| t.is_zero()) {
+                return Err(format!(
+                    "The connect timeout of {} is not valid",
+                    forward.source
+                ));
+            }
+            if forward.bind_addrs.is_empty() {
+                return Err(format!("{} has no bind address", forward.source));
+            }
+            if forward.protocol == Protocol::Udp && forward.bind_addrs.len() > 1 {
+                return Err(format!(
+                    "UDP cannot listen on several addresses on {}",
+                    forward.source
+                ));
+            }
+            if forward.lazy_connect && (forward.protocol == Protocol::Udp || forward.tls) {
+                return Err(format!(
+                    "Lazy connect cannot be used with UDP or TLS on {}",
+                    forward.source
+                ));
+            }
+            if forward.protocol == Protocol::Udp
+                && (forward.preamble.is_some() || forward.banner_len.is_some())
+            {
+                return Err(format!(
+                    "A preamble or banner cannot be used with UDP on {}",
+                    forward.source
+                ));
+            }
+            if forward.banner_len == Some(0) {
+                return Err(format!(
+                    "The banner length of {} is not valid",
+                    forward.source
+                ));
+            }
+            if forward.nodelay.is_some()
+                && forward.protocol == Protocol::Udp
+                && forward.target_protocol == Protocol::Udp
+            {
+                return Err(format!(
+                    "nodelay cannot be used with UDP on {}",
+                    forward.source
+                ));
+            }
+            if forward.is_bridged() {
+                let unsupported = [
+                    (forward.tls, "TLS"),
+                    (forward.upstream_tls.is_some(), "TLS to the targets"),
+                    (!forward.sni_routes.is_empty(), "SNI routing"),
+                    (forward.mirror.is_some(), "a mirror"),
+                    (forward.failure_response.is_some(), "a failure response"),
+                    (forward.lazy_connect, "lazy connect"),
+                    (forward.srv.is_some(), "an SRV target"),
+                    (forward.preamble.is_some(), "a preamble"),
+                    (forward.banner_len.is_some(), "a banner"),
+                ];
+                if let Some((_, feature)) = unsupported.iter().find(|(used, _)| *used) {
+                    return Err(format!(
+                        "{} cannot be bridged from {} to {} with {}",
+                        forward.source, forward.protocol, forward.target_protocol, feature
+                    ));
+                }
+            }
+            let routes = forward
+                .sni_routes
+                .values()
+                .chain(forward.ip_routes.iter().map(|(_, t)| t));
+            if let Some(target) = forward
+                .targets
+                .iter()
+                .chain(routes.flatten())
+                .find(|t| !self.target_policy.permits(t.ip()))
+            {
+                return Err(format!(
+                    "{} is a target of {} but not an allowed one",
+                    target, forward.source
+                ));
+            }
+            if forward.tls && self.tls_cert.is_none() {
+                return Err(format!(
+                    "TLS is enabled for {} but no certificate is given",
+                    forward.source
+                ));
+            }
+        }
+
+        if self.once && forwards.iter().any(|f| f.protocol == Protocol::Udp) {
+            return Err("--once cannot be used with UDP forwards".to_string());
+        }
+        if let Some(mode) = self.compress {
+            let flag = match mode {
+                CompressMode::Target => "--compress target",
+                CompressMode::Client => "--compress client",
+            };
+            // Both instances must see the same bytes before the handshake
+            // and only the framed ones after it
+            for forward in forwards
+                .iter()
+                .filter(|f| f.protocol == Protocol::Tcp && !f.is_bridged())
+            {
+                let unsupported = match mode {
+                    CompressMode::Target => vec![
+                        (forward.upstream_tls.is_some(), "TLS to the targets"),
+                        (!forward.sni_routes.is_empty(), "SNI routing"),
+                        (forward.lazy_connect, "lazy connect"),
+                        (forward.preamble.is_some(), "a preamble"),
+                        (forward.banner_len.is_some(), "a banner"),
+                    ],
+                    CompressMode::Client => vec![
+                        (forward.tls, "TLS"),
+                        (!forward.sni_routes.is_empty(), "SNI routing"),
+                        (forward.lazy_connect, "lazy connect"),
+                        (forward.failure_response.is_some(), "a failure response"),
+                    ],
+                };
+                if let Some((_, feature)) = unsupported.iter().find(|(used, _)| *used) {
+                    return Err(format!(
+                        "{} cannot be used with {}, which uses {}",
+                        flag, forward.source, feature
+                    ));
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        if let Some(f) = forwards
+            .iter()
+            .find(|f| matches!(f.source, super::Source::Unix(_)))
+        {
+            return Err(format!("{} is only supported on unix", f.source));
+        }
+        if self.socket_activation {
+            if let Some(f) = forwards
+                .iter()
+                .find(|f| matches!(f.source, super::Source::Unix(_)))
+            {
+                return Err(format!("--socket-activation cannot listen on {}", f.source));
+            }
+        }
+        if let Some(f) = forwards.iter().find(|f| f.is_bridged()) {
+            let unsupported = [
+                (self.socks5.is_some(), "--socks5"),
+                (self.http_proxy.is_some(), "--http-proxy"),
+                (self.proxy_protocol, "--proxy-protocol"),
+                (self.transparent, "--transparent"),
+            ];
+            if let Some((_, flag)) = unsupported.iter().find(|(used, _)| *used) {
+                return Err(format!(
+                    "{} cannot be used with {}, which is bridged",
+                    flag, f.source
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sort the forwards in ascending order of source port, Unix sockets last,
+/// trying the targets of the preferred family first
+pub(super) fn sort_forwards(forwards: &mut [Forward], prefer: Option<IpFamily>) {
+    if let Some(family) = prefer {
+        for forward in forwards.iter_mut() {
+            family.sort(&mut forward.targets);
+        }
+    }
+    forwards.sort_by(|a, b| (&a.source, a.protocol).cmp(&(&b.source, b.protocol)));
+}
@@ -0,0 +1,73 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+/// An IP network such as `10.0.0.0/8`, a bare address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network, IPv4-mapped IPv6 addresses count as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` bits of `a` and `b` are the same
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let (n_bytes, n_bits) = (prefix_len as usize / 8, prefix_len % 8);
+    if a[..n_bytes] != b[..n_bytes] {
+        return false;
+    }
+    if n_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - n_bits);
+    a[n_bytes] & mask == b[n_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = match addr.parse::<IpAddr>() {
+            Ok(addr) => addr.to_canonical(),
+            Err(_) => return Err(format!("{} is not a valid CIDR", s)),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len.map(str::parse::<u8>) {
+            None => max_len,
+            Some(Ok(len)) if len <= max_len => len,
+            Some(_) => return Err(format!("{} is not a valid CIDR", s)),
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Serialized the way it is displayed
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
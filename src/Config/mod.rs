@@ -1,79 +1,1736 @@
-use std::{fs, io::ErrorKind, net::SocketAddr};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    fmt::Display,
+    fs,
+    io::{self, ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use dns_lookup::lookup_host;
 use getopts::Options;
+use log::LevelFilter;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-const DEFAULT_BUFF_SIZE_KB: usize = 4;
-const DEFAULT_N_THREADS: usize = 4;
+use crate::{
+    Dns,
+    HttpProxy::HttpProxy,
+    Meter::{MeterFormat, MeterGranularity},
+    Socks5::Socks5Proxy,
+};
 
-#[derive(Debug)]
+mod builder;
+mod cidr;
+pub use builder::ConfigBuilder;
+pub use cidr::Cidr;
+
+/// Where the throughput of a bulk transfer stops growing, see the throughput
+/// benchmark
+const DEFAULT_BUFF_SIZE: usize = 64 * 1024;
+/// The largest buffer of a connection direction, every connection allocates its own
+const MAX_BUFF_SIZE: usize = 64 * 1024 * 1024;
+const BUFF_SIZE_RANGE: &str = "it must be between 1b and 64m";
+const DEFAULT_BUFFER_POOL_SIZE: usize = 64;
+const DEFAULT_QUIT_COMMAND: &str = "q";
+const DEFAULT_BREAKER_WINDOW_SECS: u64 = 10;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 30;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CAPTURE_MAX_KB: usize = 10 * 1024;
+const DEFAULT_DNS_PORT: u16 = 53;
+const DEFAULT_HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+const DEFAULT_CONNECT_BACKOFF_MS: u64 = 100;
+const DEFAULT_BIND_RETRY_DELAY_MS: u64 = 500;
+const DEFAULT_UDP_TIMEOUT_SECS: u64 = 30;
+/// The largest UDP payload
+const DEFAULT_UDP_MAX_DATAGRAM: usize = 65507;
+const DEFAULT_STATS_PERSIST_INTERVAL_SECS: u64 = 60;
+
+/// The number of threads used when none or `auto` is given, one per core
+pub fn available_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// The version of this build, printed by --version and at startup
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Forward lines merged after the command line and config file ones
+const CONFIG_ENV: &str = "PORTFORWARD_CONFIG";
+const STDIN_PATH: &str = "-";
+
+const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DUAL_STACK_BIND_ADDR: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// The listening side of a forward, which also identifies it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Source {
+    Port(u16),
+    Unix(Arc<Path>),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Port(port) => write!(f, "{}", port),
+            Source::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Serialized the way it is displayed
+impl Serialize for Source {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Serialize a value the way it is displayed
+fn serialize_display<T: Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Serialize the length of bytes that may hold a secret, such as a preamble
+fn serialize_len<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    bytes.as_ref().map(Vec::len).serialize(serializer)
+}
+
+/// Serialize the username of proxy credentials, leaving the password out
+pub(crate) fn serialize_username<S: Serializer>(
+    auth: &Option<(String, String)>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    auth.as_ref()
+        .map(|(username, _)| username)
+        .serialize(serializer)
+}
+
+impl<'de> Deserialize<'de> for Source {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Source::Unix(Path::new(path).into())),
+            None => s.parse().map(Source::Port).map_err(de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Forward {
-    pub s_port: u16,
-    pub target: SocketAddr,
+    pub source: Source,
+    /// The addresses a port source listens on, never empty
+    pub bind_addrs: Vec<IpAddr>,
+    pub targets: Vec<SocketAddr>,
+    pub target_hosts: Vec<String>,
+    pub protocol: Protocol,
+    /// The protocol spoken to the targets, a TCP client framing the
+    /// datagrams of a UDP target or the other way around if not `protocol`
+    pub target_protocol: Protocol,
+    pub name: Option<String>,
+    pub tls: bool,
+    /// The buffer size of both directions in bytes, the global one if not set
+    pub buffer_size: Option<usize>,
+    /// Where to send a copy of the client to target traffic
+    pub mirror: Option<SocketAddr>,
+    /// Written to the client when no target can be connected to
+    pub failure_response: Option<Vec<u8>>,
+    /// Balance the connections across the targets by these weights, every
+    /// address of a weighted target has its weight and the others 1. Empty
+    /// when no target is weighted, the targets are then tried in order.
+    pub weights: HashMap<SocketAddr, u32>,
+    /// The targets of TLS clients by lowercase SNI hostname, the other
+    /// clients go to `targets`
+    pub sni_routes: HashMap<String, Vec<SocketAddr>>,
+    /// The targets of clients by network, the first network with the client
+    /// wins and the other clients go to `targets`
+    pub ip_routes: Vec<(Cidr, Vec<SocketAddr>)>,
+    /// Speak TLS to the targets instead of forwarding the bytes as is
+    pub upstream_tls: Option<UpstreamTls>,
+    /// The SRV record the targets were looked up from, looked up again
+    /// while a TCP forward runs
+    pub srv: Option<String>,
+    /// How long a connect to a target may take, the global one if not set
+    pub connect_timeout: Option<Duration>,
+    /// How many more rounds the targets are tried when none could be
+    /// connected to, the global one if not set
+    pub connect_retries: Option<u32>,
+    /// The wait before the first retry, doubled before every next one, the
+    /// global one if not set
+    pub connect_backoff: Option<Duration>,
+    /// Wait for the first bytes of a client before connecting to a target,
+    /// only for protocols where the client speaks first
+    pub lazy_connect: bool,
+    /// Set TCP_NODELAY on the client and target sockets, the global
+    /// `--nodelay` if not set
+    pub nodelay: Option<bool>,
+    /// Written to every target right after connecting, before anything else,
+    /// for backends that expect a fixed token or magic bytes first
+    #[serde(serialize_with = "serialize_len")]
+    pub preamble: Option<Vec<u8>>,
+    /// The length of the banner every target sends first, read and dropped
+    /// before the target is forwarded to the client
+    pub banner_len: Option<usize>,
 }
 
-#[derive(Debug)]
+impl Forward {
+    /// A TCP forward from `bind` to `targets` tried in order, with every
+    /// other setting left unset. Port 0 lets the OS pick the port.
+    pub fn tcp(bind: SocketAddr, targets: Vec<SocketAddr>) -> Self {
+        Forward {
+            source: Source::Port(bind.port()),
+            bind_addrs: vec![bind.ip()],
+            target_hosts: targets.iter().map(SocketAddr::to_string).collect(),
+            targets,
+            protocol: Protocol::Tcp,
+            target_protocol: Protocol::Tcp,
+            name: None,
+            tls: false,
+            buffer_size: None,
+            mirror: None,
+            failure_response: None,
+            weights: HashMap::new(),
+            sni_routes: HashMap::new(),
+            ip_routes: Vec::new(),
+            upstream_tls: None,
+            srv: None,
+            connect_timeout: None,
+            connect_retries: None,
+            connect_backoff: None,
+            lazy_connect: false,
+            nodelay: None,
+            preamble: None,
+            banner_len: None,
+        }
+    }
+
+    /// A UDP forward from `bind` to the first of `targets`, like [`Forward::tcp`]
+    pub fn udp(bind: SocketAddr, targets: Vec<SocketAddr>) -> Self {
+        Forward {
+            protocol: Protocol::Udp,
+            target_protocol: Protocol::Udp,
+            ..Forward::tcp(bind, targets)
+        }
+    }
+
+    /// Whether the forward speaks another protocol to the targets than it
+    /// listens on
+    pub fn is_bridged(&self) -> bool {
+        self.protocol != self.target_protocol
+    }
+
+    /// Whether both forwards listen on the same source. Forwards are told
+    /// apart by their source and protocol, so the bind address does not count.
+    pub(crate) fn conflicts_with(&self, other: &Forward) -> bool {
+        self.protocol == other.protocol && self.source == other.source
+    }
+
+    /// Describe the forward in errors as `tcp 0.0.0.0:8080 -> web:80`
+    pub(crate) fn describe(&self) -> String {
+        let listen = match &self.source {
+            Source::Port(port) => self
+                .bind_addrs
+                .iter()
+                .map(|a| SocketAddr::new(*a, *port).to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            Source::Unix(_) => self.source.to_string(),
+        };
+        let targets = match &self.srv {
+            Some(srv) => format!("srv:{}", srv),
+            None => self.target_hosts.join(","),
+        };
+        match self.is_bridged() {
+            true => format!(
+                "{} {} -> {} {}",
+                self.protocol, listen, self.target_protocol, targets
+            ),
+            false => format!("{} {} -> {}", self.protocol, listen, targets),
+        }
+    }
+}
+
+/// The forwards read so far and where each was declared, so that a conflict
+/// names both sides
+#[derive(Default)]
+struct DeclaredForwards {
+    forwards: Vec<Forward>,
+    origins: Vec<String>,
+}
+
+impl DeclaredForwards {
+    /// Add the forwards declared at `origin`, failing on the first that
+    /// listens where an earlier one does
+    fn extend(&mut self, forwards: Vec<Forward>, origin: String) -> Result<(), String> {
+        for forward in forwards {
+            if let Some(i) = self
+                .forwards
+                .iter()
+                .position(|f| f.conflicts_with(&forward))
+            {
+                return Err(format!(
+                    "{} from {} conflicts with {} from {}",
+                    forward.describe(),
+                    origin,
+                    self.forwards[i].describe(),
+                    self.origins[i]
+                ));
+            }
+            self.forwards.push(forward);
+            self.origins.push(origin.clone());
+        }
+        Ok(())
+    }
+}
+
+/// How to open TLS to the targets of a forward
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UpstreamTls {
+    /// The certificate chain and key presented to targets that ask for one
+    pub client_cert: Option<(String, String)>,
+    /// The PEM bundle the targets are verified against, the system one if not given
+    pub ca: Option<String>,
+    /// The name the targets are verified as
+    pub server_name: String,
+}
+
+/// What a forward does with the clients past its connection limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaxConnsMode {
+    /// Accept and close them right away
+    #[default]
+    Reject,
+    /// Leave them in the listen backlog until a connection closes
+    Queue,
+}
+
+/// Which side of a forward `--compress` compresses, the two instances at
+/// either end of the compressed link each taking one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressMode {
+    /// Compress the stream to the targets, another instance
+    Target,
+    /// Decompress the stream from the clients, another instance
+    Client,
+}
+
+/// The address family tried first when a target resolves to both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn matches(self, ip: IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => ip.to_canonical().is_ipv4(),
+            IpFamily::V6 => ip.to_canonical().is_ipv6(),
+        }
+    }
+
+    /// Move the addresses of this family first, keeping the resolver order otherwise
+    pub fn sort(self, addrs: &mut [SocketAddr]) {
+        addrs.sort_by_key(|a| !self.matches(a.ip()));
+    }
+}
+
+impl Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Which target addresses may be connected to, for when targets are not
+/// fully static, such as with routing by SNI or a route callback
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct TargetPolicy {
+    /// Only these networks if any, on top of the private ones
+    pub allow: Vec<Cidr>,
+    /// Never these networks, even if allowed
+    pub deny: Vec<Cidr>,
+    /// Deny the loopback, private and link-local networks that are not allowed
+    pub deny_private: bool,
+}
+
+impl TargetPolicy {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty() || self.deny_private
+    }
+
+    /// Whether a target at `ip` may be connected to
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        if self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return true;
+        }
+        self.allow.is_empty() && !(self.deny_private && is_private(ip))
+    }
+}
+
+/// Whether `ip` is only reachable from the host or its own networks
+fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // The shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
+
+/// When to stop dialing a target that keeps failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive connect failures that open the breaker
+    pub threshold: u32,
+    /// Failures further apart than this do not count as consecutive
+    pub window: Duration,
+    /// How long the breaker stays open before a probe is let through
+    pub cooldown: Duration,
+}
+
+/// A forward entry of a JSON config file
+#[derive(Deserialize)]
+struct JsonForward {
+    name: Option<String>,
+    source_port: Option<u16>,
+    source_path: Option<PathBuf>,
+    bind_addr: Option<IpAddr>,
+    #[serde(default)]
+    bind_addrs: Vec<IpAddr>,
+    targets: Vec<String>,
+    #[serde(default)]
+    protocol: Protocol,
+    target_protocol: Option<Protocol>,
+    tls: Option<bool>,
+    buffer_size_kb: Option<usize>,
+    mirror: Option<String>,
+    failure_response: Option<String>,
+    #[serde(default)]
+    sni_routes: HashMap<String, String>,
+    #[serde(default)]
+    ip_routes: Vec<JsonIpRoute>,
+    upstream_tls: Option<JsonUpstreamTls>,
+    connect_timeout_secs: Option<u64>,
+    connect_retries: Option<u32>,
+    connect_backoff_ms: Option<u64>,
+    #[serde(default)]
+    lazy_connect: bool,
+    nodelay: Option<bool>,
+    preamble_hex: Option<String>,
+    preamble_file: Option<String>,
+    banner_len: Option<usize>,
+}
+
+/// A client network and its target, in a JSON forward
+#[derive(Deserialize)]
+struct JsonIpRoute {
+    cidr: String,
+    target: String,
+}
+
+/// The TLS to the targets, in a JSON forward
+#[derive(Deserialize)]
+struct JsonUpstreamTls {
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    ca: Option<String>,
+    server_name: Option<String>,
+}
+
+/// Serialized with every default filled in and every target resolved, the
+/// passwords of the proxies left out
+#[derive(Debug, Serialize)]
 pub struct Config {
     pub forwards: Vec<Forward>,
-    pub buffer_size_kb: usize,
+    /// In bytes, like the other buffer sizes
+    pub buffer_size: usize,
+    pub buffer_up: Option<usize>,
+    pub buffer_down: Option<usize>,
+    pub buffer_pool_size: usize,
     pub n_thread: usize,
+    pub max_conns: Option<usize>,
+    pub max_conns_mode: MaxConnsMode,
+    pub max_conns_per_ip: Option<usize>,
+    /// How long a UDP flow may stay idle before it is closed
+    pub udp_timeout: Duration,
+    /// The most UDP flows a forward tracks at once
+    pub udp_max_flows: Option<usize>,
+    /// The largest datagram relayed, larger ones are dropped
+    pub udp_max_datagram: usize,
+    pub idle_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// How long a read waits for more bytes to write along with it
+    pub coalesce_delay: Option<Duration>,
+    /// How many bytes a write waits for, the buffer size if not set
+    pub coalesce_bytes: Option<usize>,
+    pub compress: Option<CompressMode>,
+    pub max_lifetime: Option<Duration>,
+    pub max_bytes_per_conn: Option<usize>,
+    pub max_inflight_kb: Option<usize>,
+    pub total_rate_kbps: Option<usize>,
+    pub accept_rate: Option<usize>,
+    /// How long the accept rate takes to ramp up after a forward starts or
+    /// a target comes back up
+    pub slow_start: Option<Duration>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub target_policy: TargetPolicy,
+    pub health_check_interval: Option<Duration>,
+    pub health_check_timeout: Duration,
+    /// Connections kept dialed ahead of the clients to each target
+    pub pool_size: Option<usize>,
+    /// How long a pooled connection may wait for a client before it is
+    /// closed and dialed again
+    pub pool_idle_timeout: Duration,
+    pub drain_timeout: Option<Duration>,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    /// SO_LINGER of the client and target sockets, 0 resets on close
+    pub linger: Option<Duration>,
+    pub rcvbuf: Option<usize>,
+    pub sndbuf: Option<usize>,
+    pub reuse_port: bool,
+    /// Listen on the sockets passed by systemd instead of binding
+    pub socket_activation: bool,
+    pub strict_bind: bool,
+    /// How many more times a forward binds when its address is in use
+    pub bind_retries: u32,
+    pub bind_retry_delay: Duration,
+    /// Stop accepting after the first connection and exit once it closes
+    pub once: bool,
+    pub transparent: bool,
+    pub bind_interface: Option<String>,
+    pub bind_interface_outbound: bool,
+    /// The local address the target connections originate from
+    pub source_addr: Option<IpAddr>,
+    pub socks5: Option<Socks5Proxy>,
+    pub http_proxy: Option<HttpProxy>,
+    pub capture_dir: Option<PathBuf>,
+    pub capture_max_kb: usize,
+    pub hexdump: Option<usize>,
+    pub access_log: Option<String>,
+    pub event_log_size: Option<usize>,
+    pub events_json: bool,
+    pub pidfile: Option<String>,
+    pub error_log: Option<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub statsd_addr: Option<SocketAddr>,
+    pub stats_file: Option<PathBuf>,
+    pub stats_persist_interval: Duration,
+    pub otel_endpoint: Option<String>,
+    pub dual_stack: bool,
+    pub resolve_on_connect: bool,
+    /// The target hosts that did not resolve at startup with their error,
+    /// left for the connections to resolve
+    pub unresolved_hosts: Vec<(String, String)>,
+    pub sticky: bool,
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// How long a connect to a target may take, unbounded if not set
+    pub connect_timeout: Option<Duration>,
+    /// How many more rounds the targets are tried when none could be
+    /// connected to
+    pub connect_retries: u32,
+    /// The wait before the first retry, doubled before every next one
+    pub connect_backoff: Duration,
+    pub dns_server: Option<SocketAddr>,
+    pub dns_cache_ttl: Option<Duration>,
+    pub prefer: Option<IpFamily>,
+    pub proxy_protocol: bool,
+    /// Take the client address from the PROXY header of every connection
+    pub accept_proxy_protocol: bool,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub splice: bool,
+    pub meter: bool,
+    pub meter_format: MeterFormat,
+    pub meter_granularity: MeterGranularity,
+    /// Leave the connections that moved no bytes out of the printed rates
+    pub meter_hide_idle: bool,
+    /// Print the bytes of every forward since the start and the uptime next
+    /// to its rates
+    pub meter_show_totals: bool,
+    pub meter_capacity: usize,
+    #[serde(serialize_with = "serialize_display")]
+    pub log_level: LevelFilter,
+    pub quit_command: String,
+    pub check: bool,
+    /// Log the effective config as JSON at startup
+    pub print_config_json: bool,
+    /// The forwards were read from stdin, which is then closed for commands
+    pub config_from_stdin: bool,
+    /// Set when read from the command line, to reload the forwards
+    #[serde(skip)]
+    pub(crate) forward_sources: Option<ForwardSources>,
 }
 
 fn get_opts() -> Options {
     // Read options
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
+    opts.optflag("V", "version", "print the version");
+    opts.optflag(
+        "",
+        "check",
+        "Validate the config and print the resolved forwards without starting",
+    );
+    opts.optflag(
+        "",
+        "print-config-json",
+        "Log the effective config as JSON at startup, with every default filled in",
+    );
+    opts.optflagmulti("v", "verbose", "Log more, repeat for even more detail");
+    opts.optflagmulti(
+        "q",
+        "quiet",
+        "Log less, repeat to log only errors or nothing",
+    );
     opts.optopt(
         "b",
         "buff",
-        "The buffer size of each handler thread in KB",
+        "The buffer size of each connection direction in KB, or with a b, k or m suffix, defaults to 64k",
         "BUFF_SIZE",
     );
     opts.optopt(
+        "",
+        "buff-up",
+        "The buffer size of the client to target direction, like BUFF_SIZE, defaults to BUFF_SIZE",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "buff-down",
+        "The buffer size of the target to client direction, like BUFF_SIZE, defaults to BUFF_SIZE",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "buffer-pool-size",
+        "The number of idle buffers kept for reuse per forward, 0 to disable",
+        "N_BUFF",
+    );
+    opts.optmulti(
         "f",
         "conf",
-        "A list of information for port forwarding",
+        "A list of information for port forwarding, parsed as JSON if it ends with .json, - for stdin. May be repeated, the files are read in order",
         "CONFIG_FILE",
     );
-    opts.optopt("t", "nthread", "The number of handler threads", "N_THREAD");
-    return opts;
+    opts.optopt(
+        "t",
+        "nthread",
+        "The number of handler threads, 0 or auto for one per core, defaults to auto",
+        "N_THREAD",
+    );
+    opts.optopt(
+        "",
+        "quit-command",
+        "The line to type on stdin to shut down, defaults to q",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "max-conns",
+        "The maximum number of concurrent connections per forward",
+        "MAX_CONNS",
+    );
+    opts.optopt(
+        "",
+        "max-conns-mode",
+        "Close the connections past MAX_CONNS, or leave them in the backlog until one closes, defaults to reject",
+        "reject|queue",
+    );
+    opts.optopt(
+        "",
+        "max-conns-per-ip",
+        "The maximum number of concurrent connections per client IP and forward",
+        "MAX_CONNS",
+    );
+    opts.optopt(
+        "",
+        "udp-timeout",
+        "Close a UDP flow after it has been idle for this many seconds, defaults to 30",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "udp-max-flows",
+        "The maximum number of UDP flows per forward, the datagrams of new clients are dropped past it",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "udp-max-datagram",
+        "Drop and count as errors the datagrams larger than this many bytes rather than relay them truncated, defaults to 65507",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "total-rate-kbps",
+        "Cap the combined throughput of every forward at this many kilobytes per second",
+        "KBPS",
+    );
+    opts.optopt(
+        "",
+        "accept-rate",
+        "Accept at most this many connections per second on each forward, the others wait in the backlog",
+        "CONNS",
+    );
+    opts.optopt(
+        "",
+        "slow-start-duration",
+        "Ramp the accept rate up from a tenth over this many seconds after a forward starts or a health checked target comes back up",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "allow-targets",
+        "Only connect to targets in these networks, which may be private ones denied by --deny-private-targets",
+        "CIDR,...",
+    );
+    opts.optopt(
+        "",
+        "deny-targets",
+        "Never connect to targets in these networks, even if allowed",
+        "CIDR,...",
+    );
+    opts.optflag(
+        "",
+        "deny-private-targets",
+        "Refuse to connect to loopback, private and link-local targets that are not allowed by --allow-targets",
+    );
+    opts.optopt(
+        "",
+        "breaker-threshold",
+        "Stop dialing a target for a cooldown after this many consecutive connect failures",
+        "FAILURES",
+    );
+    opts.optopt(
+        "",
+        "breaker-window",
+        "The number of seconds within which failures count as consecutive, defaults to 10",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "breaker-cooldown",
+        "The number of seconds a target is not dialed once its breaker opens, defaults to 30",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "health-check-interval",
+        "Probe every target with a TCP connect this often and skip the ones that are down",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "health-check-timeout",
+        "The number of seconds a health check may take to connect, defaults to 2",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "pool-size",
+        "Keep this many connections to each target dialed ahead of the clients, only for backends that do not care which connection a client gets",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "pool-idle-timeout",
+        "The number of seconds a pooled connection is kept before it is dialed again, defaults to 30",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "idle-timeout",
+        "Close a connection after it has been idle for this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "write-timeout",
+        "Close a connection if a write to either side does not complete within this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "coalesce-delay-ms",
+        "Hold back what was read for up to this many milliseconds to write it along with the next reads, fewer writes for streams of tiny packets at the cost of latency",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "coalesce-bytes",
+        "Write what was held back by --coalesce-delay-ms as soon as this many bytes are, defaults to the buffer size",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "compress",
+        "Compress the TCP streams between two instances, the one in front compressing to its targets and the one behind them decompressing from its clients",
+        "target|client",
+    );
+    opts.optopt(
+        "",
+        "max-lifetime",
+        "Close a connection after it has been open for this many seconds, even if it is active",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-bytes-per-conn",
+        "Close a connection once it has moved this many bytes, uploaded and downloaded together",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-inflight-kb",
+        "Limit the data a connection holds between reading and writing, split between both directions",
+        "KB",
+    );
+    opts.optopt(
+        "",
+        "drain-timeout",
+        "On shutdown, abort connections still open after this many seconds",
+        "SECONDS",
+    );
+    opts.optflag(
+        "",
+        "nodelay",
+        "Set TCP_NODELAY on client and target sockets",
+    );
+    opts.optopt(
+        "",
+        "keepalive",
+        "Send TCP keepalive probes after a socket has been idle for this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "keepalive-interval",
+        "The number of seconds between TCP keepalive probes",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "linger",
+        "Set SO_LINGER on client and target sockets so closing waits up to this many seconds for unsent data, 0 resets the connection instead",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "rcvbuf",
+        "Set SO_RCVBUF on client and target sockets, the kernel may clamp it",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "sndbuf",
+        "Set SO_SNDBUF on client and target sockets, the kernel may clamp it",
+        "BYTES",
+    );
+    opts.optflag(
+        "",
+        "reuse-port",
+        "Set SO_REUSEPORT so several instances can listen on the same port (unix only)",
+    );
+    opts.optflag(
+        "",
+        "socket-activation",
+        "Listen on the sockets passed by systemd in LISTEN_FDS, in the order of the forwards (unix only)",
+    );
+    opts.optflag(
+        "",
+        "strict-bind",
+        "Exit if any forward fails to bind instead of serving the others",
+    );
+    opts.optopt(
+        "",
+        "bind-retries",
+        "Bind again this many times when the address is still in use, e.g. by the process being replaced, defaults to 0",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "bind-retry-delay",
+        "Wait this many milliseconds before every bind retry, defaults to 500",
+        "MS",
+    );
+    opts.optflag(
+        "",
+        "once",
+        "Accept a single connection and exit once it closes",
+    );
+    opts.optflag(
+        "",
+        "transparent",
+        "Connect intercepted connections to their original destination, using the targets for the others (linux only)",
+    );
+    opts.optopt(
+        "",
+        "bind-interface",
+        "Listen on this network interface only, with SO_BINDTODEVICE (linux only)",
+        "IFACE",
+    );
+    opts.optflag(
+        "",
+        "bind-interface-outbound",
+        "Connect to the targets out of the --bind-interface interface too",
+    );
+    opts.optopt(
+        "",
+        "source-addr",
+        "Connect to the targets from this local address, which must be assigned to the host",
+        "IP",
+    );
+    opts.optflag(
+        "",
+        "ipv6",
+        "Listen on [::] for both IPv4 and IPv6 clients unless a bind address is given",
+    );
+    opts.optflag(
+        "",
+        "resolve-on-connect",
+        "Resolve target hostnames on every connection instead of once at startup, starting even if some cannot be resolved yet",
+    );
+    opts.optflag(
+        "",
+        "sticky",
+        "Send the connections of a client IP to the same target while it is up",
+    );
+    opts.optopt(
+        "",
+        "happy-eyeballs-delay",
+        "Race the next target after a connect attempt takes this many milliseconds, 0 to try targets one at a time, defaults to 250",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "connect-timeout",
+        "Give up on a connect attempt to a target after this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "connect-retries",
+        "Try the targets again this many times when none could be connected to, defaults to 0",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "connect-backoff",
+        "Wait this many milliseconds before the first connect retry, doubled before every next one, defaults to 100",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "dns-server",
+        "Resolve target hostnames through this DNS server instead of the system resolver",
+        "IP[:PORT]",
+    );
+    opts.optopt(
+        "",
+        "dns-cache-ttl",
+        "Reuse the hostnames resolved on connect for this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "prefer",
+        "Try the targets of this address family first when a host resolves to both",
+        "ipv4|ipv6",
+    );
+    opts.optflag(
+        "",
+        "proxy-protocol",
+        "Send a PROXY protocol v1 header with the client address to the target",
+    );
+    opts.optflag(
+        "",
+        "accept-proxy-protocol",
+        "Require a PROXY protocol v1 or v2 header on every TCP connection and meter its client address",
+    );
+    opts.optopt(
+        "",
+        "tls-cert",
+        "Terminate TLS on the listening side with this PEM certificate chain",
+        "CERT_FILE",
+    );
+    opts.optopt(
+        "",
+        "tls-key",
+        "The PEM private key of the TLS certificate",
+        "KEY_FILE",
+    );
+    opts.optflag(
+        "",
+        "no-splice",
+        "Disable the zero-copy splice(2) forwarding on Linux",
+    );
+    opts.optflag(
+        "",
+        "no-meter",
+        "Do not meter the traffic, no rates, stats or summary are printed",
+    );
+    opts.optopt(
+        "",
+        "meter-format",
+        "Print the meter as human readable lines or as one JSON object per line, defaults to human",
+        "human|json",
+    );
+    opts.optopt(
+        "",
+        "meter-granularity",
+        "Print the rates of every connection or only of every forward, defaults to connection",
+        "connection|forward",
+    );
+    opts.optflag(
+        "",
+        "meter-hide-idle",
+        "Leave the connections that moved no bytes during an interval out of the printed rates",
+    );
+    opts.optflag(
+        "",
+        "meter-show-totals",
+        "Print the bytes of every forward since the start and the uptime next to its rates",
+    );
+    opts.optopt(
+        "",
+        "meter-capacity",
+        "The number of messages queued for the meter before transfers go uncounted, defaults to 1048576",
+        "N_MSG",
+    );
+    opts.optopt(
+        "",
+        "failure-response",
+        "Write this to the client when no target can be connected to, \\r and \\n are unescaped",
+        "TEXT",
+    );
+    opts.optopt(
+        "",
+        "mirror",
+        "Copy the client to target traffic of every forward to this address, best effort",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "socks5",
+        "Connect to targets through this SOCKS5 proxy",
+        "[USER:PASS@]HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "http-proxy",
+        "Connect to targets through this HTTP proxy with CONNECT",
+        "[USER:PASS@]HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "capture",
+        "Record the raw bytes of each connection direction to files in this directory",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "capture-max-kb",
+        "The maximum size of each capture file in KB, defaults to 10240",
+        "KB",
+    );
+    opts.optopt(
+        "",
+        "hexdump",
+        "Log a hex dump of the first BYTES of every chunk forwarded, for debugging",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "access-log",
+        "Append a Common Log Format line per closed connection to this file",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "event-log",
+        "Keep the last N connection events in memory, printed by the log command",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "events-json",
+        "Print a JSON line on stdout when a connection opens and closes",
+    );
+    opts.optopt(
+        "",
+        "pidfile",
+        "Write the process ID to this file, it is removed on shutdown",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "error-log",
+        "Append the log messages to this file instead of stderr, the meter stays on stdout",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "metrics-addr",
+        "Serve Prometheus metrics at /metrics on this address",
+        "ADDR",
+    );
+    opts.optopt(
+        "",
+        "statsd",
+        "Send the counters of every meter interval to this StatsD server over UDP, with DogStatsD tags",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "stats-file",
+        "Save the cumulative counters of every forward to this JSON file and continue from it on startup",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "stats-persist-interval",
+        "The number of seconds between saves of the stats file, defaults to 60",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "otel-endpoint",
+        "Export a trace span per connection to this OTLP/HTTP endpoint",
+        "URL",
+    );
+    opts
+}
+
+pub fn print_version() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), VERSION);
+}
+
+pub fn print_usage(program: &str) {
+    let brief = format!(
+        "Usage: {} FORWARD_LIST [options]\n\nForwards are also read from the {} environment variable, separated by newlines or ;\nA forward may end with proto=tcp|udp target_proto=tcp|udp name=NAME buff=SIZE tls=on|off bind=ADDR,...\nconnect_timeout=SECS connect_retries=N connect_backoff=MS lazy=on|off nodelay=on|off options\nand, for backends that expect them, preamble=HEX or preamble_file=PATH sent to the target first and banner=BYTES read from it first\nunix:/path sources and reloading the config on SIGHUP are unix only",
+        program, CONFIG_ENV
+    );
+    print!("{}", get_opts().usage(&brief));
+}
+
+/// Split a `host:port` or `[ipv6]:port` string into host and port
+fn split_host_port(target: &str) -> Result<(&str, &str), String> {
+    let (host, port) = match target.strip_prefix('[') {
+        Some(rest) => match rest.split_once("]:") {
+            Some(hp) => hp,
+            None => return Err(format!("invalid target: {}", target)),
+        },
+        None => match target.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, port),
+            _ => return Err(format!("invalid target: {}", target)),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("invalid target: {}", target));
+    }
+    Ok((host, port))
 }
 
-pub fn print_usage(program: &str) {
-    let brief = format!("Usage: {} FORWARD_LIST [options]", program);
-    print!("{}", get_opts().usage(&brief));
+/// Parse a buffer size such as `512b`, `64k` or `1m` into bytes, a bare
+/// number is in KB. Sizes of zero or past the maximum are rejected.
+fn parse_buffer_size(s: &str) -> Option<usize> {
+    let (n, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, "k"),
+    };
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        _ => return None,
+    };
+    let n_bytes = n.parse::<usize>().ok()?.checked_mul(unit)?;
+    (n_bytes > 0 && n_bytes <= MAX_BUFF_SIZE).then_some(n_bytes)
+}
+
+/// Split the optional `@weight` suffix off a target
+fn split_weight(target: &str) -> Result<(&str, Option<u32>), String> {
+    match target.rsplit_once('@') {
+        Some((target, weight)) => match weight.parse::<u32>() {
+            Ok(w) if w > 0 => Ok((target, Some(w))),
+            _ => Err(format!("{} is not a valid weight for {}", weight, target)),
+        },
+        None => Ok((target, None)),
+    }
+}
+
+/// The weight of every address of the targets, empty if none is weighted
+fn get_weights(targets: &[(Vec<SocketAddr>, Option<u32>)]) -> HashMap<SocketAddr, u32> {
+    if targets.iter().all(|(_, weight)| weight.is_none()) {
+        return HashMap::new();
+    }
+    let mut weights = HashMap::new();
+    for (addrs, weight) in targets {
+        for addr in addrs {
+            weights.entry(*addr).or_insert(weight.unwrap_or(1));
+        }
+    }
+    weights
+}
+
+/// Join a host and a port, bracketing IPv6 addresses
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Replace the `\r`, `\n`, `\t` and `\\` escapes of a command line string
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Parse a comma separated list of networks
+fn parse_cidrs(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',').map(str::parse).collect()
+}
+
+/// Decode a hex string such as `cafe01`
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Read the preamble of a forward as is
+fn read_preamble_file(file_path: &str) -> Result<Vec<u8>, String> {
+    match fs::read(file_path) {
+        Ok(preamble) if preamble.is_empty() => Err(format!("{file_path} is empty")),
+        Ok(preamble) => Ok(preamble),
+        Err(e) => Err(format!("Failed to read the preamble {file_path}: {e}")),
+    }
+}
+
+/// Parse a `first-last` port range, a single port being a range of one. Port
+/// 0, which binds a port picked by the OS, cannot be part of a longer range.
+fn get_port_range(s: &str) -> Result<(u16, u16), String> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    match (first.parse::<u16>(), last.parse::<u16>()) {
+        (Ok(0), Ok(last)) if last > 0 => Err(format!("{} is not a valid port range", s)),
+        (Ok(first), Ok(last)) if first <= last => Ok((first, last)),
+        _ => Err(format!("{} is not a valid port", s)),
+    }
+}
+
+/// Resolve a host into every address it resolves to
+fn resolve_host(host: &str, dns_server: Option<SocketAddr>) -> Result<Vec<IpAddr>, String> {
+    // A literal address needs no resolver
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    let hosts = match dns_server {
+        Some(server) => Dns::lookup_host(server, host)?,
+        None => match lookup_host(host) {
+            Ok(hosts) => hosts,
+            Err(e) => return Err(format!("{}", e)),
+        },
+    };
+
+    // The resolver may return the same address once per socket type
+    let mut addrs: Vec<IpAddr> = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        if !addrs.contains(&host) {
+            addrs.push(host);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(format!("{} does not resolve to any address", host));
+    }
+    Ok(addrs)
+}
+
+/// Resolves the hosts of one config, each host once however many forwards
+/// name it, so that they all get the same addresses
+struct HostResolver {
+    dns_server: Option<SocketAddr>,
+    resolved: RefCell<HashMap<String, Result<Vec<IpAddr>, String>>>,
+}
+
+impl HostResolver {
+    fn new(dns_server: Option<SocketAddr>) -> Self {
+        HostResolver {
+            dns_server,
+            resolved: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve a host like [`resolve_host`], looking it up only the first time
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        if let Some(result) = self.resolved.borrow().get(host) {
+            return result.clone();
+        }
+        let result = resolve_host(host, self.dns_server);
+        self.resolved
+            .borrow_mut()
+            .insert(host.to_string(), result.clone());
+        result
+    }
+}
+
+/// Resolve a target into every address its host resolves to
+fn get_targets(target: &str, resolver: &HostResolver) -> Result<Vec<SocketAddr>, String> {
+    let (host, port) = split_host_port(target)?;
+    let port = match port.parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => return Err(format!("{} is not a valid port", port)),
+    };
+    let addrs = resolver.resolve(host)?;
+    Ok(addrs
+        .into_iter()
+        .map(|a| SocketAddr::new(a, port))
+        .collect())
+}
+
+/// Resolve the host of a forward target. A host that does not resolve is
+/// added to `unresolved` with its error if given, for the connections to
+/// resolve, instead of failing the whole config.
+fn resolve_target_host(
+    host: &str,
+    resolver: &HostResolver,
+    unresolved: Option<&mut Vec<(String, String)>>,
+) -> Result<Vec<IpAddr>, String> {
+    match (resolver.resolve(host), unresolved) {
+        (Err(e), Some(unresolved)) => {
+            unresolved.push((host.to_string(), e));
+            Ok(Vec::new())
+        }
+        (result, _) => result,
+    }
+}
+
+/// Resolve a `host:port` to its first address
+fn get_addr(s: &str, resolver: &HostResolver) -> Result<SocketAddr, String> {
+    match get_targets(s, resolver)?.first() {
+        Some(addr) => Ok(*addr),
+        None => Err(format!("{} did not resolve to any address", s)),
+    }
+}
+
+/// Look up the SRV record `name` into its targets, best priority first, each
+/// as its host, port and the addresses the host resolves to
+fn get_srv_targets(
+    name: &str,
+    resolver: &HostResolver,
+) -> Result<Vec<(String, u16, Vec<IpAddr>)>, String> {
+    let records = Dns::lookup_srv(resolver.dns_server, name)
+        .map_err(|e| format!("cannot look up the SRV record {}: {}", name, e))?;
+    if records.is_empty() {
+        return Err(format!("the SRV record {} has no targets", name));
+    }
+    records
+        .iter()
+        .map(|r| Ok((r.host.clone(), r.port, resolver.resolve(&r.host)?)))
+        .collect()
+}
+
+/// Parse a `[user:pass@]host:port` upstream proxy into its credentials and address
+fn get_proxy(
+    s: &str,
+    resolver: &HostResolver,
+) -> Result<(Option<(String, String)>, SocketAddr), String> {
+    let (auth, addr) = match s.rsplit_once('@') {
+        Some((auth, addr)) => match auth.split_once(':') {
+            Some((user, pass)) if !user.is_empty() && user.len() <= 255 && pass.len() <= 255 => {
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            _ => return Err(format!("invalid proxy credentials in {}", s)),
+        },
+        None => (None, s),
+    };
+    Ok((auth, get_addr(addr, resolver)?))
+}
+
+/// Parse a `sport:host:port` or `unix:/path:host:port` forward, a
+/// `first-last:host:first-last` port range gives one forward per port.
+/// `first-last:host:port` forwards every port of the range to the same
+/// port. Several comma separated targets can be given, each with an
+/// optional `@weight`. A `srv:<name>` target takes the targets of an SRV
+/// record instead. A leading `name=<name>` names every forward of the line
+/// and a `udp:` prefix forwards UDP instead of TCP. Trailing `key=value`
+/// options, see [`InlineOptions`], set the rest.
+fn get_forwards(
+    s: &str,
+    default_bind_addr: IpAddr,
+    tls: bool,
+    resolver: &HostResolver,
+    mut unresolved: Option<&mut Vec<(String, String)>>,
+) -> Result<Vec<Forward>, String> {
+    let mut tokens = s.split_whitespace();
+    let (name, s) = match tokens.next() {
+        Some(prefix) => match prefix.strip_prefix("name=") {
+            Some("") => return Err(format!("invalid target: {}", s)),
+            Some(name) => (Some(name.to_string()), tokens.next().unwrap_or_default()),
+            None => (None, prefix),
+        },
+        None => return Err(format!("invalid target: {}", s)),
+    };
+    let options = InlineOptions::parse(tokens)?;
+    let name = match (name, options.name) {
+        (Some(_), Some(_)) => return Err(format!("{} is given two names", s)),
+        (name, inline_name) => name.or(inline_name),
+    };
+    let (protocol, s) = match (s.strip_prefix("udp:"), options.protocol) {
+        (Some(_), Some(Protocol::Tcp)) => {
+            return Err(format!("{} is UDP but proto=tcp is given", s))
+        }
+        (Some(rest), _) => (Protocol::Udp, rest),
+        (None, protocol) => (protocol.unwrap_or(Protocol::Tcp), s),
+    };
+    let target_protocol = options.target_protocol.unwrap_or(protocol);
+    let tls = match options.tls {
+        Some(true) if protocol == Protocol::Udp => {
+            return Err(format!("TLS cannot be used with UDP on {}", s))
+        }
+        Some(true) if !tls => {
+            return Err(format!(
+                "TLS is enabled for {} but no certificate is given",
+                s
+            ))
+        }
+        Some(on) => on,
+        // Bridged clients speak frames, not TLS
+        None => tls && protocol == Protocol::Tcp && target_protocol == Protocol::Tcp,
+    };
+    let (sources, target) = match s.strip_prefix("unix:") {
+        Some(_) if protocol == Protocol::Udp => {
+            return Err(format!("UDP cannot be forwarded from a Unix socket: {}", s))
+        }
+        Some(rest) => match rest.split_once(':') {
+            Some((path, target)) if !path.is_empty() => {
+                (vec![Source::Unix(Path::new(path).into())], target)
+            }
+            _ => return Err(format!("invalid target: {}", s)),
+        },
+        None => {
+            let s_port = s.split(':').take(1).collect::<Vec<&str>>()[0];
+            let target = match s.get(s_port.len() + 1..) {
+                Some(target) => target,
+                None => return Err(format!("invalid target: {}", s)),
+            };
+            let (first, last) = get_port_range(s_port)?;
+            ((first..=last).map(Source::Port).collect(), target)
+        }
+    };
+    let mut hosts = Vec::new();
+    let srv = target.strip_prefix("srv:").map(str::to_string);
+    if let Some(srv) = &srv {
+        if sources.len() != 1 {
+            return Err(format!(
+                "an SRV target cannot be used with a port range: {}",
+                s
+            ));
+        }
+        if srv.contains(',') {
+            return Err(format!(
+                "an SRV target cannot be combined with other targets: {}",
+                s
+            ));
+        }
+        for (host, t_port, addrs) in get_srv_targets(srv, resolver)? {
+            hosts.push((host, addrs, t_port, 0, None));
+        }
+    }
+    for target in target.split(',').filter(|_| srv.is_none()) {
+        let (target, weight) = split_weight(target)?;
+        let (host, t_port) = split_host_port(target)?;
+        let (t_first, t_last) = get_port_range(t_port)?;
+        // A single target port takes every source port, e.g. 9000-9100:host:80
+        let t_step = match t_first == t_last {
+            true => 0,
+            false if (t_last - t_first) as usize + 1 == sources.len() => 1,
+            false => {
+                return Err(format!(
+                    "the source and target port ranges of {} are not the same length",
+                    s
+                ))
+            }
+        };
+
+        // Resolve the host once for the whole range
+        hosts.push((
+            host.to_string(),
+            resolve_target_host(host, resolver, unresolved.as_deref_mut())?,
+            t_first,
+            t_step,
+            weight,
+        ));
+    }
+    let forwards = sources
+        .into_iter()
+        .enumerate()
+        .map(|(i, source)| {
+            let targets = hosts
+                .iter()
+                .map(|(_, addrs, t_first, t_step, weight)| {
+                    let t_port = t_first + t_step * i as u16;
+                    let addrs = addrs.iter().map(|a| SocketAddr::new(*a, t_port));
+                    (addrs.collect::<Vec<_>>(), *weight)
+                })
+                .collect::<Vec<_>>();
+            Forward {
+                source,
+                bind_addrs: options
+                    .bind_addrs
+                    .clone()
+                    .unwrap_or_else(|| vec![default_bind_addr]),
+                targets: targets
+                    .iter()
+                    .flat_map(|(addrs, _)| addrs)
+                    .copied()
+                    .collect(),
+                target_hosts: hosts
+                    .iter()
+                    .map(|(host, _, t_first, t_step, _)| {
+                        join_host_port(host, t_first + t_step * i as u16)
+                    })
+                    .collect(),
+                protocol,
+                target_protocol,
+                name: name.clone(),
+                tls,
+                buffer_size: options.buffer_size,
+                mirror: None,
+                failure_response: None,
+                weights: get_weights(&targets),
+                sni_routes: HashMap::new(),
+                ip_routes: Vec::new(),
+                upstream_tls: None,
+                srv: srv.clone(),
+                connect_timeout: options.connect_timeout,
+                connect_retries: options.connect_retries,
+                connect_backoff: options.connect_backoff,
+                lazy_connect: options.lazy_connect.unwrap_or(false),
+                nodelay: options.nodelay,
+                preamble: options.preamble.clone(),
+                banner_len: options.banner_len,
+            }
+        })
+        .collect();
+    Ok(forwards)
 }
 
-fn get_forward(s: &str) -> Result<Forward, String> {
-    let s_port = s.split(':').take(1).collect::<Vec<&str>>()[0];
+/// The `key=value` options trailing a forward line, such as
+/// `8080:host:80 proto=tcp name=web buff=64k tls=on bind=127.0.0.1,10.0.0.5`
+/// or `connect_timeout=5 connect_retries=3 connect_backoff=200 lazy=on nodelay=on`
+/// or `target_proto=udp` or `preamble=cafe01 banner=16` or `preamble_file=token.bin`
+#[derive(Default)]
+struct InlineOptions {
+    protocol: Option<Protocol>,
+    target_protocol: Option<Protocol>,
+    name: Option<String>,
+    buffer_size: Option<usize>,
+    tls: Option<bool>,
+    /// Comma separated, such as `bind=127.0.0.1,10.0.0.5`
+    bind_addrs: Option<Vec<IpAddr>>,
+    /// In seconds
+    connect_timeout: Option<Duration>,
+    connect_retries: Option<u32>,
+    /// In milliseconds
+    connect_backoff: Option<Duration>,
+    lazy_connect: Option<bool>,
+    nodelay: Option<bool>,
+    /// Hex encoded, or read from a file with `preamble_file`
+    preamble: Option<Vec<u8>>,
+    banner_len: Option<usize>,
+}
 
-    let target = &s[s_port.len() + 1..];
-    let vs = target.split(':').collect::<Vec<&str>>();
-    if vs.len() != 2 {
-        return Err(format!("invalid target: {}", s));
+impl InlineOptions {
+    fn parse<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut options = InlineOptions::default();
+        for token in tokens {
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) if !value.is_empty() => (key, value),
+                _ => return Err(format!("{} is not a key=value option", token)),
+            };
+            let duplicate = match key {
+                "proto" => options
+                    .protocol
+                    .replace(match value {
+                        "tcp" => Protocol::Tcp,
+                        "udp" => Protocol::Udp,
+                        _ => return Err(format!("{} is not a valid protocol", value)),
+                    })
+                    .is_some(),
+                "target_proto" => options
+                    .target_protocol
+                    .replace(match value {
+                        "tcp" => Protocol::Tcp,
+                        "udp" => Protocol::Udp,
+                        _ => return Err(format!("{} is not a valid protocol", value)),
+                    })
+                    .is_some(),
+                "name" => options.name.replace(value.to_string()).is_some(),
+                "buff" => options
+                    .buffer_size
+                    .replace(match parse_buffer_size(value) {
+                        Some(n_bytes) => n_bytes,
+                        None => {
+                            return Err(format!(
+                                "{} is not a valid buffer size, {}",
+                                value, BUFF_SIZE_RANGE
+                            ))
+                        }
+                    })
+                    .is_some(),
+                "tls" => options
+                    .tls
+                    .replace(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("tls must be on or off, not {}", value)),
+                    })
+                    .is_some(),
+                "bind" => options
+                    .bind_addrs
+                    .replace(match value.split(',').map(str::parse).collect() {
+                        Ok(bind_addrs) => bind_addrs,
+                        Err(_) => {
+                            return Err(format!("{} is not a valid bind address list", value))
+                        }
+                    })
+                    .is_some(),
+                "connect_timeout" => options
+                    .connect_timeout
+                    .replace(match value.parse() {
+                        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => return Err(format!("{} is not a valid connect timeout", value)),
+                    })
+                    .is_some(),
+                "connect_retries" => options
+                    .connect_retries
+                    .replace(match value.parse() {
+                        Ok(retries) => retries,
+                        Err(_) => return Err(format!("{} is not a valid retry count", value)),
+                    })
+                    .is_some(),
+                "connect_backoff" => options
+                    .connect_backoff
+                    .replace(match value.parse() {
+                        Ok(ms) => Duration::from_millis(ms),
+                        Err(_) => return Err(format!("{} is not a valid connect backoff", value)),
+                    })
+                    .is_some(),
+                "lazy" => options
+                    .lazy_connect
+                    .replace(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("lazy must be on or off, not {}", value)),
+                    })
+                    .is_some(),
+                "nodelay" => options
+                    .nodelay
+                    .replace(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("nodelay must be on or off, not {}", value)),
+                    })
+                    .is_some(),
+                "preamble" => options
+                    .preamble
+                    .replace(match parse_hex(value) {
+                        Some(preamble) => preamble,
+                        None => return Err(format!("{} is not a valid hex preamble", value)),
+                    })
+                    .is_some(),
+                "preamble_file" => options
+                    .preamble
+                    .replace(read_preamble_file(value)?)
+                    .is_some(),
+                "banner" => options
+                    .banner_len
+                    .replace(match value.parse() {
+                        Ok(n_bytes) if n_bytes > 0 => n_bytes,
+                        _ => return Err(format!("{} is not a valid banner length", value)),
+                    })
+                    .is_some(),
+                _ => return Err(format!("unknown option {}", token)),
+            };
+            if duplicate {
+                return Err(format!("{} is given twice", key));
+            }
+        }
+        Ok(options)
     }
-
-    let host = match lookup_host(vs[0]) {
-        Ok(hosts) => hosts,
-        Err(e) => return Err(format!("{}", e)),
-    }[0];
-
-    let port = match vs[1].parse::<u16>() {
-        Ok(port) => port,
-        Err(_) => return Err(format!("{} is not a valid port", vs[1])),
-    };
-
-    let target = SocketAddr::new(host, port);
-    let s_port = match s_port.parse::<u16>() {
-        Ok(port) => port,
-        Err(_) => return Err(format!("{} is not a valid port", s_port)),
-    };
-    return Ok(Forward { s_port, target });
 }
 
 pub fn get_config(args: &[String]) -> Result<Config, String> {
-    let mut buffer_size_kb: usize = DEFAULT_BUFF_SIZE_KB;
-    let mut n_thread: usize = DEFAULT_N_THREADS;
+    get_config_with_stdin(args, io::stdin())
+}
+
+/// Like [`get_config`], the config file `-` being read from `stdin`
+pub fn get_config_with_stdin(args: &[String], mut stdin: impl Read) -> Result<Config, String> {
+    let mut builder = ConfigBuilder::new();
 
     // Read options
     let opts = get_opts();
@@ -87,78 +1744,983 @@ pub fn get_config(args: &[String]) -> Result<Config, String> {
         return Err("Help".to_string());
     }
 
+    // Version
+    if matches.opt_present("V") {
+        return Err("Version".to_string());
+    }
+
+    // Check only
+    builder = builder.check(matches.opt_present("check"));
+    builder = builder.print_config_json(matches.opt_present("print-config-json"));
+
+    // Log level
+    builder = builder.log_level(match (matches.opt_count("v"), matches.opt_count("q")) {
+        (0, 0) => LevelFilter::Info,
+        (1, 0) => LevelFilter::Debug,
+        (_, 0) => LevelFilter::Trace,
+        (0, 1) => LevelFilter::Warn,
+        (0, 2) => LevelFilter::Error,
+        (0, _) => LevelFilter::Off,
+        _ => return Err("--verbose and --quiet cannot be given together".to_string()),
+    });
+
     // Buffer size
     if let Some(bs) = matches.opt_str("b") {
-        buffer_size_kb = match bs.parse() {
-            Ok(b) => b,
-            Err(_) => return Err(format!("{bs} is not a valid buffer size")),
+        builder = match parse_buffer_size(&bs) {
+            Some(n_bytes) => builder.buffer_size(n_bytes),
+            None => {
+                return Err(format!(
+                    "{bs} is not a valid buffer size, {BUFF_SIZE_RANGE}"
+                ))
+            }
+        }
+    }
+
+    if let Some(bu) = matches.opt_str("buff-up") {
+        builder = match parse_buffer_size(&bu) {
+            Some(n_bytes) => builder.buffer_up(n_bytes),
+            None => {
+                return Err(format!(
+                    "{bu} is not a valid upload buffer size, {BUFF_SIZE_RANGE}"
+                ))
+            }
+        }
+    }
+    if let Some(bd) = matches.opt_str("buff-down") {
+        builder = match parse_buffer_size(&bd) {
+            Some(n_bytes) => builder.buffer_down(n_bytes),
+            None => {
+                return Err(format!(
+                    "{bd} is not a valid download buffer size, {BUFF_SIZE_RANGE}"
+                ))
+            }
+        }
+    }
+
+    // Buffer pool size
+    if let Some(bps) = matches.opt_str("buffer-pool-size") {
+        builder = match bps.parse() {
+            Ok(n) => builder.buffer_pool_size(n),
+            Err(_) => return Err(format!("{bps} is not a valid buffer pool size")),
         }
     }
 
     // N thread
     if let Some(nt) = matches.opt_str("t") {
-        n_thread = match nt.parse() {
-            Ok(n) => n,
+        builder = match nt.parse() {
+            Ok(0) => builder.n_threads(available_threads()),
+            Ok(n) => builder.n_threads(n),
+            Err(_) if nt == "auto" => builder.n_threads(available_threads()),
             Err(_) => return Err(format!("{nt} is not a valid number of threads")),
         }
     }
 
-    // Forwards
-    let mut forwards: Vec<Forward> = Vec::with_capacity(matches.free.len());
-    for s in &matches.free {
-        let forward = get_forward(&s)?;
-        if forwards
-            .iter()
-            .map(|f| f.s_port)
-            .collect::<Vec<u16>>()
-            .contains(&forward.s_port)
-        {
-            return Err(format!(
-                "Cannot declare the same port twice. Found {} twice.",
-                forward.s_port
-            ));
+    // Quit command
+    if let Some(qc) = matches.opt_str("quit-command") {
+        builder = builder.quit_command(qc.trim());
+    }
+
+    // Max connections
+    if let Some(mc) = matches.opt_str("max-conns") {
+        builder = match mc.parse() {
+            Ok(m) if m > 0 => builder.max_conns(m),
+            _ => return Err(format!("{mc} is not a valid maximum number of connections")),
+        }
+    }
+    if let Some(mm) = matches.opt_str("max-conns-mode") {
+        builder = match mm.as_str() {
+            "reject" => builder.max_conns_mode(MaxConnsMode::Reject),
+            "queue" => builder.max_conns_mode(MaxConnsMode::Queue),
+            _ => return Err(format!("{mm} is not a valid connection limit mode")),
+        }
+    }
+    if let Some(mc) = matches.opt_str("max-conns-per-ip") {
+        builder = match mc.parse() {
+            Ok(m) if m > 0 => builder.max_conns_per_ip(m),
+            _ => return Err(format!("{mc} is not a valid maximum number of connections")),
         }
-        forwards.push(forward);
     }
 
-    // Read config file put into the forwards vector if it is not present
-    if let Some(file_path) = matches.opt_str("f") {
-        for file_f in read_config_file(&file_path)? {
-            if forwards.len() == 0 || forwards.iter().all(|f| f.s_port != file_f.s_port) {
-                forwards.push(file_f);
+    // UDP flows
+    if let Some(ut) = matches.opt_str("udp-timeout") {
+        builder = match ut.parse() {
+            Ok(secs) if secs > 0 => builder.udp_timeout(Duration::from_secs(secs)),
+            _ => return Err(format!("{ut} is not a valid UDP timeout")),
+        }
+    }
+    if let Some(mf) = matches.opt_str("udp-max-flows") {
+        builder = match mf.parse() {
+            Ok(m) if m > 0 => builder.udp_max_flows(m),
+            _ => return Err(format!("{mf} is not a valid maximum number of UDP flows")),
+        }
+    }
+    if let Some(md) = matches.opt_str("udp-max-datagram") {
+        builder = match md.parse() {
+            Ok(n_bytes) if n_bytes > 0 && n_bytes <= DEFAULT_UDP_MAX_DATAGRAM => {
+                builder.udp_max_datagram(n_bytes)
             }
+            _ => {
+                return Err(format!(
+                    "{md} is not a valid datagram size, it must be between 1 and {DEFAULT_UDP_MAX_DATAGRAM}"
+                ))
+            }
+        }
+    }
+
+    // Idle timeout
+    if let Some(it) = matches.opt_str("idle-timeout") {
+        builder = match it.parse() {
+            Ok(secs) if secs > 0 => builder.idle_timeout(Duration::from_secs(secs)),
+            _ => return Err(format!("{it} is not a valid idle timeout")),
         }
     }
 
-    // If no forward list return error
-    if forwards.len() == 0 {
-        return Err("no forward list found".to_string());
+    // Write timeout
+    if let Some(wt) = matches.opt_str("write-timeout") {
+        builder = match wt.parse() {
+            Ok(secs) if secs > 0 => builder.write_timeout(Duration::from_secs(secs)),
+            _ => return Err(format!("{wt} is not a valid write timeout")),
+        }
     }
 
-    // Sort the array in ascending order of source port
-    forwards.sort_by(|f1, f2| f1.s_port.cmp(&f2.s_port));
+    // Write coalescing
+    if let Some(cd) = matches.opt_str("coalesce-delay-ms") {
+        builder = match cd.parse() {
+            Ok(ms) if ms > 0 => builder.coalesce_delay(Duration::from_millis(ms)),
+            _ => return Err(format!("{cd} is not a valid coalescing delay")),
+        }
+    }
+    if let Some(cb) = matches.opt_str("coalesce-bytes") {
+        builder = match cb.parse() {
+            Ok(n_bytes) if n_bytes > 0 => builder.coalesce_bytes(n_bytes),
+            _ => return Err(format!("{cb} is not a valid number of bytes to coalesce")),
+        }
+    }
 
-    return Ok(Config {
-        forwards,
-        buffer_size_kb,
-        n_thread,
-    });
+    // Compression between instances
+    if let Some(c) = matches.opt_str("compress") {
+        builder = match c.as_str() {
+            "target" => builder.compress(CompressMode::Target),
+            "client" => builder.compress(CompressMode::Client),
+            _ => return Err(format!("{c} is not a valid side to compress")),
+        }
+    }
+
+    // Maximum lifetime
+    if let Some(ml) = matches.opt_str("max-lifetime") {
+        builder = match ml.parse() {
+            Ok(secs) if secs > 0 => builder.max_lifetime(Duration::from_secs(secs)),
+            _ => return Err(format!("{ml} is not a valid maximum lifetime")),
+        }
+    }
+
+    // Quota per connection
+    if let Some(mb) = matches.opt_str("max-bytes-per-conn") {
+        builder = match mb.parse() {
+            Ok(n_bytes) if n_bytes > 0 => builder.max_bytes_per_conn(n_bytes),
+            _ => {
+                return Err(format!(
+                    "{mb} is not a valid number of bytes per connection"
+                ))
+            }
+        }
+    }
+
+    // In-flight limit
+    if let Some(mi) = matches.opt_str("max-inflight-kb") {
+        builder = match mi.parse() {
+            Ok(kb) if kb > 0 => builder.max_inflight_kb(kb),
+            _ => return Err(format!("{mi} is not a valid in-flight limit")),
+        }
+    }
+
+    // Total rate
+    if let Some(tr) = matches.opt_str("total-rate-kbps") {
+        builder = match tr.parse() {
+            Ok(kbps) if kbps > 0 => builder.total_rate_kbps(kbps),
+            _ => return Err(format!("{tr} is not a valid total rate")),
+        }
+    }
+
+    // Accept rate
+    if let Some(ar) = matches.opt_str("accept-rate") {
+        builder = match ar.parse() {
+            Ok(rate) if rate > 0 => builder.accept_rate(rate),
+            _ => return Err(format!("{ar} is not a valid accept rate")),
+        }
+    }
+    if let Some(ss) = matches.opt_str("slow-start-duration") {
+        builder = match ss.parse() {
+            Ok(secs) if secs > 0 => builder.slow_start(Duration::from_secs(secs)),
+            _ => return Err(format!("{ss} is not a valid slow start duration")),
+        }
+    }
+
+    // Circuit breaker
+    let mut breaker_window = Duration::from_secs(DEFAULT_BREAKER_WINDOW_SECS);
+    if let Some(bw) = matches.opt_str("breaker-window") {
+        breaker_window = match bw.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(format!("{bw} is not a valid breaker window")),
+        }
+    }
+    let mut breaker_cooldown = Duration::from_secs(DEFAULT_BREAKER_COOLDOWN_SECS);
+    if let Some(bc) = matches.opt_str("breaker-cooldown") {
+        breaker_cooldown = match bc.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(format!("{bc} is not a valid breaker cooldown")),
+        }
+    }
+    if let Some(bt) = matches.opt_str("breaker-threshold") {
+        builder = match bt.parse() {
+            Ok(threshold) if threshold > 0 => builder.circuit_breaker(CircuitBreakerConfig {
+                threshold,
+                window: breaker_window,
+                cooldown: breaker_cooldown,
+            }),
+            _ => return Err(format!("{bt} is not a valid breaker threshold")),
+        }
+    }
+
+    // Target policy
+    let mut target_policy = TargetPolicy {
+        deny_private: matches.opt_present("deny-private-targets"),
+        ..TargetPolicy::default()
+    };
+    if let Some(at) = matches.opt_str("allow-targets") {
+        target_policy.allow = parse_cidrs(&at)?;
+    }
+    if let Some(dt) = matches.opt_str("deny-targets") {
+        target_policy.deny = parse_cidrs(&dt)?;
+    }
+    builder = builder.target_policy(target_policy);
+
+    // Health checks
+    let mut health_check_timeout = Duration::from_secs(DEFAULT_HEALTH_CHECK_TIMEOUT_SECS);
+    if let Some(ht) = matches.opt_str("health-check-timeout") {
+        health_check_timeout = match ht.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(format!("{ht} is not a valid health check timeout")),
+        }
+    }
+    if let Some(hi) = matches.opt_str("health-check-interval") {
+        builder = match hi.parse() {
+            Ok(secs) if secs > 0 => {
+                builder.health_check(Duration::from_secs(secs), health_check_timeout)
+            }
+            _ => return Err(format!("{hi} is not a valid health check interval")),
+        }
+    }
+
+    // Connection pool
+    let mut pool_idle_timeout = Duration::from_secs(DEFAULT_POOL_IDLE_TIMEOUT_SECS);
+    if let Some(pt) = matches.opt_str("pool-idle-timeout") {
+        pool_idle_timeout = match pt.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(format!("{pt} is not a valid pool idle timeout")),
+        };
+        if !matches.opt_present("pool-size") {
+            return Err("--pool-idle-timeout requires --pool-size".to_string());
+        }
+    }
+    if let Some(ps) = matches.opt_str("pool-size") {
+        builder = match ps.parse() {
+            Ok(size) if size > 0 => builder.pool(size, pool_idle_timeout),
+            _ => return Err(format!("{ps} is not a valid pool size")),
+        }
+    }
+
+    // Drain timeout
+    if let Some(dt) = matches.opt_str("drain-timeout") {
+        builder = match dt.parse() {
+            Ok(secs) => builder.drain_timeout(Duration::from_secs(secs)),
+            _ => return Err(format!("{dt} is not a valid drain timeout")),
+        }
+    }
+
+    // TCP_NODELAY
+    builder = builder.nodelay(matches.opt_present("nodelay"));
+
+    // Keepalive
+    if let Some(ka) = matches.opt_str("keepalive") {
+        builder = match ka.parse() {
+            Ok(secs) if secs > 0 => builder.keepalive(Duration::from_secs(secs)),
+            _ => return Err(format!("{ka} is not a valid keepalive time")),
+        }
+    }
+    if let Some(ki) = matches.opt_str("keepalive-interval") {
+        builder = match ki.parse() {
+            Ok(secs) if secs > 0 => builder.keepalive_interval(Duration::from_secs(secs)),
+            _ => return Err(format!("{ki} is not a valid keepalive interval")),
+        }
+    }
+
+    // SO_LINGER
+    if let Some(lg) = matches.opt_str("linger") {
+        builder = match lg.parse() {
+            Ok(secs) => builder.linger(Duration::from_secs(secs)),
+            Err(_) => return Err(format!("{lg} is not a valid linger time")),
+        }
+    }
+
+    // Socket buffers
+    if let Some(rb) = matches.opt_str("rcvbuf") {
+        builder = match rb.parse::<u32>() {
+            Ok(n_bytes) if n_bytes > 0 && n_bytes <= i32::MAX as u32 => {
+                builder.rcvbuf(n_bytes as usize)
+            }
+            _ => return Err(format!("{rb} is not a valid receive buffer size")),
+        }
+    }
+    if let Some(sb) = matches.opt_str("sndbuf") {
+        builder = match sb.parse::<u32>() {
+            Ok(n_bytes) if n_bytes > 0 && n_bytes <= i32::MAX as u32 => {
+                builder.sndbuf(n_bytes as usize)
+            }
+            _ => return Err(format!("{sb} is not a valid send buffer size")),
+        }
+    }
+
+    // SO_REUSEPORT
+    builder = builder.reuse_port(matches.opt_present("reuse-port"));
+
+    // Socket activation
+    builder = builder.socket_activation(matches.opt_present("socket-activation"));
+
+    // Strict bind
+    builder = builder.strict_bind(matches.opt_present("strict-bind"));
+
+    // Bind retries
+    if let Some(br) = matches.opt_str("bind-retries") {
+        builder = match br.parse() {
+            Ok(retries) => builder.bind_retries(retries),
+            Err(_) => return Err(format!("{br} is not a valid retry count")),
+        }
+    }
+    if let Some(bd) = matches.opt_str("bind-retry-delay") {
+        builder = match bd.parse() {
+            Ok(ms) => builder.bind_retry_delay(Duration::from_millis(ms)),
+            Err(_) => return Err(format!("{bd} is not a valid bind retry delay")),
+        }
+    }
+
+    // One connection
+    builder = builder.once(matches.opt_present("once"));
+
+    // Transparent proxy
+    builder = builder.transparent(matches.opt_present("transparent"));
+
+    // Network interface
+    if let Some(interface) = matches.opt_str("bind-interface") {
+        builder = builder.bind_interface(interface);
+    }
+    builder = builder.bind_interface_outbound(matches.opt_present("bind-interface-outbound"));
+
+    // Source address
+    if let Some(sa) = matches.opt_str("source-addr") {
+        builder = match sa.parse::<IpAddr>() {
+            Ok(ip) => builder.source_addr(ip.to_canonical()),
+            Err(_) => return Err(format!("{sa} is not a valid source address")),
+        }
+    }
+
+    // DNS server, used for every hostname from here on
+    let dns_server = match matches.opt_str("dns-server") {
+        Some(ds) => match ds.parse::<SocketAddr>() {
+            Ok(addr) => Some(addr),
+            Err(_) => match ds.parse::<IpAddr>() {
+                Ok(ip) => Some(SocketAddr::new(ip, DEFAULT_DNS_PORT)),
+                Err(_) => return Err(format!("{ds} is not a valid DNS server address")),
+            },
+        },
+        None => None,
+    };
+    if let Some(dns_server) = dns_server {
+        builder = builder.dns_server(dns_server);
+    }
+    let resolver = HostResolver::new(dns_server);
+
+    // SOCKS5 proxy
+    if let Some(sp) = matches.opt_str("socks5") {
+        let (auth, addr) = get_proxy(&sp, &resolver)?;
+        builder = builder.socks5(Socks5Proxy { addr, auth });
+    }
+
+    // HTTP proxy
+    if let Some(hp) = matches.opt_str("http-proxy") {
+        let (auth, addr) = get_proxy(&hp, &resolver)?;
+        builder = builder.http_proxy(HttpProxy { addr, auth });
+    }
+
+    // Capture
+    let mut capture_max_kb = DEFAULT_CAPTURE_MAX_KB;
+    if let Some(cm) = matches.opt_str("capture-max-kb") {
+        capture_max_kb = match cm.parse() {
+            Ok(kb) if kb > 0 => kb,
+            _ => return Err(format!("{cm} is not a valid capture size")),
+        }
+    }
+    if let Some(cd) = matches.opt_str("capture") {
+        builder = builder.capture(cd, capture_max_kb);
+    }
+
+    // Hex dump
+    if let Some(hd) = matches.opt_str("hexdump") {
+        builder = match hd.parse() {
+            Ok(n_bytes) if n_bytes > 0 => builder.hexdump(n_bytes),
+            _ => return Err(format!("{hd} is not a valid hex dump size")),
+        }
+    }
+
+    // Access log
+    if let Some(al) = matches.opt_str("access-log") {
+        builder = builder.access_log(al);
+    }
+
+    // Event log
+    if let Some(el) = matches.opt_str("event-log") {
+        builder = match el.parse() {
+            Ok(size) => builder.event_log_size(size),
+            Err(_) => return Err(format!("{el} is not a valid event log size")),
+        }
+    }
+    builder = builder.events_json(matches.opt_present("events-json"));
+
+    // PID file
+    if let Some(pf) = matches.opt_str("pidfile") {
+        builder = builder.pidfile(pf);
+    }
+
+    // Error log
+    if let Some(el) = matches.opt_str("error-log") {
+        builder = builder.error_log(el);
+    }
+
+    // Metrics address
+    if let Some(ma) = matches.opt_str("metrics-addr") {
+        builder = match ma.parse() {
+            Ok(a) => builder.metrics_addr(a),
+            Err(_) => return Err(format!("{ma} is not a valid metrics address")),
+        }
+    }
+
+    // StatsD
+    if let Some(sd) = matches.opt_str("statsd") {
+        builder = builder.statsd_addr(get_addr(&sd, &resolver)?);
+    }
+
+    // Stats file
+    if let Some(sf) = matches.opt_str("stats-file") {
+        builder = builder.stats_file(sf);
+    }
+    if let Some(spi) = matches.opt_str("stats-persist-interval") {
+        builder = match spi.parse() {
+            Ok(secs) if secs > 0 => builder.stats_persist_interval(Duration::from_secs(secs)),
+            _ => return Err(format!("{spi} is not a valid stats persist interval")),
+        }
+    }
+
+    // OpenTelemetry endpoint
+    if let Some(oe) = matches.opt_str("otel-endpoint") {
+        builder = builder.otel_endpoint(oe);
+    }
+
+    // Dual-stack
+    let dual_stack = matches.opt_present("ipv6");
+    builder = builder.dual_stack(dual_stack);
+    let default_bind_addr = if dual_stack {
+        DUAL_STACK_BIND_ADDR
+    } else {
+        DEFAULT_BIND_ADDR
+    };
+
+    // Resolve on connect
+    let resolve_on_connect = matches.opt_present("resolve-on-connect");
+    builder = builder.resolve_on_connect(resolve_on_connect);
+
+    // Sticky sessions
+    builder = builder.sticky(matches.opt_present("sticky"));
+
+    // Happy eyeballs
+    if let Some(he) = matches.opt_str("happy-eyeballs-delay") {
+        builder = match he.parse() {
+            Ok(0) => builder.happy_eyeballs_delay(None),
+            Ok(ms) => builder.happy_eyeballs_delay(Some(Duration::from_millis(ms))),
+            Err(_) => return Err(format!("{he} is not a valid happy eyeballs delay")),
+        }
+    }
+
+    // Connect timeout and retries
+    if let Some(ct) = matches.opt_str("connect-timeout") {
+        builder = match ct.parse() {
+            Ok(secs) if secs > 0 => builder.connect_timeout(Duration::from_secs(secs)),
+            _ => return Err(format!("{ct} is not a valid connect timeout")),
+        }
+    }
+    if let Some(cr) = matches.opt_str("connect-retries") {
+        builder = match cr.parse() {
+            Ok(retries) => builder.connect_retries(retries),
+            Err(_) => return Err(format!("{cr} is not a valid retry count")),
+        }
+    }
+    if let Some(cb) = matches.opt_str("connect-backoff") {
+        builder = match cb.parse() {
+            Ok(ms) => builder.connect_backoff(Duration::from_millis(ms)),
+            Err(_) => return Err(format!("{cb} is not a valid connect backoff")),
+        }
+    }
+
+    // DNS cache
+    if let Some(dt) = matches.opt_str("dns-cache-ttl") {
+        builder = match dt.parse() {
+            Ok(secs) if secs > 0 => builder.dns_cache_ttl(Duration::from_secs(secs)),
+            _ => return Err(format!("{dt} is not a valid DNS cache TTL")),
+        }
+    }
+
+    // Preferred address family
+    if let Some(pf) = matches.opt_str("prefer") {
+        builder = match pf.as_str() {
+            "ipv4" => builder.prefer(IpFamily::V4),
+            "ipv6" => builder.prefer(IpFamily::V6),
+            _ => return Err(format!("{pf} is not a valid address family")),
+        }
+    }
+
+    // PROXY protocol
+    builder = builder.proxy_protocol(matches.opt_present("proxy-protocol"));
+    builder = builder.accept_proxy_protocol(matches.opt_present("accept-proxy-protocol"));
+
+    // TLS
+    let tls = match (matches.opt_str("tls-cert"), matches.opt_str("tls-key")) {
+        (Some(cert), Some(key)) => {
+            builder = builder.tls(cert, key);
+            true
+        }
+        (None, None) => false,
+        _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+    };
+
+    // Splice
+    builder = builder.splice(!matches.opt_present("no-splice"));
+
+    // Meter
+    builder = builder.meter(!matches.opt_present("no-meter"));
+    if let Some(mf) = matches.opt_str("meter-format") {
+        builder = match mf.as_str() {
+            "human" => builder.meter_format(MeterFormat::Human),
+            "json" => builder.meter_format(MeterFormat::Json),
+            _ => return Err(format!("{mf} is not a valid meter format")),
+        }
+    }
+    if let Some(mg) = matches.opt_str("meter-granularity") {
+        builder = match mg.as_str() {
+            "connection" => builder.meter_granularity(MeterGranularity::Connection),
+            "forward" => builder.meter_granularity(MeterGranularity::Forward),
+            _ => return Err(format!("{mg} is not a valid meter granularity")),
+        }
+    }
+    builder = builder.meter_hide_idle(matches.opt_present("meter-hide-idle"));
+    builder = builder.meter_show_totals(matches.opt_present("meter-show-totals"));
+    if let Some(mc) = matches.opt_str("meter-capacity") {
+        builder = match mc.parse() {
+            Ok(n) => builder.meter_capacity(n),
+            Err(_) => return Err(format!("{mc} is not a valid meter capacity")),
+        }
+    }
+
+    // Forwards
+    let file_paths = matches.opt_strs("f");
+    if file_paths.iter().filter(|p| *p == STDIN_PATH).count() > 1 {
+        return Err(format!(
+            "{STDIN_PATH} can only be given once as a config file"
+        ));
+    }
+    // Stdin can only be read once, its text is kept for the reloads
+    let stdin = match file_paths.iter().any(|p| p == STDIN_PATH) {
+        true => {
+            let mut s = String::new();
+            if let Err(e) = stdin.read_to_string(&mut s) {
+                return Err(format!("Failed to read the config from stdin: {e}"));
+            }
+            builder = builder.config_from_stdin(true);
+            Some(s)
+        }
+        false => None,
+    };
+    let sources = ForwardSources {
+        mirror: matches.opt_str("mirror"),
+        failure_response: matches
+            .opt_str("failure-response")
+            .map(|r| unescape(&r).into_bytes()),
+        args: matches.free,
+        file_paths,
+        stdin,
+        default_bind_addr,
+        tls,
+        dns_server,
+        resolve_on_connect,
+    };
+    let (forwards, unresolved_hosts) = sources.read()?;
+    for forward in forwards {
+        builder = builder.add_forward(forward);
+    }
+    builder.config.unresolved_hosts = unresolved_hosts;
+    builder.config.forward_sources = Some(sources);
+
+    builder.build()
+}
+
+/// The target hosts that do not resolve yet with their error
+pub type UnresolvedHosts = Vec<(String, String)>;
+
+impl Config {
+    /// Read the forwards again from where this config read them, validated
+    /// against its other options, with the hosts left to resolve on connect
+    pub fn reload_forwards(&self) -> Result<(Vec<Forward>, UnresolvedHosts), String> {
+        let Some(sources) = &self.forward_sources else {
+            return Err("the forwards were not read from the command line".to_string());
+        };
+        let (mut forwards, unresolved_hosts) = sources.read()?;
+        self.check_forwards(&forwards)?;
+        builder::sort_forwards(&mut forwards, self.prefer);
+        Ok((forwards, unresolved_hosts))
+    }
+}
+
+/// Where the forwards of a config are declared, kept to read them again on
+/// reload without parsing the rest of the command line
+#[derive(Debug)]
+pub(crate) struct ForwardSources {
+    /// The forward lists of the command line
+    args: Vec<String>,
+    file_paths: Vec<String>,
+    /// The text of the config file `-`
+    stdin: Option<String>,
+    default_bind_addr: IpAddr,
+    tls: bool,
+    dns_server: Option<SocketAddr>,
+    resolve_on_connect: bool,
+    mirror: Option<String>,
+    failure_response: Option<Vec<u8>>,
 }
 
-fn read_config_file(file_path: &str) -> Result<Vec<Forward>, String> {
-    let config = match fs::read_to_string(file_path) {
-        Ok(s) => s,
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            return Err(format!("{file_path} does not exists"));
+impl ForwardSources {
+    /// Read the forwards with the hosts left to resolve on connect, which
+    /// with --resolve-on-connect are the target hosts that do not resolve yet
+    fn read(&self) -> Result<(Vec<Forward>, UnresolvedHosts), String> {
+        let resolver = HostResolver::new(self.dns_server);
+        let mut unresolved_hosts = Vec::new();
+        let mut unresolved = self.resolve_on_connect.then_some(&mut unresolved_hosts);
+        let mut declared = DeclaredForwards::default();
+        for s in &self.args {
+            let forwards = get_forwards(
+                s,
+                self.default_bind_addr,
+                self.tls,
+                &resolver,
+                unresolved.as_deref_mut(),
+            )?;
+            declared.extend(forwards, format!("`{}`", s))?;
+        }
+
+        // Add the forwards of the config files in order, which may not listen
+        // where the forwards of the command line or an earlier file already do
+        for file_path in &self.file_paths {
+            let config = self.read_file(file_path)?;
+            if file_path.ends_with(".json") {
+                read_json_config_file(
+                    file_path,
+                    &config,
+                    self.default_bind_addr,
+                    self.tls,
+                    &resolver,
+                    unresolved.as_deref_mut(),
+                    &mut declared,
+                )?
+            } else {
+                read_config_file(
+                    file_path,
+                    &config,
+                    self.default_bind_addr,
+                    self.tls,
+                    &resolver,
+                    unresolved.as_deref_mut(),
+                    &mut declared,
+                )?
+            };
+        }
+
+        // Same for the forward lines of the environment, separated by newlines or ;
+        if let Ok(env_config) = env::var(CONFIG_ENV) {
+            parse_config_lines(
+                env_config.split(['\n', ';']),
+                CONFIG_ENV,
+                self.default_bind_addr,
+                self.tls,
+                &resolver,
+                unresolved,
+                &mut declared,
+            )?;
+        }
+        let mut forwards = declared.forwards;
+
+        // Mirror the forwards that do not have their own mirror, same for the
+        // failure response
+        let mirror = self
+            .mirror
+            .as_ref()
+            .map(|m| get_addr(m, &resolver))
+            .transpose()?;
+        for forward in &mut forwards {
+            if forward.mirror.is_none() {
+                forward.mirror = mirror;
+            }
+            if forward.failure_response.is_none() {
+                forward.failure_response = self.failure_response.clone();
+            }
+        }
+        Ok((forwards, unresolved_hosts))
+    }
+
+    /// Read a config file, `-` being the text read from stdin
+    fn read_file(&self, file_path: &str) -> Result<String, String> {
+        if let (STDIN_PATH, Some(stdin)) = (file_path, &self.stdin) {
+            return Ok(stdin.clone());
+        }
+        match fs::read_to_string(file_path) {
+            Ok(s) => Ok(s),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(format!("{file_path} does not exists"))
+            }
+            Err(e) => Err(e.to_string()),
         }
-        Err(e) => {
-            return Err(e.to_string());
+    }
+}
+
+fn read_config_file(
+    file_path: &str,
+    config: &str,
+    default_bind_addr: IpAddr,
+    tls: bool,
+    resolver: &HostResolver,
+    unresolved: Option<&mut Vec<(String, String)>>,
+    declared: &mut DeclaredForwards,
+) -> Result<(), String> {
+    parse_config_lines(
+        config.lines(),
+        file_path,
+        default_bind_addr,
+        tls,
+        resolver,
+        unresolved,
+        declared,
+    )
+}
+
+/// Parse the forward lines read from `origin`, skipping blank lines and comments
+fn parse_config_lines<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    origin: &str,
+    default_bind_addr: IpAddr,
+    tls: bool,
+    resolver: &HostResolver,
+    mut unresolved: Option<&mut Vec<(String, String)>>,
+    declared: &mut DeclaredForwards,
+) -> Result<(), String> {
+    for (i, line) in lines.enumerate() {
+        // Skip blank lines and comments
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        let forwards = get_forwards(
+            line,
+            default_bind_addr,
+            tls,
+            resolver,
+            unresolved.as_deref_mut(),
+        )
+        .map_err(|e| format!("{} line {}: {}", origin, i + 1, e))?;
+        declared.extend(forwards, format!("{} line {}", origin, i + 1))?;
+    }
+    Ok(())
+}
+
+fn read_json_config_file(
+    file_path: &str,
+    config: &str,
+    default_bind_addr: IpAddr,
+    tls: bool,
+    resolver: &HostResolver,
+    mut unresolved: Option<&mut Vec<(String, String)>>,
+    declared: &mut DeclaredForwards,
+) -> Result<(), String> {
+    let json_forwards: Vec<JsonForward> = match serde_json::from_str(config) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("{file_path} is not a valid config file: {e}")),
     };
-    let lines: Vec<&str> = config.lines().collect();
-    let mut forwards: Vec<Forward> = Vec::with_capacity(lines.len());
-    for line in lines {
-        forwards.push(get_forward(line)?);
+    for (i, f) in json_forwards.into_iter().enumerate() {
+        let source = match (f.source_port, f.source_path) {
+            (Some(port), None) => Source::Port(port),
+            (None, Some(path)) => Source::Unix(path.into()),
+            _ => return Err("exactly one of source_port and source_path must be given".to_string()),
+        };
+        if f.targets.is_empty() {
+            return Err(format!("no target found for {}", source));
+        }
+        if f.protocol == Protocol::Udp {
+            if matches!(source, Source::Unix(_)) {
+                return Err(format!("UDP cannot be forwarded from {}", source));
+            }
+            if f.tls == Some(true) || !f.sni_routes.is_empty() || f.upstream_tls.is_some() {
+                return Err(format!("TLS cannot be used with UDP on {}", source));
+            }
+        }
+        if f.tls == Some(true) && !tls {
+            return Err(format!(
+                "TLS is enabled for {} but no certificate is given",
+                source
+            ));
+        }
+        let target_protocol = f.target_protocol.unwrap_or(f.protocol);
+        let tls = match f.tls {
+            Some(tls) => f.protocol == Protocol::Tcp && tls,
+            // Bridged clients speak frames, not TLS
+            None => tls && f.protocol == Protocol::Tcp && target_protocol == Protocol::Tcp,
+        };
+        if !f.sni_routes.is_empty() && tls {
+            return Err(format!(
+                "SNI routing cannot be used with TLS termination on {}",
+                source
+            ));
+        }
+        let mut sni_routes = HashMap::with_capacity(f.sni_routes.len());
+        for (sni, target) in &f.sni_routes {
+            let sni = sni.trim_end_matches('.').to_ascii_lowercase();
+            sni_routes.insert(sni, get_targets(target, resolver)?);
+        }
+        if f.upstream_tls.is_some() && !f.sni_routes.is_empty() {
+            return Err(format!(
+                "SNI routing cannot be used with TLS to the targets on {}",
+                source
+            ));
+        }
+        let bind_addrs = match (f.bind_addr, f.bind_addrs.is_empty()) {
+            (Some(_), false) => {
+                return Err(format!(
+                    "only one of bind_addr and bind_addrs can be given for {}",
+                    source
+                ))
+            }
+            (Some(bind_addr), true) => vec![bind_addr],
+            (None, true) => vec![default_bind_addr],
+            (None, false) => f.bind_addrs,
+        };
+        let mut ip_routes = Vec::with_capacity(f.ip_routes.len());
+        for route in &f.ip_routes {
+            ip_routes.push((route.cidr.parse()?, get_targets(&route.target, resolver)?));
+        }
+        let mut targets = Vec::with_capacity(f.targets.len());
+        let mut target_hosts = Vec::with_capacity(f.targets.len());
+        let srv = f.targets[0].strip_prefix("srv:").map(str::to_string);
+        if let Some(srv) = &srv {
+            if f.targets.len() != 1 {
+                return Err(format!(
+                    "an SRV target cannot be combined with other targets on {}",
+                    source
+                ));
+            }
+            for (host, port, addrs) in get_srv_targets(srv, resolver)? {
+                let addrs = addrs.into_iter().map(|a| SocketAddr::new(a, port));
+                targets.push((addrs.collect(), None));
+                target_hosts.push(join_host_port(&host, port));
+            }
+        }
+        for target in f.targets.iter().filter(|_| srv.is_none()) {
+            let (target, weight) = split_weight(target)?;
+            let (host, port) = split_host_port(target)?;
+            let port = match port.parse::<u16>() {
+                Ok(port) => port,
+                Err(_) => return Err(format!("{} is not a valid port", port)),
+            };
+            let addrs = resolve_target_host(host, resolver, unresolved.as_deref_mut())?;
+            let addrs = addrs.into_iter().map(|a| SocketAddr::new(a, port));
+            targets.push((addrs.collect(), weight));
+            target_hosts.push(target.to_string());
+        }
+        let upstream_tls = match f.upstream_tls {
+            Some(t) => Some(UpstreamTls {
+                client_cert: match (t.client_cert, t.client_key) {
+                    (Some(cert), Some(key)) => Some((cert, key)),
+                    (None, None) => None,
+                    _ => {
+                        return Err(format!(
+                            "client_cert and client_key must be given together for {}",
+                            source
+                        ))
+                    }
+                },
+                ca: t.ca,
+                // The host of the first target unless given
+                server_name: match t.server_name {
+                    Some(server_name) => server_name,
+                    None => split_host_port(&target_hosts[0])?.0.to_string(),
+                },
+            }),
+            None => None,
+        };
+        let preamble = match (f.preamble_hex, f.preamble_file) {
+            (Some(hex), None) => match parse_hex(&hex) {
+                Some(preamble) => Some(preamble),
+                None => return Err(format!("{} is not a valid hex preamble", hex)),
+            },
+            (None, Some(file_path)) => Some(read_preamble_file(&file_path)?),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "preamble_hex and preamble_file cannot be given together for {}",
+                    source
+                ))
+            }
+        };
+        let forward = Forward {
+            source,
+            bind_addrs,
+            targets: targets
+                .iter()
+                .flat_map(|(addrs, _)| addrs)
+                .copied()
+                .collect(),
+            target_hosts,
+            protocol: f.protocol,
+            target_protocol,
+            name: f.name,
+            tls,
+            // Validated with the other buffer sizes
+            buffer_size: f.buffer_size_kb.map(|kb| kb.saturating_mul(1024)),
+            mirror: f
+                .mirror
+                .as_deref()
+                .map(|m| get_addr(m, resolver))
+                .transpose()?,
+            failure_response: f.failure_response.map(String::into_bytes),
+            weights: get_weights(&targets),
+            sni_routes,
+            ip_routes,
+            upstream_tls,
+            srv,
+            // Validated with the global connect timeout
+            connect_timeout: f.connect_timeout_secs.map(Duration::from_secs),
+            connect_retries: f.connect_retries,
+            connect_backoff: f.connect_backoff_ms.map(Duration::from_millis),
+            lazy_connect: f.lazy_connect,
+            nodelay: f.nodelay,
+            preamble,
+            // Validated with the inline banner lengths
+            banner_len: f.banner_len,
+        };
+        declared.extend(vec![forward], format!("{} entry {}", file_path, i + 1))?;
     }
-    return Ok(forwards);
+    Ok(())
 }
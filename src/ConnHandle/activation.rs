@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    env, io,
+    os::fd::{FromRawFd, OwnedFd},
+    process,
+};
+
+use socket2::{SockRef, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::Config::{Forward, Protocol, Source};
+
+/// The first file descriptor passed by systemd
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The listening sockets passed by systemd socket activation. Every bind
+/// address of a TCP forward and every UDP forward takes the next socket, in
+/// the order of the forwards. The sockets are kept open so a forward changed
+/// on reload listens on the same socket again.
+#[derive(Debug)]
+pub struct ActivatedSockets {
+    sockets: HashMap<(Source, Protocol), Vec<OwnedFd>>,
+}
+
+impl ActivatedSockets {
+    /// Take the sockets of `LISTEN_FDS`, which must be as many as `forwards`
+    /// listen on
+    pub fn from_env(forwards: &[Forward]) -> Result<Self, String> {
+        let pid = env::var("LISTEN_PID")
+            .map_err(|_| "LISTEN_PID is not set, the process was not socket activated")?;
+        if pid.parse() != Ok(process::id()) {
+            return Err(format!("LISTEN_PID {} is not the PID of this process", pid));
+        }
+        let n_fds = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<i32>().ok())
+            .filter(|n| *n >= 0)
+            .ok_or("LISTEN_FDS is not a number of sockets")?;
+        let n_sockets = |f: &Forward| match f.protocol {
+            Protocol::Tcp => f.bind_addrs.len(),
+            Protocol::Udp => 1,
+        };
+        let expected = forwards.iter().map(n_sockets).sum::<usize>();
+        if n_fds as usize != expected {
+            return Err(format!(
+                "systemd passed {} sockets but the forwards listen on {}",
+                n_fds, expected
+            ));
+        }
+
+        // SAFETY: the sockets from SD_LISTEN_FDS_START on are passed to this
+        // process, which owns them from now on
+        let mut fds = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n_fds)
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        let sockets = forwards
+            .iter()
+            .map(|f| {
+                let key = (f.source.clone(), f.protocol);
+                (key, fds.by_ref().take(n_sockets(f)).collect())
+            })
+            .collect();
+        Ok(ActivatedSockets { sockets })
+    }
+
+    /// A copy of the sockets of `forward`, `None` if systemd passed none for
+    /// it, e.g. for a forward added on reload
+    pub(super) fn sockets(&self, forward: &Forward) -> Option<io::Result<Vec<OwnedFd>>> {
+        let fds = self
+            .sockets
+            .get(&(forward.source.clone(), forward.protocol))?;
+        Some(fds.iter().map(OwnedFd::try_clone).collect())
+    }
+}
+
+/// Listen on a TCP socket passed by systemd, which must be bound to `port`
+/// unless any port will do
+pub(super) fn tcp_listener(fd: OwnedFd, port: u16) -> io::Result<TcpListener> {
+    check_socket(&fd, Type::STREAM, port)?;
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Receive on a UDP socket passed by systemd, which must be bound to `port`
+/// unless any port will do
+pub(super) fn udp_socket(fd: OwnedFd, port: u16) -> io::Result<UdpSocket> {
+    check_socket(&fd, Type::DGRAM, port)?;
+    let socket = std::net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Catch sockets passed in another order than the forwards
+fn check_socket(fd: &OwnedFd, expected: Type, port: u16) -> io::Result<()> {
+    let socket = SockRef::from(fd);
+    let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    if socket.r#type()? != expected {
+        return invalid("the socket passed by systemd is of another protocol".to_string());
+    }
+    let bound = socket
+        .local_addr()?
+        .as_socket()
+        .map_or(0, |addr| addr.port());
+    if port != 0 && bound != port {
+        return invalid(format!(
+            "the socket passed by systemd is bound to port {bound}, not {port}"
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+/// Smooth weighted round-robin across the targets of a forward, which
+/// interleaves the targets instead of sending each its share in a burst
+#[derive(Default)]
+pub(super) struct Balancer {
+    current: Mutex<HashMap<SocketAddr, i64>>,
+}
+
+impl Balancer {
+    /// Pick the target of the next connection and return it first, followed
+    /// by the other targets in order to fall back on
+    pub(super) fn order(
+        &self,
+        targets: &[SocketAddr],
+        weights: &HashMap<SocketAddr, u32>,
+    ) -> Vec<SocketAddr> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut picked = None;
+        for (i, target) in targets.iter().enumerate() {
+            let weight = weights.get(target).copied().unwrap_or(1) as i64;
+            let c = current.entry(*target).or_default();
+            *c += weight;
+            total += weight;
+            if picked.is_none_or(|(_, max)| *c > max) {
+                picked = Some((i, *c));
+            }
+        }
+        let mut ordered = targets.to_vec();
+        if let Some((i, _)) = picked {
+            *current.get_mut(&targets[i]).unwrap() -= total;
+            ordered[..=i].rotate_right(1);
+        }
+        ordered
+    }
+}
+
+/// Order the targets by rendezvous hashing of the client IP, weighted by
+/// the target weights. A client keeps landing on the same target while the
+/// targets do not change, and only the clients of a target that goes away
+/// move to their next one.
+pub(super) fn sticky_order(
+    targets: &[SocketAddr],
+    weights: &HashMap<SocketAddr, u32>,
+    client: IpAddr,
+) -> Vec<SocketAddr> {
+    let mut scored = targets
+        .iter()
+        .map(|target| {
+            let mut hasher = DefaultHasher::new();
+            (client, target).hash(&mut hasher);
+            // Map the hash into (0, 1) so the weighted score is finite
+            let u = ((hasher.finish() >> 11) as f64 + 1.0) / ((1u64 << 53) as f64 + 2.0);
+            let weight = weights.get(target).copied().unwrap_or(1) as f64;
+            (weight / -u.ln(), *target)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, target)| target).collect()
+}
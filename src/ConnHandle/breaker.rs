@@ -0,0 +1,115 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Instant};
+
+use log::{info, warn};
+
+use crate::Config::CircuitBreakerConfig;
+
+#[derive(Default)]
+struct TargetState {
+    failures: u32,
+    last_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+/// Per-target circuit breakers of a forward
+pub(super) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    targets: Mutex<HashMap<SocketAddr, TargetState>>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `target` may be dialed. Once the cooldown is over a single probe
+    /// is let through, and the breaker stays open for another cooldown unless
+    /// the probe reports back first.
+    pub(super) fn allow(&self, target: &SocketAddr) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        let state = match targets.get_mut(target) {
+            Some(state) => state,
+            None => return true,
+        };
+        match state.open_until {
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                state.open_until = Some(Instant::now() + self.config.cooldown);
+                state.probing = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub(super) fn record_success(&self, target: &SocketAddr) {
+        let mut targets = self.targets.lock().unwrap();
+        if let Some(state) = targets.remove(target) {
+            if state.open_until.is_some() {
+                info!("Circuit breaker for {} closed", target);
+            }
+        }
+    }
+
+    pub(super) fn record_failure(&self, target: &SocketAddr) {
+        let mut targets = self.targets.lock().unwrap();
+        let state = targets.entry(*target).or_default();
+        let now = Instant::now();
+        if state.probing {
+            state.probing = false;
+            state.open_until = Some(now + self.config.cooldown);
+            return warn!("Probe of {} failed, circuit breaker stays open", target);
+        }
+        if state.open_until.is_some() {
+            return;
+        }
+
+        // Failures too far apart are not consecutive
+        if state
+            .last_failure
+            .is_some_and(|t| now.duration_since(t) > self.config.window)
+        {
+            state.failures = 0;
+        }
+        state.failures += 1;
+        state.last_failure = Some(now);
+        if state.failures >= self.config.threshold {
+            state.open_until = Some(now + self.config.cooldown);
+            warn!(
+                "Circuit breaker for {} opened after {} failures, retrying in {}s",
+                target,
+                state.failures,
+                self.config.cooldown.as_secs()
+            );
+        }
+    }
+
+    /// Open the breaker of a target that refused a connection without
+    /// waiting for the threshold, nothing listens on it
+    pub(super) fn trip(&self, target: &SocketAddr) {
+        let mut targets = self.targets.lock().unwrap();
+        let state = targets.entry(*target).or_default();
+        if state.open_until.is_some() && !state.probing {
+            return;
+        }
+        state.probing = false;
+        state.open_until = Some(Instant::now() + self.config.cooldown);
+        warn!(
+            "Circuit breaker for {} opened, it refused a connection, retrying in {}s",
+            target,
+            self.config.cooldown.as_secs()
+        );
+    }
+}
+
+/// The error of a connection rejected because every target's breaker is open
+pub(super) fn open_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        "circuit breaker open for every target",
+    )
+}
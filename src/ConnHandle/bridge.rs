@@ -0,0 +1,156 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use log::warn;
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    select,
+    time::sleep,
+};
+
+use super::{
+    udp::{connect_udp, datagram_buff, drop_oversized, MAX_DATAGRAM_SIZE},
+    CloseCause, ConnState, IdleTracker,
+};
+use crate::Meter::{Direction, MeterMessageSender};
+
+/// Reads the datagrams framed on a stream, each after its length as a big
+/// endian u16. Waiting for a frame can be cancelled without losing the place
+/// in the stream.
+pub(super) struct FrameReader<R> {
+    stream: R,
+    buff: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub(super) fn new(stream: R) -> Self {
+        FrameReader {
+            stream,
+            buff: Vec::new(),
+        }
+    }
+
+    /// The next datagram, `None` once the stream ends between two frames
+    pub(super) async fn next(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        loop {
+            if let [high, low, ..] = self.buff[..] {
+                let len = u16::from_be_bytes([high, low]) as usize;
+                if len > MAX_DATAGRAM_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("a frame of {} bytes does not fit in a datagram", len),
+                    ));
+                }
+                if self.buff.len() >= 2 + len {
+                    let datagram = self.buff[2..2 + len].to_vec();
+                    self.buff.drain(..2 + len);
+                    return Ok(Some(datagram));
+                }
+            }
+            // What was read is kept in the buffer if this is cancelled
+            self.buff.reserve(MAX_DATAGRAM_SIZE + 2 - self.buff.len());
+            if self.stream.read_buf(&mut self.buff).await? == 0 {
+                return match self.buff.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the stream ended within a frame",
+                    )),
+                };
+            }
+        }
+    }
+}
+
+/// Write `datagram` as one frame
+pub(super) async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    datagram: &[u8],
+) -> Result<(), io::Error> {
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    frame.extend_from_slice(datagram);
+    stream.write_all(&frame).await
+}
+
+/// Send every frame of the client to `target` as a datagram and every reply
+/// back as a frame, until the client closes or both stay idle for
+/// `idle_timeout`. Datagrams larger than `max_datagram` are dropped both ways.
+pub(super) async fn bridge_to_udp<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    target: SocketAddr,
+    idle_timeout: Option<Duration>,
+    max_datagram: usize,
+    conn: &Arc<ConnState>,
+    meter_msg_sender: &MeterMessageSender,
+) -> Result<CloseCause, io::Error> {
+    let socket = connect_udp(target).await?;
+    let (rstream, mut wstream) = split(stream);
+    let mut frames = FrameReader::new(rstream);
+    let activity = idle_timeout.map(IdleTracker::new);
+    let mut buff = datagram_buff(max_datagram);
+    loop {
+        let remaining = activity
+            .as_ref()
+            .map_or(Duration::MAX, IdleTracker::remaining);
+        let (direction, n_bytes) = select! {
+            frame = frames.next() => {
+                let Some(datagram) = frame? else {
+                    return Ok(CloseCause::Client);
+                };
+                if datagram.len() > max_datagram {
+                    drop_oversized(conn.peer(), max_datagram, &conn.forward, meter_msg_sender)
+                        .await;
+                    continue;
+                }
+                if let Err(e) = socket.send(&datagram).await {
+                    warn!("#{} {}", conn.id, e);
+                    continue;
+                }
+                (Direction::From, datagram.len())
+            },
+            received = socket.recv(&mut buff) => match received {
+                Ok(n_bytes) if n_bytes > max_datagram => {
+                    drop_oversized(target, max_datagram, &conn.forward, meter_msg_sender).await;
+                    continue;
+                }
+                Ok(n_bytes) => {
+                    write_frame(&mut wstream, &buff[..n_bytes]).await?;
+                    (Direction::To, n_bytes)
+                }
+                // e.g. an ICMP port unreachable for a previous datagram
+                Err(e) => {
+                    warn!("#{} {}", conn.id, e);
+                    continue;
+                }
+            },
+            _ = sleep(remaining), if activity.is_some() => {
+                match activity.as_ref().is_some_and(|a| a.remaining().is_zero()) {
+                    true => return Ok(CloseCause::Idle),
+                    false => continue,
+                }
+            },
+        };
+        if let Some(activity) = &activity {
+            activity.touch();
+        }
+        let n_conn_bytes = match direction {
+            Direction::From => &conn.from_n_bytes,
+            Direction::To => &conn.to_n_bytes,
+        };
+        n_conn_bytes.fetch_add(n_bytes, Ordering::Relaxed);
+        let _ = meter_msg_sender
+            .send(
+                conn.id,
+                conn.peer().clone(),
+                conn.forward.clone(),
+                direction,
+                n_bytes,
+            )
+            .await;
+    }
+}
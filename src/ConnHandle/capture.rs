@@ -0,0 +1,54 @@
+use std::path::Path;
+
+use log::warn;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+const CAPTURE_CHN_BUFF_SIZE: usize = 64;
+
+/// Records the raw bytes of one direction of a connection to a file, up to
+/// a maximum size. The file is written by a background task.
+pub(super) struct Capture {
+    sender: Option<Sender<Vec<u8>>>,
+    remaining: usize,
+}
+
+impl Capture {
+    /// Capture into `<dir>/conn-<id>-<direction>.bin`
+    pub(super) fn spawn(dir: &Path, conn_id: u32, direction: &str, max_size: usize) -> Capture {
+        let path = dir.join(format!("conn-{}-{}.bin", conn_id, direction));
+        let (sender, receiver) = channel(CAPTURE_CHN_BUFF_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = write_loop(&path, receiver).await {
+                warn!("Failed to capture to {}: {}", path.display(), e);
+            }
+        });
+        Capture {
+            sender: Some(sender),
+            remaining: max_size,
+        }
+    }
+
+    pub(super) async fn write(&mut self, data: &[u8]) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        let len = data.len().min(self.remaining);
+        self.remaining -= len;
+        if sender.send(data[..len].to_vec()).await.is_err() || self.remaining == 0 {
+            self.sender = None;
+        }
+    }
+}
+
+async fn write_loop(path: &Path, mut receiver: Receiver<Vec<u8>>) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path).await?);
+    while let Some(data) = receiver.recv().await {
+        file.write_all(&data).await?;
+    }
+    file.flush().await
+}
@@ -0,0 +1,130 @@
+use std::{fmt::Display, io, time::Duration};
+
+use crate::Config::Source;
+
+/// Why a forward could not start or stopped on its own
+#[derive(Debug)]
+pub enum ForwardError {
+    /// The source could not be listened on, e.g. `AddrInUse`
+    Bind { forward: Source, error: io::Error },
+    /// The listener failed in a way that accepting again would not fix
+    Accept { forward: Source, error: io::Error },
+    /// The forward lost its shutdown channel or its task before being shut down
+    Shutdown { forward: Source },
+}
+
+impl ForwardError {
+    /// The source of the forward that failed
+    pub fn forward(&self) -> &Source {
+        match self {
+            ForwardError::Bind { forward, .. }
+            | ForwardError::Accept { forward, .. }
+            | ForwardError::Shutdown { forward } => forward,
+        }
+    }
+
+    /// Copy an error shared by several waiters
+    pub(crate) fn unshare(&self) -> ForwardError {
+        let copy = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match self {
+            ForwardError::Bind { forward, error } => ForwardError::Bind {
+                forward: forward.clone(),
+                error: copy(error),
+            },
+            ForwardError::Accept { forward, error } => ForwardError::Accept {
+                forward: forward.clone(),
+                error: copy(error),
+            },
+            ForwardError::Shutdown { forward } => ForwardError::Shutdown {
+                forward: forward.clone(),
+            },
+        }
+    }
+}
+
+impl Display for ForwardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardError::Bind { forward, error } => {
+                write!(f, "cannot bind {}: {}", forward, error)
+            }
+            ForwardError::Accept { forward, error } => {
+                write!(f, "cannot accept on {}: {}", forward, error)
+            }
+            ForwardError::Shutdown { forward } => {
+                write!(f, "{} stopped without being shut down", forward)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForwardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ForwardError::Bind { error, .. } | ForwardError::Accept { error, .. } => Some(error),
+            ForwardError::Shutdown { .. } => None,
+        }
+    }
+}
+
+/// None of the target hosts of a forward resolved when a client connected,
+/// carried inside an [`io::Error`] so it can be told apart from a failed connect
+#[derive(Debug)]
+pub(super) struct ResolveError {
+    pub(super) hosts: Vec<String>,
+    pub(super) error: io::Error,
+}
+
+impl ResolveError {
+    /// Whether `e` is, or wraps, a [`ResolveError`]
+    pub(super) fn is(e: &(dyn std::error::Error + 'static)) -> bool {
+        e.is::<ResolveError>()
+            || e.downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .is_some_and(|e| e.is::<ResolveError>())
+    }
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot resolve {}: {}",
+            self.hosts.join(", "),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Nothing moved on a connection for its idle timeout, carried inside the
+/// [`io::ErrorKind::TimedOut`] error so it can be told apart from a write
+/// that timed out
+#[derive(Debug)]
+pub(super) struct IdleTimeout {
+    pub(super) timeout: Duration,
+}
+
+impl IdleTimeout {
+    /// Whether `e` wraps an [`IdleTimeout`]
+    pub(super) fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<IdleTimeout>())
+    }
+}
+
+impl Display for IdleTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "connection idle for more than {}s",
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for IdleTimeout {}
@@ -0,0 +1,69 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Mutex, time::Duration};
+
+use log::{info, warn};
+use tokio::time::timeout;
+
+use super::{dial, ConnOptions};
+
+/// The targets of a forward that failed their last health check
+#[derive(Default)]
+pub(super) struct TargetHealth {
+    down: Mutex<HashSet<SocketAddr>>,
+}
+
+impl TargetHealth {
+    pub(super) fn is_up(&self, target: &SocketAddr) -> bool {
+        !self.down.lock().unwrap().contains(target)
+    }
+
+    /// Returns whether the target came back up
+    fn set_up(&self, target: SocketAddr, up: bool) -> bool {
+        let mut down = self.down.lock().unwrap();
+        if up && down.remove(&target) {
+            info!("Target {} is up", target);
+            return true;
+        } else if !up && down.insert(target) {
+            warn!("Target {} is down", target);
+        }
+        false
+    }
+
+    /// Take a target that refused a connection down right away, rather than
+    /// on the next failed probe
+    pub(super) fn refused(&self, target: SocketAddr) {
+        if self.down.lock().unwrap().insert(target) {
+            warn!(
+                "Target {} is down, it refused a connection, until a health check passes",
+                target
+            );
+        }
+    }
+
+    /// Probe every target with a TCP connect every `interval`, forever,
+    /// calling `came_up` when a target that was down is up again
+    pub(super) async fn check_loop(
+        &self,
+        targets: &[SocketAddr],
+        interval: Duration,
+        probe_timeout: Duration,
+        options: &ConnOptions,
+        came_up: impl Fn(),
+    ) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let probes = targets.iter().map(|target| async move {
+                let up = matches!(
+                    timeout(probe_timeout, dial(target, options)).await,
+                    Ok(Ok(_))
+                );
+                (*target, up)
+            });
+            for (target, up) in futures::future::join_all(probes).await {
+                if self.set_up(target, up) {
+                    came_up();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::Write;
+
+use log::info;
+
+/// The bytes shown on each line of a dump
+const BYTES_PER_LINE: usize = 16;
+
+/// Logs a hex and ASCII dump of the start of every chunk of one direction of
+/// a connection
+pub(super) struct HexDump {
+    conn_id: u32,
+    direction: &'static str,
+    max_len: usize,
+}
+
+impl HexDump {
+    /// Dump at most `max_len` bytes of each chunk
+    pub(super) fn new(conn_id: u32, direction: &'static str, max_len: usize) -> HexDump {
+        HexDump {
+            conn_id,
+            direction,
+            max_len,
+        }
+    }
+
+    pub(super) fn log(&self, chunk: &[u8]) {
+        let shown = &chunk[..chunk.len().min(self.max_len)];
+        let mut dump = format!(
+            "#{} {} {} bytes, first {}:",
+            self.conn_id,
+            self.direction,
+            chunk.len(),
+            shown.len()
+        );
+        for (i, line) in shown.chunks(BYTES_PER_LINE).enumerate() {
+            let _ = write!(dump, "\n{:08x} ", i * BYTES_PER_LINE);
+            for j in 0..BYTES_PER_LINE {
+                let _ = match line.get(j) {
+                    Some(b) => write!(dump, " {:02x}", b),
+                    None => write!(dump, "   "),
+                };
+            }
+            let ascii = line.iter().map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            });
+            let _ = write!(dump, "  |{}|", ascii.collect::<String>());
+        }
+        info!("{}", dump);
+    }
+}
@@ -0,0 +1,64 @@
+use std::net::SocketAddr;
+
+use log::warn;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+
+/// The number of buffers queued for a mirror before it is given up on
+const MIRROR_CHN_BUFF_SIZE: usize = 64;
+
+/// A best-effort copy of the client to target traffic of a connection.
+/// Writes never wait for the mirror, a mirror that falls behind or fails is
+/// dropped without affecting the connection.
+pub(super) struct Mirror {
+    sender: Option<Sender<Vec<u8>>>,
+    addr: SocketAddr,
+    conn_id: u32,
+}
+
+impl Mirror {
+    /// Connect to the mirror in the background
+    pub(super) fn spawn(addr: SocketAddr, conn_id: u32) -> Mirror {
+        let (sender, receiver) = channel(MIRROR_CHN_BUFF_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = write_loop(addr, receiver).await {
+                warn!("#{} mirror {} dropped: {}", conn_id, addr, e);
+            }
+        });
+        Mirror {
+            sender: Some(sender),
+            addr,
+            conn_id,
+        }
+    }
+
+    pub(super) fn send(&mut self, data: &[u8]) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        match sender.try_send(data.to_vec()) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "#{} mirror {} dropped: it is not keeping up",
+                    self.conn_id, self.addr
+                );
+                self.sender = None;
+            }
+            // The write loop already logged why it stopped
+            Err(TrySendError::Closed(_)) => self.sender = None,
+        }
+    }
+}
+
+async fn write_loop(addr: SocketAddr, mut receiver: Receiver<Vec<u8>>) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    while let Some(data) = receiver.recv().await {
+        stream.write_all(&data).await?;
+    }
+    stream.shutdown().await
+}
@@ -1,47 +1,113 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::Display,
+    future::Future,
     hash::Hash,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::OnceLock,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
 
-use futures::io;
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream,
-    },
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
     select,
-    sync::{mpsc::Receiver, Mutex},
+    sync::{mpsc::Receiver, oneshot, watch, Mutex, Semaphore},
     task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    client,
+    rustls::{self, pki_types::ServerName, AlertDescription},
+    TlsAcceptor, TlsConnector,
 };
+use tracing::{field, info_span, Instrument, Span};
 
-use crate::Meter::MeterMessageSender;
+use self::{
+    balancer::Balancer,
+    breaker::CircuitBreaker,
+    capture::Capture,
+    error::{IdleTimeout, ResolveError},
+    health::TargetHealth,
+    hexdump::HexDump,
+    mirror::Mirror,
+    pool::ConnPool,
+    slow_start::SlowStart,
+    srv::SrvDiscovery,
+};
+use crate::{
+    AccessLog::{AccessLog, AccessLogEntry},
+    BufferPool::{BufferPool, BufferProvider, HeapBuffers},
+    Compress::{self, Compressor, Decompressor, WireMeter},
+    Config::{
+        CircuitBreakerConfig, CompressMode, Config, ConfigBuilder, Forward, IpFamily, MaxConnsMode,
+        Source, TargetPolicy,
+    },
+    Dns::Resolver,
+    EventLog::{print_event_json, ConnEventKind, EventLog},
+    HttpProxy::HttpProxy,
+    Meter::{format_bytes, format_rate, Direction, MeterMessageSender},
+    RateLimit::RateLimiter,
+    Socks5::Socks5Proxy,
+    Tls::{load_acceptor, load_connector},
+};
+
+#[cfg(unix)]
+mod activation;
+mod balancer;
+mod breaker;
+mod bridge;
+mod capture;
+mod error;
+mod health;
+mod hexdump;
+mod mirror;
+mod pool;
+mod proxy_protocol;
+mod slow_start;
+mod sni;
+#[cfg(target_os = "linux")]
+mod splice;
+mod srv;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod udp;
+
+#[cfg(unix)]
+pub use self::activation::ActivatedSockets;
+pub use self::{error::ForwardError, udp::accept_udp};
 
 static JOIN_HANDLE_ID: OnceLock<Mutex<u32>> = OnceLock::new();
-struct JoinHandleWithId<T>(u32, JoinHandle<T>);
-impl<T> JoinHandleWithId<T> {
-    async fn new(handle: JoinHandle<T>) -> Result<JoinHandleWithId<T>, io::Error> {
-        let id = {
-            // Get id value
-            let mut id_guard = JOIN_HANDLE_ID.get_or_init(|| Mutex::new(0)).lock().await;
-            let id = *id_guard;
-
-            // Update id to +1
-            if *id_guard >= u32::MAX {
-                *id_guard = 0;
-            } else {
-                *id_guard += 1;
-            }
 
-            // Return id
-            id
-        };
-        Ok(JoinHandleWithId(id, handle))
+/// Get a new connection id, wrapping around after `u32::MAX`
+pub async fn next_conn_id() -> u32 {
+    // Get id value
+    let mut id_guard = JOIN_HANDLE_ID.get_or_init(|| Mutex::new(0)).lock().await;
+    let id = *id_guard;
+
+    // Update id to +1
+    if *id_guard == u32::MAX {
+        *id_guard = 0;
+    } else {
+        *id_guard += 1;
     }
+
+    // Return id
+    id
 }
+
+struct JoinHandleWithId<T>(u32, JoinHandle<T>);
 impl<T> std::ops::Deref for JoinHandleWithId<T> {
     type Target = JoinHandle<T>;
 
@@ -66,131 +132,2194 @@ impl<T> Hash for JoinHandleWithId<T> {
     }
 }
 
-pub async fn accept_conn(
-    src_port: u16,
-    target: SocketAddr,
-    buff_size: usize,
+/// Aborts the task when dropped, so aborting a connection also stops both of its directions
+struct AbortOnDrop<T>(JoinHandle<T>);
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+const LISTEN_BACKLOG: i32 = 1024;
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// How long a client of a lazily connected forward may take to send anything
+const FIRST_BYTES_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a target may take to send its banner
+const BANNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether an accept failed because the process or system ran out of file descriptors
+fn is_fd_exhausted(e: &std::io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE));
+    #[cfg(not(target_os = "linux"))]
+    return matches!(e.raw_os_error(), Some(23 | 24));
+}
+
+/// Log the port picked by the OS for a forward bound to port 0
+fn log_os_port(forward: &Forward, local_addr: Option<SocketAddr>) {
+    if let (Source::Port(0), Some(local_addr)) = (&forward.source, local_addr) {
+        info!(
+            "The {} forward on port 0 is listening on port {}",
+            forward.protocol,
+            local_addr.port()
+        );
+    }
+}
+
+/// Whether accepting failed because the listener itself is unusable, so
+/// every later accept would fail too
+fn is_listener_broken(e: &std::io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK)
+    );
+    #[cfg(not(target_os = "linux"))]
+    return matches!(e.raw_os_error(), Some(9 | 22));
+}
+
+/// Bind with `bind`, trying again while the address is still in use, e.g.
+/// by the process this one replaces. Other errors fail right away.
+async fn bind_with_retries<T>(
+    forward: &Forward,
+    options: &ConnOptions,
+    bind: impl Fn() -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let mut retry = 0;
+    loop {
+        match bind() {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && retry < options.bind_retries => {
+                retry += 1;
+                warn!(
+                    "{} is in use, bind retry {}/{} in {}ms...",
+                    forward.source,
+                    retry,
+                    options.bind_retries,
+                    options.bind_retry_delay.as_millis()
+                );
+                sleep(options.bind_retry_delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Bind a listener, accepting both IPv4 and IPv6 clients on `[::]`
+fn bind_listener(addr: SocketAddr, options: &ConnOptions) -> Result<TcpListener, std::io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(target_os = "linux")]
+    if options.transparent {
+        transparent::set_listener_transparent(&socket, addr);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &options.bind_interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = options;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// A callback invoked with the peer address and the forward of a connection
+pub type ConnCallback = Arc<dyn Fn(&PeerAddr, &Forward) + Send + Sync>;
+
+/// Picks the targets of a connection from its peer address and forward, in
+/// the order they are tried
+pub type RouteCallback = Arc<dyn Fn(&PeerAddr, &Forward) -> Vec<SocketAddr> + Send + Sync>;
+
+/// Callbacks invoked when a connection is routed, opened and closed
+#[derive(Default, Clone)]
+pub struct ConnCallbacks {
+    /// Chooses the targets instead of the forward, which routes the
+    /// connections it returns none for
+    pub route: Option<RouteCallback>,
+    pub on_connect: Option<ConnCallback>,
+    pub on_disconnect: Option<ConnCallback>,
+}
+
+impl std::fmt::Debug for ConnCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnCallbacks")
+            .field("route", &self.route.is_some())
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .finish()
+    }
+}
+
+/// The address of a client, Unix socket clients are identified by the listening path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(Arc<Path>),
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(sockaddr) => write!(f, "{}", sockaddr),
+            PeerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A listener of the source side of a forward, with one TCP listener per
+/// bind address
+enum Listener {
+    Tcp(Vec<TcpListener>),
+    #[cfg(unix)]
+    Unix(UnixListener, Arc<Path>),
+}
+
+/// A client connection accepted by a [`Listener`]
+enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A connection to a target, over TLS if the forward speaks it to its targets
+enum TargetStream {
+    Tcp(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl Listener {
+    fn bind(forward: &Forward, options: &ConnOptions) -> Result<Listener, std::io::Error> {
+        match &forward.source {
+            Source::Port(port) => {
+                #[cfg(unix)]
+                if let Some(fds) = options
+                    .activated_sockets
+                    .as_ref()
+                    .and_then(|s| s.sockets(forward))
+                {
+                    let listeners = fds?
+                        .into_iter()
+                        .map(|fd| activation::tcp_listener(fd, *port))
+                        .collect::<Result<_, _>>()?;
+                    return Ok(Listener::Tcp(listeners));
+                }
+                let mut listeners: Vec<TcpListener> = Vec::with_capacity(forward.bind_addrs.len());
+                for bind_addr in &forward.bind_addrs {
+                    // Every address listens on the port the OS picked for the first
+                    let port = match listeners.first() {
+                        Some(first) => first.local_addr()?.port(),
+                        None => *port,
+                    };
+                    listeners.push(bind_listener(SocketAddr::new(*bind_addr, port), options)?);
+                }
+                Ok(Listener::Tcp(listeners))
+            }
+            #[cfg(unix)]
+            Source::Unix(path) => Ok(Listener::Unix(UnixListener::bind(path)?, path.clone())),
+            #[cfg(not(unix))]
+            Source::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are only supported on unix",
+            )),
+        }
+    }
+
+    /// The address a TCP listener is bound to, with the port picked by the OS
+    /// if bound to port 0
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listeners) => listeners.first()?.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_, _) => None,
+        }
+    }
+
+    async fn accept(&self) -> Result<(ClientStream, PeerAddr), std::io::Error> {
+        match self {
+            Listener::Tcp(listeners) => {
+                let accepts = listeners.iter().map(|l| Box::pin(l.accept()));
+                let (accepted, _, _) = futures::future::select_all(accepts).await;
+                let (stream, p) = accepted?;
+                let peer = SocketAddr::new(p.ip().to_canonical(), p.port());
+                Ok((ClientStream::Tcp(stream), PeerAddr::Tcp(peer)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), PeerAddr::Unix(path.clone())))
+            }
+        }
+    }
+
+    /// Stop listening, removing the socket file of a Unix listener
+    fn close(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, path) = self {
+            drop(listener);
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Apply the TCP_NODELAY, keepalive and socket buffer options to a connected
+/// socket. The buffer sizes granted by the kernel are logged as it may clamp
+/// them, Linux also reports twice the size for its bookkeeping.
+fn configure_stream(
+    stream: &TcpStream,
+    forward: &Forward,
+    options: &ConnOptions,
+) -> Result<(), std::io::Error> {
+    if forward.nodelay.unwrap_or(options.nodelay) {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = options.keepalive {
+        let mut params = TcpKeepalive::new().with_time(keepalive);
+        if let Some(interval) = options.keepalive_interval {
+            params = params.with_interval(interval);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&params)?;
+    }
+    if let Some(linger) = options.linger {
+        SockRef::from(stream).set_linger(Some(linger))?;
+    }
+    if let Some(rcvbuf) = options.rcvbuf {
+        let socket = SockRef::from(stream);
+        socket.set_recv_buffer_size(rcvbuf)?;
+        debug!(
+            "SO_RCVBUF of {}: {} bytes requested, {} granted",
+            stream.peer_addr()?,
+            rcvbuf,
+            socket.recv_buffer_size()?
+        );
+    }
+    if let Some(sndbuf) = options.sndbuf {
+        let socket = SockRef::from(stream);
+        socket.set_send_buffer_size(sndbuf)?;
+        debug!(
+            "SO_SNDBUF of {}: {} bytes requested, {} granted",
+            stream.peer_addr()?,
+            sndbuf,
+            socket.send_buffer_size()?
+        );
+    }
+    Ok(())
+}
+
+/// The SO_RCVBUF the kernel grants a socket asking for `n_bytes`
+pub fn granted_rcvbuf(n_bytes: usize) -> Result<usize, std::io::Error> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_recv_buffer_size(n_bytes)?;
+    socket.recv_buffer_size()
+}
+
+/// The SO_SNDBUF the kernel grants a socket asking for `n_bytes`
+pub fn granted_sndbuf(n_bytes: usize) -> Result<usize, std::io::Error> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_send_buffer_size(n_bytes)?;
+    socket.send_buffer_size()
+}
+
+/// Options applied to every connection of a forward
+#[derive(Debug, Clone)]
+pub struct ConnOptions {
+    /// The buffer size of each direction in bytes
+    pub buff_size: usize,
+    /// The client to target buffer size, `buff_size` if not set
+    pub buff_up: Option<usize>,
+    /// The target to client buffer size, `buff_size` if not set
+    pub buff_down: Option<usize>,
+    pub buffer_pool_size: usize,
+    /// Where the forwarding buffers come from and go back to
+    pub buffer_provider: Arc<dyn BufferProvider>,
+    pub max_conns: Option<usize>,
+    pub max_conns_mode: MaxConnsMode,
+    pub max_conns_per_ip: Option<usize>,
+    /// How long a UDP flow may stay idle before it is closed
+    pub udp_timeout: Duration,
+    pub udp_max_flows: Option<usize>,
+    /// The largest datagram relayed, UDP or framed, larger ones are dropped
+    pub udp_max_datagram: usize,
+    pub idle_timeout: Option<Duration>,
+    /// Close a connection whose write does not complete in time
+    pub write_timeout: Option<Duration>,
+    /// How long a read waits for more bytes to write along with it, an
+    /// application level Nagle
+    pub coalesce_delay: Option<Duration>,
+    pub coalesce_bytes: Option<usize>,
+    /// Which side of the forwards is another instance the stream to is compressed
+    pub compress: Option<CompressMode>,
+    /// How long a connection may stay open, active or not
+    pub max_lifetime: Option<Duration>,
+    /// Close a connection once it has moved this many bytes in both directions
+    pub max_bytes_per_conn: Option<usize>,
+    /// Bytes a connection may hold between reading and writing, its two
+    /// directions each getting a buffer of at most half of them
+    pub max_inflight: Option<usize>,
+    /// Shared by every clone of these options
+    pub rate_limiter: Option<RateLimiter>,
+    /// New connections accepted per second, each forward has its own bucket
+    pub accept_rate: Option<usize>,
+    /// How long the accept rate takes to ramp up
+    pub slow_start: Option<Duration>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Checked before every connect to a target
+    pub target_policy: TargetPolicy,
+    pub health_check_interval: Option<Duration>,
+    pub health_check_timeout: Duration,
+    /// Connections kept dialed ahead of the clients to each target
+    pub pool_size: Option<usize>,
+    pub pool_idle_timeout: Duration,
+    pub resolve_on_connect: bool,
+    /// Send the connections of a client IP to the same target
+    pub sticky: bool,
+    /// How long a target connect may take before the next target is raced against it
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// The connect timeout, retries and backoff of the forwards that do not
+    /// set their own
+    pub connect_timeout: Option<Duration>,
+    pub connect_retries: u32,
+    pub connect_backoff: Duration,
+    pub prefer: Option<IpFamily>,
+    /// Resolves the target hostnames when they are resolved on connect
+    pub resolver: Resolver,
+    pub proxy_protocol: bool,
+    /// Take the client address from the PROXY header of every connection
+    pub accept_proxy_protocol: bool,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub splice: bool,
+    pub drain_timeout: Option<Duration>,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub linger: Option<Duration>,
+    pub rcvbuf: Option<usize>,
+    pub sndbuf: Option<usize>,
+    pub reuse_port: bool,
+    /// How many more times a forward binds when its address is in use
+    pub bind_retries: u32,
+    pub bind_retry_delay: Duration,
+    /// Listen on the sockets passed by systemd instead of binding
+    #[cfg(unix)]
+    pub activated_sockets: Option<Arc<ActivatedSockets>>,
+    /// Stop accepting after the first connection
+    pub once: bool,
+    /// Connect to the original destination of intercepted connections
+    pub transparent: bool,
+    /// Only accept clients arriving on this network interface
+    pub bind_interface: Option<String>,
+    /// Connect to the targets out of this network interface
+    pub outbound_interface: Option<String>,
+    /// The local address the target connections originate from
+    pub source_addr: Option<IpAddr>,
+    pub socks5: Option<Socks5Proxy>,
+    pub http_proxy: Option<HttpProxy>,
+    pub capture_dir: Option<PathBuf>,
+    pub capture_max_size: usize,
+    /// Log a dump of the first bytes of every chunk
+    pub hexdump: Option<usize>,
+    pub access_log: Option<AccessLog>,
+    pub event_log: Option<EventLog>,
+    pub events_json: bool,
+    pub callbacks: ConnCallbacks,
+}
+
+/// The options of the command line defaults
+impl Default for ConnOptions {
+    fn default() -> Self {
+        ConnOptions::from(&ConfigBuilder::new().config)
+    }
+}
+
+impl ConnOptions {
+    /// The buffer of a direction, cut to its half of the in-flight limit
+    fn direction_buff_size(&self, buff_size: usize) -> usize {
+        match self.max_inflight {
+            Some(max_inflight) => buff_size.min((max_inflight / 2).max(1)),
+            None => buff_size,
+        }
+    }
+}
+
+impl From<&Config> for ConnOptions {
+    fn from(config: &Config) -> Self {
+        ConnOptions {
+            buff_size: config.buffer_size,
+            buff_up: config.buffer_up,
+            buff_down: config.buffer_down,
+            buffer_pool_size: config.buffer_pool_size,
+            buffer_provider: Arc::new(HeapBuffers),
+            max_conns: config.max_conns,
+            max_conns_mode: config.max_conns_mode,
+            max_conns_per_ip: config.max_conns_per_ip,
+            udp_timeout: config.udp_timeout,
+            udp_max_flows: config.udp_max_flows,
+            udp_max_datagram: config.udp_max_datagram,
+            idle_timeout: config.idle_timeout,
+            write_timeout: config.write_timeout,
+            coalesce_delay: config.coalesce_delay,
+            coalesce_bytes: config.coalesce_bytes,
+            compress: config.compress,
+            max_lifetime: config.max_lifetime,
+            max_bytes_per_conn: config.max_bytes_per_conn,
+            max_inflight: config.max_inflight_kb.map(|kb| kb * 1024),
+            rate_limiter: config
+                .total_rate_kbps
+                .map(|kbps| RateLimiter::new(kbps * 1024)),
+            accept_rate: config.accept_rate,
+            slow_start: config.slow_start,
+            circuit_breaker: config.circuit_breaker,
+            target_policy: config.target_policy.clone(),
+            health_check_interval: config.health_check_interval,
+            health_check_timeout: config.health_check_timeout,
+            pool_size: config.pool_size,
+            pool_idle_timeout: config.pool_idle_timeout,
+            resolve_on_connect: config.resolve_on_connect,
+            sticky: config.sticky,
+            happy_eyeballs_delay: config.happy_eyeballs_delay,
+            connect_timeout: config.connect_timeout,
+            connect_retries: config.connect_retries,
+            connect_backoff: config.connect_backoff,
+            prefer: config.prefer,
+            resolver: Resolver::new(config.dns_server, config.dns_cache_ttl),
+            proxy_protocol: config.proxy_protocol,
+            accept_proxy_protocol: config.accept_proxy_protocol,
+            tls_cert: config.tls_cert.clone(),
+            tls_key: config.tls_key.clone(),
+            splice: config.splice,
+            drain_timeout: config.drain_timeout,
+            nodelay: config.nodelay,
+            keepalive: config.keepalive,
+            keepalive_interval: config.keepalive_interval,
+            linger: config.linger,
+            rcvbuf: config.rcvbuf,
+            sndbuf: config.sndbuf,
+            reuse_port: config.reuse_port,
+            bind_retries: config.bind_retries,
+            bind_retry_delay: config.bind_retry_delay,
+            #[cfg(unix)]
+            activated_sockets: None,
+            once: config.once,
+            transparent: config.transparent,
+            bind_interface: config.bind_interface.clone(),
+            outbound_interface: config
+                .bind_interface
+                .clone()
+                .filter(|_| config.bind_interface_outbound),
+            source_addr: config.source_addr,
+            socks5: config.socks5.clone(),
+            http_proxy: config.http_proxy.clone(),
+            capture_dir: config.capture_dir.clone(),
+            capture_max_size: config.capture_max_kb * 1024,
+            hexdump: config.hexdump,
+            access_log: None,
+            event_log: config.event_log_size.map(EventLog::new),
+            events_json: config.events_json,
+            callbacks: ConnCallbacks::default(),
+        }
+    }
+}
+
+/// Live state of an open connection
+struct ConnState {
+    id: u32,
+    /// The address the client connected from
+    accepted_peer: PeerAddr,
+    /// The real client, named by the PROXY header of the connection
+    proxied_peer: OnceLock<PeerAddr>,
+    forward: Source,
+    target: OnceLock<SocketAddr>,
+    started: Instant,
+    from_n_bytes: AtomicUsize,
+    to_n_bytes: AtomicUsize,
+}
+
+impl ConnState {
+    /// The client, as named by the PROXY header if it sent one
+    fn peer(&self) -> &PeerAddr {
+        self.proxied_peer.get().unwrap_or(&self.accepted_peer)
+    }
+}
+
+/// A point in time view of an open connection
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    pub id: u32,
+    pub peer: PeerAddr,
+    pub forward: Source,
+    pub target: Option<SocketAddr>,
+    pub age: Duration,
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+}
+
+/// The open connections of a forward keyed by connection id
+#[derive(Clone, Default)]
+pub struct ConnRegistry(Arc<std::sync::Mutex<HashMap<u32, Arc<ConnState>>>>);
+
+impl ConnRegistry {
+    /// The open connections in the order they were accepted
+    pub fn snapshot(&self) -> Vec<ConnInfo> {
+        let conns = self.0.lock().unwrap();
+        let mut infos = conns
+            .values()
+            .map(|c| ConnInfo {
+                id: c.id,
+                peer: c.peer().clone(),
+                forward: c.forward.clone(),
+                target: c.target.get().copied(),
+                age: c.started.elapsed(),
+                from_n_bytes: c.from_n_bytes.load(Ordering::Relaxed),
+                to_n_bytes: c.to_n_bytes.load(Ordering::Relaxed),
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by_key(|c| c.age);
+        infos.reverse();
+        infos
+    }
+
+    /// Register a connection until the returned guard is dropped
+    fn register(&self, conn: Arc<ConnState>) -> RegistryGuard {
+        let id = conn.id;
+        self.0.lock().unwrap().insert(id, conn);
+        RegistryGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Removes a connection from its registry when dropped, even if the connection is aborted
+struct RegistryGuard {
+    registry: ConnRegistry,
+    id: u32,
+}
+
+impl Drop for RegistryGuard {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The number of open connections of every client IP of a forward
+#[derive(Clone, Default)]
+struct IpConnCounts(Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>);
+
+impl IpConnCounts {
+    /// Count a connection from `ip`, `None` if the client already has `max` open
+    fn acquire(&self, ip: IpAddr, max: usize) -> Option<IpConnGuard> {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnGuard {
+            counts: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Stops counting a connection when the connection closes
+struct IpConnGuard {
+    counts: IpConnCounts,
+    ip: IpAddr,
+}
+
+impl Drop for IpConnGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// State shared by every connection of a forward
+struct ForwardContext {
+    /// Swapped when a reload only changes the targets, every connection
+    /// keeps the forward it was accepted with, see [`Self::forward`]
+    forward: watch::Receiver<Arc<Forward>>,
+    options: ConnOptions,
+    tls_acceptor: Option<TlsAcceptor>,
+    /// Opens TLS to the targets, verifying them as the server name
+    tls_connector: Option<(TlsConnector, ServerName<'static>)>,
+    up_buff_pool: BufferPool,
+    down_buff_pool: BufferPool,
+    breaker: Option<CircuitBreaker>,
+    health: TargetHealth,
+    balancer: Balancer,
+    slow_start: Option<SlowStart>,
+    /// Follows the SRV record of the targets, if they came from one
+    srv: Option<Arc<SrvDiscovery>>,
+    pool: Option<ConnPool>,
+}
+
+impl ForwardContext {
+    /// The forward new connections are accepted with, taken once on accept
+    /// and passed along with the connection from there
+    fn forward(&self) -> Arc<Forward> {
+        self.forward.borrow().clone()
+    }
+}
+
+/// Health check and keep the pools of the current targets of a forward full,
+/// until the returned tasks are dropped
+fn spawn_target_tasks(
+    ctx: &Arc<ForwardContext>,
+) -> (Option<AbortOnDrop<()>>, Option<AbortOnDrop<()>>) {
+    let forward = ctx.forward();
+    let health_check_interval = ctx
+        .options
+        .health_check_interval
+        .filter(|_| !forward.is_bridged());
+    let health_check = health_check_interval.map(|interval| {
+        let (ctx, forward) = (ctx.clone(), forward.clone());
+        AbortOnDrop(tokio::spawn(async move {
+            ctx.health
+                .check_loop(
+                    &forward.targets,
+                    interval,
+                    ctx.options.health_check_timeout,
+                    &ctx.options,
+                    || {
+                        if let Some(slow_start) = &ctx.slow_start {
+                            slow_start.restart();
+                        }
+                    },
+                )
+                .await
+        }))
+    });
+    let pool_refill = ctx.pool.as_ref().map(|_| {
+        let (ctx, forward) = (ctx.clone(), forward.clone());
+        AbortOnDrop(tokio::spawn(async move {
+            if let Some(pool) = &ctx.pool {
+                pool.refill_loop(&forward.targets, &ctx.options).await
+            }
+        }))
+    });
+    (health_check, pool_refill)
+}
+
+/// Accept the connections of the forward on `forward_receiver` until shut
+/// down. Whether the listener could be bound is sent to `bound` once known,
+/// with the address of a TCP listener. A bind error is returned instead only
+/// if `bound` is no longer received. A forward sent later on applies to the
+/// connections accepted after it, the open ones keep their targets.
+pub async fn accept_conn(
+    mut forward_receiver: watch::Receiver<Arc<Forward>>,
+    options: ConnOptions,
+    meter_msg_sender: MeterMessageSender,
+    registry: ConnRegistry,
+    bound: oneshot::Sender<Result<Option<SocketAddr>, ForwardError>>,
+    mut shutdown_msg_receiver: Receiver<()>,
+) -> Result<(), ForwardError> {
+    let forward = forward_receiver.borrow_and_update().clone();
+    let source = forward.source.clone();
+    let name: Option<Arc<str>> = forward.name.as_deref().map(Arc::from);
+    let bind = || {
+        let tls_acceptor = match (forward.tls, &options.tls_cert, &options.tls_key) {
+            (true, Some(cert), Some(key)) => Some(load_acceptor(cert, key)?),
+            _ => None,
+        };
+        let tls_connector = match &forward.upstream_tls {
+            Some(upstream_tls) => {
+                let server_name =
+                    ServerName::try_from(upstream_tls.server_name.clone()).map_err(|_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("{} is not a valid server name", upstream_tls.server_name),
+                        )
+                    })?;
+                Some((load_connector(upstream_tls)?, server_name))
+            }
+            None => None,
+        };
+        Ok((
+            tls_acceptor,
+            tls_connector,
+            Listener::bind(&forward, &options)?,
+        ))
+    };
+    let (tls_acceptor, tls_connector, listener) =
+        match bind_with_retries(&forward, &options, bind).await {
+            Ok(bound_listener) => {
+                let local_addr = bound_listener.2.local_addr();
+                log_os_port(&forward, local_addr);
+                let _ = bound.send(Ok(local_addr));
+                bound_listener
+            }
+            Err(error) => {
+                let e = ForwardError::Bind {
+                    forward: source,
+                    error,
+                };
+                return bound.send(Err(e)).or_else(|unsent| unsent.map(drop));
+            }
+        };
+    // The buffer size of the forward applies to both directions
+    let up_size = forward
+        .buffer_size
+        .or(options.buff_up)
+        .unwrap_or(options.buff_size);
+    let down_size = forward
+        .buffer_size
+        .or(options.buff_down)
+        .unwrap_or(options.buff_size);
+    let (up_size, down_size) = (
+        options.direction_buff_size(up_size),
+        options.direction_buff_size(down_size),
+    );
+    let new_pool = |size| {
+        BufferPool::with_provider(
+            size,
+            options.buffer_pool_size,
+            options.buffer_provider.clone(),
+        )
+    };
+    let up_buff_pool = new_pool(up_size);
+    let down_buff_pool = match down_size == up_size {
+        true => up_buff_pool.clone(),
+        false => new_pool(down_size),
+    };
+    let breaker = options.circuit_breaker.map(CircuitBreaker::new);
+    let srv = forward
+        .srv
+        .clone()
+        .map(|name| Arc::new(SrvDiscovery::new(name)));
+    // Bridged forwards have no TCP targets to pool or health check
+    let pool = options
+        .pool_size
+        .filter(|_| !forward.is_bridged())
+        .map(|size| ConnPool::new(size, options.pool_idle_timeout));
+    let slow_start = options.slow_start.map(SlowStart::new);
+    let ctx = Arc::new(ForwardContext {
+        forward: forward_receiver.clone(),
+        options,
+        tls_acceptor,
+        tls_connector,
+        up_buff_pool,
+        down_buff_pool,
+        breaker,
+        health: TargetHealth::default(),
+        balancer: Balancer::default(),
+        slow_start,
+        srv,
+        pool,
+    });
+
+    // Health check and pool the targets until the forward stops or they
+    // are swapped
+    let mut _target_tasks = spawn_target_tasks(&ctx);
+
+    // Follow the SRV record until the forward stops
+    let _srv_refresh = ctx.srv.clone().map(|srv| {
+        let ctx = ctx.clone();
+        AbortOnDrop(tokio::spawn(
+            async move { srv.refresh_loop(&ctx.options).await },
+        ))
+    });
+
+    let mut conns: HashSet<JoinHandleWithId<()>> = HashSet::new();
+    let ip_counts = IpConnCounts::default();
+    let accept_limiter = ctx.options.accept_rate.map(RateLimiter::new);
+    let once = ctx.options.once;
+    // A slot per connection below the limit, when the others are queued
+    let conn_slots = match (ctx.options.max_conns, ctx.options.max_conns_mode) {
+        (Some(max_conns), MaxConnsMode::Queue) => Some(Arc::new(Semaphore::new(max_conns))),
+        _ => None,
+    };
+
+    loop {
+        // Wait for an incoming connections or a shutdown command
+        let accept = async {
+            // Leave the connections over the limit in the backlog until a
+            // connection closes and gives its slot back
+            let slot = match &conn_slots {
+                Some(conn_slots) => Some(conn_slots.clone().acquire_owned().await.unwrap()),
+                None => None,
+            };
+            // Leave the connections over the rate in the backlog
+            if let Some(accept_limiter) = &accept_limiter {
+                let share = ctx.slow_start.as_ref().map_or(1.0, SlowStart::share);
+                accept_limiter.acquire_share(1, share).await;
+            }
+            let (stream, peer) = listener.accept().await?;
+            Ok::<_, std::io::Error>((stream, peer, slot))
+        };
+        let (stream, peer, slot) = select! {
+            conn_future = accept => {
+                // Counted so that failing over and over can be alerted on
+                if conn_future.is_err() {
+                    let _ = meter_msg_sender.accept_error(source.clone()).await;
+                }
+                match conn_future {
+                    Ok(conn) => conn,
+                    Err(e) if is_fd_exhausted(&e) => {
+                        // Retrying right away would fail the same way until a connection closes
+                        warn!("{e}, pausing accept on {} for {}ms", source, ACCEPT_BACKOFF.as_millis());
+                        sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                    Err(error) if is_listener_broken(&error) => {
+                        return Err(ForwardError::Accept { forward: source, error });
+                    }
+                    Err(e) => {
+                        warn!("{e}");
+                        continue;
+                    }
+                }
+            },
+            shutdown_future = shutdown_msg_receiver.recv() => {
+                if shutdown_future.is_none() {
+                    return Err(ForwardError::Shutdown { forward: source });
+                }
+                break;
+            },
+            Ok(()) = forward_receiver.changed() => {
+                info!(
+                    "Forwarding new connections on {} to {}",
+                    source,
+                    ctx.forward().target_hosts.join(", ")
+                );
+                _target_tasks = spawn_target_tasks(&ctx);
+                continue;
+            },
+        };
+
+        // Reject the connection if the limit is reached
+        conns.retain(|c| !c.is_finished());
+        if let (Some(max_conns), MaxConnsMode::Reject) =
+            (ctx.options.max_conns, ctx.options.max_conns_mode)
+        {
+            if conns.len() >= max_conns {
+                warn!(
+                    "Connection limit of {} reached on {}, rejecting {}",
+                    max_conns, source, peer
+                );
+                drop(stream);
+                continue;
+            }
+        }
+
+        // Reject the connection if its client has too many open already
+        let mut ip_guard = None;
+        if let (Some(max), PeerAddr::Tcp(addr)) = (ctx.options.max_conns_per_ip, &peer) {
+            let ip = addr.ip().to_canonical();
+            ip_guard = match ip_counts.acquire(ip, max) {
+                Some(guard) => Some(guard),
+                None => {
+                    warn!(
+                        "Connection limit of {} per IP reached on {}, rejecting {}",
+                        max, source, peer
+                    );
+                    drop(stream);
+                    continue;
+                }
+            };
+        }
+
+        // Handle connection
+        let forward = ctx.forward();
+        let id = next_conn_id().await;
+        let conn = Arc::new(ConnState {
+            id,
+            accepted_peer: peer,
+            proxied_peer: OnceLock::new(),
+            forward: source.clone(),
+            target: OnceLock::new(),
+            started: Instant::now(),
+            from_n_bytes: AtomicUsize::new(0),
+            to_n_bytes: AtomicUsize::new(0),
+        });
+        let registry_guard = registry.register(conn.clone());
+        let meter_msg_sender = meter_msg_sender.clone();
+        let ctx = ctx.clone();
+        let name = name.clone();
+        // One trace span per connection, filled in as the connection goes
+        let span = info_span!(
+            "conn",
+            conn.id = id,
+            client = %conn.peer(),
+            forward = %source,
+            target = field::Empty,
+            from_n_bytes = field::Empty,
+            to_n_bytes = field::Empty,
+            error = field::Empty,
+        );
+        let join_handle =
+            tokio::spawn(
+                async move {
+                    let _registry_guard = registry_guard;
+                    let _ip_guard = ip_guard;
+                    let _slot = slot;
+
+                    // Metering is best effort, the meter may already be shut down
+                    let _ = meter_msg_sender
+                        .conn_opened(conn.forward.clone(), name, ctx.options.max_conns)
+                        .await;
+                    record_event(&ctx, &conn, ConnEventKind::Opened);
+                    let (failed, unresolved) =
+                        match handle_conn(stream, &ctx, &forward, &conn, meter_msg_sender.clone())
+                            .await
+                        {
+                            Ok(()) => (false, false),
+                            Err(e) => {
+                                warn!("#{} {}", conn.id, e);
+                                record_event(&ctx, &conn, ConnEventKind::Failed(e.to_string()));
+                                (true, ResolveError::is(&*e))
+                            }
+                        };
+                    record_event(
+                        &ctx,
+                        &conn,
+                        ConnEventKind::Closed {
+                            duration: conn.started.elapsed(),
+                            from_n_bytes: conn.from_n_bytes.load(Ordering::Relaxed),
+                            to_n_bytes: conn.to_n_bytes.load(Ordering::Relaxed),
+                        },
+                    );
+                    if unresolved {
+                        let _ = meter_msg_sender.resolve_error(conn.forward.clone()).await;
+                    } else if failed {
+                        let _ = meter_msg_sender.error(conn.forward.clone()).await;
+                    }
+
+                    // Log failed connections too, they have no target
+                    if let Some(access_log) = &ctx.options.access_log {
+                        let duration = conn.started.elapsed();
+                        access_log
+                            .log(AccessLogEntry {
+                                peer: conn.peer(),
+                                forward: &conn.forward,
+                                target: conn.target.get().copied(),
+                                started: SystemTime::now() - duration,
+                                duration,
+                                from_n_bytes: conn.from_n_bytes.load(Ordering::Relaxed),
+                                to_n_bytes: conn.to_n_bytes.load(Ordering::Relaxed),
+                            })
+                            .await;
+                    }
+                    let span = Span::current();
+                    span.record("from_n_bytes", conn.from_n_bytes.load(Ordering::Relaxed));
+                    span.record("to_n_bytes", conn.to_n_bytes.load(Ordering::Relaxed));
+                    span.record("error", failed);
+                    let _ = meter_msg_sender.conn_closed(conn.forward.clone()).await;
+                }
+                .instrument(span),
+            );
+
+        // Insert handle to hashset
+        conns.insert(JoinHandleWithId(id, join_handle));
+
+        if once {
+            info!("Stopping {} after its first connection", source);
+            break;
+        }
+    }
+
+    // Stop listening so the port can be bound again while connections drain,
+    // closing the shutdown channel tells the forwarder that it can
+    listener.close();
+    shutdown_msg_receiver.close();
+
+    // Wait for existing connections to disconnect
+    let mut conns = conns.into_iter().collect::<Vec<_>>();
+    let drain = async {
+        for c in conns.iter_mut() {
+            if let Err(e) = (&mut c.1).await {
+                warn!("{}", e);
+            }
+        }
+    };
+    let drain_timeout = match ctx.options.drain_timeout {
+        Some(drain_timeout) => drain_timeout,
+        None => {
+            drain.await;
+            return Ok(());
+        }
+    };
+    if timeout(drain_timeout, drain).await.is_ok() {
+        return Ok(());
+    }
+
+    // Abort the connections that did not finish in time
+    conns.retain(|c| !c.is_finished());
+    warn!(
+        "Aborting {} connection(s) on {} after a drain timeout of {}s",
+        conns.len(),
+        source,
+        drain_timeout.as_secs()
+    );
+    for c in conns {
+        c.abort();
+        if let Err(e) = c.1.await {
+            if e.is_cancelled() {
+                let _ = meter_msg_sender.conn_closed(source.clone()).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Record an event of `conn` if the event log is enabled
+fn record_event(ctx: &ForwardContext, conn: &ConnState, kind: ConnEventKind) {
+    if ctx.options.events_json {
+        let target = conn.target.get().copied();
+        print_event_json(conn.id, conn.peer(), &conn.forward, target, &kind);
+    }
+    if let Some(event_log) = &ctx.options.event_log {
+        event_log.record(conn.id, conn.peer(), &conn.forward, kind);
+    }
+}
+
+async fn handle_conn(
+    mut src_stream: ClientStream,
+    ctx: &ForwardContext,
+    forward: &Forward,
+    conn: &Arc<ConnState>,
+    meter_msg_sender: MeterMessageSender,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let options = &ctx.options;
+
+    // Take the real client from the header of the load balancer in front
+    if options.accept_proxy_protocol {
+        let proxied = match &mut src_stream {
+            ClientStream::Tcp(src_stream) => proxy_protocol::read_header(src_stream).await?,
+            #[cfg(unix)]
+            ClientStream::Unix(src_stream) => proxy_protocol::read_header(src_stream).await?,
+        };
+        if let Some(addr) = proxied {
+            let peer = PeerAddr::Tcp(SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+            debug!("#{} {} is proxying {}", conn.id, conn.accepted_peer, peer);
+            Span::current().record("client", field::display(&peer));
+            let _ = conn.proxied_peer.set(peer);
+        }
+    }
+
+    // Agree to decompress what the instance in front of this one compresses
+    if options.compress == Some(CompressMode::Client) && !forward.is_bridged() {
+        match &mut src_stream {
+            ClientStream::Tcp(src_stream) => Compress::accept_handshake(src_stream).await?,
+            #[cfg(unix)]
+            ClientStream::Unix(src_stream) => Compress::accept_handshake(src_stream).await?,
+        }
+    }
+
+    let original_dst = match (&src_stream, &forward.source) {
+        #[cfg(target_os = "linux")]
+        (ClientStream::Tcp(src_stream), Source::Port(port)) if options.transparent => {
+            transparent::original_dst(src_stream, *port)?
+        }
+        _ => None,
+    };
+    let client = match conn.peer() {
+        PeerAddr::Tcp(addr) => Some(addr.ip()),
+        PeerAddr::Unix(_) => None,
+    };
+
+    // Route by the SNI of the ClientHello, which is replayed to the target
+    let mut client_hello = Vec::new();
+    let mut sni_targets = None;
+    if !forward.sni_routes.is_empty() {
+        let (hello, sni) = match &mut src_stream {
+            ClientStream::Tcp(src_stream) => sni::read_client_hello(src_stream).await?,
+            #[cfg(unix)]
+            ClientStream::Unix(src_stream) => sni::read_client_hello(src_stream).await?,
+        };
+        client_hello = hello;
+        sni_targets = sni.as_deref().and_then(|sni| forward.sni_routes.get(sni));
+        debug!(
+            "#{} SNI {} {}",
+            conn.id,
+            sni.as_deref().unwrap_or("(none)"),
+            if sni_targets.is_some() {
+                "matched"
+            } else {
+                "did not match, using the default targets"
+            }
+        );
+    }
+
+    // Wait for the client to speak before connecting, the bytes are replayed
+    // to the target. Clients that leave without a word, such as port
+    // scanners, never cost a target connection.
+    let mut first_bytes = Vec::new();
+    if forward.lazy_connect && forward.sni_routes.is_empty() {
+        first_bytes = match &mut src_stream {
+            ClientStream::Tcp(src_stream) => read_first_bytes(src_stream, options).await?,
+            #[cfg(unix)]
+            ClientStream::Unix(src_stream) => read_first_bytes(src_stream, options).await?,
+        };
+        if first_bytes.is_empty() {
+            info!("#{} {} left before sending anything", conn.id, conn.peer());
+            return Ok(());
+        }
+    }
+
+    // Route by the callback of the embedding application
+    let routed_targets = options
+        .callbacks
+        .route
+        .as_ref()
+        .map(|route| route(conn.peer(), forward))
+        .filter(|targets| !targets.is_empty());
+
+    // Route by the network of the client
+    let ip_targets = client.and_then(|ip| {
+        forward
+            .ip_routes
+            .iter()
+            .find(|(cidr, _)| cidr.contains(ip))
+            .map(|(_, targets)| targets)
+    });
+
+    // Frame the datagrams of a UDP target, the client speaks frames too
+    if forward.is_bridged() {
+        let target = *routed_targets
+            .as_ref()
+            .or(ip_targets)
+            .unwrap_or(&forward.targets)
+            .first()
+            .ok_or("no target to send to")?;
+        check_target(target, options)?;
+        let _ = conn.target.set(target);
+        record_event(ctx, conn, ConnEventKind::Connected(target));
+        Span::current().record("target", field::display(target));
+        info!(
+            "Opening handle #{} for {}, bridged to UDP...",
+            conn.id,
+            conn.peer()
+        );
+        if let Some(on_connect) = &options.callbacks.on_connect {
+            on_connect(conn.peer(), forward);
+        }
+        let (idle_timeout, max_datagram) = (options.idle_timeout, options.udp_max_datagram);
+        let meter_msg_sender = &meter_msg_sender;
+        let result = match src_stream {
+            ClientStream::Tcp(src_stream) => {
+                configure_stream(&src_stream, forward, options)?;
+                bridge::bridge_to_udp(
+                    src_stream,
+                    target,
+                    idle_timeout,
+                    max_datagram,
+                    conn,
+                    meter_msg_sender,
+                )
+                .await
+            }
+            #[cfg(unix)]
+            ClientStream::Unix(src_stream) => {
+                bridge::bridge_to_udp(
+                    src_stream,
+                    target,
+                    idle_timeout,
+                    max_datagram,
+                    conn,
+                    meter_msg_sender,
+                )
+                .await
+            }
+        };
+        log_conn_closed(
+            conn,
+            ctx,
+            forward,
+            result.as_ref().map_or(CloseCause::Error, |c| *c),
+        );
+        result?;
+        return Ok(());
+    }
+
+    // The targets of the SRV record once looked up, by priority
+    let srv_targets = ctx
+        .srv
+        .as_ref()
+        .map(|srv| srv.targets())
+        .filter(|targets| !targets.groups.is_empty());
+
+    let connected = async {
+        if let Some(original_dst) = original_dst {
+            connect_target(&[original_dst], None, ctx, forward).await
+        } else if let Some(routed_targets) = &routed_targets {
+            connect_target(routed_targets, client, ctx, forward).await
+        } else if let Some(sni_targets) = sni_targets {
+            connect_target(sni_targets, client, ctx, forward).await
+        } else if let Some(ip_targets) = ip_targets {
+            connect_target(ip_targets, client, ctx, forward).await
+        } else if let Some(srv_targets) = &srv_targets {
+            let groups = srv_targets
+                .groups
+                .iter()
+                .map(Vec::as_slice)
+                .collect::<Vec<_>>();
+            connect_by_priority(&groups, &srv_targets.weights, client, ctx, forward).await
+        } else if options.resolve_on_connect {
+            connect_target(
+                &resolve_targets(forward, options).await?,
+                client,
+                ctx,
+                forward,
+            )
+            .await
+        } else {
+            connect_target(&forward.targets, client, ctx, forward).await
+        }
+    }
+    .instrument(info_span!("connect"))
+    .await;
+    let (mut tgt_stream, tgt_sockaddr) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            if let Some(response) = &forward.failure_response {
+                if let Err(e) = write_failure_response(src_stream, response, ctx).await {
+                    warn!("#{} failed to send the failure response: {}", conn.id, e);
+                }
+            }
+            return Err(e.into());
+        }
+    };
+    let _ = conn.target.set(tgt_sockaddr);
+    record_event(ctx, conn, ConnEventKind::Connected(tgt_sockaddr));
+    Span::current().record("target", field::display(tgt_sockaddr));
+    match &src_stream {
+        ClientStream::Tcp(src_stream) => configure_stream(src_stream, forward, options)?,
+        #[cfg(unix)]
+        ClientStream::Unix(_) => {}
+    }
+    configure_stream(&tgt_stream, forward, options)?;
+
+    // Tell the target about the real client before any payload
+    if options.proxy_protocol {
+        let header = match (&src_stream, conn.peer()) {
+            (ClientStream::Tcp(src_stream), PeerAddr::Tcp(src_sockaddr)) => {
+                proxy_protocol_header(*src_sockaddr, src_stream.local_addr()?)
+            }
+            _ => PROXY_UNKNOWN.to_string(),
+        };
+        tgt_stream.write_all(header.as_bytes()).await?;
+    }
+    if options.compress == Some(CompressMode::Target) {
+        Compress::connect_handshake(&mut tgt_stream).await?;
+    }
+    // The fixed opening of a protocol, neither side of it reaches the client
+    if let Some(preamble) = &forward.preamble {
+        tgt_stream.write_all(preamble).await?;
+    }
+    if let Some(banner_len) = forward.banner_len {
+        read_banner(&mut tgt_stream, banner_len).await?;
+    }
+    tgt_stream.write_all(&client_hello).await?;
+    let mut tgt_stream = match &ctx.tls_connector {
+        Some((tls_connector, server_name)) => TargetStream::Tls(Box::new(
+            connect_tls(
+                tls_connector,
+                server_name,
+                tgt_stream,
+                tgt_sockaddr,
+                forward,
+            )
+            .await?,
+        )),
+        None => TargetStream::Tcp(tgt_stream),
+    };
+    if !first_bytes.is_empty() {
+        match &mut tgt_stream {
+            TargetStream::Tcp(tgt_stream) => tgt_stream.write_all(&first_bytes).await?,
+            TargetStream::Tls(tgt_stream) => tgt_stream.write_all(&first_bytes).await?,
+        }
+        conn.from_n_bytes
+            .fetch_add(first_bytes.len(), Ordering::Relaxed);
+        let _ = meter_msg_sender
+            .send(
+                conn.id,
+                conn.peer().clone(),
+                conn.forward.clone(),
+                Direction::From,
+                first_bytes.len(),
+            )
+            .await;
+    }
+    info!("Opening handle #{} for {}...", conn.id, conn.peer());
+    if let Some(on_connect) = &options.callbacks.on_connect {
+        on_connect(conn.peer(), forward);
+    }
+    let result = match tgt_stream {
+        TargetStream::Tcp(tgt_stream) => {
+            let (tgt_rstream, tgt_wstream) = tgt_stream.into_split();
+            match options.compress {
+                Some(CompressMode::Target) => {
+                    let wire = WireMeter {
+                        meter_msg_sender: meter_msg_sender.clone(),
+                        forward: conn.forward.clone(),
+                    };
+                    forward_client(
+                        src_stream,
+                        Decompressor::new(tgt_rstream, wire.clone()),
+                        Compressor::new(tgt_wstream, wire),
+                        ctx,
+                        forward,
+                        conn,
+                        meter_msg_sender,
+                    )
+                    .await
+                }
+                _ => {
+                    forward_client(
+                        src_stream,
+                        tgt_rstream,
+                        tgt_wstream,
+                        ctx,
+                        forward,
+                        conn,
+                        meter_msg_sender,
+                    )
+                    .await
+                }
+            }
+        }
+        TargetStream::Tls(tgt_stream) => {
+            let (tgt_rstream, tgt_wstream) = split(*tgt_stream);
+            forward_client(
+                src_stream,
+                tgt_rstream,
+                tgt_wstream,
+                ctx,
+                forward,
+                conn,
+                meter_msg_sender,
+            )
+            .await
+        }
+    };
+
+    log_conn_closed(
+        conn,
+        ctx,
+        forward,
+        result.as_ref().map_or(CloseCause::Error, |c| *c),
+    );
+    result?;
+    Ok(())
+}
+
+/// Log why and how a handled connection closed
+fn log_conn_closed(conn: &ConnState, ctx: &ForwardContext, forward: &Forward, cause: CloseCause) {
+    info!(
+        "Closing handle #{} for {}, {}...",
+        conn.id,
+        conn.peer(),
+        cause
+    );
+    log_conn_summary(conn);
+    if let Some(on_disconnect) = &ctx.options.callbacks.on_disconnect {
+        on_disconnect(conn.peer(), forward);
+    }
+}
+
+/// Read the first bytes of a client, at most a buffer of them. Empty if the
+/// client closed without sending any.
+async fn read_first_bytes<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    options: &ConnOptions,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut buff = vec![0; options.buff_up.unwrap_or(options.buff_size)];
+    let n_bytes = match timeout(FIRST_BYTES_TIMEOUT, stream.read(&mut buff)).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "the client did not send anything in time",
+            ))
+        }
+    };
+    buff.truncate(n_bytes);
+    Ok(buff)
+}
+
+/// Read and drop the banner a target sends first
+async fn read_banner<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    banner_len: usize,
+) -> Result<(), std::io::Error> {
+    let mut banner = vec![0; banner_len];
+    match timeout(BANNER_TIMEOUT, stream.read_exact(&mut banner)).await {
+        Ok(result) => result.map(drop),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "the target did not send its banner in time",
+        )),
+    }
+}
+
+/// Tell the client that no target could be connected to, then close
+async fn write_failure_response(
+    src_stream: ClientStream,
+    response: &[u8],
+    ctx: &ForwardContext,
+) -> Result<(), std::io::Error> {
+    async fn write<S: AsyncWrite + Unpin>(mut stream: S, response: &[u8]) -> std::io::Result<()> {
+        stream.write_all(response).await?;
+        stream.shutdown().await
+    }
+    match (src_stream, &ctx.tls_acceptor) {
+        (ClientStream::Tcp(s), Some(tls_acceptor)) => {
+            write(tls_acceptor.accept(s).await?, response).await
+        }
+        #[cfg(unix)]
+        (ClientStream::Unix(s), Some(tls_acceptor)) => {
+            write(tls_acceptor.accept(s).await?, response).await
+        }
+        (ClientStream::Tcp(s), None) => write(s, response).await,
+        #[cfg(unix)]
+        (ClientStream::Unix(s), None) => write(s, response).await,
+    }
+}
+
+/// Log the bytes and the average throughput of each direction over the
+/// lifetime of a connection
+fn log_conn_summary(conn: &ConnState) {
+    let secs = conn.started.elapsed().as_secs_f64();
+    let (from_n_bytes, to_n_bytes) = (
+        conn.from_n_bytes.load(Ordering::Relaxed) as f64,
+        conn.to_n_bytes.load(Ordering::Relaxed) as f64,
+    );
+    info!(
+        "#{} {} lasted {:.2}s, ul: {} ({}), dl: {} ({})",
+        conn.id,
+        conn.peer(),
+        secs,
+        format_bytes(from_n_bytes),
+        format_rate(from_n_bytes / secs),
+        format_bytes(to_n_bytes),
+        format_rate(to_n_bytes / secs)
+    );
+}
+
+/// Forward a client to the halves of its target, terminating TLS on the
+/// client if the forward does
+async fn forward_client<TR, TW>(
+    src_stream: ClientStream,
+    tgt_rstream: TR,
+    tgt_wstream: TW,
+    ctx: &ForwardContext,
+    forward: &Forward,
+    conn: &Arc<ConnState>,
+    meter_msg_sender: MeterMessageSender,
+) -> Result<CloseCause, std::io::Error>
+where
+    TR: AsyncRead + Unpin + Send + 'static,
+    TW: AsyncWrite + Unpin + Send + 'static,
+{
+    match (src_stream, &ctx.tls_acceptor) {
+        (ClientStream::Tcp(src_stream), Some(tls_acceptor)) => {
+            forward_tls(
+                src_stream,
+                tls_acceptor,
+                tgt_rstream,
+                tgt_wstream,
+                ctx,
+                forward,
+                conn,
+                meter_msg_sender,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        (ClientStream::Unix(src_stream), Some(tls_acceptor)) => {
+            forward_tls(
+                src_stream,
+                tls_acceptor,
+                tgt_rstream,
+                tgt_wstream,
+                ctx,
+                forward,
+                conn,
+                meter_msg_sender,
+            )
+            .await
+        }
+        (ClientStream::Tcp(src_stream), None) => {
+            let (src_rstream, src_wstream) = src_stream.into_split();
+            Ok(forward_plain(
+                src_rstream,
+                src_wstream,
+                tgt_rstream,
+                tgt_wstream,
+                ctx,
+                forward,
+                conn,
+                meter_msg_sender,
+            )
+            .await)
+        }
+        #[cfg(unix)]
+        (ClientStream::Unix(src_stream), None) => {
+            let (src_rstream, src_wstream) = src_stream.into_split();
+            Ok(forward_plain(
+                src_rstream,
+                src_wstream,
+                tgt_rstream,
+                tgt_wstream,
+                ctx,
+                forward,
+                conn,
+                meter_msg_sender,
+            )
+            .await)
+        }
+    }
+}
+
+/// Forward a client without TLS, decompressing what it sends and
+/// compressing what it receives if it is an instance with `--compress target`
+#[allow(clippy::too_many_arguments)]
+async fn forward_plain<R, W, TR, TW>(
+    src_rstream: R,
+    src_wstream: W,
+    tgt_rstream: TR,
+    tgt_wstream: TW,
+    ctx: &ForwardContext,
+    forward: &Forward,
+    conn: &Arc<ConnState>,
     meter_msg_sender: MeterMessageSender,
-    mut shutdown_msg_receiver: Receiver<()>,
-) -> Result<(), std::io::Error> {
-    let listener = TcpListener::bind(SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-        src_port,
-    ))
-    .await?;
-
-    let mut conns = HashSet::new();
-
-    loop {
-        // Wait for an incoming connections or a shutdown command
-        let (stream, peer) = select! {
-            conn_future = listener.accept() => {
-                match conn_future {
-                    Ok((s, p)) => (s, p),
-                    Err(e) => {
-                        eprintln!("{e}");
-                        continue;
-                    }
-                }
-            },
-            shutdown_future = shutdown_msg_receiver.recv() => {
-                shutdown_future.expect("Unexpected shutdown of channel");
-                break;
-            },
-        };
-
-        // Handle connection
-        let meter_msg_sender = meter_msg_sender.clone();
-        let join_handle = tokio::spawn(async move {
-            if let Err(e) = handle_conn(stream, peer, target, buff_size, meter_msg_sender).await {
-                eprintln!("{}", e);
-            }
-        });
-
-        // Insert handle to hashset
-        conns.insert(JoinHandleWithId::new(join_handle).await.unwrap());
-
-        // Remove closed connections from hashset
-        conns.retain(|c| !c.is_finished());
+) -> CloseCause
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    TR: AsyncRead + Unpin + Send + 'static,
+    TW: AsyncWrite + Unpin + Send + 'static,
+{
+    if ctx.options.compress != Some(CompressMode::Client) {
+        return forward_halves(
+            src_rstream,
+            src_wstream,
+            tgt_rstream,
+            tgt_wstream,
+            ctx,
+            forward,
+            conn,
+            meter_msg_sender,
+        )
+        .await;
     }
+    let wire = WireMeter {
+        meter_msg_sender: meter_msg_sender.clone(),
+        forward: conn.forward.clone(),
+    };
+    forward_halves(
+        Decompressor::new(src_rstream, wire.clone()),
+        Compressor::new(src_wstream, wire),
+        tgt_rstream,
+        tgt_wstream,
+        ctx,
+        forward,
+        conn,
+        meter_msg_sender,
+    )
+    .await
+}
 
-    // Wait for existing connections to disconnect
-    for c in conns {
-        if let Err(e) = c.1.await {
-            eprintln!("{}", e);
+/// Open TLS to a target, telling certificate errors apart from the others
+async fn connect_tls(
+    tls_connector: &TlsConnector,
+    server_name: &ServerName<'static>,
+    tgt_stream: TcpStream,
+    tgt_sockaddr: SocketAddr,
+    forward: &Forward,
+) -> Result<client::TlsStream<TcpStream>, std::io::Error> {
+    let e = match tls_connector.connect(server_name.clone(), tgt_stream).await {
+        Ok(tls_stream) => return Ok(tls_stream),
+        Err(e) => e,
+    };
+    let has_client_cert = forward
+        .upstream_tls
+        .as_ref()
+        .is_some_and(|t| t.client_cert.is_some());
+    let reason = match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::InvalidCertificate(e)) => {
+            format!("the certificate of the target is not trusted: {:?}", e)
         }
-    }
+        Some(rustls::Error::AlertReceived(alert)) if is_cert_alert(*alert) => match has_client_cert
+        {
+            true => format!("the target rejected the client certificate: {:?}", alert),
+            false => format!("the target requires a client certificate: {:?}", alert),
+        },
+        _ => e.to_string(),
+    };
+    Err(std::io::Error::new(
+        e.kind(),
+        format!("TLS handshake with {} failed, {}", tgt_sockaddr, reason),
+    ))
+}
 
-    Ok(())
+/// Whether a TLS alert is about the certificate the peer was given
+fn is_cert_alert(alert: AlertDescription) -> bool {
+    matches!(
+        alert,
+        AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA
+            | AlertDescription::CertificateRequired
+            | AlertDescription::AccessDenied
+            | AlertDescription::HandshakeFailure
+    )
 }
 
-async fn handle_conn(
-    src_stream: TcpStream,
-    src_sockaddr: SocketAddr,
-    tgt_sockaddr: SocketAddr,
-    buff_size: usize,
+/// Terminate TLS on the client stream and forward the decrypted data
+#[allow(clippy::too_many_arguments)]
+async fn forward_tls<S, TR, TW>(
+    src_stream: S,
+    tls_acceptor: &TlsAcceptor,
+    tgt_rstream: TR,
+    tgt_wstream: TW,
+    ctx: &ForwardContext,
+    forward: &Forward,
+    conn: &Arc<ConnState>,
     meter_msg_sender: MeterMessageSender,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let tgt_stream = TcpStream::connect(tgt_sockaddr).await?;
+) -> Result<CloseCause, std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    TR: AsyncRead + Unpin + Send + 'static,
+    TW: AsyncWrite + Unpin + Send + 'static,
+{
+    let (src_rstream, src_wstream) = split(tls_acceptor.accept(src_stream).await?);
+    Ok(forward_halves(
+        src_rstream,
+        src_wstream,
+        tgt_rstream,
+        tgt_wstream,
+        ctx,
+        forward,
+        conn,
+        meter_msg_sender,
+    )
+    .await)
+}
 
-    println!("Opening handle for {}...", src_sockaddr);
-    let (src_rstream, src_wstream) = src_stream.into_split();
-    let (tgt_rstream, tgt_wstream) = tgt_stream.into_split();
+/// Forward both directions until they end, returning which side ended the
+/// connection
+#[allow(clippy::too_many_arguments)]
+async fn forward_halves<R, W, TR, TW>(
+    src_rstream: R,
+    src_wstream: W,
+    tgt_rstream: TR,
+    tgt_wstream: TW,
+    ctx: &ForwardContext,
+    forward: &Forward,
+    conn: &Arc<ConnState>,
+    meter_msg_sender: MeterMessageSender,
+) -> CloseCause
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    TR: AsyncRead + Unpin + Send + 'static,
+    TW: AsyncWrite + Unpin + Send + 'static,
+{
+    let splice = ctx.options.splice;
+    let activity = ctx.options.idle_timeout.map(IdleTracker::new);
 
+    let max_bytes = ctx.options.max_bytes_per_conn;
     let s2t = {
         let meter_msg_sender = meter_msg_sender.clone();
+        let loop_options = LoopOptions {
+            buff_pool: ctx.up_buff_pool.clone(),
+            splice,
+            activity: activity.clone(),
+            write_timeout: ctx.options.write_timeout,
+            coalesce: Coalesce::new(&ctx.options),
+            rate_limiter: ctx.options.rate_limiter.clone(),
+            mirror: forward.mirror.map(|addr| Mirror::spawn(addr, conn.id)),
+            capture: ctx
+                .options
+                .capture_dir
+                .as_ref()
+                .map(|dir| Capture::spawn(dir, conn.id, "s2t", ctx.options.capture_max_size)),
+            hexdump: ctx
+                .options
+                .hexdump
+                .map(|max_len| HexDump::new(conn.id, "s2t", max_len)),
+        };
+        let conn = conn.clone();
         tokio::spawn(async move {
             handle_forward(
                 src_rstream,
                 tgt_wstream,
-                buff_size,
-                MeterWrapper {
-                    meter_msg_sender,
-                    socket_addr: src_sockaddr,
-                    direction: crate::Meter::Direction::From,
-                },
+                loop_options,
+                MeterWrapper::new(meter_msg_sender, Direction::From, conn, max_bytes),
             )
             .await
         })
     };
 
     let t2s = {
-        let meter_msg_sender = meter_msg_sender;
+        let meter_msg_sender = meter_msg_sender.clone();
+        let loop_options = LoopOptions {
+            buff_pool: ctx.down_buff_pool.clone(),
+            splice,
+            activity,
+            write_timeout: ctx.options.write_timeout,
+            coalesce: Coalesce::new(&ctx.options),
+            rate_limiter: ctx.options.rate_limiter.clone(),
+            mirror: None,
+            capture: ctx
+                .options
+                .capture_dir
+                .as_ref()
+                .map(|dir| Capture::spawn(dir, conn.id, "t2s", ctx.options.capture_max_size)),
+            hexdump: ctx
+                .options
+                .hexdump
+                .map(|max_len| HexDump::new(conn.id, "t2s", max_len)),
+        };
+        let conn = conn.clone();
         tokio::spawn(async move {
             handle_forward(
                 tgt_rstream,
                 src_wstream,
-                buff_size,
-                MeterWrapper {
-                    meter_msg_sender,
-                    socket_addr: src_sockaddr,
-                    direction: crate::Meter::Direction::To,
-                },
+                loop_options,
+                MeterWrapper::new(meter_msg_sender, Direction::To, conn, max_bytes),
             )
             .await
         })
     };
 
-    let (s2t_r, t2s_r) = tokio::join!(s2t, t2s);
-    match s2t_r {
-        Ok(task_result) => {
-            if let Err(e) = task_result {
-                eprintln!("{}", e);
+    // Past its lifetime, the directions are aborted which closes both sockets.
+    // A direction that timed out or reached the quota aborts the other, which
+    // may otherwise wait forever on a stalled peer.
+    // The direction that ends first tells which side closed the connection
+    let directions = async {
+        let (mut s2t, mut t2s) = (AbortOnDrop(s2t), AbortOnDrop(t2s));
+        select! {
+            s2t_r = &mut s2t => {
+                let cause = CloseCause::of(&s2t_r, CloseCause::Client);
+                match ends_conn(&s2t_r) {
+                    true => (s2t_r, Ok(Ok(())), cause),
+                    false => (s2t_r, t2s.await, cause),
+                }
+            },
+            t2s_r = &mut t2s => {
+                let cause = CloseCause::of(&t2s_r, CloseCause::Target);
+                match ends_conn(&t2s_r) {
+                    true => (Ok(Ok(())), t2s_r, cause),
+                    false => (s2t.await, t2s_r, cause),
+                }
+            },
+        }
+    };
+    let (s2t_r, t2s_r, cause) = match ctx.options.max_lifetime {
+        Some(max_lifetime) => {
+            let remaining = max_lifetime.saturating_sub(conn.started.elapsed());
+            match timeout(remaining, directions).await {
+                Ok(results) => results,
+                Err(_) => {
+                    info!(
+                        "#{} reached the maximum lifetime of {}s, closing",
+                        conn.id,
+                        max_lifetime.as_secs()
+                    );
+                    return CloseCause::Lifetime;
+                }
             }
         }
-        Err(join_err) => eprintln!("{}", join_err),
+        None => directions.await,
     };
-    match t2s_r {
-        Ok(task_result) => {
-            if let Err(e) = task_result {
-                eprintln!("{}", e);
+    let mut failed = false;
+    for result in [s2t_r, t2s_r] {
+        match result {
+            Ok(Ok(())) => (),
+            // Reaching the quota or the idle timeout is not a failure
+            Ok(Err(HandleForwardError {
+                loop_error: Some(e),
+                shutdown_error: None,
+            })) if e.kind() == std::io::ErrorKind::QuotaExceeded || IdleTimeout::is(&e) => {
+                info!("#{} {}", conn.id, e);
+            }
+            Ok(Err(e)) => {
+                warn!("#{} {}", conn.id, e);
+                failed = true;
+            }
+            Err(join_err) => {
+                warn!("#{} {}", conn.id, join_err);
+                failed = true;
+            }
+        }
+    }
+
+    // Count a connection that failed in both directions once
+    if failed {
+        let _ = meter_msg_sender.error(conn.forward.clone()).await;
+    }
+    cause
+}
+
+/// Why a forwarded connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseCause {
+    /// The client was done sending first
+    Client,
+    /// The target was done sending first
+    Target,
+    /// Either direction failed first
+    Error,
+    Lifetime,
+    /// Nothing moved for the idle timeout
+    Idle,
+}
+
+impl CloseCause {
+    /// The cause of a connection whose first direction to end is `first`,
+    /// `by` the side it reads from if it ended cleanly
+    fn of(
+        first: &Result<Result<(), HandleForwardError>, tokio::task::JoinError>,
+        by: CloseCause,
+    ) -> CloseCause {
+        match first {
+            Ok(Ok(())) => by,
+            Ok(Err(HandleForwardError {
+                loop_error: Some(e),
+                ..
+            })) if IdleTimeout::is(e) => CloseCause::Idle,
+            _ => CloseCause::Error,
+        }
+    }
+}
+
+impl Display for CloseCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CloseCause::Client => "closed by client",
+            CloseCause::Target => "closed by target",
+            CloseCause::Error => "error",
+            CloseCause::Lifetime => "maximum lifetime reached",
+            CloseCause::Idle => "idle",
+        })
+    }
+}
+
+const PROXY_UNKNOWN: &str = "PROXY UNKNOWN\r\n";
+
+/// Build a PROXY protocol v1 header for a connection from `src` to `dst`
+fn proxy_protocol_header(src: SocketAddr, dst: SocketAddr) -> String {
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            src_ip,
+            dst_ip,
+            src.port(),
+            dst.port()
+        ),
+        (IpAddr::V6(_), IpAddr::V6(_)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            src_ip,
+            dst_ip,
+            src.port(),
+            dst.port()
+        ),
+        _ => PROXY_UNKNOWN.to_string(),
+    }
+}
+
+/// Resolve the target hosts again, falling back to the addresses resolved at
+/// startup. Fails with a [`ResolveError`] when neither gives an address.
+async fn resolve_targets(
+    forward: &Forward,
+    options: &ConnOptions,
+) -> Result<Vec<SocketAddr>, std::io::Error> {
+    let mut targets: Vec<SocketAddr> = Vec::with_capacity(forward.targets.len());
+    let mut last_error = None;
+    for host in &forward.target_hosts {
+        match options.resolver.lookup_host(host).await {
+            Ok(addrs) => {
+                for addr in addrs {
+                    if !targets.contains(&addr) {
+                        targets.push(addr);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Failed to resolve {}: {}", host, e);
+                last_error = Some(e);
             }
         }
-        Err(join_err) => eprintln!("{}", join_err),
+    }
+    if targets.is_empty() {
+        // Fall back to the addresses resolved at startup, if any
+        return match (last_error, forward.targets.is_empty()) {
+            (Some(error), true) => Err(std::io::Error::other(ResolveError {
+                hosts: forward.target_hosts.clone(),
+                error,
+            })),
+            _ => Ok(forward.targets.clone()),
+        };
+    }
+    if let Some(family) = options.prefer {
+        family.sort(&mut targets);
+    }
+    Ok(targets)
+}
+
+/// Connect to a target directly or through the upstream proxy
+async fn dial(
+    tgt_sockaddr: &SocketAddr,
+    options: &ConnOptions,
+) -> Result<TcpStream, std::io::Error> {
+    match (&options.socks5, &options.http_proxy) {
+        (Some(socks5), _) => {
+            let stream = connect_tcp(socks5.addr, options).await?;
+            socks5.tunnel(stream, tgt_sockaddr).await
+        }
+        (None, Some(http_proxy)) => {
+            let stream = connect_tcp(http_proxy.addr, options).await?;
+            http_proxy.tunnel(stream, tgt_sockaddr).await
+        }
+        (None, None) => connect_tcp(*tgt_sockaddr, options).await,
+    }
+}
+
+/// Take a pooled connection to a target if there is one, otherwise dial it
+async fn dial_pooled(
+    tgt_sockaddr: &SocketAddr,
+    ctx: &ForwardContext,
+    forward: &Forward,
+) -> Result<TcpStream, std::io::Error> {
+    check_target(*tgt_sockaddr, &ctx.options)?;
+    if let Some(stream) = ctx.pool.as_ref().and_then(|pool| pool.take(tgt_sockaddr)) {
+        return Ok(stream);
+    }
+    let dial = dial(tgt_sockaddr, &ctx.options);
+    match forward.connect_timeout.or(ctx.options.connect_timeout) {
+        Some(connect_timeout) => timeout(connect_timeout, dial).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("connect timed out after {}s", connect_timeout.as_secs()),
+            )
+        })?,
+        None => dial.await,
+    }
+}
+
+/// Refuse a target the target policy does not permit
+fn check_target(target: SocketAddr, options: &ConnOptions) -> Result<(), std::io::Error> {
+    if options.target_policy.permits(target.ip()) {
+        return Ok(());
+    }
+    warn!(
+        "Refusing to connect to {}, it is not an allowed target",
+        target
+    );
+    Err(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("{} is not an allowed target", target),
+    ))
+}
+
+/// Open a TCP connection, through the outbound interface and from the
+/// source address if set
+async fn connect_tcp(addr: SocketAddr, options: &ConnOptions) -> Result<TcpStream, std::io::Error> {
+    if options.outbound_interface.is_none() && options.source_addr.is_none() {
+        return TcpStream::connect(addr).await;
+    }
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
     };
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &options.outbound_interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    if let Some(source_addr) = options.source_addr {
+        if source_addr.is_ipv4() != addr.is_ipv4() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot connect to {} from {}", addr, source_addr),
+            ));
+        }
+        socket.bind(SocketAddr::new(source_addr, 0)).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("cannot bind the source address {}: {}", source_addr, e),
+            )
+        })?;
+    }
+    socket.connect(addr).await
+}
 
-    println!("Closing handle for {}...", src_sockaddr);
-    Ok(())
+/// Connect to the first reachable target in order, see [`connect_by_priority`]
+async fn connect_target(
+    tgt_sockaddrs: &[SocketAddr],
+    client: Option<IpAddr>,
+    ctx: &ForwardContext,
+    forward: &Forward,
+) -> Result<(TcpStream, SocketAddr), std::io::Error> {
+    connect_by_priority(&[tgt_sockaddrs], &forward.weights, client, ctx, forward).await
+}
+
+/// Connect to the first reachable target of the groups, see
+/// [`connect_round`]. When none could be connected to, the targets are tried
+/// again up to the retries of the forward, backing off longer every time.
+async fn connect_by_priority(
+    groups: &[&[SocketAddr]],
+    weights: &HashMap<SocketAddr, u32>,
+    client: Option<IpAddr>,
+    ctx: &ForwardContext,
+    forward: &Forward,
+) -> Result<(TcpStream, SocketAddr), std::io::Error> {
+    let retries = forward
+        .connect_retries
+        .unwrap_or(ctx.options.connect_retries);
+    let mut backoff = forward
+        .connect_backoff
+        .unwrap_or(ctx.options.connect_backoff);
+    let mut retry = 0;
+    loop {
+        match connect_round(groups, weights, client, ctx, forward).await {
+            Err(e) if retry < retries => {
+                retry += 1;
+                warn!(
+                    "No target of {} could be connected to ({}), retry {}/{} in {}ms...",
+                    forward.source,
+                    e,
+                    retry,
+                    retries,
+                    backoff.as_millis()
+                );
+                sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Connect to the first reachable target of the groups in order, skipping
+/// the targets whose circuit breaker is open. Targets that failed their
+/// health check are only tried if every target did. Within a group, with
+/// sticky sessions the targets are ordered by the client IP, otherwise
+/// weighted targets start from the target picked by the balancer. When an
+/// attempt takes longer than the happy eyeballs delay, the next target is
+/// raced against it.
+async fn connect_round(
+    groups: &[&[SocketAddr]],
+    weights: &HashMap<SocketAddr, u32>,
+    client: Option<IpAddr>,
+    ctx: &ForwardContext,
+    forward: &Forward,
+) -> Result<(TcpStream, SocketAddr), std::io::Error> {
+    let breaker = ctx.breaker.as_ref();
+    let any_up = groups
+        .iter()
+        .flat_map(|g| g.iter())
+        .any(|t| ctx.health.is_up(t));
+    let mut tgt_sockaddrs = Vec::new();
+    for group in groups {
+        let group = group
+            .iter()
+            .filter(|t| !any_up || ctx.health.is_up(t))
+            .copied()
+            .collect::<Vec<_>>();
+        tgt_sockaddrs.extend(match client {
+            Some(client) if ctx.options.sticky => balancer::sticky_order(&group, weights, client),
+            _ if !weights.is_empty() => ctx.balancer.order(&group, weights),
+            _ => group,
+        });
+    }
+    let delay = ctx.options.happy_eyeballs_delay;
+    let mut attempts = FuturesUnordered::new();
+    let mut next = 0;
+    let mut last_error = None;
+    loop {
+        // Start an attempt on the next target whose breaker allows it
+        while let Some(tgt_sockaddr) = tgt_sockaddrs.get(next) {
+            next += 1;
+            if breaker.is_some_and(|b| !b.allow(tgt_sockaddr)) {
+                continue;
+            }
+            attempts
+                .push(async move { (tgt_sockaddr, dial_pooled(tgt_sockaddr, ctx, forward).await) });
+            break;
+        }
+        if attempts.is_empty() {
+            break;
+        }
+
+        // Wait for an attempt to finish, or for the delay to start the next one
+        let (tgt_sockaddr, result) = select! {
+            Some(finished) = attempts.next() => finished,
+            _ = sleep(delay.unwrap_or_default()), if delay.is_some() && next < tgt_sockaddrs.len() => continue,
+        };
+        match result {
+            Ok(stream) => {
+                if let Some(breaker) = breaker {
+                    breaker.record_success(tgt_sockaddr);
+                }
+                return Ok((stream, *tgt_sockaddr));
+            }
+            Err(e) if is_refused(&e, &ctx.options) => {
+                // Nothing listens on the target, e.g. the SSH process of a
+                // local tunnel died, which no retry soon will fix
+                warn!(
+                    "{} refused the connection, nothing listens on it anymore (a dead tunnel endpoint?)",
+                    tgt_sockaddr
+                );
+                if let Some(breaker) = breaker {
+                    breaker.trip(tgt_sockaddr);
+                }
+                if ctx.options.health_check_interval.is_some() {
+                    ctx.health.refused(*tgt_sockaddr);
+                }
+                last_error = Some(e);
+            }
+            Err(e) => {
+                warn!("Failed to connect to {}: {}", tgt_sockaddr, e);
+                if let Some(breaker) = breaker {
+                    breaker.record_failure(tgt_sockaddr);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| match breaker {
+        Some(_) if !tgt_sockaddrs.is_empty() => breaker::open_error(),
+        _ => std::io::Error::new(std::io::ErrorKind::NotFound, "no target to connect to"),
+    }))
+}
+
+/// Whether the target itself refused a connection, through a proxy it is
+/// the proxy that refused
+fn is_refused(e: &std::io::Error, options: &ConnOptions) -> bool {
+    e.kind() == std::io::ErrorKind::ConnectionRefused
+        && options.socks5.is_none()
+        && options.http_proxy.is_none()
 }
 
 struct HandleForwardError {
@@ -198,6 +2327,21 @@ struct HandleForwardError {
     shutdown_error: Option<std::io::Error>,
 }
 
+/// Whether a direction stopped on a timeout or the quota, which ends the
+/// other direction too
+fn ends_conn(result: &Result<Result<(), HandleForwardError>, tokio::task::JoinError>) -> bool {
+    match result {
+        Ok(Err(HandleForwardError {
+            loop_error: Some(e),
+            ..
+        })) => matches!(
+            e.kind(),
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::QuotaExceeded
+        ),
+        _ => false,
+    }
+}
+
 impl Display for HandleForwardError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut error_strings: Vec<String> = Vec::with_capacity(2);
@@ -212,33 +2356,289 @@ impl Display for HandleForwardError {
     }
 }
 
+/// The chunks of a busy direction whose bytes are sent to the meter together
+const METER_BATCH_CHUNKS: usize = 16;
+/// How long a direction may wait for its next chunk before the bytes of the
+/// batch are sent to the meter anyway
+const METER_BATCH_INTERVAL: Duration = Duration::from_millis(10);
+
 struct MeterWrapper {
     meter_msg_sender: MeterMessageSender,
-    socket_addr: SocketAddr,
-    direction: crate::Meter::Direction,
+    direction: Direction,
+    conn: Arc<ConnState>,
+    closed: AtomicBool,
+    /// The most bytes the connection may move in both directions together
+    max_bytes: Option<usize>,
+    /// The bytes and chunks not sent to the meter yet
+    pending_bytes: AtomicUsize,
+    pending_chunks: AtomicUsize,
 }
 
 impl MeterWrapper {
+    fn new(
+        meter_msg_sender: MeterMessageSender,
+        direction: Direction,
+        conn: Arc<ConnState>,
+        max_bytes: Option<usize>,
+    ) -> Self {
+        MeterWrapper {
+            closed: AtomicBool::new(!meter_msg_sender.is_enabled()),
+            meter_msg_sender,
+            direction,
+            conn,
+            max_bytes,
+            pending_bytes: AtomicUsize::new(0),
+            pending_chunks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fail once the connection has moved its quota of bytes
+    fn check_quota(&self) -> Result<(), std::io::Error> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+        let n_bytes = self.conn.from_n_bytes.load(Ordering::Relaxed)
+            + self.conn.to_n_bytes.load(Ordering::Relaxed);
+        if n_bytes < max_bytes {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::QuotaExceeded,
+            format!(
+                "reached the quota of {} after moving {}, closing",
+                format_bytes(max_bytes as f64),
+                format_bytes(n_bytes as f64)
+            ),
+        ))
+    }
+
+    /// Meter the bytes along with those of the batch, forwarding goes on
+    /// unmetered if the meter has shut down
     async fn send(&self, n_bytes: usize) {
-        self.meter_msg_sender
-            .send(self.socket_addr, self.direction, n_bytes)
-            .await
-            .unwrap();
+        if self.count(n_bytes) {
+            self.pending_chunks.store(0, Ordering::Relaxed);
+            let n_bytes = n_bytes + self.pending_bytes.swap(0, Ordering::Relaxed);
+            self.send_to_meter(n_bytes).await;
+        }
+    }
+
+    /// Meter the bytes in a batch, sent once it has [`METER_BATCH_CHUNKS`]
+    /// chunks or by [`MeterWrapper::flush`]
+    async fn send_batched(&self, n_bytes: usize) {
+        if !self.count(n_bytes) {
+            return;
+        }
+        self.pending_bytes.fetch_add(n_bytes, Ordering::Relaxed);
+        if self.pending_chunks.fetch_add(1, Ordering::Relaxed) + 1 >= METER_BATCH_CHUNKS {
+            self.flush().await;
+        }
+    }
+
+    /// Whether the batch has bytes not sent to the meter yet
+    fn has_pending(&self) -> bool {
+        self.pending_bytes.load(Ordering::Relaxed) > 0
+    }
+
+    /// Send the bytes of the batch to the meter
+    async fn flush(&self) {
+        if self.has_pending() {
+            self.send(0).await;
+        }
+    }
+
+    /// Count the bytes on the connection, returning whether they are to be
+    /// sent to the meter too
+    fn count(&self, n_bytes: usize) -> bool {
+        match self.direction {
+            Direction::From => &self.conn.from_n_bytes,
+            Direction::To => &self.conn.to_n_bytes,
+        }
+        .fetch_add(n_bytes, Ordering::Relaxed);
+        !self.closed.load(Ordering::Relaxed)
+    }
+
+    async fn send_to_meter(&self, n_bytes: usize) {
+        let res = self
+            .meter_msg_sender
+            .send(
+                self.conn.id,
+                self.conn.peer().clone(),
+                self.conn.forward.clone(),
+                self.direction,
+                n_bytes,
+            )
+            .await;
+        if res.is_err() {
+            warn!(
+                "Meter is closed, #{} {} is no longer metered",
+                self.conn.id,
+                self.conn.peer()
+            );
+            self.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Tracks the last time data moved in either direction of a connection
+#[derive(Clone)]
+struct IdleTracker {
+    timeout: Duration,
+    last_activity: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl IdleTracker {
+    fn new(timeout: Duration) -> Self {
+        IdleTracker {
+            timeout,
+            last_activity: Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn remaining(&self) -> Duration {
+        let idle_for = self.last_activity.lock().unwrap().elapsed();
+        self.timeout.saturating_sub(idle_for)
+    }
+
+    fn timeout_error(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            IdleTimeout {
+                timeout: self.timeout,
+            },
+        )
+    }
+}
+
+/// How one direction of a connection is forwarded
+struct LoopOptions {
+    /// Its buffers bound the bytes read but not yet written
+    buff_pool: BufferPool,
+    splice: bool,
+    activity: Option<IdleTracker>,
+    write_timeout: Option<Duration>,
+    coalesce: Option<Coalesce>,
+    rate_limiter: Option<RateLimiter>,
+    mirror: Option<Mirror>,
+    capture: Option<Capture>,
+    hexdump: Option<HexDump>,
+}
+
+/// How long and for how many bytes reads are held back to be written at once
+#[derive(Clone, Copy)]
+struct Coalesce {
+    delay: Duration,
+    n_bytes: usize,
+}
+
+impl Coalesce {
+    fn new(options: &ConnOptions) -> Option<Self> {
+        Some(Coalesce {
+            delay: options.coalesce_delay?,
+            n_bytes: options.coalesce_bytes.unwrap_or(usize::MAX),
+        })
+    }
+}
+
+/// The error of a write that did not complete within `write_timeout`
+fn write_timeout_error(write_timeout: Duration) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("write blocked for more than {}s", write_timeout.as_secs()),
+    )
+}
+
+/// Whose bytes [`forward_stream`] counts, the connection is opened and
+/// closed on the meter by the caller
+pub struct StreamMeter {
+    pub meter_msg_sender: MeterMessageSender,
+    /// e.g. from [`next_conn_id`] so it does not collide with the forwards
+    pub conn_id: u32,
+    pub peer: PeerAddr,
+    pub forward: Source,
+    pub direction: Direction,
+}
+
+/// Forward everything `src_rstream` sends to `tgt_wstream` until it ends,
+/// then shut `tgt_wstream` down, like one direction of a forward. The
+/// buffer, idle and write timeouts, rate limit, quota and splice options
+/// apply, the buffer holding at most half of `max_inflight` as in a forward.
+/// The bytes are metered as `meter` says. Returns the number of bytes
+/// forwarded.
+pub async fn forward_stream<R, W>(
+    src_rstream: R,
+    tgt_wstream: W,
+    meter: StreamMeter,
+    options: &ConnOptions,
+) -> Result<usize, std::io::Error>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    let buff_size = match meter.direction {
+        Direction::From => options.buff_up,
+        Direction::To => options.buff_down,
     }
+    .unwrap_or(options.buff_size);
+    let buff_size = options.direction_buff_size(buff_size);
+    let loop_options = LoopOptions {
+        buff_pool: BufferPool::with_provider(buff_size, 0, options.buffer_provider.clone()),
+        splice: options.splice,
+        activity: options.idle_timeout.map(IdleTracker::new),
+        write_timeout: options.write_timeout,
+        coalesce: Coalesce::new(options),
+        rate_limiter: options.rate_limiter.clone(),
+        mirror: None,
+        capture: None,
+        hexdump: None,
+    };
+    let conn = Arc::new(ConnState {
+        id: meter.conn_id,
+        accepted_peer: meter.peer,
+        proxied_peer: OnceLock::new(),
+        forward: meter.forward,
+        target: OnceLock::new(),
+        started: Instant::now(),
+        from_n_bytes: AtomicUsize::new(0),
+        to_n_bytes: AtomicUsize::new(0),
+    });
+    let meter = MeterWrapper::new(
+        meter.meter_msg_sender,
+        meter.direction,
+        conn.clone(),
+        options.max_bytes_per_conn,
+    );
+    handle_forward(src_rstream, tgt_wstream, loop_options, meter)
+        .await
+        .map_err(|e| e.loop_error.or(e.shutdown_error).unwrap())?;
+    Ok(conn.from_n_bytes.load(Ordering::Relaxed) + conn.to_n_bytes.load(Ordering::Relaxed))
 }
 
-async fn handle_forward(
-    mut src_rstream: OwnedReadHalf,
-    mut tgt_wstream: OwnedWriteHalf,
-    buff_size: usize,
+async fn handle_forward<R, W>(
+    mut src_rstream: R,
+    mut tgt_wstream: W,
+    loop_options: LoopOptions,
     meter: MeterWrapper,
-) -> Result<(), HandleForwardError> {
-    let loop_res = forward_loop(&mut src_rstream, &mut tgt_wstream, buff_size, meter).await;
+) -> Result<(), HandleForwardError>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    let loop_res = forward_loop(&mut src_rstream, &mut tgt_wstream, loop_options, &meter).await;
+    meter.flush().await;
 
-    let shutdown_res = match tgt_wstream.shutdown().await {
-        Ok(_) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::NotConnected => Ok(()),
-        Err(e) => Err(e),
+    // A stalled peer would block the shutdown as well
+    let shutdown_res = match &loop_res {
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(()),
+        _ => match flush_and_shutdown(&mut tgt_wstream).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e),
+        },
     };
 
     // Error gathering
@@ -259,21 +2659,213 @@ async fn handle_forward(
     Err(error)
 }
 
-async fn forward_loop(
-    src_rstream: &mut OwnedReadHalf,
-    tgt_wstream: &mut OwnedWriteHalf,
-    buff_size: usize,
-    meter: MeterWrapper,
-) -> Result<(), std::io::Error> {
-    let mut buff = vec![0; buff_size * 1024];
+/// Flush what the stream still buffers, such as TLS records, then shut it
+/// down, so success means everything was handed to the socket
+async fn flush_and_shutdown<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<(), std::io::Error> {
+    stream.flush().await?;
+    stream.shutdown().await
+}
+
+async fn forward_loop<R, W>(
+    src_rstream: &mut R,
+    tgt_wstream: &mut W,
+    loop_options: LoopOptions,
+    meter: &MeterWrapper,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    let LoopOptions {
+        buff_pool,
+        splice,
+        activity,
+        write_timeout,
+        coalesce,
+        rate_limiter,
+        mut mirror,
+        mut capture,
+        hexdump,
+    } = loop_options;
     meter.send(0).await; // Send 0 to initialize the meter
+
+    // Use the zero-copy path when both ends are plain TCP sockets
+    // Mirrored, captured, dumped and coalesced data has to pass through userspace
+    #[cfg(target_os = "linux")]
+    if splice && mirror.is_none() && capture.is_none() && hexdump.is_none() && coalesce.is_none() {
+        let src_any = src_rstream as &mut dyn std::any::Any;
+        let tgt_any = tgt_wstream as &mut dyn std::any::Any;
+        if let (Some(src), Some(tgt)) = (
+            src_any.downcast_mut::<tokio::net::tcp::OwnedReadHalf>(),
+            tgt_any.downcast_mut::<tokio::net::tcp::OwnedWriteHalf>(),
+        ) {
+            match splice::Pipe::new(buff_pool.buff_size()) {
+                Ok(pipe) => {
+                    return splice::splice_loop(
+                        src,
+                        tgt,
+                        pipe,
+                        activity.as_ref(),
+                        write_timeout,
+                        rate_limiter.as_ref(),
+                        meter,
+                    )
+                    .await;
+                }
+                Err(e) => warn!("splice unavailable, falling back to copying: {}", e),
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = splice;
+
+    // Check out a buffer, it goes back to the pool when the loop ends
+    let mut buff = buff_pool.take();
     loop {
-        let bytes_read = src_rstream.read(&mut buff).await?;
+        let read_res = {
+            let read = async {
+                match &activity {
+                    Some(activity) => read_until_idle(src_rstream, &mut buff[..], activity).await,
+                    None => src_rstream.read(&mut buff[..]).await,
+                }
+            };
+            tokio::pin!(read);
+            // Send the batch to the meter once the source goes quiet
+            match meter.has_pending() {
+                true => match timeout(METER_BATCH_INTERVAL, &mut read).await {
+                    Ok(read_res) => read_res,
+                    Err(_) => {
+                        meter.flush().await;
+                        read.await
+                    }
+                },
+                false => read.await,
+            }
+        };
+        // A TLS client that half-closes without close_notify is done sending,
+        // not failed, the other direction keeps going
+        let mut bytes_read = match read_res {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+            res => res?,
+        };
         if bytes_read == 0 {
             break;
         };
-        tgt_wstream.write(&buff[..bytes_read]).await?;
-        meter.send(bytes_read).await;
+        let mut eof = false;
+        if let Some(coalesce) = &coalesce {
+            (bytes_read, eof) = read_more(src_rstream, &mut buff[..], bytes_read, coalesce).await?;
+        }
+        if let Some(rate_limiter) = &rate_limiter {
+            rate_limiter.acquire(bytes_read).await;
+        }
+        let mut written = 0;
+        let write = write_counted(tgt_wstream, &buff[..bytes_read], &mut written);
+        let write_res = match write_timeout {
+            Some(write_timeout) => timeout(write_timeout, write)
+                .await
+                .unwrap_or_else(|_| Err(write_timeout_error(write_timeout))),
+            None => write.await,
+        };
+        if let Err(e) = write_res {
+            // Only the bytes the target took count
+            if written > 0 {
+                meter.send(written).await;
+            }
+            if e.kind() == std::io::ErrorKind::WriteZero {
+                log_write_zero(meter);
+                return Ok(());
+            }
+            return Err(e);
+        }
+        if let Some(mirror) = &mut mirror {
+            mirror.send(&buff[..bytes_read]);
+        }
+        if let Some(capture) = &mut capture {
+            capture.write(&buff[..bytes_read]).await;
+        }
+        if let Some(hexdump) = &hexdump {
+            hexdump.log(&buff[..bytes_read]);
+        }
+        if let Some(activity) = &activity {
+            activity.touch();
+        }
+        meter.send_batched(bytes_read).await;
+        meter.check_quota()?;
+        if eof {
+            break;
+        }
     }
     Ok(())
 }
+
+/// Read on after the `filled` bytes of `buff` until the coalescing delay is
+/// over or enough bytes are held back. Returns how many bytes are and whether
+/// the source reached its end in the meantime.
+async fn read_more<R: AsyncRead + Unpin>(
+    src_rstream: &mut R,
+    buff: &mut [u8],
+    mut filled: usize,
+    coalesce: &Coalesce,
+) -> Result<(usize, bool), std::io::Error> {
+    let deadline = tokio::time::Instant::now() + coalesce.delay;
+    let limit = buff.len().min(coalesce.n_bytes);
+    while filled < limit {
+        match tokio::time::timeout_at(deadline, src_rstream.read(&mut buff[filled..limit])).await {
+            Err(_) => break,
+            Ok(Ok(0)) => return Ok((filled, true)),
+            Ok(Ok(n)) => filled += n,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok((filled, true))
+            }
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    Ok((filled, false))
+}
+
+/// A side that takes no more bytes ends the direction writing to it, like
+/// the end of the side it reads from, rather than failing the connection
+fn log_write_zero(meter: &MeterWrapper) {
+    let (side, direction) = match meter.direction {
+        Direction::From => ("the target", "upload"),
+        Direction::To => ("the client", "download"),
+    };
+    debug!(
+        "#{} {} takes no more bytes, ending the {} direction",
+        meter.conn.id, side, direction
+    );
+}
+
+/// Write all of `buff` like `write_all`, keeping the number of bytes written
+/// in `written` so it is known when a write fails midway, then flush what the
+/// stream still holds of them, such as the last compressed frame
+async fn write_counted<W: AsyncWrite + Unpin>(
+    tgt_wstream: &mut W,
+    buff: &[u8],
+    written: &mut usize,
+) -> Result<(), std::io::Error> {
+    while *written < buff.len() {
+        match tgt_wstream.write(&buff[*written..]).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => *written += n,
+        }
+    }
+    tgt_wstream.flush().await
+}
+
+async fn read_until_idle<R: AsyncRead + Unpin>(
+    src_rstream: &mut R,
+    buff: &mut [u8],
+    activity: &IdleTracker,
+) -> Result<usize, std::io::Error> {
+    loop {
+        // Time out only if the other direction has not been active either
+        let remaining = activity.remaining();
+        if remaining.is_zero() {
+            return Err(activity.timeout_error());
+        }
+        if let Ok(res) = timeout(remaining, src_rstream.read(buff)).await {
+            return res;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::{net::TcpStream, select, sync::Notify, time::sleep};
+
+use super::{dial, ConnOptions};
+
+/// How often the pools are reaped and refilled while no connection is taken
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connections to the targets of a forward dialed ahead of the clients. A
+/// pooled connection is only ever given to one client, so this only saves
+/// the connect time and suits backends that do not care which connection a
+/// client gets.
+pub(super) struct ConnPool {
+    size: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<SocketAddr, VecDeque<(Instant, TcpStream)>>>,
+    taken: Notify,
+}
+
+impl ConnPool {
+    pub(super) fn new(size: usize, idle_timeout: Duration) -> Self {
+        ConnPool {
+            size,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+            taken: Notify::new(),
+        }
+    }
+
+    /// Take a pooled connection to `target` that is still usable, if any
+    pub(super) fn take(&self, target: &SocketAddr) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let pool = idle.get_mut(target)?;
+        while let Some((dialed, stream)) = pool.pop_front() {
+            if dialed.elapsed() < self.idle_timeout && is_open(&stream) {
+                self.taken.notify_one();
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Keep the pool of every target full, closing the connections that
+    /// waited past the idle timeout or were closed by the target, forever
+    pub(super) async fn refill_loop(&self, targets: &[SocketAddr], options: &ConnOptions) {
+        loop {
+            let dials = targets.iter().map(|target| async move {
+                let missing = {
+                    let mut idle = self.idle.lock().unwrap();
+                    let pool = idle.entry(*target).or_default();
+                    pool.retain(|(dialed, stream)| {
+                        dialed.elapsed() < self.idle_timeout && is_open(stream)
+                    });
+                    self.size.saturating_sub(pool.len())
+                };
+                for _ in 0..missing {
+                    match dial(target, options).await {
+                        Ok(stream) => {
+                            let mut idle = self.idle.lock().unwrap();
+                            let pool = idle.entry(*target).or_default();
+                            pool.push_back((Instant::now(), stream));
+                        }
+                        Err(e) => {
+                            // Retried on the next round
+                            debug!("Failed to dial a pooled connection to {}: {}", target, e);
+                            break;
+                        }
+                    }
+                }
+            });
+            futures::future::join_all(dials).await;
+            select! {
+                _ = self.taken.notified() => {},
+                _ = sleep(REFILL_INTERVAL) => {},
+            }
+        }
+    }
+}
+
+/// Whether the target has neither closed a pooled connection nor sent
+/// anything on it, which no client could make sense of
+fn is_open(stream: &TcpStream) -> bool {
+    matches!(stream.try_read(&mut [0; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
@@ -0,0 +1,116 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+/// How long a client may take to send its PROXY header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest v1 header, CRLF included
+const MAX_V1_HEADER_SIZE: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_COMMAND_LOCAL: u8 = 0x20;
+const V2_COMMAND_PROXY: u8 = 0x21;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+
+/// Read the PROXY protocol v1 or v2 header a load balancer sends ahead of
+/// the client's bytes, no further than its end. Returns the address of the
+/// real client, `None` if the header names none, e.g. for a health check of
+/// the load balancer. Fails if the header is missing or malformed.
+pub(super) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, io::Error> {
+    let read = async {
+        let mut header = [0; 12];
+        stream.read_exact(&mut header).await?;
+        if header == V2_SIGNATURE {
+            read_v2(stream).await
+        } else if header.starts_with(b"PROXY ") {
+            read_v1(stream, header.to_vec()).await
+        } else {
+            Err(invalid("the connection does not start with a PROXY header"))
+        }
+    };
+    match timeout(HEADER_TIMEOUT, read).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the client did not send a PROXY header in time",
+        )),
+    }
+}
+
+/// Read the rest of `PROXY TCP4|TCP6|UNKNOWN src dst sport dport\r\n`
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut line: Vec<u8>,
+) -> Result<Option<SocketAddr>, io::Error> {
+    // Byte by byte, the bytes after the header belong to the target
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_HEADER_SIZE {
+            return Err(invalid("the PROXY header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("the PROXY header is not text"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, sport, _] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("the PROXY header has an invalid source address"))?;
+            let port = sport
+                .parse::<u16>()
+                .map_err(|_| invalid("the PROXY header has an invalid source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("the PROXY header is malformed")),
+    }
+}
+
+/// Read the rest of a binary header after its signature, skipping its TLVs
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error> {
+    let command = stream.read_u8().await?;
+    let family = stream.read_u8().await? >> 4;
+    let len = stream.read_u16().await? as usize;
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs).await?;
+    match command {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => {
+            return Err(invalid(
+                "the PROXY header has an unknown version or command",
+            ))
+        }
+    }
+    match family {
+        V2_FAMILY_INET if addrs.len() >= 12 => {
+            let ip: [u8; 4] = addrs[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        V2_FAMILY_INET6 if addrs.len() >= 36 => {
+            let ip: [u8; 16] = addrs[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        V2_FAMILY_INET | V2_FAMILY_INET6 => {
+            Err(invalid("the PROXY header is too short for its addresses"))
+        }
+        // Unix sockets and unspecified families carry no client address
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
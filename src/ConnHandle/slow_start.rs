@@ -0,0 +1,35 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The share of the accept rate allowed when a slow start begins
+const MIN_SHARE: f64 = 0.1;
+
+/// Ramps the accept rate of a forward up from a tenth of it to all of it,
+/// once the forward starts and again whenever a target comes back up, so a
+/// cold backend is not hit by every waiting client at once
+pub(super) struct SlowStart {
+    duration: Duration,
+    started: Mutex<Instant>,
+}
+
+impl SlowStart {
+    pub(super) fn new(duration: Duration) -> Self {
+        SlowStart {
+            duration,
+            started: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(super) fn restart(&self) {
+        *self.started.lock().unwrap() = Instant::now();
+    }
+
+    /// The share of the accept rate allowed now, growing linearly
+    pub(super) fn share(&self) -> f64 {
+        let elapsed = self.started.lock().unwrap().elapsed();
+        let progress = (elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0);
+        MIN_SHARE + (1.0 - MIN_SHARE) * progress
+    }
+}
@@ -0,0 +1,101 @@
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+/// How long a client may take to send its ClientHello
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const RECORD_HEADER_SIZE: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// Read the first TLS record of a client, which holds its ClientHello.
+/// Returns the bytes read, to be replayed to the target, and the SNI
+/// hostname if the record is a ClientHello with one.
+pub(super) async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(Vec<u8>, Option<String>), io::Error> {
+    let read = async {
+        let mut record = vec![0; RECORD_HEADER_SIZE];
+        stream.read_exact(&mut record).await?;
+        if record[0] != CONTENT_TYPE_HANDSHAKE {
+            return Ok((record, None));
+        }
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(RECORD_HEADER_SIZE + len, 0);
+        stream.read_exact(&mut record[RECORD_HEADER_SIZE..]).await?;
+        let sni = parse_sni(&record[RECORD_HEADER_SIZE..]);
+        Ok((record, sni))
+    };
+    match timeout(CLIENT_HELLO_TIMEOUT, read).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the client did not send a ClientHello in time",
+        )),
+    }
+}
+
+/// A cursor over a ClientHello that yields `None` once out of bytes
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Take a block prefixed by its length of `len_size` bytes
+    fn block(&mut self, len_size: usize) -> Option<Reader<'a>> {
+        let len = self
+            .take(len_size)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        Some(Reader(self.take(len)?))
+    }
+}
+
+/// The lowercase SNI hostname of a ClientHello handshake message
+fn parse_sni(handshake: &[u8]) -> Option<String> {
+    let mut r = Reader(handshake);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = r.block(3)?;
+    hello.take(2 + 32)?; // Version and random
+    hello.block(1)?; // Session id
+    hello.block(2)?; // Cipher suites
+    hello.block(1)?; // Compression methods
+    let mut extensions = hello.block(2)?;
+    while let Some(extension_type) = extensions.u16() {
+        let mut extension = extensions.block(2)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = extension.block(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.block(2)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return Some(String::from_utf8_lossy(name.0).to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,151 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::Duration,
+};
+
+use tokio::{
+    io::Interest,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time::timeout,
+};
+
+use super::{log_write_zero, write_timeout_error, IdleTracker, MeterWrapper};
+use crate::RateLimit::RateLimiter;
+
+/// A non-blocking pipe used as the in-kernel buffer between two sockets
+pub(super) struct Pipe {
+    r: OwnedFd,
+    w: OwnedFd,
+    /// The most bytes moved through the pipe at once
+    len: usize,
+}
+
+impl Pipe {
+    pub(super) fn new(size: usize) -> Result<Pipe, io::Error> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = unsafe {
+            Pipe {
+                r: OwnedFd::from_raw_fd(fds[0]),
+                w: OwnedFd::from_raw_fd(fds[1]),
+                len: size,
+            }
+        };
+
+        // Best effort, the kernel keeps its default size if this is not allowed
+        unsafe { libc::fcntl(pipe.w.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) };
+        Ok(pipe)
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> Result<usize, io::Error> {
+    let n = unsafe {
+        libc::splice(
+            fd_in,
+            ptr::null_mut(),
+            fd_out,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+async fn readable(stream: &TcpStream, activity: Option<&IdleTracker>) -> Result<(), io::Error> {
+    let activity = match activity {
+        Some(activity) => activity,
+        None => return stream.readable().await,
+    };
+    loop {
+        // Time out only if the other direction has not been active either
+        let remaining = activity.remaining();
+        if remaining.is_zero() {
+            return Err(activity.timeout_error());
+        }
+        if let Ok(res) = timeout(remaining, stream.readable()).await {
+            return res;
+        }
+    }
+}
+
+/// Move data from `src_rstream` to `tgt_wstream` through `pipe` without copying it to userspace
+pub(super) async fn splice_loop(
+    src_rstream: &mut OwnedReadHalf,
+    tgt_wstream: &mut OwnedWriteHalf,
+    pipe: Pipe,
+    activity: Option<&IdleTracker>,
+    write_timeout: Option<Duration>,
+    rate_limiter: Option<&RateLimiter>,
+    meter: &MeterWrapper,
+) -> Result<(), io::Error> {
+    let (src, tgt): (&TcpStream, &TcpStream) = (src_rstream.as_ref(), tgt_wstream.as_ref());
+    let (src_fd, tgt_fd) = (src.as_raw_fd(), tgt.as_raw_fd());
+    let (pipe_r, pipe_w, len) = (pipe.r.as_raw_fd(), pipe.w.as_raw_fd(), pipe.len);
+    loop {
+        // Move data from the source socket into the empty pipe
+        let bytes_read = loop {
+            readable(src, activity).await?;
+            match src.try_io(Interest::READABLE, || splice(src_fd, pipe_w, len)) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if bytes_read == 0 {
+            break;
+        }
+
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire(bytes_read).await;
+        }
+
+        // Drain the pipe into the target socket
+        let mut in_pipe = bytes_read;
+        let drain = async {
+            while in_pipe > 0 {
+                tgt.writable().await?;
+                match tgt.try_io(Interest::WRITABLE, || splice(pipe_r, tgt_fd, in_pipe)) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => in_pipe -= n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        };
+        let drain_res = match write_timeout {
+            Some(write_timeout) => timeout(write_timeout, drain)
+                .await
+                .unwrap_or_else(|_| Err(write_timeout_error(write_timeout))),
+            None => drain.await,
+        };
+        if let Err(e) = drain_res {
+            // Only the bytes the target took count
+            if in_pipe < bytes_read {
+                meter.send(bytes_read - in_pipe).await;
+            }
+            if e.kind() == io::ErrorKind::WriteZero {
+                log_write_zero(meter);
+                return Ok(());
+            }
+            return Err(e);
+        }
+        if let Some(activity) = activity {
+            activity.touch();
+        }
+        meter.send(bytes_read).await;
+        meter.check_quota()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::time::interval;
+
+use super::ConnOptions;
+
+/// How often the SRV record of a forward is looked up again
+const SRV_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The targets of an SRV record, grouped by priority with the best first
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct SrvTargets {
+    pub(super) groups: Vec<Vec<SocketAddr>>,
+    /// The weight of every address, a zero weight counts as 1
+    pub(super) weights: HashMap<SocketAddr, u32>,
+}
+
+/// Keeps the targets of a forward in step with its SRV record
+pub(super) struct SrvDiscovery {
+    name: String,
+    targets: Mutex<Arc<SrvTargets>>,
+}
+
+impl SrvDiscovery {
+    pub(super) fn new(name: String) -> Self {
+        SrvDiscovery {
+            name,
+            targets: Mutex::default(),
+        }
+    }
+
+    /// The targets of the last lookup, empty until one succeeds
+    pub(super) fn targets(&self) -> Arc<SrvTargets> {
+        self.targets.lock().unwrap().clone()
+    }
+
+    /// Look up the record now and every interval after, keeping the
+    /// previous targets when a lookup fails
+    pub(super) async fn refresh_loop(&self, options: &ConnOptions) {
+        let mut interval = interval(SRV_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.lookup(options).await {
+                Ok(targets) => {
+                    let mut current = self.targets.lock().unwrap();
+                    if **current != targets {
+                        info!(
+                            "The SRV record {} points to {}",
+                            self.name,
+                            format_groups(&targets.groups)
+                        );
+                        *current = Arc::new(targets);
+                    }
+                }
+                Err(e) => warn!("Failed to look up the SRV record {}: {}", self.name, e),
+            }
+        }
+    }
+
+    async fn lookup(&self, options: &ConnOptions) -> Result<SrvTargets, String> {
+        let records = options
+            .resolver
+            .lookup_srv(&self.name)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut targets = SrvTargets::default();
+        let mut priority = None;
+        for record in records {
+            let host_port = format!("{}:{}", record.host, record.port);
+            let mut addrs = match options.resolver.lookup_host(&host_port).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    warn!("Failed to resolve {}: {}", host_port, e);
+                    continue;
+                }
+            };
+            if let Some(family) = options.prefer {
+                family.sort(&mut addrs);
+            }
+            if priority != Some(record.priority) {
+                priority = Some(record.priority);
+                targets.groups.push(Vec::new());
+            }
+            let group = targets.groups.last_mut().unwrap();
+            for addr in addrs {
+                if let Entry::Vacant(entry) = targets.weights.entry(addr) {
+                    entry.insert(u32::from(record.weight).max(1));
+                    group.push(addr);
+                }
+            }
+        }
+        targets.groups.retain(|group| !group.is_empty());
+        if targets.groups.is_empty() {
+            return Err("no target could be resolved".to_string());
+        }
+        Ok(targets)
+    }
+}
+
+/// Format the groups as `a, b then c`
+fn format_groups(groups: &[Vec<SocketAddr>]) -> String {
+    groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .collect::<Vec<_>>()
+        .join(" then ")
+}
@@ -0,0 +1,49 @@
+use std::{io, net::SocketAddr};
+
+use log::warn;
+use socket2::{SockRef, Socket};
+use tokio::net::TcpStream;
+
+/// Set IP_TRANSPARENT so TPROXY rules can deliver connections addressed to
+/// other hosts, REDIRECT rules keep working without it
+pub(super) fn set_listener_transparent(socket: &Socket, addr: SocketAddr) {
+    let result = match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+        SocketAddr::V6(_) => socket.set_ip_transparent_v6(true),
+    };
+    if let Err(e) = result {
+        warn!(
+            "Failed to set IP_TRANSPARENT on {}: {}, only REDIRECT rules are supported",
+            addr, e
+        );
+    }
+}
+
+/// The destination a client originally connected to, `None` if the
+/// connection was not intercepted and is addressed to the listener itself
+pub(super) fn original_dst(
+    stream: &TcpStream,
+    listen_port: u16,
+) -> Result<Option<SocketAddr>, io::Error> {
+    let local_addr = stream.local_addr()?;
+    let local_addr = SocketAddr::new(local_addr.ip().to_canonical(), local_addr.port());
+
+    // Rewritten by a REDIRECT or DNAT rule, conntrack knows the original
+    let sock = SockRef::from(stream);
+    let redirected = match local_addr {
+        SocketAddr::V4(_) => sock.original_dst_v4(),
+        SocketAddr::V6(_) => sock.original_dst_v6(),
+    };
+    if let Some(dst) = redirected.ok().and_then(|dst| dst.as_socket()) {
+        let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+        if dst != local_addr {
+            return Ok(Some(dst));
+        }
+    }
+
+    // Delivered by a TPROXY rule, the local address is the original one
+    if local_addr.port() != listen_port {
+        return Ok(Some(local_addr));
+    }
+    Ok(None)
+}
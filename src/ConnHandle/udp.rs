@@ -0,0 +1,481 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    sync::{atomic::AtomicUsize, atomic::Ordering, Arc, Mutex, OnceLock},
+    time::Instant,
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{tcp::OwnedReadHalf, UdpSocket},
+    select,
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver},
+        oneshot,
+    },
+    time::{sleep, timeout},
+};
+
+use super::{
+    bind_with_retries,
+    bridge::{write_frame, FrameReader},
+    check_target, configure_stream, connect_tcp, log_conn_summary, log_os_port, next_conn_id,
+    AbortOnDrop, CloseCause, ConnOptions, ConnRegistry, ConnState, ForwardError, IdleTracker,
+    PeerAddr,
+};
+use crate::{
+    Config::{self, Forward, Source},
+    Meter::{Direction, MeterMessageSender},
+};
+
+/// The largest UDP payload
+pub(super) const MAX_DATAGRAM_SIZE: usize = 65507;
+/// The datagrams of a flow bridged to TCP waiting to be written, including
+/// while it connects, more are dropped
+const FLOW_QUEUE_SIZE: usize = 64;
+
+/// The datagrams of one client, relayed through a socket connected to the target
+struct Flow {
+    target: FlowTarget,
+    activity: IdleTracker,
+    conn: Arc<ConnState>,
+    task: AbortOnDrop<()>,
+}
+
+/// Where a flow sends the datagrams of its client
+#[derive(Clone)]
+enum FlowTarget {
+    Udp(Arc<UdpSocket>),
+    /// Queued for the task writing them as frames to the TCP target
+    Tcp(mpsc::Sender<Vec<u8>>),
+}
+
+impl FlowTarget {
+    async fn send(&self, datagram: &[u8]) -> Result<(), io::Error> {
+        match self {
+            FlowTarget::Udp(socket) => socket.send(datagram).await.map(drop),
+            FlowTarget::Tcp(queue) => match queue.try_send(datagram.to_vec()) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "the TCP target falls behind, dropping the datagram",
+                )),
+                Err(TrySendError::Closed(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+        }
+    }
+}
+
+/// Where a flow receives the replies of the target
+enum FlowReplies {
+    Udp(Arc<UdpSocket>),
+    Tcp {
+        frames: FrameReader<OwnedReadHalf>,
+        /// Writes the queued datagrams, stopped with the flow
+        _writer: AbortOnDrop<()>,
+    },
+}
+
+impl FlowReplies {
+    /// The next reply in `buff` and its size, which is larger than `buff`
+    /// if it did not fit, `None` once the TCP target has closed
+    async fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, io::Error> {
+        match self {
+            FlowReplies::Udp(socket) => socket.recv(buff).await.map(Some),
+            FlowReplies::Tcp { frames, .. } => Ok(frames.next().await?.map(|datagram| {
+                let n_bytes = datagram.len().min(buff.len());
+                buff[..n_bytes].copy_from_slice(&datagram[..n_bytes]);
+                datagram.len()
+            })),
+        }
+    }
+}
+
+/// The replies of a flow once its target is connected to
+type PendingReplies = BoxFuture<'static, Result<FlowReplies, io::Error>>;
+
+type Flows = Arc<Mutex<HashMap<SocketAddr, Flow>>>;
+
+/// A buffer for datagrams of up to `max_datagram` bytes, with a byte more to
+/// tell the larger ones the OS truncated apart
+pub(super) fn datagram_buff(max_datagram: usize) -> Vec<u8> {
+    vec![0; max_datagram + 1]
+}
+
+/// Drop a datagram from `from` larger than `max_datagram` bytes, counting
+/// it as an error of `forward`
+pub(super) async fn drop_oversized(
+    from: impl Display,
+    max_datagram: usize,
+    forward: &Source,
+    meter_msg_sender: &MeterMessageSender,
+) {
+    warn!(
+        "Dropping a datagram from {} larger than {} bytes",
+        from, max_datagram
+    );
+    let _ = meter_msg_sender.error(forward.clone()).await;
+}
+
+/// Bind a UDP socket, receiving both IPv4 and IPv6 datagrams on `[::]`
+fn bind_udp(addr: SocketAddr, options: &ConnOptions) -> Result<UdpSocket, io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &options.bind_interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    #[cfg(unix)]
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = options;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Relay the datagrams of every client to the first target of its route,
+/// each client gets its own socket so the replies can be told apart. Flows
+/// are closed after being idle for the UDP timeout, and the datagrams of new
+/// clients are dropped while the flow limit is reached. Whether the socket
+/// could be bound is sent to `bound`, like [`accept_conn`](super::accept_conn).
+pub async fn accept_udp(
+    forward: Forward,
+    options: ConnOptions,
+    meter_msg_sender: MeterMessageSender,
+    registry: ConnRegistry,
+    bound: oneshot::Sender<Result<Option<SocketAddr>, ForwardError>>,
+    mut shutdown_msg_receiver: Receiver<()>,
+) -> Result<(), ForwardError> {
+    let bind = || {
+        let port = match &forward.source {
+            Source::Port(port) => *port,
+            Source::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("UDP cannot be forwarded from {}", forward.source),
+                ))
+            }
+        };
+        let target = match forward.targets.first() {
+            Some(target) => *target,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no target to send to",
+                ))
+            }
+        };
+        #[cfg(unix)]
+        if let Some(fds) = options
+            .activated_sockets
+            .as_ref()
+            .and_then(|s| s.sockets(&forward))
+        {
+            let fd = fds?.pop().expect("one socket per UDP forward");
+            return Ok((Arc::new(super::activation::udp_socket(fd, port)?), target));
+        }
+        let socket = bind_udp(SocketAddr::new(forward.bind_addrs[0], port), &options)?;
+        Ok((Arc::new(socket), target))
+    };
+    let (socket, target) = match bind_with_retries(&forward, &options, bind).await {
+        Ok(bound_socket) => {
+            let local_addr = bound_socket.0.local_addr().ok();
+            log_os_port(&forward, local_addr);
+            let _ = bound.send(Ok(local_addr));
+            bound_socket
+        }
+        Err(error) => {
+            let e = ForwardError::Bind {
+                forward: forward.source.clone(),
+                error,
+            };
+            return bound.send(Err(e)).or_else(|unsent| unsent.map(drop));
+        }
+    };
+    let name = forward.name.as_deref().map(Arc::from);
+    let flows = Flows::default();
+    let max_datagram = options.udp_max_datagram;
+    let mut buff = datagram_buff(max_datagram);
+
+    loop {
+        // Wait for a datagram or a shutdown command
+        let (n_bytes, client) = select! {
+            received = socket.recv_from(&mut buff) => match received {
+                Ok((n_bytes, client)) => {
+                    (n_bytes, SocketAddr::new(client.ip().to_canonical(), client.port()))
+                }
+                Err(e) => {
+                    warn!("{e}");
+                    continue;
+                }
+            },
+            shutdown_future = shutdown_msg_receiver.recv() => {
+                if shutdown_future.is_none() {
+                    return Err(ForwardError::Shutdown { forward: forward.source.clone() });
+                }
+                break;
+            },
+        };
+        if n_bytes > max_datagram {
+            drop_oversized(client, max_datagram, &forward.source, &meter_msg_sender).await;
+            continue;
+        }
+
+        // Find the flow of the client, opening one for a new client
+        let existing = flows
+            .lock()
+            .unwrap()
+            .get(&client)
+            .map(|f| (f.target.clone(), f.activity.clone(), f.conn.clone()));
+        let (flow_target, activity, conn) = match existing {
+            Some(flow) => flow,
+            None => {
+                // Drop the datagram if the flow limit is reached
+                if let Some(max_flows) = options.udp_max_flows {
+                    if flows.lock().unwrap().len() >= max_flows {
+                        warn!(
+                            "UDP flow limit of {} reached on {}, dropping the datagram of {}",
+                            max_flows, forward.source, client
+                        );
+                        continue;
+                    }
+                }
+                let target = forward
+                    .ip_routes
+                    .iter()
+                    .find(|(cidr, _)| cidr.contains(client.ip()))
+                    .and_then(|(_, targets)| targets.first().copied())
+                    .unwrap_or(target);
+                let (flow_target, replies) = match open_flow(target, &forward, &options).await {
+                    Ok(flow) => flow,
+                    Err(e) => {
+                        warn!("Failed to open a UDP flow to {}: {}", target, e);
+                        let _ = meter_msg_sender.error(forward.source.clone()).await;
+                        continue;
+                    }
+                };
+                let conn = Arc::new(ConnState {
+                    id: next_conn_id().await,
+                    accepted_peer: PeerAddr::Tcp(client),
+                    proxied_peer: OnceLock::new(),
+                    forward: forward.source.clone(),
+                    target: OnceLock::from(target),
+                    started: Instant::now(),
+                    from_n_bytes: AtomicUsize::new(0),
+                    to_n_bytes: AtomicUsize::new(0),
+                });
+                info!("Opening UDP flow #{} for {}...", conn.id, client);
+                let _ = meter_msg_sender
+                    .conn_opened(forward.source.clone(), name.clone(), None)
+                    .await;
+                let activity = IdleTracker::new(options.udp_timeout);
+                let task = tokio::spawn(relay_replies(
+                    socket.clone(),
+                    replies,
+                    activity.clone(),
+                    max_datagram,
+                    conn.clone(),
+                    registry.clone(),
+                    flows.clone(),
+                    meter_msg_sender.clone(),
+                ));
+                flows.lock().unwrap().insert(
+                    client,
+                    Flow {
+                        target: flow_target.clone(),
+                        activity: activity.clone(),
+                        conn: conn.clone(),
+                        task: AbortOnDrop(task),
+                    },
+                );
+                (flow_target, activity, conn)
+            }
+        };
+
+        // Relay the datagram to the target
+        activity.touch();
+        match flow_target.send(&buff[..n_bytes]).await {
+            Ok(_) => {
+                conn.from_n_bytes.fetch_add(n_bytes, Ordering::Relaxed);
+                let _ = meter_msg_sender
+                    .send(
+                        conn.id,
+                        conn.peer().clone(),
+                        conn.forward.clone(),
+                        Direction::From,
+                        n_bytes,
+                    )
+                    .await;
+            }
+            Err(e) => warn!("#{} {}", conn.id, e),
+        }
+    }
+
+    // Close every flow, there is no connection to drain
+    let flows = flows.lock().unwrap().drain().collect::<Vec<_>>();
+    for (_, flow) in flows {
+        drop(flow.task);
+        log_conn_summary(&flow.conn);
+        let _ = meter_msg_sender
+            .conn_closed(flow.conn.forward.clone())
+            .await;
+    }
+    Ok(())
+}
+
+/// Open the flow of a client to `target`, returning where to send its
+/// datagrams and the replies of the target once they can be received. A
+/// flow bridged to TCP queues the datagrams while the replies connect, so
+/// the datagrams of the other clients do not wait on it.
+async fn open_flow(
+    target: SocketAddr,
+    forward: &Forward,
+    options: &ConnOptions,
+) -> Result<(FlowTarget, PendingReplies), io::Error> {
+    check_target(target, options)?;
+    if forward.target_protocol == Config::Protocol::Udp {
+        let socket = Arc::new(connect_udp(target).await?);
+        let replies = future::ready(Ok(FlowReplies::Udp(socket.clone())));
+        return Ok((FlowTarget::Udp(socket), replies.boxed()));
+    }
+    let (queue, queued) = mpsc::channel::<Vec<u8>>(FLOW_QUEUE_SIZE);
+    let replies = connect_flow(target, forward.clone(), options.clone(), queued);
+    Ok((FlowTarget::Tcp(queue), replies.boxed()))
+}
+
+/// Connect a flow bridged to TCP to `target`, then write the datagrams
+/// `queued` as frames. Without a connect timeout, the connect may take as
+/// long as the flow may stay idle.
+async fn connect_flow(
+    target: SocketAddr,
+    forward: Forward,
+    options: ConnOptions,
+    mut queued: mpsc::Receiver<Vec<u8>>,
+) -> Result<FlowReplies, io::Error> {
+    let connect_timeout = forward
+        .connect_timeout
+        .or(options.connect_timeout)
+        .unwrap_or(options.udp_timeout);
+    let stream = timeout(connect_timeout, connect_tcp(target, &options))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))?;
+    configure_stream(&stream, &forward, &options)?;
+    let (rstream, mut wstream) = stream.into_split();
+    let writer = tokio::spawn(async move {
+        while let Some(datagram) = queued.recv().await {
+            if let Err(e) = write_frame(&mut wstream, &datagram).await {
+                warn!("Failed to write a datagram to {}: {}", target, e);
+                break;
+            }
+        }
+    });
+    Ok(FlowReplies::Tcp {
+        frames: FrameReader::new(rstream),
+        _writer: AbortOnDrop(writer),
+    })
+}
+
+/// Open a socket that only exchanges datagrams with `target`
+pub(super) async fn connect_udp(target: SocketAddr) -> Result<UdpSocket, io::Error> {
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Send the replies of the target back to the client until the flow is idle
+/// or its TCP target closes
+#[allow(clippy::too_many_arguments)]
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    replies: PendingReplies,
+    activity: IdleTracker,
+    max_datagram: usize,
+    conn: Arc<ConnState>,
+    registry: ConnRegistry,
+    flows: Flows,
+    meter_msg_sender: MeterMessageSender,
+) {
+    let _registry_guard = registry.register(conn.clone());
+    let client = match conn.peer() {
+        PeerAddr::Tcp(client) => *client,
+        PeerAddr::Unix(_) => unreachable!("UDP clients have an address"),
+    };
+    let mut buff = datagram_buff(max_datagram);
+    let cause = 'relay: {
+        let mut replies = match replies.await {
+            Ok(replies) => replies,
+            Err(e) => {
+                let target = conn.target.get().expect("UDP flows have a target");
+                warn!("#{} failed to connect to {}: {}", conn.id, target, e);
+                let _ = meter_msg_sender.error(conn.forward.clone()).await;
+                break 'relay CloseCause::Error;
+            }
+        };
+        loop {
+            let n_bytes = select! {
+                received = replies.recv(&mut buff) => match received {
+                    Ok(Some(n_bytes)) => n_bytes,
+                    Ok(None) => break CloseCause::Target,
+                    // e.g. an ICMP port unreachable for a previous datagram
+                    Err(e) if matches!(replies, FlowReplies::Udp(_)) => {
+                        warn!("#{} {}", conn.id, e);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("#{} {}", conn.id, e);
+                        break CloseCause::Error;
+                    }
+                },
+                _ = sleep(activity.remaining()) => {
+                    if activity.remaining().is_zero() {
+                        break CloseCause::Idle;
+                    }
+                    continue;
+                },
+            };
+            activity.touch();
+            if n_bytes > max_datagram {
+                let target = conn.target.get().expect("UDP flows have a target");
+                drop_oversized(target, max_datagram, &conn.forward, &meter_msg_sender).await;
+                continue;
+            }
+            if let Err(e) = socket.send_to(&buff[..n_bytes], client).await {
+                warn!("#{} {}", conn.id, e);
+                continue;
+            }
+            conn.to_n_bytes.fetch_add(n_bytes, Ordering::Relaxed);
+            let _ = meter_msg_sender
+                .send(
+                    conn.id,
+                    conn.peer().clone(),
+                    conn.forward.clone(),
+                    Direction::To,
+                    n_bytes,
+                )
+                .await;
+        }
+    };
+
+    info!("Closing UDP flow #{} for {}, {}...", conn.id, client, cause);
+    log_conn_summary(&conn);
+    let _ = meter_msg_sender.conn_closed(conn.forward.clone()).await;
+
+    // Removing the flow aborts this task, nothing may be awaited after it
+    flows.lock().unwrap().remove(&client);
+}
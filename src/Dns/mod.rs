@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    lookup::SrvLookup,
+    Resolver as SyncResolver, TokioAsyncResolver,
+};
+
+/// A resolver config that only queries `server`
+fn server_config(server: SocketAddr) -> ResolverConfig {
+    ResolverConfig::from_parts(
+        None,
+        Vec::new(),
+        NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true),
+    )
+}
+
+/// Resolve `host` through `server`, this must be called outside of the tokio runtime
+pub fn lookup_host(server: SocketAddr, host: &str) -> Result<Vec<IpAddr>, String> {
+    let resolver = SyncResolver::new(server_config(server), ResolverOpts::default())
+        .map_err(|e| e.to_string())?;
+    match resolver.lookup_ip(host) {
+        Ok(lookup) => Ok(lookup.iter().collect()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A target of an SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower is preferred, the higher ones are only used when the lower ones fail
+    pub priority: u16,
+    /// The share of connections among the records of the same priority
+    pub weight: u16,
+    pub host: String,
+    pub port: u16,
+}
+
+/// The targets of an SRV lookup, best priority first. A target of `.`
+/// means the service is not available and is left out.
+fn srv_records(lookup: SrvLookup) -> Vec<SrvRecord> {
+    let mut records = lookup
+        .iter()
+        .filter(|srv| !srv.target().is_root())
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            port: srv.port(),
+        })
+        .collect::<Vec<_>>();
+    records.sort_by_key(|r| r.priority);
+    records
+}
+
+/// Look up the SRV record `name` through `server`, or the system resolver if
+/// not given. This must be called outside of the tokio runtime.
+pub fn lookup_srv(server: Option<SocketAddr>, name: &str) -> Result<Vec<SrvRecord>, String> {
+    let resolver = match server {
+        Some(server) => SyncResolver::new(server_config(server), ResolverOpts::default()),
+        None => SyncResolver::from_system_conf(),
+    }
+    .map_err(|e| e.to_string())?;
+    match resolver.srv_lookup(name) {
+        Ok(lookup) => Ok(srv_records(lookup)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Resolved addresses kept until they expire
+#[derive(Debug)]
+struct DnsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl DnsCache {
+    fn get(&self, host_port: &str) -> Option<Vec<SocketAddr>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(host_port) {
+            Some((resolved, addrs)) if resolved.elapsed() < self.ttl => Some(addrs.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, host_port: &str, addrs: Vec<SocketAddr>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (resolved, _)| resolved.elapsed() < self.ttl);
+        entries.insert(host_port.to_string(), (Instant::now(), addrs));
+    }
+}
+
+/// Resolves target hostnames at connect time, through the system resolver
+/// or a given DNS server. Clones share the cache.
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    server: Option<TokioAsyncResolver>,
+    cache: Option<Arc<DnsCache>>,
+}
+
+impl Resolver {
+    /// Resolve through `server` if given, caching the answers for `cache_ttl` if given
+    pub fn new(server: Option<SocketAddr>, cache_ttl: Option<Duration>) -> Self {
+        Resolver {
+            server: server
+                .map(|s| TokioAsyncResolver::tokio(server_config(s), ResolverOpts::default())),
+            cache: cache_ttl.map(|ttl| {
+                Arc::new(DnsCache {
+                    ttl,
+                    entries: Mutex::new(HashMap::new()),
+                })
+            }),
+        }
+    }
+
+    /// Resolve a `host:port` into every address it resolves to
+    pub async fn lookup_host(&self, host_port: &str) -> Result<Vec<SocketAddr>, io::Error> {
+        if let Ok(addr) = host_port.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        if let Some(addrs) = self.cache.as_ref().and_then(|c| c.get(host_port)) {
+            return Ok(addrs);
+        }
+        let addrs = self.resolve(host_port).await?;
+        if let Some(cache) = &self.cache {
+            cache.insert(host_port, addrs.clone());
+        }
+        Ok(addrs)
+    }
+
+    /// Look up the SRV record `name`, bypassing the cache
+    pub async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, io::Error> {
+        let lookup = match &self.server {
+            Some(resolver) => resolver.srv_lookup(name).await,
+            None => {
+                TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(io::Error::other)?
+                    .srv_lookup(name)
+                    .await
+            }
+        };
+        Ok(srv_records(lookup.map_err(io::Error::other)?))
+    }
+
+    async fn resolve(&self, host_port: &str) -> Result<Vec<SocketAddr>, io::Error> {
+        let resolver = match &self.server {
+            Some(resolver) => resolver,
+            None => return Ok(tokio::net::lookup_host(host_port).await?.collect()),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid target: {}", host_port),
+            )
+        };
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let lookup = resolver.lookup_ip(host).await.map_err(io::Error::other)?;
+        Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
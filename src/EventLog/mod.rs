@@ -0,0 +1,162 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+
+use crate::{AccessLog::civil_date, Config::Source, ConnHandle::PeerAddr, Meter::format_bytes};
+
+/// What happened to a connection
+#[derive(Debug, Clone)]
+pub enum ConnEventKind {
+    Opened,
+    Connected(SocketAddr),
+    Failed(String),
+    Closed {
+        duration: Duration,
+        from_n_bytes: usize,
+        to_n_bytes: usize,
+    },
+}
+
+/// A lifecycle event of a connection
+#[derive(Debug, Clone)]
+pub struct ConnEvent {
+    pub time: SystemTime,
+    pub id: u32,
+    pub peer: PeerAddr,
+    pub forward: Source,
+    pub kind: ConnEventKind,
+}
+
+impl Display for ConnEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} #{} {} -> {} ",
+            format_time(self.time),
+            self.id,
+            self.peer,
+            self.forward
+        )?;
+        match &self.kind {
+            ConnEventKind::Opened => write!(f, "opened"),
+            ConnEventKind::Connected(target) => write!(f, "connected to {}", target),
+            ConnEventKind::Failed(e) => write!(f, "failed: {}", e),
+            ConnEventKind::Closed {
+                duration,
+                from_n_bytes,
+                to_n_bytes,
+            } => write!(
+                f,
+                "closed after {:.3}s, ul: {}, dl: {}",
+                duration.as_secs_f64(),
+                format_bytes(*from_n_bytes as f64),
+                format_bytes(*to_n_bytes as f64)
+            ),
+        }
+    }
+}
+
+/// Keeps the last connection events in memory, the oldest are overwritten
+/// once full
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    events: Arc<Mutex<VecDeque<ConnEvent>>>,
+    capacity: usize,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Record an event of connection `id` that happened now
+    pub fn record(&self, id: u32, peer: &PeerAddr, forward: &Source, kind: ConnEventKind) {
+        let event = ConnEvent {
+            time: SystemTime::now(),
+            id,
+            peer: peer.clone(),
+            forward: forward.clone(),
+            kind,
+        };
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// The recorded events, oldest first
+    pub fn events(&self) -> Vec<ConnEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Print the opening of a connection once connected to `target`, and its
+/// closing, as one JSON object per line on stdout for `--events-json`
+pub fn print_event_json(
+    id: u32,
+    peer: &PeerAddr,
+    forward: &Source,
+    target: Option<SocketAddr>,
+    kind: &ConnEventKind,
+) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let event = match kind {
+        ConnEventKind::Connected(target) => json!({
+            "event": "open",
+            "id": id,
+            "peer": peer.to_string(),
+            "forward": forward.to_string(),
+            "target": target.to_string(),
+            "ts": ts,
+        }),
+        // A connection that failed has no target
+        ConnEventKind::Closed {
+            duration,
+            from_n_bytes,
+            to_n_bytes,
+        } => json!({
+            "event": "close",
+            "id": id,
+            "peer": peer.to_string(),
+            "forward": forward.to_string(),
+            "target": target.map(|t| t.to_string()),
+            "ts": ts,
+            "ul_bytes": from_n_bytes,
+            "dl_bytes": to_n_bytes,
+            "duration": duration.as_secs_f64(),
+        }),
+        ConnEventKind::Opened | ConnEventKind::Failed(_) => return,
+    };
+    println!("{}", event);
+}
+
+/// Format a time as `2000-10-10T13:55:36.123456Z`
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days as i64);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
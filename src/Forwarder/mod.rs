@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    future::{self, BoxFuture, Shared},
+    FutureExt,
+};
+use log::{error, info, warn};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, Sender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    Config::{Forward, Protocol, Source},
+    ConnHandle::{accept_conn, accept_udp, ConnInfo, ConnOptions, ConnRegistry, ForwardError},
+    Meter::{
+        DroppedMessages, ForwardTotals, Meter, MeterFormat, MeterGranularity, MeterHandle,
+        MeterMessageSender, MeterSnapshot, SharedTotals, ShutdownError, Stats,
+        DEFAULT_METER_CAPACITY,
+    },
+};
+
+/// Whether a forward could be bound and to which TCP or UDP address, shared
+/// by every waiter
+type BindResult = Shared<BoxFuture<'static, Result<Option<SocketAddr>, Arc<ForwardError>>>>;
+
+/// Runs forwards on the current tokio runtime and meters their traffic
+pub struct Forwarder {
+    /// `None` if metering is disabled
+    meter: Option<Meter>,
+    meter_msg_sender: MeterMessageSender,
+    meter_handle: Option<MeterHandle>,
+    forwards: Mutex<HashMap<(Source, Protocol), ForwarderHandle>>,
+}
+
+impl Default for Forwarder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Forwarder {
+    /// Create a forwarder, this must be called from within a tokio runtime
+    pub fn new() -> Self {
+        Self::with_meter_format(MeterFormat::default())
+    }
+
+    /// Create a forwarder whose meter prints in `format`
+    pub fn with_meter_format(format: MeterFormat) -> Self {
+        Self::with_meter_options(format, MeterGranularity::default())
+    }
+
+    /// Create a forwarder whose meter prints in `format`, showing the rates
+    /// at `granularity`
+    pub fn with_meter_options(format: MeterFormat, granularity: MeterGranularity) -> Self {
+        Self::with_meter_capacity(format, granularity, DEFAULT_METER_CAPACITY)
+    }
+
+    /// Create a forwarder like [`Forwarder::with_meter_options`] whose meter
+    /// channel holds `capacity` messages, dropping transfers past it
+    pub fn with_meter_capacity(
+        format: MeterFormat,
+        granularity: MeterGranularity,
+        capacity: usize,
+    ) -> Self {
+        let (meter, meter_msg_sender, meter_handle) =
+            Meter::with_capacity(format, granularity, capacity);
+        Forwarder {
+            meter: Some(meter),
+            meter_msg_sender,
+            meter_handle: Some(meter_handle),
+            forwards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a forwarder that does not meter, its counters stay empty
+    pub fn without_meter() -> Self {
+        Forwarder {
+            meter: None,
+            meter_msg_sender: MeterMessageSender::disabled(),
+            meter_handle: None,
+            forwards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start accepting connections for `forward`
+    pub fn start(&self, forward: Forward, options: ConnOptions) -> ForwarderHandle {
+        let meter_msg_sender = self.meter_msg_sender.clone();
+        let (shutdown_sender, shutdown_receiver) = mpsc::channel(1);
+        let (bound_sender, bound_receiver) = oneshot::channel();
+        let (stopped_sender, stopped_receiver) = oneshot::channel::<()>();
+        let (forward_sender, forward_receiver) = watch::channel(Arc::new(forward.clone()));
+        let task_forward = forward.clone();
+        let source = forward.source.clone();
+        let registry = ConnRegistry::default();
+        let task_registry = registry.clone();
+        let join_handle = tokio::spawn(async move {
+            // Dropped when the task ends, which wakes the waiters
+            let _stopped = stopped_sender;
+            let result = match task_forward.protocol {
+                Protocol::Tcp => {
+                    accept_conn(
+                        forward_receiver,
+                        options,
+                        meter_msg_sender,
+                        task_registry,
+                        bound_sender,
+                        shutdown_receiver,
+                    )
+                    .await
+                }
+                Protocol::Udp => {
+                    accept_udp(
+                        task_forward,
+                        options,
+                        meter_msg_sender,
+                        task_registry,
+                        bound_sender,
+                        shutdown_receiver,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+        });
+        ForwarderHandle {
+            forward,
+            forward_sender,
+            shutdown_sender,
+            join_handle,
+            bound: bound_receiver
+                .map(|bound| match bound {
+                    Ok(result) => result.map_err(Arc::new),
+                    // The task panicked or was aborted before binding
+                    Err(_) => Err(Arc::new(ForwardError::Shutdown { forward: source })),
+                })
+                .boxed()
+                .shared(),
+            stopped: stopped_receiver.map(drop).boxed().shared(),
+            totals: self.totals(),
+            registry,
+        }
+    }
+
+    /// Start `forward` like [`Forwarder::start`] and wait for it to bind,
+    /// returning its handle and the address it is bound to unless it listens
+    /// on a Unix socket
+    pub async fn start_bound(
+        &self,
+        forward: Forward,
+        options: ConnOptions,
+    ) -> Result<(ForwarderHandle, Option<SocketAddr>), ForwardError> {
+        let handle = self.start(forward, options);
+        let local_addr = handle.wait_bound().await?;
+        Ok((handle, local_addr))
+    }
+
+    /// Start `forward` and keep it running until it is removed, there can be
+    /// one forward per source and protocol
+    pub fn add_forward(&self, forward: Forward, options: ConnOptions) -> Result<(), ForwardError> {
+        let mut forwards = self.forwards.lock().unwrap();
+        let key = (forward.source.clone(), forward.protocol);
+        if forwards.contains_key(&key) {
+            return Err(ForwardError::Bind {
+                error: io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("a {} forward is already running", forward.protocol),
+                ),
+                forward: forward.source,
+            });
+        }
+        let handle = self.start(forward, options);
+        forwards.insert(key, handle);
+        Ok(())
+    }
+
+    /// Start `forward` like [`Forwarder::add_forward`] once it is bound,
+    /// returning the address it is bound to unless it listens on a Unix
+    /// socket. A forward that fails to bind is not added.
+    pub async fn add_forward_bound(
+        &self,
+        forward: Forward,
+        options: ConnOptions,
+    ) -> Result<Option<SocketAddr>, ForwardError> {
+        let (source, protocol) = (forward.source.clone(), forward.protocol);
+        self.add_forward(forward, options)?;
+        let bound = match self
+            .forwards
+            .lock()
+            .unwrap()
+            .get(&(source.clone(), protocol))
+        {
+            Some(handle) => handle.bound.clone(),
+            None => return Ok(None),
+        };
+        bound.await.map_err(|e| {
+            self.take_forward(&source, protocol);
+            e.unshare()
+        })
+    }
+
+    /// Stop the `protocol` forward on `source` and wait for its connections to drain
+    pub async fn remove_forward(
+        &self,
+        source: &Source,
+        protocol: Protocol,
+    ) -> Result<(), io::Error> {
+        match self.take_forward(source, protocol) {
+            Some(handle) => handle.shutdown().await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {} forward is running on {}", protocol, source),
+            )),
+        }
+    }
+
+    /// Stop the forwards on port `src_port` of every protocol, leaving the
+    /// others running. Returns once they no longer listen and their
+    /// connections drained, within the drain timeout if there is one.
+    pub async fn shutdown_forward(&self, src_port: u16) -> Result<(), io::Error> {
+        let source = Source::Port(src_port);
+        let handles = [Protocol::Tcp, Protocol::Udp]
+            .into_iter()
+            .filter_map(|protocol| self.take_forward(&source, protocol))
+            .collect::<Vec<_>>();
+        if handles.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No forward is running on {}", source),
+            ));
+        }
+        info!("Stopping forward on {}", source);
+        for result in future::join_all(handles.into_iter().map(|h| h.shutdown())).await {
+            result?;
+        }
+        info!("Forward on {} stopped", source);
+        Ok(())
+    }
+
+    /// Unregister the `protocol` forward on `source` without stopping it, so
+    /// it can be shut down elsewhere
+    pub fn take_forward(&self, source: &Source, protocol: Protocol) -> Option<ForwarderHandle> {
+        self.forwards
+            .lock()
+            .unwrap()
+            .remove(&(source.clone(), protocol))
+    }
+
+    /// Make the added forwards those of `forwards`. A TCP forward whose
+    /// targets alone changed keeps running, see
+    /// [`ForwarderHandle::swap_targets`]. The other removed and changed
+    /// forwards stop accepting and drain in the returned tasks, then the new
+    /// and changed ones are started. The meter keeps running, so the counters
+    /// of a forward that stays carry on across the reload.
+    pub async fn reload(
+        &self,
+        forwards: Vec<Forward>,
+        options: &ConnOptions,
+    ) -> Vec<JoinHandle<()>> {
+        // Swap the targets of forwards that only changed them
+        for forward in &forwards {
+            let mut handles = self.forwards.lock().unwrap();
+            let key = (forward.source.clone(), forward.protocol);
+            if let Some(handle) = handles.get_mut(&key) {
+                if handle.forward() != forward && handle.swap_targets(forward.clone()) {
+                    info!("Swapping the targets of forward on {}", forward.source);
+                }
+            }
+        }
+
+        // Stop forwards that are removed or changed
+        let running_forwards = self.forwards();
+        let mut draining = Vec::new();
+        for forward in running_forwards.iter().filter(|f| !forwards.contains(f)) {
+            let handle = match self.take_forward(&forward.source, forward.protocol) {
+                Some(handle) => handle,
+                None => continue,
+            };
+            info!("Stopping forward on {}", forward.source);
+            handle.stop_accepting().await;
+            draining.push(tokio::spawn(async move {
+                if let Err(e) = handle.shutdown().await {
+                    warn!("{}", e);
+                }
+            }));
+        }
+
+        // Start forwards that are new or changed
+        for forward in forwards {
+            if running_forwards.contains(&forward) {
+                continue;
+            }
+            info!("Starting forward on {}", forward.source);
+            if let Err(e) = self.add_forward_bound(forward, options.clone()).await {
+                warn!("Failed to start forward, {}", e);
+            }
+        }
+        draining
+    }
+
+    /// The forwards added to this forwarder, in ascending order of source
+    pub fn forwards(&self) -> Vec<Forward> {
+        let forwards = self.forwards.lock().unwrap();
+        let mut forwards = forwards
+            .values()
+            .map(|h| h.forward().clone())
+            .collect::<Vec<_>>();
+        forwards.sort_by(|a, b| (&a.source, a.protocol).cmp(&(&b.source, b.protocol)));
+        forwards
+    }
+
+    /// Wait for every added forward to bind, returning the ones that failed
+    /// to with their error
+    pub async fn wait_bound(&self) -> Vec<(Forward, ForwardError)> {
+        let forwards = self
+            .forwards
+            .lock()
+            .unwrap()
+            .values()
+            .map(|h| (h.forward().clone(), h.bound.clone()))
+            .collect::<Vec<_>>();
+        let mut failed = Vec::new();
+        for (forward, bound) in forwards {
+            if let Err(e) = bound.await {
+                failed.push((forward, e.unshare()));
+            }
+        }
+        failed.sort_by(|(a, _), (b, _)| (&a.source, a.protocol).cmp(&(&b.source, b.protocol)));
+        failed
+    }
+
+    /// Wait for any added forward to stop on its own, e.g. after its single
+    /// connection with `--once`, and return it. Waits forever if none is added.
+    pub async fn wait_stopped(&self) -> Forward {
+        let stopped = self
+            .forwards
+            .lock()
+            .unwrap()
+            .values()
+            .map(|h| {
+                let forward = h.forward().clone();
+                h.stopped.clone().map(move |_| forward).boxed()
+            })
+            .collect::<Vec<_>>();
+        if stopped.is_empty() {
+            return future::pending().await;
+        }
+        future::select_all(stopped).await.0
+    }
+
+    /// The open connections of every added forward
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        let forwards = self.forwards.lock().unwrap();
+        let mut keys = forwards.keys().collect::<Vec<_>>();
+        keys.sort();
+        keys.into_iter()
+            .flat_map(|key| forwards[key].connections())
+            .collect()
+    }
+
+    /// Stop every added forward and wait for their connections to drain
+    pub async fn remove_all(&self) {
+        let handles = self
+            .forwards
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, handle)| handle)
+            .collect::<Vec<_>>();
+        let results = futures::future::join_all(handles.into_iter().map(|h| h.shutdown())).await;
+        for result in results {
+            if let Err(e) = result {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Sends to the meter of this forwarder, e.g. for
+    /// [`forward_stream`](crate::ConnHandle::forward_stream)
+    pub fn meter_msg_sender(&self) -> MeterMessageSender {
+        self.meter_msg_sender.clone()
+    }
+
+    /// Queries the meter of this forwarder, `None` if metering is disabled
+    pub fn meter_handle(&self) -> Option<MeterHandle> {
+        self.meter_handle.clone()
+    }
+
+    /// The cumulative counters of every forward started by this forwarder
+    pub fn totals(&self) -> SharedTotals {
+        match &self.meter {
+            Some(meter) => meter.totals(),
+            None => SharedTotals::default(),
+        }
+    }
+
+    /// The number of transfers the meter dropped as it fell behind
+    pub fn dropped_messages(&self) -> DroppedMessages {
+        match &self.meter {
+            Some(meter) => meter.dropped_messages(),
+            None => DroppedMessages::default(),
+        }
+    }
+
+    /// Leave the idle connections out of the printed rates, see
+    /// [`Meter::set_hide_idle`]
+    pub fn set_meter_hide_idle(&self, hide_idle: bool) {
+        if let Some(meter) = &self.meter {
+            meter.set_hide_idle(hide_idle);
+        }
+    }
+
+    /// Print the totals of every forward next to its rates, see
+    /// [`Meter::set_show_totals`]
+    pub fn set_meter_show_totals(&self, show_totals: bool) {
+        if let Some(meter) = &self.meter {
+            meter.set_show_totals(show_totals);
+        }
+    }
+
+    /// Receive a snapshot of every meter interval, see [`Meter::subscribe`]
+    pub fn subscribe(&self) -> broadcast::Receiver<MeterSnapshot> {
+        match &self.meter {
+            Some(meter) => meter.subscribe(),
+            // Closed right away, nothing is ever sent
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// The cumulative counters of every forward as of the last meter interval,
+    /// including the added forwards that have not seen a connection yet
+    pub async fn stats(&self) -> Option<Stats> {
+        let mut stats = self.meter.as_ref()?.stats().await?;
+        let forwards = self.forwards.lock().unwrap();
+        stats.include(forwards.keys().map(|(source, _)| source.clone()));
+        Some(stats)
+    }
+
+    /// Zero the cumulative counters of every forward, returning them as they
+    /// were, like [`Meter::reset`](crate::Meter::Meter::reset)
+    pub async fn reset_stats(&self) -> Option<Stats> {
+        let mut stats = self.meter.as_ref()?.reset().await?;
+        let forwards = self.forwards.lock().unwrap();
+        stats.include(forwards.keys().map(|(source, _)| source.clone()));
+        Some(stats)
+    }
+
+    /// Stop the added forwards and metering, the handles of forwards started
+    /// with [`Forwarder::start`] should be shut down first
+    pub async fn shutdown(self) -> Result<(), ShutdownError> {
+        self.remove_all().await;
+        match self.meter {
+            Some(meter) => meter.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A running forward started by a [`Forwarder`]
+pub struct ForwarderHandle {
+    forward: Forward,
+    /// Sends the forward of the connections accepted from then on
+    forward_sender: watch::Sender<Arc<Forward>>,
+    shutdown_sender: Sender<()>,
+    join_handle: JoinHandle<()>,
+    bound: BindResult,
+    /// Resolves once the forward has stopped
+    stopped: Shared<BoxFuture<'static, ()>>,
+    totals: SharedTotals,
+    registry: ConnRegistry,
+}
+
+impl ForwarderHandle {
+    pub fn forward(&self) -> &Forward {
+        &self.forward
+    }
+
+    /// Wait for the forward to bind, returning the address it is bound to,
+    /// e.g. to learn the port picked by the OS for port 0
+    pub async fn wait_bound(&self) -> Result<Option<SocketAddr>, ForwardError> {
+        self.bound.clone().await.map_err(|e| e.unshare())
+    }
+
+    /// The cumulative counters of this forward
+    pub fn stats(&self) -> ForwardTotals {
+        let totals = self.totals.lock().unwrap();
+        totals
+            .get(&self.forward.source)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The open connections of this forward
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        self.registry.snapshot()
+    }
+
+    /// Send the connections accepted from now on to the targets of
+    /// `forward`, the open ones keep theirs. Only a TCP forward that differs
+    /// from `forward` by its targets alone can be swapped, otherwise nothing
+    /// changes and false is returned.
+    pub fn swap_targets(&mut self, forward: Forward) -> bool {
+        let swappable = Forward {
+            targets: forward.targets.clone(),
+            target_hosts: forward.target_hosts.clone(),
+            ..self.forward.clone()
+        };
+        if forward.protocol != Protocol::Tcp || swappable != forward {
+            return false;
+        }
+        self.forward = forward;
+        self.forward_sender
+            .send_replace(Arc::new(self.forward.clone()));
+        true
+    }
+
+    /// Stop accepting connections, returning once the forward no longer
+    /// listens without waiting for the open connections
+    pub async fn stop_accepting(&self) {
+        let _ = self.shutdown_sender.try_send(());
+        self.shutdown_sender.closed().await;
+    }
+
+    /// Stop accepting connections and wait for the open ones to finish
+    pub async fn shutdown(self) -> Result<(), io::Error> {
+        // The forward may already have stopped on its own, e.g. if it failed to bind
+        let _ = self.shutdown_sender.try_send(());
+        self.join_handle.await.map_err(io::Error::other)
+    }
+}
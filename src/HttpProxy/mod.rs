@@ -0,0 +1,100 @@
+use std::{io, net::SocketAddr};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// The longest CONNECT response header accepted from the proxy
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// An HTTP proxy that target connections are tunneled through with CONNECT
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HttpProxy {
+    pub addr: SocketAddr,
+    /// Username and password sent as basic `Proxy-Authorization`
+    #[serde(
+        rename = "username",
+        serialize_with = "crate::Config::serialize_username"
+    )]
+    pub auth: Option<(String, String)>,
+}
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+fn base64(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+impl HttpProxy {
+    /// Connect to `target` through the proxy, the returned stream carries the
+    /// tunneled connection once the proxy answered with 200
+    pub async fn connect(&self, target: &SocketAddr) -> Result<TcpStream, io::Error> {
+        self.tunnel(TcpStream::connect(self.addr).await?, target)
+            .await
+    }
+
+    /// Tunnel to `target` over a stream already connected to the proxy
+    pub async fn tunnel(
+        &self,
+        mut stream: TcpStream,
+        target: &SocketAddr,
+    ) -> Result<TcpStream, io::Error> {
+        // Send the CONNECT request
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some((user, pass)) = &self.auth {
+            let credentials = base64(format!("{}:{}", user, pass).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response header one byte at a time so no tunneled data is consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_RESPONSE_SIZE {
+                return Err(proxy_error(format!(
+                    "{} sent a CONNECT response that is too long",
+                    self.addr
+                )));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        // Check the status code
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let (version, status) = (parts.next().unwrap_or_default(), parts.next());
+        if !version.starts_with("HTTP/") {
+            return Err(proxy_error(format!("{} is not an HTTP proxy", self.addr)));
+        }
+        if status != Some("200") {
+            return Err(proxy_error(format!(
+                "{} refused to CONNECT to {}: {}",
+                self.addr, target, status_line
+            )));
+        }
+        Ok(stream)
+    }
+}
@@ -1,15 +1,27 @@
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{Config::Source, ConnHandle::PeerAddr};
 use tokio::{
     spawn,
-    sync::mpsc::{
-        channel,
-        error::{SendError, TryRecvError},
-        Receiver, Sender,
+    sync::{
+        broadcast,
+        mpsc::{
+            channel,
+            error::{SendError, TryRecvError, TrySendError},
+            Receiver, Sender,
+        },
+        oneshot,
     },
     task::JoinHandle,
     time::sleep,
@@ -22,89 +34,472 @@ pub enum Direction {
 }
 
 #[derive(Debug)]
-pub struct Message {
-    src_sockaddr: SocketAddr,
-    direction: Direction,
-    instant: Instant,
-    n_bytes: usize,
+pub enum Message {
+    Transfer {
+        conn_id: u32,
+        peer: PeerAddr,
+        forward: Source,
+        direction: Direction,
+        #[allow(dead_code)]
+        instant: Instant,
+        n_bytes: usize,
+    },
+    ConnOpened {
+        forward: Source,
+        name: Option<Arc<str>>,
+        /// The most connections the forward accepts at once
+        max_conns: Option<usize>,
+    },
+    ConnClosed {
+        forward: Source,
+    },
+    Error {
+        forward: Source,
+    },
+    /// A connection that failed because no target could be resolved
+    ResolveError {
+        forward: Source,
+    },
+    /// A connection the listener of a forward failed to accept
+    AcceptError {
+        forward: Source,
+    },
+    /// Compressed bytes sent or received by a forward with `--compress`
+    Wire {
+        forward: Source,
+        n_bytes: usize,
+    },
+}
+
+/// Cumulative counters of a forward over the process lifetime
+#[derive(Debug, Default, Clone)]
+pub struct ForwardTotals {
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+    pub active_conns: usize,
+    pub total_conns: usize,
+    pub errors: usize,
+    /// The errors where no target could be resolved
+    pub resolve_errors: usize,
+    /// The connections the listener failed to accept, not counted as errors
+    pub accept_errors: usize,
+    /// The compressed bytes both ways, of which the others are the payload
+    pub wire_n_bytes: usize,
+}
+
+/// The cumulative counters of one forward in [`Stats`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardStats {
+    pub source: Source,
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+    pub active_conns: usize,
+    pub total_conns: usize,
+    pub errors: usize,
+    #[serde(default)]
+    pub resolve_errors: usize,
+    #[serde(default)]
+    pub accept_errors: usize,
+    #[serde(default)]
+    pub wire_n_bytes: usize,
+}
+
+/// A point in time copy of the cumulative counters of every forward
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    /// In ascending order of source
+    pub per_forward: Vec<ForwardStats>,
+}
+
+impl Stats {
+    pub fn from_totals(totals: &HashMap<Source, ForwardTotals>) -> Self {
+        let mut per_forward = totals
+            .iter()
+            .map(|(source, t)| ForwardStats {
+                source: source.clone(),
+                from_n_bytes: t.from_n_bytes,
+                to_n_bytes: t.to_n_bytes,
+                active_conns: t.active_conns,
+                total_conns: t.total_conns,
+                errors: t.errors,
+                resolve_errors: t.resolve_errors,
+                accept_errors: t.accept_errors,
+                wire_n_bytes: t.wire_n_bytes,
+            })
+            .collect::<Vec<_>>();
+        per_forward.sort_by(|a, b| a.source.cmp(&b.source));
+        Stats { per_forward }
+    }
+
+    /// Add zeroed counters for the sources that have none yet
+    pub fn include(&mut self, sources: impl IntoIterator<Item = Source>) {
+        for source in sources {
+            if let Err(i) = self.per_forward.binary_search_by(|f| f.source.cmp(&source)) {
+                self.per_forward.insert(
+                    i,
+                    ForwardStats {
+                        source,
+                        from_n_bytes: 0,
+                        to_n_bytes: 0,
+                        active_conns: 0,
+                        total_conns: 0,
+                        errors: 0,
+                        resolve_errors: 0,
+                        accept_errors: 0,
+                        wire_n_bytes: 0,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Cumulative counters keyed by the source of the forward
+pub type SharedTotals = Arc<Mutex<HashMap<Source, ForwardTotals>>>;
+
+/// The traffic of a connection during a meter interval
+#[derive(Debug, Clone)]
+pub struct ConnSnapshot {
+    pub conn_id: u32,
+    pub peer: PeerAddr,
+    pub forward: Source,
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+    /// Upload rate in bytes per second
+    pub from_rate: f64,
+    /// Download rate in bytes per second
+    pub to_rate: f64,
+}
+
+/// The traffic of a forward during a meter interval
+#[derive(Debug, Default, Clone)]
+pub struct ForwardSnapshot {
+    pub from_n_bytes: usize,
+    pub to_n_bytes: usize,
+    /// Upload rate in bytes per second
+    pub from_rate: f64,
+    /// Download rate in bytes per second
+    pub to_rate: f64,
+    pub errors: usize,
+    pub resolve_errors: usize,
+    pub accept_errors: usize,
+}
+
+/// What the meter measured during one interval, broadcast to subscribers
+#[derive(Debug, Clone)]
+pub struct MeterSnapshot {
+    pub duration: Duration,
+    /// The connections that transferred data, in ascending order of source and id
+    pub conns: Vec<ConnSnapshot>,
+    /// The forwards that transferred data or had errors
+    pub forwards: HashMap<Source, ForwardSnapshot>,
+    /// The cumulative counters of every forward
+    pub totals: HashMap<Source, ForwardTotals>,
+    /// The transfers dropped during the interval as the meter channel was full
+    pub dropped_messages: usize,
+}
+
+/// A request for the cumulative counters of every forward
+pub struct StatsRequest {
+    reply: oneshot::Sender<Stats>,
+    /// Zero the counters once read
+    reset: bool,
 }
 
 pub struct Meter {
     shutdown_sender: Sender<()>,
+    handle: MeterHandle,
+    snapshot_sender: broadcast::Sender<MeterSnapshot>,
     t_handle: JoinHandle<()>,
+    totals: SharedTotals,
+    dropped_messages: DroppedMessages,
+    hide_idle: Arc<AtomicBool>,
+    show_totals: Arc<AtomicBool>,
 }
 
+/// The number of transfers dropped since the start as the meter channel was full
+pub type DroppedMessages = Arc<AtomicUsize>;
+
 const SLEEP_MS: u64 = 500;
+/// How full the connection limit of a forward is when it gets flagged
+const NEAR_LIMIT_PERCENT: usize = 90;
+
+/// How the meter prints the rates and the summary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterFormat {
+    /// Lines with human readable units
+    #[default]
+    Human,
+    /// One JSON object per line, with rates in bytes per second
+    Json,
+}
+
+/// Whether the meter prints the rates of every connection or only of forwards
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterGranularity {
+    /// The rates of every forward followed by those of its connections
+    #[default]
+    Connection,
+    /// The rates of every forward, summing its connections
+    Forward,
+}
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_meter_thread(
     mut message_receiver: Receiver<Message>,
+    mut lifecycle_receiver: Receiver<Message>,
     mut shutdown_receiver: Receiver<()>,
+    mut stats_receiver: Receiver<StatsRequest>,
+    snapshot_sender: broadcast::Sender<MeterSnapshot>,
+    totals: SharedTotals,
+    dropped_messages: DroppedMessages,
+    hide_idle: Arc<AtomicBool>,
+    show_totals: Arc<AtomicBool>,
+    format: MeterFormat,
+    granularity: MeterGranularity,
 ) -> JoinHandle<()> {
     let t_handle = spawn(async move {
-        let mut last_run_instant = Instant::now();
+        let started = Instant::now();
+        let mut last_run_instant = started;
+        let mut last_dropped = 0;
+        let mut names: HashMap<Source, Arc<str>> = HashMap::new();
+        let mut limits: HashMap<Source, usize> = HashMap::new();
         loop {
             // Sleep for a duration
             sleep(Duration::from_millis(SLEEP_MS)).await;
 
             // Read the channel and summarize the total number of bytes
-            let mut map: HashMap<SocketAddr, (usize, usize)> = HashMap::new();
-            loop {
-                let Message {
-                    src_sockaddr,
-                    direction,
-                    n_bytes,
-                    instant: _,
-                } = match message_receiver.try_recv() {
-                    Ok(m) => m,
-                    Err(e) => match e {
-                        TryRecvError::Empty => break,
-                        TryRecvError::Disconnected => {
-                            panic!("Unexpected disconnection of message channel")
-                        }
-                    },
+            let mut map: HashMap<(u32, PeerAddr), (Source, usize, usize)> = HashMap::new();
+            let mut errors: HashMap<Source, usize> = HashMap::new();
+            let mut resolve_errors: HashMap<Source, usize> = HashMap::new();
+            let mut accept_errors: HashMap<Source, usize> = HashMap::new();
+            // The connection events first, then the transfers. Every sender
+            // being gone only means no more messages will come.
+            while let Ok(message) = lifecycle_receiver
+                .try_recv()
+                .or_else(|_| message_receiver.try_recv())
+            {
+                let (conn, forward, direction, n_bytes) = match message {
+                    Message::Transfer {
+                        conn_id,
+                        peer,
+                        forward,
+                        direction,
+                        n_bytes,
+                        instant: _,
+                    } => {
+                        let mut totals = totals.lock().unwrap();
+                        let forward_totals = totals.entry(forward.clone()).or_default();
+                        match direction {
+                            Direction::From => forward_totals.from_n_bytes += n_bytes,
+                            Direction::To => forward_totals.to_n_bytes += n_bytes,
+                        };
+                        ((conn_id, peer), forward, direction, n_bytes)
+                    }
+                    Message::ConnOpened {
+                        forward,
+                        name,
+                        max_conns,
+                    } => {
+                        match name {
+                            Some(name) => names.insert(forward.clone(), name),
+                            None => names.remove(&forward),
+                        };
+                        match max_conns {
+                            Some(max_conns) => limits.insert(forward.clone(), max_conns),
+                            None => limits.remove(&forward),
+                        };
+                        let mut totals = totals.lock().unwrap();
+                        let forward_totals = totals.entry(forward).or_default();
+                        forward_totals.active_conns += 1;
+                        forward_totals.total_conns += 1;
+                        continue;
+                    }
+                    Message::ConnClosed { forward } => {
+                        let mut totals = totals.lock().unwrap();
+                        let forward_totals = totals.entry(forward).or_default();
+                        forward_totals.active_conns = forward_totals.active_conns.saturating_sub(1);
+                        continue;
+                    }
+                    Message::Error { forward } => {
+                        let mut totals = totals.lock().unwrap();
+                        totals.entry(forward.clone()).or_default().errors += 1;
+                        *errors.entry(forward).or_default() += 1;
+                        continue;
+                    }
+                    Message::ResolveError { forward } => {
+                        let mut totals = totals.lock().unwrap();
+                        let forward_totals = totals.entry(forward.clone()).or_default();
+                        forward_totals.errors += 1;
+                        forward_totals.resolve_errors += 1;
+                        *errors.entry(forward.clone()).or_default() += 1;
+                        *resolve_errors.entry(forward).or_default() += 1;
+                        continue;
+                    }
+                    Message::AcceptError { forward } => {
+                        let mut totals = totals.lock().unwrap();
+                        totals.entry(forward.clone()).or_default().accept_errors += 1;
+                        *accept_errors.entry(forward).or_default() += 1;
+                        continue;
+                    }
+                    Message::Wire { forward, n_bytes } => {
+                        let mut totals = totals.lock().unwrap();
+                        totals.entry(forward).or_default().wire_n_bytes += n_bytes;
+                        continue;
+                    }
                 };
 
                 // Add to total
-                if let Some((from_t_n_bytes, to_t_n_bytes)) = map.get_mut(&src_sockaddr) {
-                    match direction {
-                        Direction::From => *from_t_n_bytes += n_bytes,
-                        Direction::To => *to_t_n_bytes += n_bytes,
-                    };
-                } else {
-                    match direction {
-                        Direction::From => map.insert(src_sockaddr, (n_bytes, 0)),
-                        Direction::To => map.insert(src_sockaddr, (0, n_bytes)),
-                    };
-                }
+                let (_, from_t_n_bytes, to_t_n_bytes) = map.entry(conn).or_insert((forward, 0, 0));
+                match direction {
+                    Direction::From => *from_t_n_bytes += n_bytes,
+                    Direction::To => *to_t_n_bytes += n_bytes,
+                };
             }
 
             // Calculate current instant
             let now = Instant::now();
+            let dur_sec = now.duration_since(last_run_instant).as_secs_f64();
+
+            // Summarize the interval
+            let mut conns = map
+                .into_iter()
+                .map(
+                    |((conn_id, peer), (forward, from_n_bytes, to_n_bytes))| ConnSnapshot {
+                        conn_id,
+                        peer,
+                        forward,
+                        from_n_bytes,
+                        to_n_bytes,
+                        from_rate: from_n_bytes as f64 / dur_sec,
+                        to_rate: to_n_bytes as f64 / dur_sec,
+                    },
+                )
+                .collect::<Vec<_>>();
+            conns.sort_by(|a, b| (&a.forward, a.conn_id).cmp(&(&b.forward, b.conn_id)));
+            let mut forwards: HashMap<Source, ForwardSnapshot> = HashMap::new();
+            for c in &conns {
+                let f = forwards.entry(c.forward.clone()).or_default();
+                f.from_n_bytes += c.from_n_bytes;
+                f.to_n_bytes += c.to_n_bytes;
+                f.from_rate += c.from_rate;
+                f.to_rate += c.to_rate;
+            }
+            for (forward, n_errors) in &errors {
+                forwards.entry(forward.clone()).or_default().errors += n_errors;
+            }
+            for (forward, n_errors) in resolve_errors {
+                forwards.entry(forward).or_default().resolve_errors += n_errors;
+            }
+            for (forward, n_errors) in &accept_errors {
+                forwards.entry(forward.clone()).or_default().accept_errors += n_errors;
+            }
 
-            // Print the vector
-            for (sockaddr, (from_t_n_bytes, to_t_n_bytes)) in map.iter() {
-                let dur_microsec = now.duration_since(last_run_instant).as_micros();
-                let kbytes_per_sec_from = *from_t_n_bytes as f64 / (dur_microsec as f64 / 1000f64); // B/ms = KB/s
-                let kbytes_per_sec_to = *to_t_n_bytes as f64 / (dur_microsec as f64 / 1000f64); // B/ms = KB/s
-                println!(
-                    "[{}] ul: {:.2} KB/s, dl: {:.2} KB/s",
-                    sockaddr, kbytes_per_sec_from, kbytes_per_sec_to
+            // The rates are lower than the real ones when transfers were dropped
+            let dropped = dropped_messages.load(Ordering::Relaxed);
+            let n_dropped = dropped - last_dropped;
+            last_dropped = dropped;
+            if n_dropped > 0 {
+                warn!(
+                    "Dropped {} meter messages as the meter fell behind, the rates are incomplete",
+                    n_dropped
                 );
             }
 
+            // Print the rates, leaving out the connections that moved nothing
+            // if asked to, such as the ones that only just opened
+            let moving;
+            let printed = match hide_idle.load(Ordering::Relaxed) {
+                true => {
+                    moving = conns
+                        .iter()
+                        .filter(|c| c.from_n_bytes > 0 || c.to_n_bytes > 0)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    &moving
+                }
+                false => &conns,
+            };
+            let mut errors = errors.into_iter().collect::<Vec<_>>();
+            errors.sort();
+            let mut accept_errors = accept_errors.into_iter().collect::<Vec<_>>();
+            accept_errors.sort();
+            let (active, near_limit, lifetime) = {
+                let totals = totals.lock().unwrap();
+                (
+                    active_by_label(&totals, &names),
+                    near_limit(&totals, &limits),
+                    show_totals.load(Ordering::Relaxed).then(|| Lifetime {
+                        totals: totals_by_label(&totals, &names),
+                        uptime: started.elapsed(),
+                    }),
+                )
+            };
+            match format {
+                MeterFormat::Human => {
+                    print_rates(printed, errors, &names, granularity, &active, &lifetime);
+                    print_near_limit(&near_limit, &names);
+                    print_accept_errors(&accept_errors, &names);
+                }
+                MeterFormat::Json => {
+                    match granularity {
+                        MeterGranularity::Connection => print_rates_json(printed, &errors, &names),
+                        MeterGranularity::Forward => {
+                            print_forward_rates_json(printed, errors, &names, &active, &lifetime)
+                        }
+                    }
+                    print_near_limit_json(&near_limit, &names);
+                    print_accept_errors_json(&accept_errors, &names);
+                }
+            }
+
+            // Broadcast the interval to subscribers, if there are any
+            if snapshot_sender.receiver_count() > 0 {
+                let _ = snapshot_sender.send(MeterSnapshot {
+                    duration: now.duration_since(last_run_instant),
+                    conns,
+                    forwards,
+                    totals: totals.lock().unwrap().clone(),
+                    dropped_messages: n_dropped,
+                });
+            }
+
             // Update last run instant
             last_run_instant = now;
 
+            // Answer stats requests with the counters of every message read so far
+            while let Ok(request) = stats_receiver.try_recv() {
+                let mut totals = totals.lock().unwrap();
+                let _ = request.reply.send(Stats::from_totals(&totals));
+                if request.reset {
+                    for forward_totals in totals.values_mut() {
+                        // The open connections are not history, they close later
+                        *forward_totals = ForwardTotals {
+                            active_conns: forward_totals.active_conns,
+                            ..ForwardTotals::default()
+                        };
+                    }
+                }
+            }
+
             // Check if the shutdown command has been sent
             match shutdown_receiver.try_recv() {
                 Ok(_) => {
-                    println!("Shutdown message received");
+                    debug!("Shutdown message received");
+                    match format {
+                        MeterFormat::Human => print_summary(&totals, &names),
+                        MeterFormat::Json => print_summary_json(&totals, &names),
+                    }
                     break;
                 }
                 Err(e) => match e {
                     TryRecvError::Empty => (),
+                    // The meter was dropped without being shut down
                     TryRecvError::Disconnected => {
-                        panic!("Unexpected disconnection of shutdown command channel")
+                        warn!("Meter dropped without a shutdown, stopping metering");
+                        break;
                     }
                 },
             }
@@ -113,54 +508,665 @@ fn spawn_meter_thread(
     t_handle
 }
 
+const BYTE_UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+/// Format a number of bytes with the largest unit that keeps the value above 1
+pub fn format_bytes(n_bytes: f64) -> String {
+    let mut value = n_bytes;
+    let mut unit = 0;
+    while value >= 1024f64 && unit < BYTE_UNITS.len() - 1 {
+        value /= 1024f64;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, BYTE_UNITS[unit])
+}
+
+/// Format a rate in bytes per second
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
+
+/// Print the active connections and cumulative counters of every forward
+pub fn print_stats(stats: &Stats) {
+    for t in &stats.per_forward {
+        println!(
+            "[{}] active: {}, total ul: {}, dl: {}, connections: {}, errors: {}{}{}",
+            t.source,
+            t.active_conns,
+            format_bytes(t.from_n_bytes as f64),
+            format_bytes(t.to_n_bytes as f64),
+            t.total_conns,
+            t.errors,
+            format_accept_errors(t.accept_errors),
+            format_wire(t.from_n_bytes + t.to_n_bytes, t.wire_n_bytes)
+        );
+    }
+}
+
+/// The accept errors of a forward, or nothing if it had none
+fn format_accept_errors(accept_errors: usize) -> String {
+    match accept_errors {
+        0 => String::new(),
+        n => format!(", accept errors: {}", n),
+    }
+}
+
+/// The compressed bytes of a forward and their ratio to the payload, or
+/// nothing if it does not compress
+fn format_wire(payload_n_bytes: usize, wire_n_bytes: usize) -> String {
+    if wire_n_bytes == 0 {
+        return String::new();
+    }
+    format!(
+        ", wire: {} ({:.0}% of the payload)",
+        format_bytes(wire_n_bytes as f64),
+        wire_n_bytes as f64 * 100.0 / payload_n_bytes.max(1) as f64
+    )
+}
+
+/// The label of a forward in the meter output, its name if it has one
+fn forward_label(source: &Source, names: &HashMap<Source, Arc<str>>) -> String {
+    match names.get(source) {
+        Some(name) => name.to_string(),
+        None => source.to_string(),
+    }
+}
+
+/// Group items sorted by source under the label of their forward, forwards
+/// sharing a name are grouped together in the order they first appear
+fn group_by_label<T>(
+    items: Vec<T>,
+    names: &HashMap<Source, Arc<str>>,
+    source: impl Fn(&T) -> &Source,
+) -> Vec<(String, Vec<T>)> {
+    let mut groups: Vec<(String, Vec<T>)> = Vec::new();
+    for item in items {
+        let label = forward_label(source(&item), names);
+        match groups.iter_mut().find(|(l, _)| *l == label) {
+            Some((_, group)) => group.push(item),
+            None => groups.push((label, vec![item])),
+        }
+    }
+    groups
+}
+
+/// The open connections of every forward by label, forwards sharing a name
+/// are summed
+fn active_by_label(
+    totals: &HashMap<Source, ForwardTotals>,
+    names: &HashMap<Source, Arc<str>>,
+) -> HashMap<String, usize> {
+    let mut active: HashMap<String, usize> = HashMap::new();
+    for (source, t) in totals {
+        *active.entry(forward_label(source, names)).or_default() += t.active_conns;
+    }
+    active
+}
+
+/// The bytes from and to the clients of every forward since the start, by label
+fn totals_by_label(
+    totals: &HashMap<Source, ForwardTotals>,
+    names: &HashMap<Source, Arc<str>>,
+) -> HashMap<String, (usize, usize)> {
+    let mut by_label: HashMap<String, (usize, usize)> = HashMap::new();
+    for (source, t) in totals {
+        let (from_n_bytes, to_n_bytes) = by_label.entry(forward_label(source, names)).or_default();
+        *from_n_bytes += t.from_n_bytes;
+        *to_n_bytes += t.to_n_bytes;
+    }
+    by_label
+}
+
+/// The cumulative counters printed next to the rates of every forward
+struct Lifetime {
+    /// The bytes from and to the clients by label
+    totals: HashMap<String, (usize, usize)>,
+    /// Since the meter started
+    uptime: Duration,
+}
+
+impl Lifetime {
+    /// Format the counters of the forward labelled `label`
+    fn format(&self, label: &str) -> String {
+        let (from_n_bytes, to_n_bytes) = self.totals.get(label).copied().unwrap_or_default();
+        format!(
+            " (total ul: {}, dl: {}, uptime: {}s)",
+            format_bytes(from_n_bytes as f64),
+            format_bytes(to_n_bytes as f64),
+            self.uptime.as_secs()
+        )
+    }
+}
+
+/// The forwards with at least [`NEAR_LIMIT_PERCENT`] of their connection
+/// limit open, with their open connections and limit
+fn near_limit(
+    totals: &HashMap<Source, ForwardTotals>,
+    limits: &HashMap<Source, usize>,
+) -> Vec<(Source, usize, usize)> {
+    let mut near_limit = limits
+        .iter()
+        .filter_map(|(source, &limit)| {
+            let active = totals.get(source)?.active_conns;
+            (active * 100 >= limit * NEAR_LIMIT_PERCENT).then(|| (source.clone(), active, limit))
+        })
+        .collect::<Vec<_>>();
+    near_limit.sort();
+    near_limit
+}
+
+fn print_near_limit(near_limit: &[(Source, usize, usize)], names: &HashMap<Source, Arc<str>>) {
+    for (source, active, limit) in near_limit {
+        println!(
+            "[{}] nearing the connection limit, active: {} of {}",
+            forward_label(source, names),
+            active,
+            limit
+        );
+    }
+}
+
+fn print_near_limit_json(near_limit: &[(Source, usize, usize)], names: &HashMap<Source, Arc<str>>) {
+    let timestamp = unix_timestamp();
+    for (source, active, limit) in near_limit {
+        println!(
+            "{}",
+            json!({
+                "timestamp": timestamp,
+                "forward": forward_label(source, names),
+                "active_conns": active,
+                "max_conns": limit,
+            })
+        );
+    }
+}
+
+fn print_accept_errors(accept_errors: &[(Source, usize)], names: &HashMap<Source, Arc<str>>) {
+    for (source, n_errors) in accept_errors {
+        println!(
+            "[{}] accept errors: {}",
+            forward_label(source, names),
+            n_errors
+        );
+    }
+}
+
+fn print_accept_errors_json(accept_errors: &[(Source, usize)], names: &HashMap<Source, Arc<str>>) {
+    let timestamp = unix_timestamp();
+    for (source, n_errors) in accept_errors {
+        println!(
+            "{}",
+            json!({
+                "timestamp": timestamp,
+                "forward": forward_label(source, names),
+                "accept_errors": n_errors,
+            })
+        );
+    }
+}
+
+/// Print the rates and open connections grouped by forward, then per
+/// connection unless only forwards are shown
+fn print_rates(
+    conns: &[ConnSnapshot],
+    errors: Vec<(Source, usize)>,
+    names: &HashMap<Source, Arc<str>>,
+    granularity: MeterGranularity,
+    active: &HashMap<String, usize>,
+    lifetime: &Option<Lifetime>,
+) {
+    for (label, group) in group_by_label(conns.iter().collect(), names, |c| &c.forward) {
+        let (from_rate, to_rate) = group
+            .iter()
+            .fold((0f64, 0f64), |(f, t), c| (f + c.from_rate, t + c.to_rate));
+        println!(
+            "[{}] ul: {}, dl: {}, active: {}{}",
+            label,
+            format_rate(from_rate),
+            format_rate(to_rate),
+            active.get(&label).copied().unwrap_or_default(),
+            lifetime
+                .as_ref()
+                .map_or(String::new(), |lifetime| lifetime.format(&label))
+        );
+        if granularity == MeterGranularity::Forward {
+            continue;
+        }
+        for c in group {
+            println!(
+                "  [{} #{}] ul: {}, dl: {}",
+                c.peer,
+                c.conn_id,
+                format_rate(c.from_rate),
+                format_rate(c.to_rate)
+            );
+        }
+    }
+    for (label, group) in group_by_label(errors, names, |(source, _)| source) {
+        let n_errors: usize = group.iter().map(|(_, n)| n).sum();
+        println!("[{}] errors: {}", label, n_errors);
+    }
+}
+
+fn unix_timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Print one JSON object per connection and one per forward with errors
+fn print_rates_json(
+    conns: &[ConnSnapshot],
+    errors: &[(Source, usize)],
+    names: &HashMap<Source, Arc<str>>,
+) {
+    let timestamp = unix_timestamp();
+    for c in conns {
+        println!(
+            "{}",
+            json!({
+                "timestamp": timestamp,
+                "forward": forward_label(&c.forward, names),
+                "peer": c.peer.to_string(),
+                "conn_id": c.conn_id,
+                "ul_bytes": c.from_n_bytes,
+                "dl_bytes": c.to_n_bytes,
+                "ul_rate": c.from_rate,
+                "dl_rate": c.to_rate,
+            })
+        );
+    }
+    for (source, n_errors) in errors {
+        println!(
+            "{}",
+            json!({
+                "timestamp": timestamp,
+                "forward": forward_label(source, names),
+                "errors": n_errors,
+            })
+        );
+    }
+}
+
+/// Print one JSON object per forward, summing its connections and errors
+fn print_forward_rates_json(
+    conns: &[ConnSnapshot],
+    errors: Vec<(Source, usize)>,
+    names: &HashMap<Source, Arc<str>>,
+    active: &HashMap<String, usize>,
+    lifetime: &Option<Lifetime>,
+) {
+    let timestamp = unix_timestamp();
+    let mut forwards: Vec<(String, usize, usize, f64, f64, usize)> = Vec::new();
+    for (label, group) in group_by_label(conns.iter().collect(), names, |c| &c.forward) {
+        let mut forward = (label, 0, 0, 0f64, 0f64, 0);
+        for c in group {
+            forward.1 += c.from_n_bytes;
+            forward.2 += c.to_n_bytes;
+            forward.3 += c.from_rate;
+            forward.4 += c.to_rate;
+        }
+        forwards.push(forward);
+    }
+    for (label, group) in group_by_label(errors, names, |(source, _)| source) {
+        let n_errors: usize = group.iter().map(|(_, n)| n).sum();
+        match forwards.iter_mut().find(|f| f.0 == label) {
+            Some(forward) => forward.5 = n_errors,
+            None => forwards.push((label, 0, 0, 0f64, 0f64, n_errors)),
+        }
+    }
+    for (label, from_n_bytes, to_n_bytes, from_rate, to_rate, n_errors) in forwards {
+        let mut line = json!({
+            "timestamp": timestamp,
+            "forward": label,
+            "ul_bytes": from_n_bytes,
+            "dl_bytes": to_n_bytes,
+            "ul_rate": from_rate,
+            "dl_rate": to_rate,
+            "errors": n_errors,
+            "active_conns": active.get(&label).copied().unwrap_or_default(),
+        });
+        if let Some(lifetime) = lifetime {
+            let (total_from, total_to) = lifetime.totals.get(&label).copied().unwrap_or_default();
+            line["total_ul_bytes"] = json!(total_from);
+            line["total_dl_bytes"] = json!(total_to);
+            line["uptime_secs"] = json!(lifetime.uptime.as_secs());
+        }
+        println!("{}", line);
+    }
+}
+
+/// Print the cumulative counters of every forward as JSON objects
+fn print_summary_json(totals: &SharedTotals, names: &HashMap<Source, Arc<str>>) {
+    let timestamp = unix_timestamp();
+    let totals = totals.lock().unwrap();
+    let mut forwards = totals.iter().collect::<Vec<_>>();
+    forwards.sort_by_key(|(source, _)| *source);
+    for (source, t) in forwards {
+        println!(
+            "{}",
+            json!({
+                "timestamp": timestamp,
+                "forward": forward_label(source, names),
+                "total_ul_bytes": t.from_n_bytes,
+                "total_dl_bytes": t.to_n_bytes,
+                "active_conns": t.active_conns,
+                "connections": t.total_conns,
+                "errors": t.errors,
+                "accept_errors": t.accept_errors,
+                "total_wire_bytes": t.wire_n_bytes,
+            })
+        );
+    }
+}
+
+/// Print the cumulative counters rolled up per named forward
+fn print_summary(totals: &SharedTotals, names: &HashMap<Source, Arc<str>>) {
+    let totals = totals.lock().unwrap();
+    let mut forwards = totals.iter().collect::<Vec<_>>();
+    forwards.sort_by_key(|(source, _)| *source);
+    for (label, group) in group_by_label(forwards, names, |(source, _)| source) {
+        let t = group
+            .iter()
+            .fold(ForwardTotals::default(), |mut acc, (_, t)| {
+                acc.from_n_bytes += t.from_n_bytes;
+                acc.to_n_bytes += t.to_n_bytes;
+                acc.active_conns += t.active_conns;
+                acc.total_conns += t.total_conns;
+                acc.errors += t.errors;
+                acc.resolve_errors += t.resolve_errors;
+                acc.accept_errors += t.accept_errors;
+                acc.wire_n_bytes += t.wire_n_bytes;
+                acc
+            });
+        println!(
+            "[{}] active: {}, total ul: {}, dl: {}, connections: {}, errors: {}{}{}",
+            label,
+            t.active_conns,
+            format_bytes(t.from_n_bytes as f64),
+            format_bytes(t.to_n_bytes as f64),
+            t.total_conns,
+            t.errors,
+            format_accept_errors(t.accept_errors),
+            format_wire(t.from_n_bytes + t.to_n_bytes, t.wire_n_bytes)
+        );
+    }
+}
+
 #[derive(Debug)]
 pub enum ShutdownError {
     SendCommandError(SendError<()>),
     JoinError,
 }
 
+/// Sends messages to the meter, or drops them if metering is disabled
 #[derive(Clone)]
-pub struct MeterMessageSender(Sender<Message>);
+pub struct MeterMessageSender {
+    sender: Option<Sender<Message>>,
+    /// The messages that must not be lost, kept apart from the transfers so
+    /// they stay in order
+    lifecycle_sender: Option<Sender<Message>>,
+    dropped_messages: DroppedMessages,
+}
 impl MeterMessageSender {
+    /// A sender for when there is no meter, every message is dropped
+    pub fn disabled() -> Self {
+        MeterMessageSender {
+            sender: None,
+            lifecycle_sender: None,
+            dropped_messages: DroppedMessages::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Send a message that must not be lost, such as a connection opening,
+    /// in order with the others of its kind. Only waits when the meter is
+    /// `LIFECYCLE_CHN_BUFF_SIZE` messages behind.
+    async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
+        match &self.lifecycle_sender {
+            Some(sender) => sender.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send without waiting, dropping the message if the channel is full so
+    /// the data path never waits on the meter
+    fn try_send_message(&self, message: Message) -> Result<(), SendError<Message>> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        match sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Closed(message)) => Err(SendError(message)),
+        }
+    }
+
+    /// Count a transfer, which is dropped rather than waited on when the
+    /// meter falls behind
     pub async fn send(
         &self,
-        src_sockaddr: SocketAddr,
+        conn_id: u32,
+        peer: PeerAddr,
+        forward: Source,
         direction: Direction,
         n_bytes: usize,
     ) -> Result<(), SendError<Message>> {
+        if self.sender.is_none() {
+            return Ok(());
+        }
         let instant = Instant::now();
-        self.0
-            .send(Message {
-                src_sockaddr,
-                direction,
-                instant,
-                n_bytes,
+        self.try_send_message(Message::Transfer {
+            conn_id,
+            peer,
+            forward,
+            direction,
+            instant,
+            n_bytes,
+        })
+    }
+
+    /// Count a connection of `forward` as open, `max_conns` being the most
+    /// the forward accepts at once
+    pub async fn conn_opened(
+        &self,
+        forward: Source,
+        name: Option<Arc<str>>,
+        max_conns: Option<usize>,
+    ) -> Result<(), SendError<Message>> {
+        self.send_message(Message::ConnOpened {
+            forward,
+            name,
+            max_conns,
+        })
+        .await
+    }
+
+    pub async fn conn_closed(&self, forward: Source) -> Result<(), SendError<Message>> {
+        self.send_message(Message::ConnClosed { forward }).await
+    }
+
+    /// Count a connection of `forward` that failed
+    pub async fn error(&self, forward: Source) -> Result<(), SendError<Message>> {
+        self.send_message(Message::Error { forward }).await
+    }
+
+    /// Count a connection of `forward` that failed because no target could
+    /// be resolved, as an error too
+    pub async fn resolve_error(&self, forward: Source) -> Result<(), SendError<Message>> {
+        self.send_message(Message::ResolveError { forward }).await
+    }
+
+    /// Count a connection the listener of `forward` failed to accept
+    pub async fn accept_error(&self, forward: Source) -> Result<(), SendError<Message>> {
+        self.send_message(Message::AcceptError { forward }).await
+    }
+
+    /// Count compressed bytes of `forward` as they go over the wire, dropped
+    /// like transfers when the meter falls behind
+    pub fn wire(&self, forward: Source, n_bytes: usize) -> Result<(), SendError<Message>> {
+        self.try_send_message(Message::Wire { forward, n_bytes })
+    }
+}
+
+/// Queries the meter thread, answered once it has read the messages sent
+/// before the query
+#[derive(Clone)]
+pub struct MeterHandle {
+    stats_sender: Sender<StatsRequest>,
+}
+
+impl MeterHandle {
+    /// Ask the meter thread for the counters of every forward, `None` if it has shut down
+    pub async fn stats(&self) -> Option<Stats> {
+        let (reply, receiver) = oneshot::channel();
+        self.stats_sender
+            .send(StatsRequest {
+                reply,
+                reset: false,
             })
             .await
+            .ok()?;
+        receiver.await.ok()
+    }
+
+    /// Zero the cumulative counters of every forward, returning them as they
+    /// were, `None` if the meter has shut down. The Prometheus counters are
+    /// reset with them, which scrapers take for a restart.
+    pub async fn reset(&self) -> Option<Stats> {
+        let (reply, receiver) = oneshot::channel();
+        self.stats_sender
+            .send(StatsRequest { reply, reset: true })
+            .await
+            .ok()?;
+        receiver.await.ok()
+    }
+
+    /// The bytes every forward moved from and to its clients so far, none
+    /// once the meter has shut down
+    pub async fn current_totals(&self) -> HashMap<Source, (usize, usize)> {
+        let stats = self.stats().await.unwrap_or_default();
+        stats
+            .per_forward
+            .into_iter()
+            .map(|f| (f.source, (f.from_n_bytes, f.to_n_bytes)))
+            .collect()
     }
 }
 
-const MPSC_CHN_BUFF_SIZE: usize = 1024 * 1024;
+/// The number of messages the meter channel holds before transfers are dropped
+pub const DEFAULT_METER_CAPACITY: usize = 1024 * 1024;
+/// The connection events queued for the meter before their senders wait
+const LIFECYCLE_CHN_BUFF_SIZE: usize = 64 * 1024;
+const SHUTDOWN_CHN_BUFF_SIZE: usize = 1;
+const STATS_CHN_BUFF_SIZE: usize = 16;
+const SNAPSHOT_CHN_BUFF_SIZE: usize = 16;
 
 impl Meter {
-    pub fn new() -> (Self, MeterMessageSender) {
+    pub fn new(
+        format: MeterFormat,
+        granularity: MeterGranularity,
+    ) -> (Self, MeterMessageSender, MeterHandle) {
+        Self::with_capacity(format, granularity, DEFAULT_METER_CAPACITY)
+    }
+
+    /// Create a meter whose channel holds `capacity` messages
+    pub fn with_capacity(
+        format: MeterFormat,
+        granularity: MeterGranularity,
+        capacity: usize,
+    ) -> (Self, MeterMessageSender, MeterHandle) {
         // Create message and shutdown command channels
-        let (message_sender, message_receiver) = channel::<Message>(MPSC_CHN_BUFF_SIZE);
-        let (shutdown_sender, shutdown_receiver) = channel::<()>(MPSC_CHN_BUFF_SIZE);
+        let (message_sender, message_receiver) = channel::<Message>(capacity);
+        let (lifecycle_sender, lifecycle_receiver) = channel::<Message>(LIFECYCLE_CHN_BUFF_SIZE);
+        let (shutdown_sender, shutdown_receiver) = channel::<()>(SHUTDOWN_CHN_BUFF_SIZE);
+        let (stats_sender, stats_receiver) = channel::<StatsRequest>(STATS_CHN_BUFF_SIZE);
+        let (snapshot_sender, _) = broadcast::channel(SNAPSHOT_CHN_BUFF_SIZE);
 
         // Spawn meter thread
-        let t_handle = spawn_meter_thread(message_receiver, shutdown_receiver);
+        let totals = SharedTotals::default();
+        let dropped_messages = DroppedMessages::default();
+        let hide_idle = Arc::new(AtomicBool::new(false));
+        let show_totals = Arc::new(AtomicBool::new(false));
+        let t_handle = spawn_meter_thread(
+            message_receiver,
+            lifecycle_receiver,
+            shutdown_receiver,
+            stats_receiver,
+            snapshot_sender.clone(),
+            totals.clone(),
+            dropped_messages.clone(),
+            hide_idle.clone(),
+            show_totals.clone(),
+            format,
+            granularity,
+        );
 
         // Return
+        let handle = MeterHandle { stats_sender };
         (
             Meter {
                 shutdown_sender,
+                handle: handle.clone(),
+                snapshot_sender,
                 t_handle,
+                totals,
+                dropped_messages: dropped_messages.clone(),
+                hide_idle,
+                show_totals,
+            },
+            MeterMessageSender {
+                sender: Some(message_sender),
+                lifecycle_sender: Some(lifecycle_sender),
+                dropped_messages,
             },
-            MeterMessageSender(message_sender),
+            handle,
         )
     }
 
+    pub fn totals(&self) -> SharedTotals {
+        self.totals.clone()
+    }
+
+    pub fn dropped_messages(&self) -> DroppedMessages {
+        self.dropped_messages.clone()
+    }
+
+    /// Whether to leave the connections that moved no bytes during an
+    /// interval out of the printed rates, the snapshots keep them
+    pub fn set_hide_idle(&self, hide_idle: bool) {
+        self.hide_idle.store(hide_idle, Ordering::Relaxed);
+    }
+
+    /// Whether to print the bytes of every forward since the start and the
+    /// uptime next to its rates
+    pub fn set_show_totals(&self, show_totals: bool) {
+        self.show_totals.store(show_totals, Ordering::Relaxed);
+    }
+
+    /// Receive a snapshot of every meter interval from now on. A subscriber
+    /// that falls more than a few intervals behind misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<MeterSnapshot> {
+        self.snapshot_sender.subscribe()
+    }
+
+    /// Ask the meter thread for the counters of every forward, see [`MeterHandle::stats`]
+    pub async fn stats(&self) -> Option<Stats> {
+        self.handle.stats().await
+    }
+
+    /// Zero the cumulative counters of every forward, see [`MeterHandle::reset`]
+    pub async fn reset(&self) -> Option<Stats> {
+        self.handle.reset().await
+    }
+
     pub async fn shutdown(self) -> Result<(), ShutdownError> {
         // Send shutdown command
         if let Err(e) = self.shutdown_sender.send(()).await {
@@ -168,7 +1174,7 @@ impl Meter {
         }
 
         // Wait for thread to join
-        if let Err(_) = self.t_handle.await {
+        if self.t_handle.await.is_err() {
             return Err(ShutdownError::JoinError);
         }
         Ok(())
@@ -0,0 +1,231 @@
+use std::{fmt::Write, net::SocketAddr, sync::atomic::Ordering};
+
+use log::warn;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    Config::Source,
+    Meter::{DroppedMessages, SharedTotals},
+};
+
+const REQUEST_BUFF_SIZE: usize = 1024;
+const METRICS_PATH: &str = "/metrics";
+
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    totals: SharedTotals,
+    dropped_messages: DroppedMessages,
+    mut shutdown_msg_receiver: Receiver<()>,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        // Wait for a scrape or a shutdown command
+        let (stream, _) = select! {
+            conn_future = listener.accept() => {
+                match conn_future {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{e}");
+                        continue;
+                    }
+                }
+            },
+            shutdown_future = shutdown_msg_receiver.recv() => {
+                shutdown_future.expect("Unexpected shutdown of channel");
+                break;
+            },
+        };
+
+        // Serve the scrape
+        let totals = totals.clone();
+        let dropped_messages = dropped_messages.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, totals, dropped_messages).await {
+                warn!("{}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    totals: SharedTotals,
+    dropped_messages: DroppedMessages,
+) -> Result<(), std::io::Error> {
+    // Read the request line
+    let mut buff = [0; REQUEST_BUFF_SIZE];
+    let bytes_read = stream.read(&mut buff).await?;
+    let request = String::from_utf8_lossy(&buff[..bytes_read]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    // Build the response
+    let (status, body) = if method == "GET" && path == METRICS_PATH {
+        ("200 OK", render_metrics(&totals, &dropped_messages))
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Escape a forward source for use as a Prometheus label value
+fn label_value(source: &Source) -> String {
+    source
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+fn render_metrics(totals: &SharedTotals, dropped_messages: &DroppedMessages) -> String {
+    let mut totals = totals
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(source, t)| (source.clone(), t.clone()))
+        .collect::<Vec<_>>();
+    totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let totals = totals
+        .into_iter()
+        .map(|(source, t)| (label_value(&source), t))
+        .collect::<Vec<_>>();
+
+    // Writing to a String never fails
+    let mut body = String::new();
+    writeln!(
+        body,
+        "# HELP portforward_bytes_total Total number of bytes forwarded."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_bytes_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_bytes_total{{forward=\"{}\",direction=\"upload\"}} {}",
+            port, t.from_n_bytes
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "portforward_bytes_total{{forward=\"{}\",direction=\"download\"}} {}",
+            port, t.to_n_bytes
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_active_connections Number of open connections."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_active_connections gauge").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_active_connections{{forward=\"{}\"}} {}",
+            port, t.active_conns
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_connections_total Total number of accepted connections."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_connections_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_connections_total{{forward=\"{}\"}} {}",
+            port, t.total_conns
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_errors_total Total number of connections that failed."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_errors_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_errors_total{{forward=\"{}\"}} {}",
+            port, t.errors
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_resolve_errors_total Total number of connections that failed because no target could be resolved."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_resolve_errors_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_resolve_errors_total{{forward=\"{}\"}} {}",
+            port, t.resolve_errors
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_accept_errors_total Total number of connections the listener failed to accept."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_accept_errors_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_accept_errors_total{{forward=\"{}\"}} {}",
+            port, t.accept_errors
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_wire_bytes_total Total number of compressed bytes sent and received by forwards with --compress."
+    )
+    .unwrap();
+    writeln!(body, "# TYPE portforward_wire_bytes_total counter").unwrap();
+    for (port, t) in &totals {
+        writeln!(
+            body,
+            "portforward_wire_bytes_total{{forward=\"{}\"}} {}",
+            port, t.wire_n_bytes
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "# HELP portforward_meter_dropped_messages_total Total number of transfers left uncounted as the meter fell behind."
+    )
+    .unwrap();
+    writeln!(
+        body,
+        "# TYPE portforward_meter_dropped_messages_total counter"
+    )
+    .unwrap();
+    writeln!(
+        body,
+        "portforward_meter_dropped_messages_total {}",
+        dropped_messages.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    body
+}
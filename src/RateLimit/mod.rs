@@ -0,0 +1,66 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Mutex, time::sleep};
+
+/// The number of seconds of traffic the bucket can hold when it is idle
+const BURST_SECS: f64 = 0.1;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket shared by every connection that draws from it. Waiters are
+/// served one at a time in the order they arrived, so a busy connection can
+/// only take one buffer worth of tokens before the others get their turn.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("bytes_per_sec", &self.bytes_per_sec)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec * BURST_SECS,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Wait until `n_bytes` may be sent
+    pub async fn acquire(&self, n_bytes: usize) {
+        self.acquire_share(n_bytes, 1.0).await
+    }
+
+    /// Wait until `n_bytes` may be sent at `share` of the rate, such as
+    /// while the rate ramps up
+    pub async fn acquire_share(&self, n_bytes: usize, share: f64) {
+        // The lock is held while waiting for tokens, which queues the other waiters
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec * BURST_SECS);
+        bucket.last_refill = now;
+
+        // Go into debt for the bytes and wait until it is paid back
+        bucket.tokens -= n_bytes as f64 / share;
+        if bucket.tokens < 0f64 {
+            sleep(Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)).await;
+        }
+    }
+}
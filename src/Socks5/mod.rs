@@ -0,0 +1,141 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// A SOCKS5 proxy that target connections are tunneled through
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+    /// Username and password, no authentication if `None`
+    #[serde(
+        rename = "username",
+        serialize_with = "crate::Config::serialize_username"
+    )]
+    pub auth: Option<(String, String)>,
+}
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+/// The meaning of a SOCKS5 reply code
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+impl Socks5Proxy {
+    /// Connect to `target` through the proxy, the returned stream carries the
+    /// tunneled connection once the handshake is done
+    pub async fn connect(&self, target: &SocketAddr) -> Result<TcpStream, io::Error> {
+        self.tunnel(TcpStream::connect(self.addr).await?, target)
+            .await
+    }
+
+    /// Tunnel to `target` over a stream already connected to the proxy
+    pub async fn tunnel(
+        &self,
+        mut stream: TcpStream,
+        target: &SocketAddr,
+    ) -> Result<TcpStream, io::Error> {
+        // Negotiate the authentication method
+        let method = match self.auth {
+            Some(_) => METHOD_USER_PASS,
+            None => METHOD_NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(proxy_error(format!("{} is not a SOCKS5 proxy", self.addr)));
+        }
+        if reply[1] != method {
+            return Err(proxy_error(format!(
+                "{} did not accept the authentication method",
+                self.addr
+            )));
+        }
+
+        // Authenticate with username and password
+        if let Some((user, pass)) = &self.auth {
+            let mut request = vec![AUTH_VERSION, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(proxy_error(format!(
+                    "{} rejected the username or password",
+                    self.addr
+                )));
+            }
+        }
+
+        // Ask the proxy to connect to the target
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        match target.ip() {
+            IpAddr::V4(ip) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // Read the reply and skip the bound address
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(proxy_error(format!(
+                "{} failed to connect to {}: {}",
+                self.addr,
+                target,
+                reply_message(reply[1])
+            )));
+        }
+        let addr_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => stream.read_u8().await? as usize,
+            atyp => {
+                return Err(proxy_error(format!(
+                    "{} replied with an unknown address type {}",
+                    self.addr, atyp
+                )))
+            }
+        };
+        let mut bound = vec![0; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, fmt::Write, net::SocketAddr};
+
+use log::warn;
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::Receiver,
+    },
+};
+
+use crate::{Config::Source, Meter::MeterSnapshot};
+
+/// Lines are batched into packets up to this size, which fits an Ethernet MTU
+const MAX_PACKET_SIZE: usize = 1432;
+const PREFIX: &str = "portforward";
+
+/// Send the counters of every meter interval to a StatsD server, tagged
+/// DogStatsD style with the forward and the direction. Forwards are tagged
+/// with their name if they have one.
+pub async fn send_statsd(
+    addr: SocketAddr,
+    names: HashMap<Source, String>,
+    mut snapshots: broadcast::Receiver<MeterSnapshot>,
+    mut shutdown_msg_receiver: Receiver<()>,
+) -> Result<(), std::io::Error> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    let mut total_conns: HashMap<Source, usize> = HashMap::new();
+
+    loop {
+        // Wait for the next interval or a shutdown command
+        let snapshot = select! {
+            snapshot = snapshots.recv() => match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("StatsD fell {} meter intervals behind", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = shutdown_msg_receiver.recv() => break,
+        };
+
+        // Losing a packet only loses an interval, keep sending
+        for packet in render_packets(&snapshot, &names, &mut total_conns) {
+            if let Err(e) = socket.send(packet.as_bytes()).await {
+                warn!("Failed to send StatsD metrics to {}: {}", addr, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render_packets(
+    snapshot: &MeterSnapshot,
+    names: &HashMap<Source, String>,
+    total_conns: &mut HashMap<Source, usize>,
+) -> Vec<String> {
+    let mut sources = snapshot.totals.keys().collect::<Vec<_>>();
+    sources.sort();
+
+    let mut lines = Vec::new();
+    for source in sources {
+        let tag = match names.get(source) {
+            Some(name) => name.clone(),
+            None => source.to_string(),
+        };
+        let totals = &snapshot.totals[source];
+        if let Some(f) = snapshot.forwards.get(source) {
+            for (direction, n_bytes) in [("upload", f.from_n_bytes), ("download", f.to_n_bytes)] {
+                if n_bytes > 0 {
+                    lines.push(format!(
+                        "{PREFIX}.bytes:{n_bytes}|c|#forward:{tag},direction:{direction}"
+                    ));
+                }
+            }
+            if f.errors > 0 {
+                lines.push(format!("{PREFIX}.errors:{}|c|#forward:{tag}", f.errors));
+            }
+            if f.resolve_errors > 0 {
+                lines.push(format!(
+                    "{PREFIX}.resolve_errors:{}|c|#forward:{tag}",
+                    f.resolve_errors
+                ));
+            }
+            if f.accept_errors > 0 {
+                lines.push(format!(
+                    "{PREFIX}.accept_errors:{}|c|#forward:{tag}",
+                    f.accept_errors
+                ));
+            }
+        }
+        let previous = total_conns.insert(source.clone(), totals.total_conns);
+        let opened = totals.total_conns - previous.unwrap_or(0).min(totals.total_conns);
+        if opened > 0 {
+            lines.push(format!("{PREFIX}.connections:{opened}|c|#forward:{tag}"));
+        }
+        lines.push(format!(
+            "{PREFIX}.active_connections:{}|g|#forward:{tag}",
+            totals.active_conns
+        ));
+    }
+    if snapshot.dropped_messages > 0 {
+        lines.push(format!(
+            "{PREFIX}.meter_dropped_messages:{}|c",
+            snapshot.dropped_messages
+        ));
+    }
+
+    // Batch the lines into as few packets as possible
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                // Writing to a String never fails
+                write!(packet, "\n{}", line).unwrap();
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
@@ -0,0 +1,74 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use log::warn;
+use tokio::{select, sync::mpsc::Receiver, time::sleep};
+
+use crate::Meter::{ForwardTotals, SharedTotals, Stats};
+
+/// Seed the totals with the counters saved by a previous run, starting from
+/// zero if the file is missing or malformed
+pub fn load_stats(path: &Path, totals: &SharedTotals) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!(
+                "Failed to read {}, counting from zero: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let stats: Stats = match serde_json::from_str(&content) {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("{} is malformed, counting from zero: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut totals = totals.lock().unwrap();
+    for f in stats.per_forward {
+        totals.insert(
+            f.source,
+            ForwardTotals {
+                from_n_bytes: f.from_n_bytes,
+                to_n_bytes: f.to_n_bytes,
+                active_conns: 0,
+                total_conns: f.total_conns,
+                errors: f.errors,
+                resolve_errors: f.resolve_errors,
+                accept_errors: f.accept_errors,
+                wire_n_bytes: f.wire_n_bytes,
+            },
+        );
+    }
+}
+
+/// Write the totals to a temporary file first so a crash never leaves a
+/// half written file behind
+pub fn save_stats(path: &Path, totals: &SharedTotals) -> Result<(), io::Error> {
+    let stats = Stats::from_totals(&totals.lock().unwrap());
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&stats)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Save the totals every `interval` until shut down
+pub async fn persist_stats(
+    path: &Path,
+    interval: Duration,
+    totals: SharedTotals,
+    mut shutdown_msg_receiver: Receiver<()>,
+) {
+    loop {
+        select! {
+            _ = sleep(interval) => (),
+            _ = shutdown_msg_receiver.recv() => break,
+        }
+        if let Err(e) = save_stats(path, &totals) {
+            warn!("Failed to save the stats to {}: {}", path.display(), e);
+        }
+    }
+}
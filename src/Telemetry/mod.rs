@@ -0,0 +1,38 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+
+const SERVICE_NAME: &str = "rust-portforward";
+
+/// Exports the connection spans to an OTLP endpoint until shut down
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Export spans over OTLP/HTTP to `endpoint`, this must be called outside
+    /// of the tokio runtime
+    pub fn init(endpoint: &str) -> Result<Telemetry, String> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| format!("Failed to create the OTLP exporter: {}", e))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+        let tracer = provider.tracer(SERVICE_NAME);
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber)
+            .map_err(|e| format!("Failed to install the tracing subscriber: {}", e))?;
+        Ok(Telemetry { provider })
+    }
+
+    /// Export the spans that are still buffered
+    pub fn shutdown(self) -> Result<(), String> {
+        self.provider.shutdown().map_err(|e| e.to_string())
+    }
+}
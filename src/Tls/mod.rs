@@ -0,0 +1,90 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::Config::UpstreamTls;
+
+/// Where the CA bundle of the system is found on common distributions
+const SYSTEM_CA_BUNDLES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+fn invalid_data<E: std::fmt::Display>(path: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+}
+
+/// Read every certificate of a PEM file
+fn load_certs(cert_path: &str) -> Result<Vec<CertificateDer<'static>>, io::Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| invalid_data(cert_path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_data(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(invalid_data(cert_path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+/// Build a TLS acceptor from a PEM certificate chain and private key
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, io::Error> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(key_path, e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| invalid_data(cert_path, e))?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|e| invalid_data(cert_path, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a TLS connector to the targets of a forward, verifying them against
+/// the CA bundle of the system unless one is given
+pub fn load_connector(tls: &UpstreamTls) -> Result<TlsConnector, io::Error> {
+    let ca_path = match &tls.ca {
+        Some(ca_path) => ca_path.as_str(),
+        None => match SYSTEM_CA_BUNDLES.iter().find(|p| Path::new(p).exists()) {
+            Some(ca_path) => ca_path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no CA bundle found on the system, give one with ca",
+                ))
+            }
+        },
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert).map_err(|e| invalid_data(ca_path, e))?;
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| invalid_data(ca_path, e))?
+    .with_root_certificates(roots);
+    let config = match &tls.client_cert {
+        Some((cert_path, key_path)) => {
+            let certs = load_certs(cert_path)?;
+            let key =
+                PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(key_path, e))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| invalid_data(cert_path, e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
@@ -1,4 +1,18 @@
 #![allow(non_snake_case)]
+pub mod AccessLog;
+pub mod BufferPool;
+pub mod Compress;
 pub mod Config;
 pub mod ConnHandle;
+pub mod Dns;
+pub mod EventLog;
+pub mod Forwarder;
+pub mod HttpProxy;
 pub mod Meter;
+pub mod Metrics;
+pub mod RateLimit;
+pub mod Socks5;
+pub mod StatsD;
+pub mod StatsFile;
+pub mod Telemetry;
+pub mod Tls;
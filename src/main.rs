@@ -1,109 +1,924 @@
+use log::{error, info, warn};
+#[cfg(unix)]
+use rust_portforward::ConnHandle::ActivatedSockets;
 use rust_portforward::{
-    Config::{get_config, print_usage, Config},
-    ConnHandle::accept_conn,
-    Meter,
+    AccessLog::AccessLog,
+    Config::{
+        available_threads, get_config, print_usage, print_version, Cidr, CompressMode, Config,
+        Forward, MaxConnsMode, Protocol, VERSION,
+    },
+    ConnHandle::{granted_rcvbuf, granted_sndbuf, ConnOptions},
+    EventLog::EventLog,
+    Forwarder::Forwarder,
+    Meter::{format_bytes, print_stats, DEFAULT_METER_CAPACITY},
+    Metrics::serve_metrics,
+    StatsD::send_statsd,
+    StatsFile::{load_stats, persist_stats, save_stats},
+    Telemetry::Telemetry,
+    Tls::load_acceptor,
+};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    net::IpAddr,
+    process,
+    time::Instant,
 };
-use std::env;
 use tokio::{
-    io::{stdin, AsyncReadExt},
+    io::{stdin, AsyncBufReadExt, BufReader},
+    select,
+    signal::ctrl_c,
     sync::mpsc::{self, Sender},
     task::JoinHandle,
 };
 
-const STDIN_BUFF_SIZE: usize = 8;
-const SHUTDOWN_COMMAND: &str = "q";
+const STATS_COMMAND: &str = "stats";
+const LIST_COMMAND: &str = "list";
+const LOG_COMMAND: &str = "log";
+/// Zeroes the cumulative counters, which the Prometheus counters follow, so
+/// scrapers see a counter reset as after a restart
+const RESET_COMMAND: &str = "reset";
 
 fn main() {
-    // Read Args
+    // Read Args, resolving the target hostnames
     let args = env::args().collect::<Vec<_>>();
+    let started = Instant::now();
     let config = match get_config(&args[1..]) {
         Ok(c) => c,
         Err(e) if e == "Help" => return print_usage(&args[0]),
-        Err(e) => return eprintln!("{}", e),
+        Err(e) if e == "Version" => return print_version(),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     };
+    let resolve_time = started.elapsed();
+
+    // Only validate the config, without binding anything
+    if config.check {
+        if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+            if let Err(e) = load_acceptor(cert, key) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        println!("Config is valid, FORWARD_LIST:");
+        for f in &config.forwards {
+            println!("\t{}", format_forward(f));
+        }
+        if config.print_config_json {
+            println!("{}", config_json(&config));
+        }
+        return;
+    }
+
+    // Log to the error log if given, keeping stdout for the meter
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(config.log_level).parse_default_env();
+    if let Some(error_log) = &config.error_log {
+        match OpenOptions::new().create(true).append(true).open(error_log) {
+            Ok(file) => logger.target(env_logger::Target::Pipe(Box::new(file))),
+            Err(e) => {
+                eprintln!("Failed to open error log {}: {}", error_log, e);
+                process::exit(1);
+            }
+        };
+    }
+    logger.init();
     print_config(&config);
+    if config.print_config_json {
+        info!("Effective config: {}", config_json(&config));
+    }
+
+    // Write the PID file, it is removed once shut down
+    if let Some(pidfile) = &config.pidfile {
+        if let Err(e) = fs::write(pidfile, format!("{}\n", process::id())) {
+            error!("Failed to write PID file {}: {}", pidfile, e);
+            process::exit(1);
+        }
+    }
+
+    // Export connection spans, before the runtime starts
+    let telemetry = match &config.otel_endpoint {
+        Some(endpoint) => match Telemetry::init(endpoint) {
+            Ok(telemetry) => Some(telemetry),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     // Main task loop
     let main_task_loop = async {
-        // Create a meter
-        let (meter, meter_msg_sender) = Meter::Meter::new();
+        // Create a forwarder
+        let forwarder = if config.meter {
+            Forwarder::with_meter_capacity(
+                config.meter_format,
+                config.meter_granularity,
+                config.meter_capacity,
+            )
+        } else {
+            Forwarder::without_meter()
+        };
+        forwarder.set_meter_hide_idle(config.meter_hide_idle);
+        forwarder.set_meter_show_totals(config.meter_show_totals);
+
+        // Build the connection options once so every forward shares the rate limiter
+        let mut conn_options = ConnOptions::from(&config);
+        let mut access_log_writer = None;
+        if let Some(path) = &config.access_log {
+            match AccessLog::open(path) {
+                Ok((access_log, writer)) => {
+                    conn_options.access_log = Some(access_log);
+                    access_log_writer = Some(writer);
+                }
+                Err(e) => {
+                    error!("Failed to open access log {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // Listen on the sockets passed by systemd
+        #[cfg(unix)]
+        if config.socket_activation {
+            match ActivatedSockets::from_env(&config.forwards) {
+                Ok(sockets) => conn_options.activated_sockets = Some(Arc::new(sockets)),
+                Err(e) => {
+                    error!("Failed to take the sockets passed by systemd: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // Continue the counters of the previous run
+        if let Some(stats_file) = &config.stats_file {
+            load_stats(stats_file, &forwarder.totals());
+        }
 
         // Accept connection and dispatch tasks
-        let mut join_handles: Vec<JoinHandle<()>> = Vec::with_capacity(config.forwards.len());
-        let mut shutdown_channels: Vec<Sender<()>> = Vec::with_capacity(config.forwards.len());
-        for forward in config.forwards {
-            let meter_msg_sender = meter_msg_sender.clone();
+        let mut join_handles: Vec<JoinHandle<()>> = Vec::new();
+        let mut shutdown_channels: Vec<Sender<()>> = Vec::new();
+        let binding = Instant::now();
+        for forward in config.forwards.iter().cloned() {
+            if let Err(e) = forwarder.add_forward(forward, conn_options.clone()) {
+                warn!("{}", e);
+            }
+        }
+
+        // Report the forwards that failed to bind, exiting if any did with --strict-bind
+        let failed = forwarder.wait_bound().await;
+        let bind_time = binding.elapsed();
+        for (f, e) in &failed {
+            error!("Forward {} is not running, {}", format_forward(f), e);
+        }
+        let n_forwards = config.forwards.len();
+        match failed.len() {
+            0 => info!("All {} forwards are live", n_forwards),
+            n_failed => error!(
+                "{} of {} forwards are live",
+                n_forwards - n_failed,
+                n_forwards
+            ),
+        }
+        if config.strict_bind && !failed.is_empty() {
+            error!("Exiting as --strict-bind is set");
+            if let Err(e) = forwarder.shutdown().await {
+                warn!("Failed to shut down the meter: {:?}", e);
+            }
+            return 1;
+        }
+        info!(
+            "Resolved {} targets in {}ms, bound {} listeners in {}ms, ready",
+            config
+                .forwards
+                .iter()
+                .map(|f| f.targets.len())
+                .sum::<usize>(),
+            resolve_time.as_millis(),
+            n_forwards - failed.len(),
+            bind_time.as_millis()
+        );
+
+        // Only a forward that is running can end --once
+        if config.once {
+            for (f, _) in &failed {
+                forwarder.take_forward(&f.source, f.protocol);
+            }
+            if failed.len() == n_forwards {
+                error!("Exiting as no forward can accept a connection");
+                if let Err(e) = forwarder.shutdown().await {
+                    warn!("Failed to shut down the meter: {:?}", e);
+                }
+                return 1;
+            }
+        }
+
+        // Serve metrics
+        if let Some(metrics_addr) = config.metrics_addr {
+            let totals = forwarder.totals();
+            let dropped_messages = forwarder.dropped_messages();
             let (sender, receiver) = mpsc::channel(1);
             shutdown_channels.push(sender);
             join_handles.push(tokio::spawn(async move {
-                if let Err(e) = accept_conn(
-                    forward.s_port,
-                    forward.target,
-                    config.buffer_size_kb,
-                    meter_msg_sender,
-                    receiver,
-                )
-                .await
+                if let Err(e) =
+                    serve_metrics(metrics_addr, totals, dropped_messages, receiver).await
                 {
-                    eprintln!("{}", e);
+                    error!("{}", e);
+                }
+            }));
+        }
+
+        // Send metrics to StatsD
+        if let Some(statsd_addr) = config.statsd_addr {
+            let names = config
+                .forwards
+                .iter()
+                .filter_map(|f| Some((f.source.clone(), f.name.clone()?)))
+                .collect();
+            let snapshots = forwarder.subscribe();
+            let (sender, receiver) = mpsc::channel(1);
+            shutdown_channels.push(sender);
+            join_handles.push(tokio::spawn(async move {
+                if let Err(e) = send_statsd(statsd_addr, names, snapshots, receiver).await {
+                    error!("{}", e);
                 }
             }));
         }
 
-        // Wait for quit command
-        let mut stdin = stdin();
+        // Save the counters periodically
+        if let Some(stats_file) = config.stats_file.clone() {
+            let totals = forwarder.totals();
+            let interval = config.stats_persist_interval;
+            let (sender, receiver) = mpsc::channel(1);
+            shutdown_channels.push(sender);
+            join_handles.push(tokio::spawn(async move {
+                persist_stats(&stats_file, interval, totals, receiver).await
+            }));
+        }
+
+        // Wait for quit command or signal, reload the config on SIGHUP
+        let mut stdin_lines = BufReader::new(stdin()).lines();
+        let mut sighup = UnixSignal::hangup();
+        let mut sigterm = UnixSignal::terminate();
         loop {
-            let mut buff = [0; STDIN_BUFF_SIZE];
-            let bytes_read = match stdin.read(&mut buff).await {
-                Ok(n) => n,
-                Err(e) => panic!("{}", e),
+            let line = select! {
+                read_result = stdin_lines.next_line(), if !config.config_from_stdin => match read_result {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!("Failed to read stdin: {}", e);
+                        None
+                    }
+                },
+                _ = sighup.recv() => {
+                    info!("SIGHUP received, reloading config...");
+                    reload_forwards(
+                        &config,
+                        &conn_options,
+                        &forwarder,
+                        &mut join_handles,
+                    )
+                    .await;
+                    continue;
+                },
+                forward = forwarder.wait_stopped(), if config.once => {
+                    info!("Forward {} stopped after its connection, exiting...", forward.source);
+                    break;
+                },
+                _ = ctrl_c() => {
+                    info!("SIGINT received");
+                    break;
+                },
+                _ = sigterm.recv() => {
+                    info!("SIGTERM received");
+                    break;
+                },
             };
 
-            // shutdown if stdin cannot be read
-            if bytes_read <= 0 {
-                break;
-            }
+            // shutdown if stdin is closed or cannot be read
+            let command = match line {
+                Some(line) => line,
+                None => break,
+            };
 
             // shutdown if quit command is received
-            let command = String::from_utf8_lossy(&buff[..bytes_read]);
-            if command.trim() == SHUTDOWN_COMMAND {
-                println!("Shutdown command received");
+            if command.trim() == config.quit_command {
+                info!("Shutdown command received");
                 break;
             }
+
+            // print a snapshot of the counters if stats command is received
+            if command.trim() == STATS_COMMAND {
+                match forwarder.stats().await {
+                    Some(stats) => print_stats(&stats),
+                    None => warn!("Meter is closed or disabled, no stats available"),
+                }
+            }
+
+            // print the open connections if list command is received
+            if command.trim() == LIST_COMMAND {
+                print_conns(&forwarder);
+            }
+
+            // print the recent connection events if log command is received
+            if command.trim() == LOG_COMMAND {
+                match &conn_options.event_log {
+                    Some(event_log) => print_events(event_log),
+                    None => warn!("Event log is disabled, enable it with --event-log"),
+                }
+            }
+
+            // zero the counters if reset command is received, printing them one last time
+            if command.trim() == RESET_COMMAND {
+                match forwarder.reset_stats().await {
+                    Some(stats) => {
+                        print_stats(&stats);
+                        warn!("Counters reset, the metrics counters restart from 0");
+                    }
+                    None => warn!("Meter is closed or disabled, no counters to reset"),
+                }
+            }
         }
 
         // Shutdown threads
-        println!("Shutting down threads...");
+        info!("Shutting down threads...");
         for c in shutdown_channels {
-            c.send(()).await.unwrap();
+            // The task may already have stopped on its own, e.g. if it failed to bind
+            let _ = c.send(()).await;
         }
+        forwarder.remove_all().await;
         let join_results = futures::future::join_all(join_handles).await;
         for result in join_results {
             if let Err(e) = result {
-                eprintln!("{}", e);
+                warn!("{}", e);
             }
         }
 
-        // Shutdown meter
-        println!("Shutting down meter...");
-        meter.shutdown().await.unwrap();
+        // Flush the access log once no connection can write to it
+        drop(conn_options);
+        if let Some(writer) = access_log_writer {
+            if let Err(e) = writer.await {
+                warn!("{}", e);
+            }
+        }
+
+        // Shutdown meter, every connection task has finished by now
+        info!("Shutting down meter...");
+        let totals = forwarder.totals();
+        if let Err(e) = forwarder.shutdown().await {
+            warn!("Failed to shut down the meter: {:?}", e);
+        }
+
+        // Save the final counters once the meter has counted every message
+        if let Some(stats_file) = &config.stats_file {
+            if let Err(e) = save_stats(stats_file, &totals) {
+                warn!(
+                    "Failed to save the stats to {}: {}",
+                    stats_file.display(),
+                    e
+                );
+            }
+        }
+        0
     };
 
     // Configure async runtime
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(config.n_thread)
         .build()
-        .expect("Failed to build the async run time")
-        .block_on(main_task_loop);
+        .expect("Failed to build the async run time");
+    let exit_code = runtime.block_on(main_task_loop);
+
+    // Do not wait for the blocking stdin read when shut down by a signal
+    runtime.shutdown_background();
+
+    // Flush the spans of the last connections
+    if let Some(telemetry) = telemetry {
+        if let Err(e) = telemetry.shutdown() {
+            warn!("Failed to export spans: {}", e);
+        }
+    }
+
+    if let Some(pidfile) = &config.pidfile {
+        if let Err(e) = fs::remove_file(pidfile) {
+            warn!("Failed to remove PID file {}: {}", pidfile, e);
+        }
+    }
+    if exit_code != 0 {
+        process::exit(exit_code);
+    }
+}
+
+/// A listener of a Unix signal, which never fires on other platforms where
+/// Ctrl-C is the only signal received
+struct UnixSignal {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl UnixSignal {
+    #[cfg(unix)]
+    fn new(kind: tokio::signal::unix::SignalKind, name: &str) -> Self {
+        match tokio::signal::unix::signal(kind) {
+            Ok(signal) => UnixSignal { signal },
+            Err(e) => panic!("Failed to listen for {}: {}", name, e),
+        }
+    }
+
+    fn hangup() -> Self {
+        #[cfg(unix)]
+        return Self::new(tokio::signal::unix::SignalKind::hangup(), "SIGHUP");
+        #[cfg(not(unix))]
+        return UnixSignal {};
+    }
+
+    fn terminate() -> Self {
+        #[cfg(unix)]
+        return Self::new(tokio::signal::unix::SignalKind::terminate(), "SIGTERM");
+        #[cfg(not(unix))]
+        return UnixSignal {};
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Re-read the forward list and reconcile it with the running forwards.
+/// Stopped forwards are shut down in `join_handles` so their connections can
+/// drain without blocking the reload.
+async fn reload_forwards(
+    config: &Config,
+    conn_options: &ConnOptions,
+    forwarder: &Forwarder,
+    join_handles: &mut Vec<JoinHandle<()>>,
+) {
+    let (forwards, unresolved_hosts) = match config.reload_forwards() {
+        Ok(reloaded) => reloaded,
+        Err(e) => return warn!("Failed to reload config: {}", e),
+    };
+    for (host, e) in &unresolved_hosts {
+        warn!(
+            "Cannot resolve {} yet, resolving it on connect: {}",
+            host, e
+        );
+    }
+    let draining = forwarder.reload(forwards, conn_options).await;
+    join_handles.extend(draining);
+}
+
+fn print_conns(forwarder: &Forwarder) {
+    let conns = forwarder.connections();
+    if conns.is_empty() {
+        return println!("No open connections");
+    }
+    for c in conns {
+        let target = match c.target {
+            Some(target) => target.to_string(),
+            None => "connecting".to_string(),
+        };
+        println!(
+            "#{} {} -> {} -> {}, ul: {}, dl: {}, age: {}s",
+            c.id,
+            c.peer,
+            c.forward,
+            target,
+            format_bytes(c.from_n_bytes as f64),
+            format_bytes(c.to_n_bytes as f64),
+            c.age.as_secs()
+        );
+    }
+}
+
+fn print_events(event_log: &EventLog) {
+    let events = event_log.events();
+    if events.is_empty() {
+        return println!("No connection events");
+    }
+    for event in events {
+        println!("{}", event);
+    }
+}
+
+fn format_forward(f: &Forward) -> String {
+    let mut targets = f
+        .targets
+        .iter()
+        .map(|t| match f.weights.get(t) {
+            Some(weight) => format!("{}@{}", t, weight),
+            None => t.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    // The hosts left to resolve on connect
+    if targets.is_empty() {
+        targets = f.target_hosts.join(", ");
+    }
+    let mut flags = String::new();
+    if f.protocol == Protocol::Udp {
+        flags.push_str(" [udp]");
+    }
+    if f.is_bridged() {
+        flags.push_str(&format!(" [target_proto={}]", f.target_protocol));
+    }
+    if f.bind_addrs.len() > 1 {
+        let bind_addrs = f.bind_addrs.iter().map(IpAddr::to_string);
+        flags.push_str(&format!(
+            " [bind={}]",
+            bind_addrs.collect::<Vec<_>>().join(",")
+        ));
+    }
+    if f.tls {
+        flags.push_str(" [tls]");
+    }
+    if let Some(buffer_size) = f.buffer_size {
+        flags.push_str(&format!(" [buff={}]", format_size(buffer_size)));
+    }
+    if f.lazy_connect {
+        flags.push_str(" [lazy]");
+    }
+    if let Some(preamble) = &f.preamble {
+        flags.push_str(&format!(" [preamble={} bytes]", preamble.len()));
+    }
+    if let Some(banner_len) = f.banner_len {
+        flags.push_str(&format!(" [banner={} bytes]", banner_len));
+    }
+    if let Some(nodelay) = f.nodelay {
+        flags.push_str(if nodelay {
+            " [nodelay=on]"
+        } else {
+            " [nodelay=off]"
+        });
+    }
+    if let Some(connect_timeout) = f.connect_timeout {
+        flags.push_str(&format!(
+            " [connect_timeout={}s]",
+            connect_timeout.as_secs()
+        ));
+    }
+    if let Some(retries) = f.connect_retries {
+        flags.push_str(&format!(" [connect_retries={}]", retries));
+    }
+    if let Some(backoff) = f.connect_backoff {
+        flags.push_str(&format!(" [connect_backoff={}ms]", backoff.as_millis()));
+    }
+    if let Some(mirror) = f.mirror {
+        flags.push_str(&format!(" [mirror={}]", mirror));
+    }
+    if f.failure_response.is_some() {
+        flags.push_str(" [failure-response]");
+    }
+    if !f.sni_routes.is_empty() {
+        let mut snis = f.sni_routes.keys().map(String::as_str).collect::<Vec<_>>();
+        snis.sort();
+        flags.push_str(&format!(" [sni={}]", snis.join(",")));
+    }
+    if !f.ip_routes.is_empty() {
+        let cidrs = f.ip_routes.iter().map(|(cidr, _)| cidr.to_string());
+        flags.push_str(&format!(" [cidr={}]", cidrs.collect::<Vec<_>>().join(",")));
+    }
+    if let Some(upstream_tls) = &f.upstream_tls {
+        flags.push_str(&format!(" [upstream-tls={}]", upstream_tls.server_name));
+    }
+    if let Some(srv) = &f.srv {
+        flags.push_str(&format!(" [srv={}]", srv));
+    }
+    match &f.name {
+        Some(name) => format!("{} ({}){} -> {}", f.source, name, flags, targets),
+        None => format!("{}{} -> {}", f.source, flags, targets),
+    }
+}
+
+/// Format a buffer size the way it is given, e.g. `4k`
+fn format_size(n_bytes: usize) -> String {
+    match n_bytes {
+        n if n % (1024 * 1024) == 0 => format!("{}m", n / 1024 / 1024),
+        n if n % 1024 == 0 => format!("{}k", n / 1024),
+        n => format!("{}b", n),
+    }
+}
+
+/// The config as one line of JSON
+fn config_json(config: &Config) -> String {
+    serde_json::to_string(config).unwrap_or_else(|e| format!("(failed to serialize: {})", e))
 }
 
 fn print_config(config: &Config) {
-    println!(
-        "Program started with BUFF_SIZE={}, N_THREAD={}, and FORWARD_LIST:",
-        config.buffer_size_kb, config.n_thread
+    let n_thread = if config.n_thread == available_threads() {
+        format!("{} (one per core)", config.n_thread)
+    } else {
+        config.n_thread.to_string()
+    };
+    info!(
+        "Program v{} started with BUFF_SIZE={}, N_THREAD={}, and FORWARD_LIST:",
+        VERSION,
+        format_size(config.buffer_size),
+        n_thread
     );
     for f in &config.forwards {
-        println!("\t{} -> {}", f.s_port, f.target);
+        info!("\t{}", format_forward(f));
+    }
+    if config.buffer_up.is_some() || config.buffer_down.is_some() {
+        info!(
+            "Buffer sizes: up {}, down {}",
+            format_size(config.buffer_up.unwrap_or(config.buffer_size)),
+            format_size(config.buffer_down.unwrap_or(config.buffer_size))
+        );
+    }
+    if config.dual_stack {
+        info!("Listening on [::] for both IPv4 and IPv6 clients");
+    }
+    if config.sticky {
+        info!("Sticky sessions by client IP");
+    }
+    if config.resolve_on_connect {
+        match config.dns_cache_ttl {
+            Some(ttl) => info!(
+                "Resolving target hostnames on connect, cached for {}s",
+                ttl.as_secs()
+            ),
+            None => info!("Resolving target hostnames on every connection"),
+        }
+    }
+    if config.happy_eyeballs_delay.is_none() {
+        info!("Trying targets one at a time");
+    }
+    if let Some(connect_timeout) = config.connect_timeout {
+        info!("Connect timeout: {}s", connect_timeout.as_secs());
+    }
+    if config.connect_retries > 0 {
+        info!(
+            "Retrying connects {} time(s), backing off from {}ms",
+            config.connect_retries,
+            config.connect_backoff.as_millis()
+        );
+    }
+    if let Some(dns_server) = config.dns_server {
+        info!("Resolving target hostnames through {}", dns_server);
+    }
+    if let Some(family) = config.prefer {
+        info!("Preferring {} targets", family);
+    }
+    if config.proxy_protocol {
+        info!("Sending PROXY protocol v1 headers to targets");
+    }
+    if config.accept_proxy_protocol {
+        info!("Taking the client addresses from PROXY protocol headers");
+    }
+    if let Some(max_conns) = config.max_conns {
+        info!(
+            "Maximum connections per forward: {}, {}",
+            max_conns,
+            match config.max_conns_mode {
+                MaxConnsMode::Reject => "rejecting the others",
+                MaxConnsMode::Queue => "leaving the others in the backlog",
+            }
+        );
+    }
+    if let Some(max_conns_per_ip) = config.max_conns_per_ip {
+        info!("Maximum connections per client IP: {}", max_conns_per_ip);
+    }
+    if config.forwards.iter().any(|f| f.protocol == Protocol::Udp) {
+        info!("UDP flow timeout: {}s", config.udp_timeout.as_secs());
+        if let Some(udp_max_flows) = config.udp_max_flows {
+            info!("Maximum UDP flows per forward: {}", udp_max_flows);
+        }
+        info!("Maximum UDP datagram: {} bytes", config.udp_max_datagram);
+    }
+    if let Some(accept_rate) = config.accept_rate {
+        info!(
+            "Accept rate limit: {} connections/s per forward",
+            accept_rate
+        );
+    }
+    if let Some(slow_start) = config.slow_start {
+        info!(
+            "Slow start: the accept rate ramps up over {}s",
+            slow_start.as_secs()
+        );
+    }
+    if let Some(total_rate_kbps) = config.total_rate_kbps {
+        info!("Total rate limit: {} KB/s", total_rate_kbps);
+    }
+    if let Some(breaker) = config.circuit_breaker {
+        info!(
+            "Circuit breaker opens after {} failures within {}s for {}s",
+            breaker.threshold,
+            breaker.window.as_secs(),
+            breaker.cooldown.as_secs()
+        );
+    }
+    let policy = &config.target_policy;
+    if policy.is_enabled() {
+        let list = |cidrs: &[Cidr]| match cidrs.is_empty() {
+            true => "any".to_string(),
+            false => cidrs
+                .iter()
+                .map(Cidr::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        info!(
+            "Allowed targets: {}{}, denied: {}",
+            list(&policy.allow),
+            if policy.deny_private {
+                " but private ones"
+            } else {
+                ""
+            },
+            list(&policy.deny)
+        );
+    }
+    if let Some(interval) = config.health_check_interval {
+        info!(
+            "Health checking targets every {}s with a timeout of {}s",
+            interval.as_secs(),
+            config.health_check_timeout.as_secs()
+        );
+    }
+    for (host, e) in &config.unresolved_hosts {
+        warn!(
+            "Cannot resolve {} yet, resolving it on connect: {}",
+            host, e
+        );
+    }
+    if let Some(pool_size) = config.pool_size {
+        info!(
+            "Keeping {} connection(s) to each target dialed ahead, for up to {}s",
+            pool_size,
+            config.pool_idle_timeout.as_secs()
+        );
+    }
+    if let Some(idle_timeout) = config.idle_timeout {
+        info!("Idle timeout: {}s", idle_timeout.as_secs());
+    }
+    if let Some(write_timeout) = config.write_timeout {
+        info!("Write timeout: {}s", write_timeout.as_secs());
+    }
+    if let Some(coalesce_delay) = config.coalesce_delay {
+        match config.coalesce_bytes {
+            Some(n_bytes) => info!(
+                "Coalescing writes for up to {}ms or {} bytes",
+                coalesce_delay.as_millis(),
+                n_bytes
+            ),
+            None => info!(
+                "Coalescing writes for up to {}ms",
+                coalesce_delay.as_millis()
+            ),
+        }
+    }
+    match config.compress {
+        Some(CompressMode::Target) => info!("Compressing the TCP streams to the targets"),
+        Some(CompressMode::Client) => info!("Decompressing the TCP streams from the clients"),
+        None => {}
+    }
+    if let Some(max_lifetime) = config.max_lifetime {
+        info!("Maximum connection lifetime: {}s", max_lifetime.as_secs());
+    }
+    if let Some(max_bytes_per_conn) = config.max_bytes_per_conn {
+        info!(
+            "Maximum bytes per connection: {}",
+            format_bytes(max_bytes_per_conn as f64)
+        );
+    }
+    if let Some(max_inflight_kb) = config.max_inflight_kb {
+        info!(
+            "Maximum in-flight data per connection: {}KB",
+            max_inflight_kb
+        );
+    }
+    if !config.meter {
+        info!("Metering disabled");
+    } else if config.meter_capacity != DEFAULT_METER_CAPACITY {
+        info!(
+            "Queueing up to {} meter messages, dropping transfers past it",
+            config.meter_capacity
+        );
+    }
+    if config.meter_hide_idle {
+        info!("Hiding the idle connections from the meter");
+    }
+    if config.meter_show_totals {
+        info!("Showing the totals of every forward in the meter");
+    }
+    if config.reuse_port {
+        info!("Listening with SO_REUSEPORT");
+    }
+    if config.socket_activation {
+        info!("Listening on the sockets passed by systemd");
+    }
+    if config.strict_bind {
+        info!("Exiting if any forward fails to bind");
+    }
+    if config.bind_retries > 0 {
+        info!(
+            "Retrying binds of addresses in use {} time(s), every {}ms",
+            config.bind_retries,
+            config.bind_retry_delay.as_millis()
+        );
+    }
+    if config.once {
+        info!("Exiting once the first connection closes");
+    }
+    if let Some(interface) = &config.bind_interface {
+        match config.bind_interface_outbound {
+            true => info!("Listening and connecting on interface {}", interface),
+            false => info!("Listening on interface {}", interface),
+        }
+    }
+    if let Some(source_addr) = config.source_addr {
+        info!("Connecting to the targets from {}", source_addr);
+    }
+    if config.transparent {
+        info!("Transparent proxy mode, intercepted connections go to their original destination");
+    }
+    if config.nodelay {
+        info!("TCP_NODELAY enabled");
+    }
+    if let Some(keepalive) = config.keepalive {
+        info!("TCP keepalive after {}s idle", keepalive.as_secs());
+    }
+    if let Some(linger) = config.linger {
+        info!("SO_LINGER: {}s", linger.as_secs());
+    }
+    if let Some(rcvbuf) = config.rcvbuf {
+        match granted_rcvbuf(rcvbuf) {
+            Ok(granted) => info!("SO_RCVBUF: {} bytes requested, {} granted", rcvbuf, granted),
+            Err(e) => warn!(
+                "SO_RCVBUF: {} bytes requested, cannot be set: {}",
+                rcvbuf, e
+            ),
+        }
+    }
+    if let Some(sndbuf) = config.sndbuf {
+        match granted_sndbuf(sndbuf) {
+            Ok(granted) => info!("SO_SNDBUF: {} bytes requested, {} granted", sndbuf, granted),
+            Err(e) => warn!(
+                "SO_SNDBUF: {} bytes requested, cannot be set: {}",
+                sndbuf, e
+            ),
+        }
+    }
+    if let Some(drain_timeout) = config.drain_timeout {
+        info!("Drain timeout: {}s", drain_timeout.as_secs());
+    }
+    if let Some(socks5) = &config.socks5 {
+        info!("Connecting to targets through SOCKS5 proxy {}", socks5.addr);
+    }
+    if let Some(http_proxy) = &config.http_proxy {
+        info!(
+            "Connecting to targets through HTTP proxy {}",
+            http_proxy.addr
+        );
+    }
+    if let Some(capture_dir) = &config.capture_dir {
+        info!(
+            "Capturing up to {}KB per connection direction to {}",
+            config.capture_max_kb,
+            capture_dir.display()
+        );
+    }
+    if let Some(hexdump) = config.hexdump {
+        info!(
+            "Logging a hex dump of the first {} bytes of every chunk",
+            hexdump
+        );
+    }
+    if let Some(access_log) = &config.access_log {
+        info!("Writing the access log to {}", access_log);
+    }
+    if let Some(event_log_size) = config.event_log_size {
+        info!("Keeping the last {} connection events", event_log_size);
+    }
+    if config.events_json {
+        info!("Printing connection events as JSON");
+    }
+    if let Some(pidfile) = &config.pidfile {
+        info!("Writing the process ID to {}", pidfile);
+    }
+    if let Some(statsd_addr) = config.statsd_addr {
+        info!("Sending metrics to StatsD at {}", statsd_addr);
+    }
+    if let Some(stats_file) = &config.stats_file {
+        info!(
+            "Saving stats to {} every {}s",
+            stats_file.display(),
+            config.stats_persist_interval.as_secs()
+        );
+    }
+    if let Some(metrics_addr) = config.metrics_addr {
+        info!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
+    if let Some(otel_endpoint) = &config.otel_endpoint {
+        info!("Exporting connection spans to {}", otel_endpoint);
     }
 }
@@ -0,0 +1,1228 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use rust_portforward::{
+    BufferPool::BufferProvider,
+    Compress::{Compressor, Decompressor, WireMeter},
+    Config::{
+        get_config, get_config_with_stdin, CompressMode, Forward, Protocol, Source, TargetPolicy,
+    },
+    ConnHandle::{forward_stream, next_conn_id, ConnOptions, ForwardError, PeerAddr, StreamMeter},
+    Forwarder::Forwarder,
+    Meter::{Direction, ForwardTotals, MeterFormat, MeterGranularity, MeterMessageSender},
+};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::sleep,
+};
+
+const LOCALHOST: &str = "127.0.0.1:0";
+
+/// Start a TCP server on an ephemeral port that echoes back what it receives
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind(LOCALHOST).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Send `msg` through `client` and read it back from the echo server
+async fn echo(client: &mut TcpStream, msg: &[u8]) {
+    client.write_all(msg).await.unwrap();
+    let mut buff = vec![0; msg.len()];
+    client.read_exact(&mut buff).await.unwrap();
+    assert_eq!(buff, msg);
+}
+
+/// A target that takes `capacity` bytes, then writes no more
+struct StopsAccepting {
+    capacity: usize,
+}
+
+impl AsyncWrite for StopsAccepting {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = buf.len().min(self.capacity);
+        self.capacity -= n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A source of `remaining` bytes that counts the bytes read from it
+struct CountingSource {
+    remaining: usize,
+    read: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingSource {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = buf.remaining().min(self.remaining);
+        buf.put_slice(&vec![0x5a; n]);
+        self.remaining -= n;
+        self.read.fetch_add(n, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A target that takes at most 100 bytes a write and is not ready every
+/// other write, keeping the most bytes read from `read` but not yet written
+struct ThrottledTarget {
+    read: Arc<AtomicUsize>,
+    written: usize,
+    max_inflight: Arc<AtomicUsize>,
+    ready: bool,
+}
+
+impl AsyncWrite for ThrottledTarget {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.ready {
+            self.ready = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.ready = false;
+        let inflight = self.read.load(Ordering::Relaxed) - self.written;
+        self.max_inflight.fetch_max(inflight, Ordering::Relaxed);
+        let n = buf.len().min(100);
+        self.written += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Wait for the meter to count what `done` expects of the forward on `source`
+async fn wait_totals(
+    forwarder: &Forwarder,
+    source: &Source,
+    done: impl Fn(&ForwardTotals) -> bool,
+) -> ForwardTotals {
+    for _ in 0..50 {
+        let totals = forwarder.totals().lock().unwrap().get(source).cloned();
+        if let Some(totals) = totals.filter(&done) {
+            return totals;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the meter did not count the traffic of {}", source);
+}
+
+#[tokio::test]
+async fn forwards_tcp_bytes_and_shuts_down() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+    let addr = addr.unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"hello through the forward")
+        .await
+        .unwrap();
+    let mut buff = [0; 25];
+    client.read_exact(&mut buff).await.unwrap();
+    assert_eq!(&buff, b"hello through the forward");
+    assert_eq!(handle.connections().len(), 1);
+    drop(client);
+
+    handle.shutdown().await.unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn half_closed_client_still_gets_the_whole_response() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+
+    // More than the socket buffers hold, so the echo is still coming back
+    // after the client closed its write side
+    let msg = b"request ".repeat(256 * 1024);
+    let client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    let (mut client_read, mut client_write) = client.into_split();
+    let sent = msg.clone();
+    tokio::spawn(async move {
+        client_write.write_all(&sent).await.unwrap();
+        client_write.shutdown().await.unwrap();
+        // Keep the half open until the test ends
+        sleep(Duration::from_secs(10)).await;
+    });
+    let mut received = Vec::new();
+    client_read.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received.len(), msg.len());
+    assert!(received == msg);
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+/// Hands out heap buffers and counts them
+#[derive(Debug, Default)]
+struct CountingBuffers {
+    obtained: AtomicUsize,
+    released: AtomicUsize,
+    /// The size of the largest buffer obtained
+    largest: AtomicUsize,
+}
+
+impl BufferProvider for CountingBuffers {
+    fn obtain(&self, buff_size: usize) -> Vec<u8> {
+        self.obtained.fetch_add(1, Ordering::Relaxed);
+        self.largest.fetch_max(buff_size, Ordering::Relaxed);
+        vec![0; buff_size]
+    }
+
+    fn release(&self, _: Vec<u8>) {
+        self.released.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::test]
+async fn obtains_the_buffers_from_the_provider() {
+    let target = echo_server().await;
+    let provider = Arc::new(CountingBuffers::default());
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        buffer_provider: provider.clone(),
+        splice: false,
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    echo(&mut client, b"hello").await;
+    // One buffer per direction
+    assert_eq!(provider.obtained.load(Ordering::Relaxed), 2);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+    assert_eq!(provider.released.load(Ordering::Relaxed), 2);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn forwards_udp_datagrams() {
+    let target = UdpSocket::bind(LOCALHOST).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::udp(LOCALHOST.parse().unwrap(), vec![target_addr]);
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+
+    let client = UdpSocket::bind(LOCALHOST).await.unwrap();
+    client.send_to(b"ping", addr.unwrap()).await.unwrap();
+    let mut buff = [0; 16];
+    let (n, flow) = target.recv_from(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"ping");
+    target.send_to(b"pong", flow).await.unwrap();
+    let n = client.recv(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"pong");
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn forwards_tcp_and_udp_on_the_same_port() {
+    let tcp_target = echo_server().await;
+    let udp_target = UdpSocket::bind(LOCALHOST).await.unwrap();
+    let port = std::net::TcpListener::bind(LOCALHOST)
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let args = [
+        format!("{}:{}", port, tcp_target),
+        format!("udp:{}:{}", port, udp_target.local_addr().unwrap()),
+    ];
+    let config = get_config(&args).unwrap();
+    let protocols = config
+        .forwards
+        .iter()
+        .map(|f| (f.source.clone(), f.protocol))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        protocols,
+        vec![
+            (Source::Port(port), Protocol::Tcp),
+            (Source::Port(port), Protocol::Udp)
+        ]
+    );
+
+    // Both listeners start and each gets its own traffic
+    let forwarder = Forwarder::without_meter();
+    for forward in config.forwards {
+        forwarder
+            .add_forward_bound(forward, ConnOptions::default())
+            .await
+            .unwrap();
+    }
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    echo(&mut client, b"over tcp").await;
+    let udp_client = UdpSocket::bind(LOCALHOST).await.unwrap();
+    udp_client
+        .send_to(b"over udp", ("127.0.0.1", port))
+        .await
+        .unwrap();
+    let mut buff = [0; 16];
+    let n = udp_target.recv(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"over udp");
+
+    drop(client);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn drops_and_counts_oversized_datagrams() {
+    let target = UdpSocket::bind(LOCALHOST).await.unwrap();
+    let forwarder = Forwarder::new();
+    let forward = Forward::udp(
+        LOCALHOST.parse().unwrap(),
+        vec![target.local_addr().unwrap()],
+    );
+    let options = ConnOptions {
+        udp_max_datagram: 4,
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    let client = UdpSocket::bind(LOCALHOST).await.unwrap();
+    client.send_to(b"too long", addr.unwrap()).await.unwrap();
+    client.send_to(b"fits", addr.unwrap()).await.unwrap();
+    let mut buff = [0; 16];
+    let n = target.recv(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"fits");
+    let source = handle.forward().source.clone();
+    wait_totals(&forwarder, &source, |t| t.errors == 1).await;
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn bridges_tcp_frames_to_udp_datagrams() {
+    let target = UdpSocket::bind(LOCALHOST).await.unwrap();
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward {
+        target_protocol: Protocol::Udp,
+        ..Forward::tcp(
+            LOCALHOST.parse().unwrap(),
+            vec![target.local_addr().unwrap()],
+        )
+    };
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+
+    // Two frames in one write are two datagrams
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    client.write_all(b"\0\x04ping\0\x02hi").await.unwrap();
+    let mut buff = [0; 16];
+    let (n, flow) = target.recv_from(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"ping");
+    let n = target.recv(&mut buff).await.unwrap();
+    assert_eq!(&buff[..n], b"hi");
+    target.send_to(b"pong", flow).await.unwrap();
+    let mut frame = [0; 6];
+    client.read_exact(&mut frame).await.unwrap();
+    assert_eq!(&frame, b"\0\x04pong");
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_tcp_target_that_does_not_answer_holds_up_no_other_flow() {
+    // Connects to a listener with a full backlog are not answered
+    let stalled = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    stalled
+        .bind(&LOCALHOST.parse::<SocketAddr>().unwrap().into())
+        .unwrap();
+    stalled.listen(0).unwrap();
+    let stalled_addr = stalled.local_addr().unwrap().as_socket().unwrap();
+    let _backlog = std::net::TcpStream::connect(stalled_addr).unwrap();
+    // Echoing the frames echoes the datagrams
+    let target = echo_server().await;
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward {
+        target_protocol: Protocol::Tcp,
+        ip_routes: vec![("127.0.0.2".parse().unwrap(), vec![stalled_addr])],
+        ..Forward::udp(LOCALHOST.parse().unwrap(), vec![target])
+    };
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+    let addr = addr.unwrap();
+
+    let stalled_client = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+    stalled_client.send_to(b"stalled", addr).await.unwrap();
+    let client = UdpSocket::bind(LOCALHOST).await.unwrap();
+    client.send_to(b"ping", addr).await.unwrap();
+    let mut buff = [0; 16];
+    let n = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buff))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buff[..n], b"ping");
+
+    tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn routes_by_the_route_callback() {
+    let target = echo_server().await;
+    // The forward's own target refuses connections
+    let closed = TcpListener::bind(LOCALHOST)
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![closed]);
+    let mut options = ConnOptions::default();
+    options.callbacks.route = Some(Arc::new(move |peer, _| match peer {
+        PeerAddr::Tcp(addr) if addr.ip().is_loopback() => vec![target],
+        _ => Vec::new(),
+    }));
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    echo(&mut client, b"routed").await;
+    assert_eq!(handle.connections()[0].target, Some(target));
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn refuses_a_target_the_policy_denies() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::new();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        target_policy: TargetPolicy {
+            deny_private: true,
+            ..TargetPolicy::default()
+        },
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    // Closed without reaching the loopback target
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    let mut buff = [0; 1];
+    assert_eq!(client.read(&mut buff).await.unwrap(), 0);
+    let source = handle.forward().source.clone();
+    wait_totals(&forwarder, &source, |t| t.errors == 1).await;
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_a_port_in_use() {
+    let taken = TcpListener::bind(LOCALHOST).await.unwrap();
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(taken.local_addr().unwrap(), vec![echo_server().await]);
+    match forwarder.start_bound(forward, ConnOptions::default()).await {
+        Err(ForwardError::Bind { .. }) => {}
+        Err(e) => panic!("expected a bind error, got {}", e),
+        Ok(_) => panic!("expected a bind error"),
+    }
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_keeps_the_counters_of_a_changed_forward() {
+    let target = echo_server().await;
+    let bind = std::net::TcpListener::bind(LOCALHOST)
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let forwarder = Forwarder::new();
+    let forward = Forward::tcp(bind, vec![target]);
+    let source = forward.source.clone();
+    forwarder
+        .add_forward_bound(forward.clone(), ConnOptions::default())
+        .await
+        .unwrap();
+    let mut client = TcpStream::connect(bind).await.unwrap();
+    echo(&mut client, b"hello").await;
+    wait_totals(&forwarder, &source, |t| t.from_n_bytes == 5).await;
+
+    // Change the forward while its connection is still open
+    let changed = Forward {
+        name: Some("web".to_string()),
+        ..forward
+    };
+    let draining = forwarder
+        .reload(vec![changed], &ConnOptions::default())
+        .await;
+    echo(&mut client, b"again").await;
+    let mut new_client = TcpStream::connect(bind).await.unwrap();
+    echo(&mut new_client, b"world").await;
+    // Counting on from the 5 bytes before the reload, not from 0
+    let after = wait_totals(&forwarder, &source, |t| t.from_n_bytes == 15).await;
+    assert_eq!(after.to_n_bytes, 15);
+    assert_eq!(after.total_conns, 2);
+
+    drop((client, new_client));
+    for handle in draining {
+        handle.await.unwrap();
+    }
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_swaps_the_targets_of_new_connections_only() {
+    let (old_target, new_target) = (echo_server().await, echo_server().await);
+    let bind = std::net::TcpListener::bind(LOCALHOST)
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(bind, vec![old_target]);
+    forwarder
+        .add_forward_bound(forward.clone(), ConnOptions::default())
+        .await
+        .unwrap();
+    let mut client = TcpStream::connect(bind).await.unwrap();
+    echo(&mut client, b"hello").await;
+
+    // Change the target while the connection is still open
+    let changed = Forward::tcp(bind, vec![new_target]);
+    let draining = forwarder
+        .reload(vec![changed.clone()], &ConnOptions::default())
+        .await;
+    assert!(draining.is_empty());
+    assert_eq!(forwarder.forwards(), vec![changed]);
+    echo(&mut client, b"again").await;
+    let mut new_client = TcpStream::connect(bind).await.unwrap();
+    echo(&mut new_client, b"world").await;
+    let targets = forwarder
+        .connections()
+        .iter()
+        .map(|c| c.target)
+        .collect::<Vec<_>>();
+    assert_eq!(targets, vec![Some(old_target), Some(new_target)]);
+
+    drop((client, new_client));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_leaves_the_forward_of_open_connections_alone() {
+    let (old_target, new_target) = (echo_server().await, echo_server().await);
+    let bind = std::net::TcpListener::bind(LOCALHOST)
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut options = ConnOptions::default();
+    let on_connect_seen = seen.clone();
+    options.callbacks.on_connect = Some(Arc::new(move |_, forward| {
+        on_connect_seen
+            .lock()
+            .unwrap()
+            .push(forward.targets.clone());
+    }));
+    let on_disconnect_seen = seen.clone();
+    options.callbacks.on_disconnect = Some(Arc::new(move |_, forward| {
+        on_disconnect_seen
+            .lock()
+            .unwrap()
+            .push(forward.targets.clone());
+    }));
+    let forwarder = Forwarder::without_meter();
+    forwarder
+        .add_forward_bound(Forward::tcp(bind, vec![old_target]), options.clone())
+        .await
+        .unwrap();
+    let mut client = TcpStream::connect(bind).await.unwrap();
+    echo(&mut client, b"hello").await;
+
+    // The connection closes after its targets are swapped
+    forwarder
+        .reload(vec![Forward::tcp(bind, vec![new_target])], &options)
+        .await;
+    drop(client);
+    while seen.lock().unwrap().len() < 2 {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(*seen.lock().unwrap(), vec![vec![old_target]; 2]);
+
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shuts_down_one_forward_and_keeps_the_others() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::new();
+    let mut binds = Vec::new();
+    for _ in 0..2 {
+        let bind = std::net::TcpListener::bind(LOCALHOST)
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        forwarder
+            .add_forward_bound(Forward::tcp(bind, vec![target]), ConnOptions::default())
+            .await
+            .unwrap();
+        binds.push(bind);
+    }
+
+    forwarder.shutdown_forward(binds[0].port()).await.unwrap();
+    // The port is free again and the other forward still runs
+    drop(std::net::TcpListener::bind(binds[0]).unwrap());
+    let mut client = TcpStream::connect(binds[1]).await.unwrap();
+    echo(&mut client, b"hello").await;
+    assert_eq!(forwarder.forwards().len(), 1);
+    assert!(forwarder.shutdown_forward(binds[0].port()).await.is_err());
+
+    drop(client);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn aborts_a_busy_connection_after_the_drain_timeout_then_stops_the_meter() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::new();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        drain_timeout: Some(Duration::from_millis(200)),
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+    let source = handle.forward().source.clone();
+
+    // A client that keeps the connection busy until it is aborted
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    echo(&mut client, b"hello").await;
+    let sending = tokio::spawn(async move {
+        let mut buff = [0; 5];
+        while client.write_all(b"again").await.is_ok() && client.read_exact(&mut buff).await.is_ok()
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    });
+    let meter_msg_sender = forwarder.meter_msg_sender();
+
+    tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), sending)
+        .await
+        .unwrap()
+        .unwrap();
+    // The aborted connection is counted as closed before the meter stops
+    wait_totals(&forwarder, &source, |t| t.active_conns == 0).await;
+    forwarder.shutdown().await.unwrap();
+    // A connection outliving the meter cannot reach it, but does not panic
+    assert!(meter_msg_sender.conn_closed(source).await.is_err());
+}
+
+#[tokio::test]
+async fn closes_an_idle_connection_without_counting_an_error() {
+    let target = echo_server().await;
+    let forwarder = Forwarder::new();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    echo(&mut client, b"hello").await;
+    let mut buff = [0; 1];
+    let closed = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buff)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+    let source = handle.forward().source.clone();
+    let totals = wait_totals(&forwarder, &source, |t| t.active_conns == 0).await;
+    assert_eq!(totals.errors, 0);
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_only_the_bytes_that_reached_the_target() {
+    // A target that stalls past the write timeout, then reads what it got
+    let listener = TcpListener::bind(LOCALHOST).await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        sleep(Duration::from_secs(2)).await;
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received).await;
+        received.len()
+    });
+
+    let forwarder = Forwarder::new();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        write_timeout: Some(Duration::from_secs(1)),
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    tokio::spawn(async move {
+        // More than the socket buffers hold, the write to the target times out
+        let _ = client.write_all(&vec![0; 64 * 1024 * 1024]).await;
+        sleep(Duration::from_secs(10)).await;
+    });
+
+    let received = received.await.unwrap();
+    let source = handle.forward().source.clone();
+    let totals = wait_totals(&forwarder, &source, |t| t.active_conns == 0).await;
+    assert!(received > 0);
+    assert_eq!(totals.from_n_bytes, received);
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_every_connection_when_the_meter_falls_behind() {
+    let target = echo_server().await;
+    // Room for one transfer, the others are dropped
+    let forwarder =
+        Forwarder::with_meter_capacity(MeterFormat::Human, MeterGranularity::Forward, 1);
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let (handle, addr) = forwarder
+        .start_bound(forward, ConnOptions::default())
+        .await
+        .unwrap();
+    let addr = addr.unwrap();
+
+    let clients = (0..20).map(|_| async move {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..20 {
+            echo(&mut client, b"opened then closed").await;
+        }
+    });
+    futures::future::join_all(clients).await;
+    let source = handle.forward().source.clone();
+    let totals = wait_totals(&forwarder, &source, |t| {
+        t.total_conns == 20 && t.active_conns == 0
+    })
+    .await;
+    assert_eq!(totals.errors, 0);
+
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn forwards_and_meters_any_stream_pair() {
+    let forwarder = Forwarder::new();
+    let source = Source::Port(9);
+    let (mut client, src) = tokio::io::duplex(64);
+    let (tgt, mut target) = tokio::io::duplex(64);
+    let meter_msg_sender = forwarder.meter_msg_sender();
+    meter_msg_sender
+        .conn_opened(source.clone(), None, None)
+        .await
+        .unwrap();
+    let meter = StreamMeter {
+        meter_msg_sender: meter_msg_sender.clone(),
+        conn_id: next_conn_id().await,
+        peer: PeerAddr::Tcp(LOCALHOST.parse().unwrap()),
+        forward: source.clone(),
+        direction: Direction::From,
+    };
+    let forwarded =
+        tokio::spawn(async move { forward_stream(src, tgt, meter, &ConnOptions::default()).await });
+
+    client.write_all(b"over a duplex").await.unwrap();
+    drop(client);
+    let mut received = Vec::new();
+    target.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"over a duplex");
+    assert_eq!(forwarded.await.unwrap().unwrap(), 13);
+    meter_msg_sender.conn_closed(source.clone()).await.unwrap();
+    wait_totals(&forwarder, &source, |t| t.from_n_bytes == 13).await;
+    let totals = forwarder.meter_handle().unwrap().current_totals().await;
+    assert_eq!(totals[&source], (13, 0));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn ends_a_direction_whose_target_takes_no_more_bytes() {
+    let forwarder = Forwarder::without_meter();
+    let (mut client, src) = tokio::io::duplex(64);
+    let meter = StreamMeter {
+        meter_msg_sender: forwarder.meter_msg_sender(),
+        conn_id: next_conn_id().await,
+        peer: PeerAddr::Tcp(LOCALHOST.parse().unwrap()),
+        forward: Source::Port(9),
+        direction: Direction::From,
+    };
+    let target = StopsAccepting { capacity: 5 };
+    let forwarded =
+        tokio::spawn(
+            async move { forward_stream(src, target, meter, &ConnOptions::default()).await },
+        );
+
+    // The client keeps the stream open, the direction ends all the same
+    client
+        .write_all(b"more than the target takes")
+        .await
+        .unwrap();
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), forwarded).await;
+    assert_eq!(forwarded.unwrap().unwrap().unwrap(), 5);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn holds_at_most_max_inflight_bytes_for_a_slow_target() {
+    let forwarder = Forwarder::without_meter();
+    let provider = Arc::new(CountingBuffers::default());
+    let read = Arc::new(AtomicUsize::new(0));
+    let max_inflight = Arc::new(AtomicUsize::new(0));
+    let src = CountingSource {
+        remaining: 1024 * 1024,
+        read: read.clone(),
+    };
+    let target = ThrottledTarget {
+        read,
+        written: 0,
+        max_inflight: max_inflight.clone(),
+        ready: false,
+    };
+    let meter = StreamMeter {
+        meter_msg_sender: forwarder.meter_msg_sender(),
+        conn_id: next_conn_id().await,
+        peer: PeerAddr::Tcp(LOCALHOST.parse().unwrap()),
+        forward: Source::Port(9),
+        direction: Direction::From,
+    };
+    let options = ConnOptions {
+        buff_size: 64 * 1024,
+        max_inflight: Some(1024),
+        buffer_provider: provider.clone(),
+        ..ConnOptions::default()
+    };
+
+    let forwarded = forward_stream(src, target, meter, &options).await;
+    assert_eq!(forwarded.unwrap(), 1024 * 1024);
+    // The buffer of the direction is cut to its half of the limit
+    assert_eq!(provider.obtained.load(Ordering::Relaxed), 1);
+    assert_eq!(provider.largest.load(Ordering::Relaxed), 512);
+    let max_inflight = max_inflight.load(Ordering::Relaxed);
+    assert!(max_inflight > 0);
+    assert!(max_inflight <= 512, "{} bytes were in flight", max_inflight);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn cuts_the_buffers_of_a_connection_to_max_inflight() {
+    let target = echo_server().await;
+    let provider = Arc::new(CountingBuffers::default());
+    let forwarder = Forwarder::without_meter();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let options = ConnOptions {
+        buff_size: 64 * 1024,
+        max_inflight: Some(1024),
+        buffer_provider: provider.clone(),
+        splice: false,
+        ..ConnOptions::default()
+    };
+    let (handle, addr) = forwarder.start_bound(forward, options).await.unwrap();
+
+    let mut client = TcpStream::connect(addr.unwrap()).await.unwrap();
+    echo(&mut client, &[0x5a; 4096]).await;
+    // Both directions together hold no more than the limit
+    assert_eq!(provider.obtained.load(Ordering::Relaxed), 2);
+    assert_eq!(provider.largest.load(Ordering::Relaxed), 512);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn compresses_between_two_instances() {
+    let target = echo_server().await;
+    let (front, behind) = (Forwarder::new(), Forwarder::new());
+    let options = |mode| ConnOptions {
+        compress: Some(mode),
+        ..ConnOptions::default()
+    };
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![target]);
+    let (behind_handle, behind_addr) = behind
+        .start_bound(forward, options(CompressMode::Client))
+        .await
+        .unwrap();
+    let forward = Forward::tcp(LOCALHOST.parse().unwrap(), vec![behind_addr.unwrap()]);
+    let (front_handle, front_addr) = front
+        .start_bound(forward, options(CompressMode::Target))
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect(front_addr.unwrap()).await.unwrap();
+    let msg = b"compressible ".repeat(10_000);
+    echo(&mut client, &msg).await;
+    drop(client);
+
+    // Both instances count the payload both ways and fewer bytes on the wire
+    let source = front_handle.forward().source.clone();
+    for forwarder in [&front, &behind] {
+        let totals = wait_totals(forwarder, &source, |t| {
+            t.active_conns == 0 && t.to_n_bytes == msg.len()
+        })
+        .await;
+        assert_eq!(totals.from_n_bytes, msg.len());
+        assert!(totals.wire_n_bytes > 0);
+        assert!(totals.wire_n_bytes < msg.len() / 4);
+    }
+
+    front_handle.shutdown().await.unwrap();
+    behind_handle.shutdown().await.unwrap();
+    front.shutdown().await.unwrap();
+    behind.shutdown().await.unwrap();
+}
+
+/// `len` pseudo random bytes, which do not compress
+fn random_bytes(len: usize, mut seed: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        })
+        .collect()
+}
+
+/// Send `payload` through a compressor and a decompressor joined by a pipe
+/// smaller than a frame, writing it `chunk` bytes at a time
+async fn compress_round_trip(payload: &[u8], chunk: usize) -> io::Result<Vec<u8>> {
+    let wire = || WireMeter {
+        meter_msg_sender: MeterMessageSender::disabled(),
+        forward: Source::Port(9),
+    };
+    let (compressed, decompressed) = tokio::io::duplex(1000);
+    let mut compressor = Compressor::new(compressed, wire());
+    let sent = payload.to_vec();
+    let writer = tokio::spawn(async move {
+        for chunk in sent.chunks(chunk) {
+            compressor.write_all(chunk).await.unwrap();
+        }
+        compressor.shutdown().await.unwrap();
+    });
+    let mut received = Vec::new();
+    Decompressor::new(decompressed, wire())
+        .read_to_end(&mut received)
+        .await?;
+    writer.await.unwrap();
+    Ok(received)
+}
+
+#[tokio::test]
+async fn compressed_streams_round_trip() {
+    let block = 64 * 1024;
+    let random = random_bytes(3 * block + 17, 1);
+    // Runs that compress between random bytes that do not
+    let mixed = (0..40u64)
+        .flat_map(|i| match i % 2 {
+            0 => b"compressible ".repeat(i as usize * 50),
+            _ => random_bytes(i as usize * 100, i),
+        })
+        .collect::<Vec<_>>();
+    let mut payloads = vec![random, mixed, Vec::new()];
+    for len in [1, 14, 15, 16, 270, block - 1, block, block + 1] {
+        payloads.push(random_bytes(len, len as u64));
+        payloads.push(vec![b'a'; len]);
+    }
+    for payload in &payloads {
+        for chunk in [7, 4096, 200 * 1024] {
+            let received = compress_round_trip(payload, chunk).await.unwrap();
+            assert!(
+                received == *payload,
+                "{} bytes in chunks of {}",
+                payload.len(),
+                chunk
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_pending_compressed_write_takes_none_of_its_bytes() {
+    let wire = WireMeter {
+        meter_msg_sender: MeterMessageSender::disabled(),
+        forward: Source::Port(9),
+    };
+    let (compressed, decompressed) = tokio::io::duplex(10);
+    let mut compressor = Compressor::new(compressed, wire.clone());
+    // The frame of the first write does not fit in the pipe
+    assert_eq!(compressor.write(b"first write, ").await.unwrap(), 13);
+    let pending = futures::poll!(std::pin::pin!(compressor.write(b"given up on")));
+    assert!(pending.is_pending());
+
+    // A write of other bytes instead sends those
+    let reader = tokio::spawn(async move {
+        let mut received = Vec::new();
+        Decompressor::new(decompressed, wire)
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        received
+    });
+    compressor.write_all(b"then another").await.unwrap();
+    compressor.shutdown().await.unwrap();
+    assert_eq!(reader.await.unwrap(), b"first write, then another");
+}
+
+#[tokio::test]
+async fn rejects_corrupt_compressed_frames() {
+    let wire = WireMeter {
+        meter_msg_sender: MeterMessageSender::disabled(),
+        forward: Source::Port(9),
+    };
+    // A literal then a match of it repeated over 75k times
+    let mut block = vec![0x1f, b'a', 1, 0];
+    block.extend([255; 300]);
+    block.push(0);
+    let mut matches_past_64k = (block.len() as u32).to_be_bytes().to_vec();
+    matches_past_64k.extend(block);
+    let frames: [(&[u8], io::ErrorKind); 4] = [
+        // A match before the start of the block
+        (&[0, 0, 0, 3, 0x00, 1, 0], io::ErrorKind::InvalidData),
+        // More than a block once decompressed
+        (&matches_past_64k, io::ErrorKind::InvalidData),
+        // Longer than a block
+        (&[0, 1, 0, 1, 0], io::ErrorKind::InvalidData),
+        // The stream ends within the frame
+        (&[0, 0, 0, 10, 1, 2], io::ErrorKind::UnexpectedEof),
+    ];
+    for (frame, kind) in frames {
+        let mut decompressor = Decompressor::new(frame, wire.clone());
+        let e = decompressor.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(e.kind(), kind, "{:?}", frame);
+    }
+}
+
+/// The name and type of every query a DNS server got
+type DnsQueries = Arc<std::sync::Mutex<Vec<(String, u16)>>>;
+
+/// Start a DNS server on an ephemeral UDP port that answers every A query
+/// with 127.0.0.1, returning its address and the queries it got
+fn counting_dns_server() -> (SocketAddr, DnsQueries) {
+    let socket = std::net::UdpSocket::bind(LOCALHOST).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server_queries = queries.clone();
+    std::thread::spawn(move || {
+        let mut buff = [0; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buff) {
+            // The question follows the 12 byte header, a name as labels then
+            // its type and class
+            let mut labels = Vec::new();
+            let mut i = 12;
+            while i < len && buff[i] != 0 {
+                let label_len = buff[i] as usize;
+                labels.push(String::from_utf8_lossy(&buff[i + 1..i + 1 + label_len]).to_string());
+                i += 1 + label_len;
+            }
+            let question_end = i + 5;
+            let query_type = u16::from_be_bytes([buff[i + 1], buff[i + 2]]);
+            server_queries
+                .lock()
+                .unwrap()
+                .push((labels.join("."), query_type));
+            let is_a = query_type == 1;
+            let mut reply = buff[..question_end].to_vec();
+            // A response to a recursive query, with the one question
+            reply[2..4].copy_from_slice(&[0x81, 0x80]);
+            reply[4..12].copy_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+            if is_a {
+                // The name of the question, A, IN, a TTL of 60s and the address
+                reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            let _ = socket.send_to(&reply, peer);
+        }
+    });
+    (addr, queries)
+}
+
+#[test]
+fn resolves_each_host_once_per_config() {
+    let (dns_server, queries) = counting_dns_server();
+    let args = [
+        "18401:backend.test:80",
+        "18402:backend.test:81",
+        "18403:backend.test:82,other.test:82",
+        "18404:other.test:83",
+        "--dns-server",
+        &dns_server.to_string(),
+    ]
+    .map(String::from);
+    let config = get_config(&args).unwrap();
+
+    assert_eq!(config.forwards.len(), 4);
+    for forward in &config.forwards {
+        assert!(forward.targets.iter().all(|t| t.ip().is_loopback()));
+    }
+    let mut queries = queries
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, query_type)| *query_type == 1)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    queries.sort();
+    assert_eq!(queries, ["backend.test", "other.test"]);
+}
+
+#[test]
+fn parses_bracketed_ipv6_targets() {
+    for (arg, target) in [
+        ("8080:[::1]:80", "[::1]:80"),
+        ("8080:[2001:db8::1]:443", "[2001:db8::1]:443"),
+    ] {
+        let config = get_config(&[arg.to_string()]).unwrap();
+        let target: SocketAddr = target.parse().unwrap();
+        assert_eq!(config.forwards.len(), 1);
+        assert_eq!(config.forwards[0].source, Source::Port(8080));
+        assert_eq!(config.forwards[0].targets, vec![target]);
+    }
+}
+
+#[test]
+fn skips_comments_and_blank_lines_in_a_config_file() {
+    let path = std::env::temp_dir().join(format!("forwards-{}.conf", std::process::id()));
+    std::fs::write(
+        &path,
+        "# web\n8081:127.0.0.1:80\n\n   \n  # admin, on its own port  \n8082:127.0.0.1:81  \n",
+    )
+    .unwrap();
+    let config = get_config(&["-f".to_string(), path.display().to_string()]);
+    std::fs::remove_file(&path).unwrap();
+
+    let config = config.unwrap();
+    let forwards = config
+        .forwards
+        .iter()
+        .map(|f| (f.source.clone(), f.targets.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        forwards,
+        vec![
+            (Source::Port(8081), vec!["127.0.0.1:80".parse().unwrap()]),
+            (Source::Port(8082), vec!["127.0.0.1:81".parse().unwrap()]),
+        ]
+    );
+}
+
+#[test]
+fn uses_literal_target_addresses_without_a_dns_query() {
+    let (dns_server, queries) = counting_dns_server();
+    let args = [
+        "18411:127.0.0.1:80",
+        "18412:[::1]:80",
+        "--dns-server",
+        &dns_server.to_string(),
+    ]
+    .map(String::from);
+    let config = get_config(&args).unwrap();
+
+    let targets = config
+        .forwards
+        .iter()
+        .flat_map(|f| f.targets.clone())
+        .collect::<Vec<SocketAddr>>();
+    assert_eq!(
+        targets,
+        vec!["127.0.0.1:80".parse().unwrap(), "[::1]:80".parse().unwrap()]
+    );
+    assert!(queries.lock().unwrap().is_empty());
+}
+
+#[test]
+fn reload_keeps_the_forwards_read_from_stdin() {
+    let stdin = "8091:127.0.0.1:80\n8092:127.0.0.1:81\n";
+    for (args, ports) in [
+        (vec!["-f", "-"], vec![8091, 8092]),
+        (vec!["8090:127.0.0.1:79", "-f", "-"], vec![8090, 8091, 8092]),
+    ] {
+        let args = args.into_iter().map(String::from).collect::<Vec<_>>();
+        let config = get_config_with_stdin(&args, stdin.as_bytes()).unwrap();
+        assert!(config.config_from_stdin);
+
+        // Stdin is at its end by now, the reload parses the text read at startup
+        let (forwards, _) = config.reload_forwards().unwrap();
+        let sources = forwards.iter().map(|f| f.source.clone());
+        assert_eq!(
+            sources.collect::<Vec<_>>(),
+            ports.into_iter().map(Source::Port).collect::<Vec<_>>()
+        );
+        assert_eq!(forwards, config.forwards);
+    }
+}